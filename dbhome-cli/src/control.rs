@@ -0,0 +1,39 @@
+//! Command session over TCP.
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use dbhome_common::reply;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send one command line, returning the reply payload or the error text.
+pub fn command(target: SocketAddr, line: &str) -> Result<Result<String, String>> {
+    let mut stream = TcpStream::connect_timeout(&target, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    let mut received = Vec::new();
+    let mut buf = [0; 512];
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            bail!("connection closed before the reply ended");
+        }
+        received.extend_from_slice(&buf[..n]);
+
+        let text = String::from_utf8_lossy(&received);
+        if let Some(code) = reply::complete(&text) {
+            let payload = reply::lines(&text).collect::<Vec<_>>().join("\n");
+            return Ok(if reply::is_ok(code) {
+                Ok(payload)
+            } else {
+                Err(format!("{code} {payload}"))
+            });
+        }
+    }
+}
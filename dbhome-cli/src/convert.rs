@@ -0,0 +1,76 @@
+//! Image conversion to the panel 1bpp layout.
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use dbhome_common::{
+    dither::{self, Method},
+    EPD_HEIGHT, EPD_WIDTH, FRAME_LEN,
+};
+use image::{imageops::FilterType, GrayImage, Luma};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Dither {
+    Threshold,
+    FloydSteinberg,
+    Atkinson,
+    Bayer,
+}
+
+#[derive(Args)]
+pub struct Options {
+    #[arg(long, value_enum, default_value_t = Dither::FloydSteinberg)]
+    dither: Dither,
+    /// Grey level above which a pixel is white, with --dither threshold
+    #[arg(long, default_value_t = 128)]
+    threshold: u8,
+    /// Gamma applied before dithering, below 1 lightens the image
+    #[arg(long)]
+    gamma: Option<f32>,
+    /// Stretch the grey levels to the full range before dithering
+    #[arg(long)]
+    stretch: bool,
+}
+
+/// Load any supported image, scaled to the panel size in shades of grey.
+pub fn load(path: &Path) -> Result<GrayImage> {
+    let img = image::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    Ok(img
+        .resize_exact(EPD_WIDTH as u32, EPD_HEIGHT as u32, FilterType::Triangle)
+        .to_luma8())
+}
+
+/// Adjust, dither and pack the image in a frame.
+pub fn to_frame(img: GrayImage, opts: &Options) -> Vec<u8> {
+    let mut pixels = img.into_raw();
+    if opts.stretch {
+        dither::stretch_contrast(&mut pixels);
+    }
+    if let Some(gamma) = opts.gamma {
+        dither::gamma(&mut pixels, gamma);
+    }
+
+    let method = match opts.dither {
+        Dither::Threshold => Method::Threshold(opts.threshold),
+        Dither::FloydSteinberg => Method::FloydSteinberg,
+        Dither::Atkinson => Method::Atkinson,
+        Dither::Bayer => Method::Bayer,
+    };
+    dither::dither(&mut pixels, EPD_WIDTH, method);
+
+    let mut frame = vec![0; FRAME_LEN];
+    dither::pack(&pixels, EPD_WIDTH, &mut frame);
+    frame
+}
+
+/// Unpack a frame to an image, to check what the panel will show.
+pub fn preview(frame: &[u8]) -> GrayImage {
+    GrayImage::from_fn(EPD_WIDTH as u32, EPD_HEIGHT as u32, |x, y| {
+        let idx = y as usize * EPD_WIDTH + x as usize;
+        if frame[idx / 8] & (0x80 >> (idx % 8)) != 0 {
+            Luma([0xff])
+        } else {
+            Luma([0x00])
+        }
+    })
+}
@@ -0,0 +1,38 @@
+//! Panel lookup over mDNS.
+use std::{
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use dbhome_common::dns;
+
+const ATTEMPTS: u32 = 3;
+
+/// Ask `<name>.local` on the mDNS group and wait for its address.
+pub fn lookup(name: &str, timeout: Duration) -> Result<Ipv4Addr> {
+    let fqdn = format!("{name}.local");
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    let group = SocketAddrV4::new(dns::MDNS_ADDR.into(), dns::MDNS_PORT);
+
+    let mut query = [0; 512];
+    let n = dns::encode_query(0, &fqdn, &mut query).with_context(|| format!("bad name {fqdn}"))?;
+
+    let mut buf = [0; 1500];
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&query[..n], group)?;
+
+        let deadline = Instant::now() + timeout / ATTEMPTS;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+            let Ok(len) = socket.recv(&mut buf) else {
+                break;
+            };
+            if let Ok(Some(addr)) = dns::parse_response(&buf[..len], &fqdn) {
+                return Ok(addr.into());
+            }
+        }
+    }
+
+    bail!("no answer for {fqdn}")
+}
@@ -0,0 +1,191 @@
+//! Host companion of the dbhome-epd firmware: converts and uploads images,
+//! and talks to the command session.
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use dbhome_common::{compress::Compression, CONTROL_PORT, DEFAULT_HOSTNAME, FRAME_PORT};
+
+mod control;
+mod convert;
+mod discover;
+mod upload;
+
+#[derive(Parser)]
+#[command(version, about = "Drive a dbhome-epd panel")]
+struct Cli {
+    /// Address of the panel, looked up over mDNS when missing
+    #[arg(long, short = 'H', env = "DBHOME_HOST", global = true)]
+    host: Option<IpAddr>,
+    /// mDNS name of the panel, without the .local suffix
+    #[arg(long, default_value = DEFAULT_HOSTNAME, global = true)]
+    name: String,
+    /// Port of the command session
+    #[arg(long, default_value_t = CONTROL_PORT, global = true)]
+    port: u16,
+    /// Port frames are uploaded to
+    #[arg(long, default_value_t = FRAME_PORT, global = true)]
+    frame_port: u16,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert an image and show it on the panel
+    Image {
+        path: PathBuf,
+        #[arg(long, value_enum, default_value_t = Codec::Rle)]
+        compress: Codec,
+        #[command(flatten)]
+        convert: convert::Options,
+        /// Also save the converted image as PNG
+        #[arg(long)]
+        save: Option<PathBuf>,
+        /// Pause in milliseconds between bursts of datagrams, so the panel keeps up
+        #[arg(long, default_value_t = upload::DEFAULT_DELAY_MS)]
+        delay: u64,
+    },
+    /// Convert an image without sending it, to PNG or raw 1bpp by extension
+    Convert {
+        path: PathBuf,
+        output: PathBuf,
+        #[command(flatten)]
+        convert: convert::Options,
+    },
+    /// Switch a led on or off
+    Led { color: Led, state: State },
+    /// Ask the panel for its status
+    Status,
+    /// Send a raw command line
+    Cmd {
+        #[arg(required = true, trailing_var_arg = true)]
+        args: Vec<String>,
+    },
+    /// Print the address of the panel found over mDNS
+    Discover,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Codec {
+    None,
+    Rle,
+    Lzss,
+}
+
+impl From<Codec> for Compression {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::None => Compression::None,
+            Codec::Rle => Compression::Rle,
+            Codec::Lzss => Compression::Lzss,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Led {
+    Red,
+    Green,
+    Blue,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum State {
+    On,
+    Off,
+}
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+impl Cli {
+    fn target(&self) -> Result<IpAddr> {
+        if let Some(host) = self.host {
+            return Ok(host);
+        }
+        let addr = discover::lookup(&self.name, LOOKUP_TIMEOUT)
+            .with_context(|| format!("{}.local not found, pass --host", self.name))?;
+        Ok(addr.into())
+    }
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    match &cli.command {
+        Command::Image {
+            path,
+            compress,
+            convert,
+            save,
+            delay,
+        } => {
+            let frame = convert::to_frame(convert::load(path)?, convert);
+            if let Some(save) = save {
+                convert::preview(&frame).save(save)?;
+            }
+            let target = SocketAddr::new(cli.target()?, cli.frame_port);
+            upload::send(
+                &frame,
+                target,
+                (*compress).into(),
+                Duration::from_millis(*delay),
+            )
+        }
+        Command::Convert {
+            path,
+            output,
+            convert,
+        } => {
+            let frame = convert::to_frame(convert::load(path)?, convert);
+            if output.extension().is_some_and(|e| e == "png") {
+                convert::preview(&frame).save(output)?;
+            } else {
+                std::fs::write(output, &frame)?;
+            }
+            Ok(())
+        }
+        Command::Led { color, state } => {
+            let line = format!("led {} {}", arg_name(color), arg_name(state));
+            command(cli, &line)
+        }
+        Command::Status => command(cli, "system status"),
+        Command::Cmd { args } => command(cli, &args.join(" ")),
+        Command::Discover => {
+            println!("{}", cli.target()?);
+            Ok(())
+        }
+    }
+}
+
+fn arg_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_owned())
+        .unwrap_or_default()
+}
+
+fn command(cli: &Cli, line: &str) -> Result<()> {
+    let target = SocketAddr::new(cli.target()?, cli.port);
+    match control::command(target, line)? {
+        Ok(reply) => {
+            println!("{reply}");
+            Ok(())
+        }
+        Err(reply) => bail!("{reply}"),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! Framebuffer upload over the UDP frame protocol.
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use dbhome_common::{
+    compress::{self, Compression},
+    frame::{self, FrameError, Packet, MAX_CHUNKS, MAX_PACKET_LEN, MAX_PAYLOAD_LEN},
+};
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRIES: usize = 5;
+
+/// Receive buffer of the panel frame socket, in bytes and in datagrams.
+const DEVICE_RX_BYTES: usize = 4096;
+const DEVICE_RX_SLOTS: usize = 10;
+
+/// Pause between bursts in milliseconds unless told otherwise, as client.py did.
+pub const DEFAULT_DELAY_MS: u64 = 100;
+
+struct Chunk {
+    offset: usize,
+    data: Vec<u8>,
+}
+
+fn make_chunks(frame: &[u8], compression: Compression) -> Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset < frame.len() {
+        let mut data = vec![0; MAX_PAYLOAD_LEN];
+        let (used, written) = compress::compress(compression, &frame[offset..], &mut data);
+        data.truncate(written);
+        chunks.push(Chunk { offset, data });
+        offset += used;
+    }
+
+    if chunks.len() > MAX_CHUNKS {
+        bail!("frame needs {} chunks, at most {MAX_CHUNKS}", chunks.len());
+    }
+    Ok(chunks)
+}
+
+/// Keeps a burst of datagrams within the panel receive buffer, then waits
+/// `delay` for it to drain before the next one.
+struct Pacer {
+    delay: Duration,
+    bytes: usize,
+    slots: usize,
+}
+
+impl Pacer {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            bytes: 0,
+            slots: 0,
+        }
+    }
+
+    /// Account for a datagram of `len` bytes, returning whether a pause
+    /// comes first.
+    fn take(&mut self, len: usize) -> bool {
+        let pause =
+            self.slots > 0 && (self.bytes + len > DEVICE_RX_BYTES || self.slots == DEVICE_RX_SLOTS);
+        if pause {
+            self.reset();
+        }
+        self.bytes += len;
+        self.slots += 1;
+        pause
+    }
+
+    fn send(&mut self, socket: &UdpSocket, datagram: &[u8], target: SocketAddr) -> Result<()> {
+        if self.take(datagram.len()) {
+            thread::sleep(self.delay);
+        }
+        socket.send_to(datagram, target)?;
+        Ok(())
+    }
+
+    /// The panel answered, so everything sent so far was read.
+    fn reset(&mut self) {
+        self.bytes = 0;
+        self.slots = 0;
+    }
+}
+
+/// Send a whole frame, resending what the panel reports missing, until it is acked.
+///
+/// Datagrams go out in bursts that fit the panel receive buffer, `delay`
+/// apart: the panel drops whatever overflows it.
+pub fn send(
+    frame: &[u8],
+    target: SocketAddr,
+    compression: Compression,
+    delay: Duration,
+) -> Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    let frame_id = std::process::id() as u16 ^ std::time::UNIX_EPOCH.elapsed()?.as_millis() as u16;
+    send_on(&socket, frame_id, frame, target, compression, delay)
+}
+
+fn send_on(
+    socket: &UdpSocket,
+    frame_id: u16,
+    frame: &[u8],
+    target: SocketAddr,
+    compression: Compression,
+    delay: Duration,
+) -> Result<()> {
+    socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+
+    let chunks = make_chunks(frame, compression)?;
+    let round = Round {
+        frame_id,
+        chunks: chunks.len() as u16,
+        crc: frame::crc32(frame),
+    };
+    let commit = Packet::Commit {
+        frame_id,
+        chunks: round.chunks,
+        len: frame.len() as u32,
+        crc: round.crc,
+    };
+
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut pending = frame::chunk_mask(round.chunks);
+    let mut pacer = Pacer::new(delay);
+
+    for _ in 0..RETRIES {
+        for (idx, chunk) in chunks.iter().enumerate() {
+            if pending & (1 << idx) == 0 {
+                continue;
+            }
+            let n = Packet::Data {
+                frame_id,
+                chunk: idx as u16,
+                offset: chunk.offset as u32,
+                compression,
+                payload: &chunk.data,
+            }
+            .encode(&mut buf)?;
+            pacer.send(socket, &buf[..n], target)?;
+        }
+
+        // Replies already queued answer the chunks or an earlier Commit
+        let mut rejected = 0;
+        match round.drain(socket)? {
+            Some(Reply::Ack) => return Ok(()),
+            Some(Reply::Chunks(missing)) => rejected = missing,
+            _ => {}
+        }
+
+        let n = commit.encode(&mut buf)?;
+        pacer.send(socket, &buf[..n], target)?;
+
+        pending = match round.wait(socket, &mut rejected) {
+            Some(Reply::Ack) => return Ok(()),
+            Some(Reply::Nack(error, missing)) => {
+                let missing = missing | rejected;
+                eprintln!("nack {error:?}, resending {} chunks", missing.count_ones());
+                missing
+            }
+            _ => {
+                eprintln!("no reply, resending frame");
+                frame::chunk_mask(round.chunks)
+            }
+        };
+        pacer.reset();
+    }
+
+    bail!("frame not acknowledged after {RETRIES} attempts")
+}
+
+/// What a reply means for the frame being sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reply {
+    /// The frame is on the panel.
+    Ack,
+    /// Answer to a Commit, with the chunks to send again.
+    Nack(FrameError, u64),
+    /// Data chunks the panel rejected, the Commit answer is still to come.
+    Chunks(u64),
+}
+
+/// The frame being sent, to tell its replies from stale ones.
+struct Round {
+    frame_id: u16,
+    chunks: u16,
+    crc: u32,
+}
+
+impl Round {
+    fn classify(&self, packet: &Packet) -> Option<Reply> {
+        match *packet {
+            Packet::Ack {
+                frame_id,
+                chunks,
+                crc,
+            } if frame_id == self.frame_id && chunks == self.chunks && crc == self.crc => {
+                Some(Reply::Ack)
+            }
+            Packet::Nack {
+                frame_id,
+                error,
+                missing,
+            } if frame_id == self.frame_id => {
+                // A Data chunk is refused alone, a Commit with one of its
+                // own errors or with every chunk of the frame
+                let commit = matches!(
+                    error,
+                    FrameError::Missing | FrameError::UnknownFrame | FrameError::FrameCrc
+                ) || missing.count_ones() != 1
+                    || missing == frame::chunk_mask(self.chunks);
+                Some(if commit {
+                    Reply::Nack(error, missing)
+                } else {
+                    Reply::Chunks(missing)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn recv(&self, socket: &UdpSocket) -> std::io::Result<Option<Reply>> {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let n = socket.recv(&mut buf)?;
+        Ok(Packet::decode(&buf[..n])
+            .ok()
+            .and_then(|packet| self.classify(&packet)))
+    }
+
+    /// Read the replies already queued without waiting. Commit answers are
+    /// from a round given up on and dropped, rejected chunks are merged.
+    fn drain(&self, socket: &UdpSocket) -> Result<Option<Reply>> {
+        socket.set_nonblocking(true)?;
+        let mut rejected = 0;
+        let ret = loop {
+            match self.recv(socket) {
+                Ok(Some(Reply::Ack)) => break Ok(Some(Reply::Ack)),
+                Ok(Some(Reply::Chunks(missing))) => rejected |= missing,
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    break Ok((rejected != 0).then_some(Reply::Chunks(rejected)))
+                }
+                Err(e) => break Err(e.into()),
+            }
+        };
+        socket.set_nonblocking(false)?;
+        ret
+    }
+
+    /// Wait for the answer to the Commit just sent, merging the chunks
+    /// rejected meanwhile in `rejected`.
+    fn wait(&self, socket: &UdpSocket, rejected: &mut u64) -> Option<Reply> {
+        loop {
+            match self.recv(socket) {
+                Ok(Some(Reply::Chunks(missing))) => *rejected |= missing,
+                Ok(Some(reply)) => return Some(reply),
+                Ok(None) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dbhome_common::frame::{Action, Assembler};
+
+    use super::*;
+
+    const ID: u16 = 0x1234;
+
+    fn round() -> Round {
+        Round {
+            frame_id: ID,
+            chunks: 3,
+            crc: 0xdead_beef,
+        }
+    }
+
+    fn nack(frame_id: u16, error: FrameError, missing: u64) -> Packet<'static> {
+        Packet::Nack {
+            frame_id,
+            error,
+            missing,
+        }
+    }
+
+    #[test]
+    fn classify() {
+        let round = round();
+        let ack = |frame_id, chunks, crc| Packet::Ack {
+            frame_id,
+            chunks,
+            crc,
+        };
+        assert_eq!(round.classify(&ack(ID, 3, 0xdead_beef)), Some(Reply::Ack));
+        assert_eq!(round.classify(&ack(ID + 1, 3, 0xdead_beef)), None);
+        assert_eq!(round.classify(&ack(ID, 3, 0)), None);
+
+        assert_eq!(
+            round.classify(&nack(ID, FrameError::ChunkCrc, 0b010)),
+            Some(Reply::Chunks(0b010))
+        );
+        assert_eq!(
+            round.classify(&nack(ID, FrameError::Missing, 0b010)),
+            Some(Reply::Nack(FrameError::Missing, 0b010))
+        );
+        assert_eq!(
+            round.classify(&nack(ID, FrameError::OutOfRange, 0b111)),
+            Some(Reply::Nack(FrameError::OutOfRange, 0b111))
+        );
+        // A garbled Commit has no chunk to blame
+        assert_eq!(
+            round.classify(&nack(ID, FrameError::Short, 0)),
+            Some(Reply::Nack(FrameError::Short, 0))
+        );
+        assert_eq!(round.classify(&nack(ID + 1, FrameError::Missing, 1)), None);
+    }
+
+    #[test]
+    fn pacing() {
+        let mut pacer = Pacer::new(Duration::ZERO);
+        // Four full datagrams fill the 4096 bytes
+        let full: Vec<bool> = (0..9).map(|_| pacer.take(MAX_PACKET_LEN)).collect();
+        assert_eq!(
+            full,
+            [false, false, false, false, true, false, false, false, true]
+        );
+
+        // Small ones are bound by the 10 slots
+        let mut pacer = Pacer::new(Duration::ZERO);
+        let small: Vec<usize> = (0..25).filter(|_| pacer.take(40)).collect();
+        // Pauses before the 11th and the 21st
+        assert_eq!(small, [10, 20]);
+        assert_eq!(pacer.slots, 5);
+
+        pacer.reset();
+        assert!(!pacer.take(MAX_PACKET_LEN));
+    }
+
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    /// Panel side on a local socket, `drop` tells which datagrams, counted
+    /// from 0, never reach the assembler. Returns the frame once acked.
+    fn device(drop: fn(usize, &Packet) -> bool) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let socket = socket();
+        let addr = socket.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut assembler = Assembler::new();
+            let mut frame = vec![0; dbhome_common::EPD_WIDTH * dbhome_common::EPD_HEIGHT / 8];
+            let mut buf = [0; MAX_PACKET_LEN];
+            let mut out = [0; MAX_PACKET_LEN];
+            for seen in 0.. {
+                let (n, peer) = socket.recv_from(&mut buf).unwrap();
+                let packet = Packet::decode(&buf[..n]).unwrap();
+                if drop(seen, &packet) {
+                    continue;
+                }
+                match assembler.handle(&mut frame, &packet) {
+                    Action::None => {}
+                    Action::Reply(reply) => {
+                        let n = reply.encode(&mut out).unwrap();
+                        socket.send_to(&out[..n], peer).unwrap();
+                    }
+                    Action::Complete(ack) => {
+                        let n = ack.encode(&mut out).unwrap();
+                        socket.send_to(&out[..n], peer).unwrap();
+                        return frame;
+                    }
+                }
+            }
+            unreachable!()
+        });
+        (addr, handle)
+    }
+
+    fn test_frame() -> Vec<u8> {
+        (0..dbhome_common::EPD_WIDTH * dbhome_common::EPD_HEIGHT / 8)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn uploads() {
+        let frame = test_frame();
+        let (addr, device) = device(|_, _| false);
+        send_on(
+            &socket(),
+            ID,
+            &frame,
+            addr,
+            Compression::None,
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(device.join().unwrap(), frame);
+    }
+
+    #[test]
+    fn resends_missing_chunks() {
+        let frame = test_frame();
+        // Chunks 2 and 5 of the first round get lost
+        let (addr, device) =
+            device(|seen, packet| seen < 6 && matches!(packet, Packet::Data { chunk: 2 | 5, .. }));
+        send_on(
+            &socket(),
+            ID,
+            &frame,
+            addr,
+            Compression::Rle,
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(device.join().unwrap(), frame);
+    }
+
+    #[test]
+    fn stale_nack_is_skipped() {
+        let device = socket();
+        let client = socket();
+        let round = round();
+        let mut out = [0; MAX_PACKET_LEN];
+        let mut reply = |packet: Packet| {
+            let n = packet.encode(&mut out).unwrap();
+            device
+                .send_to(&out[..n], client.local_addr().unwrap())
+                .unwrap();
+        };
+
+        // Left over from a round given up on, then this round's chunk error
+        reply(nack(ID, FrameError::Missing, 0b111));
+        reply(nack(ID, FrameError::ChunkCrc, 0b001));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(round.drain(&client).unwrap(), Some(Reply::Chunks(0b001)));
+        assert_eq!(round.drain(&client).unwrap(), None);
+
+        // Another chunk error before the Commit answer
+        reply(nack(ID, FrameError::Compression, 0b100));
+        reply(nack(ID + 1, FrameError::Missing, 0b111));
+        reply(nack(ID, FrameError::Missing, 0b010));
+        let mut rejected = 0b001;
+        assert_eq!(
+            round.wait(&client, &mut rejected),
+            Some(Reply::Nack(FrameError::Missing, 0b010))
+        );
+        assert_eq!(rejected, 0b101);
+    }
+}
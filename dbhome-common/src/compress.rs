@@ -0,0 +1,483 @@
+//! Chunk compression for framebuffer uploads.
+//!
+//! Every chunk is compressed on its own, so a chunk can be decompressed
+//! straight into the framebuffer at its offset without any other buffer,
+//! and losing one chunk does not spoil the others.
+//!
+//! Run-length, PackBits style. A control byte `c` is followed by:
+//! - `c < 0x80`: `c + 1` literal bytes,
+//! - `c >= 0x80`: one byte repeated `c - 0x80 + 2` times.
+//!
+//! LZSS, the heatshrink family with a byte aligned stream. A flag byte
+//! announces the next 8 items, MSB first: a set bit is a literal byte, a
+//! cleared bit a two byte back-reference `dddddddd ddddllll` copying
+//! `l + 3` bytes from `d + 1` bytes back in the output of the same chunk.
+
+use crate::frame::FrameError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Rle,
+    Lzss,
+}
+
+/// Low bits of the Data packet flags hold the compression.
+pub const FLAGS_MASK: u8 = 0x03;
+
+impl Compression {
+    pub fn flags(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Rle => 1,
+            Compression::Lzss => 2,
+        }
+    }
+
+    pub fn from_flags(flags: u8) -> Result<Self, FrameError> {
+        match flags & FLAGS_MASK {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Rle),
+            2 => Ok(Compression::Lzss),
+            _ => Err(FrameError::Compression),
+        }
+    }
+}
+
+const RLE_MAX_LITERAL: usize = 128;
+const RLE_MAX_RUN: usize = 129;
+
+const LZ_MIN_MATCH: usize = 3;
+const LZ_MAX_MATCH: usize = LZ_MIN_MATCH + 0x0f;
+const LZ_WINDOW: usize = 4096;
+
+/// Decompress `input` into `out`, returning the number of bytes written.
+pub fn decompress(
+    compression: Compression,
+    input: &[u8],
+    out: &mut [u8],
+) -> Result<usize, FrameError> {
+    match compression {
+        Compression::None => {
+            let dst = out.get_mut(..input.len()).ok_or(FrameError::OutOfRange)?;
+            dst.copy_from_slice(input);
+            Ok(input.len())
+        }
+        Compression::Rle => rle_decompress(input, out),
+        Compression::Lzss => lzss_decompress(input, out),
+    }
+}
+
+/// Compress as much of `input` as fits in `out`.
+///
+/// Returns how many input bytes were consumed and how many output bytes
+/// were written, so a frame can be cut in chunks that fit a datagram.
+pub fn compress(compression: Compression, input: &[u8], out: &mut [u8]) -> (usize, usize) {
+    match compression {
+        Compression::None => {
+            let n = core::cmp::min(input.len(), out.len());
+            out[..n].copy_from_slice(&input[..n]);
+            (n, n)
+        }
+        Compression::Rle => rle_compress(input, out),
+        Compression::Lzss => lzss_compress(input, out),
+    }
+}
+
+fn rle_decompress(input: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+    let mut src = 0;
+    let mut dst = 0;
+
+    while src < input.len() {
+        let ctrl = input[src] as usize;
+        src += 1;
+
+        if ctrl < 0x80 {
+            let n = ctrl + 1;
+            let lit = input.get(src..src + n).ok_or(FrameError::Compression)?;
+            out.get_mut(dst..dst + n)
+                .ok_or(FrameError::OutOfRange)?
+                .copy_from_slice(lit);
+            src += n;
+            dst += n;
+        } else {
+            let n = ctrl - 0x80 + 2;
+            let value = *input.get(src).ok_or(FrameError::Compression)?;
+            out.get_mut(dst..dst + n)
+                .ok_or(FrameError::OutOfRange)?
+                .fill(value);
+            src += 1;
+            dst += n;
+        }
+    }
+
+    Ok(dst)
+}
+
+fn run_len(input: &[u8]) -> usize {
+    input
+        .iter()
+        .take(RLE_MAX_RUN)
+        .take_while(|b| **b == input[0])
+        .count()
+}
+
+fn rle_compress(input: &[u8], out: &mut [u8]) -> (usize, usize) {
+    let mut src = 0;
+    let mut dst = 0;
+
+    while src < input.len() {
+        let run = run_len(&input[src..]);
+        if run >= 2 {
+            if dst + 2 > out.len() {
+                break;
+            }
+            out[dst] = (0x80 + run - 2) as u8;
+            out[dst + 1] = input[src];
+            src += run;
+            dst += 2;
+            continue;
+        }
+
+        // Literals up to the next run worth encoding
+        let mut n = 1;
+        while src + n < input.len() && n < RLE_MAX_LITERAL && run_len(&input[src + n..]) < 3 {
+            n += 1;
+        }
+        if dst + 2 > out.len() {
+            break;
+        }
+        n = core::cmp::min(n, out.len() - dst - 1);
+        out[dst] = (n - 1) as u8;
+        out[dst + 1..dst + 1 + n].copy_from_slice(&input[src..src + n]);
+        src += n;
+        dst += n + 1;
+    }
+
+    (src, dst)
+}
+
+fn lzss_decompress(input: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+    let mut src = 0;
+    let mut dst = 0;
+
+    while src < input.len() {
+        let flags = input[src];
+        src += 1;
+
+        for bit in (0..8).rev() {
+            if src >= input.len() {
+                break;
+            }
+
+            if flags & (1 << bit) != 0 {
+                *out.get_mut(dst).ok_or(FrameError::OutOfRange)? = input[src];
+                src += 1;
+                dst += 1;
+                continue;
+            }
+
+            let pair = input.get(src..src + 2).ok_or(FrameError::Compression)?;
+            let dist = (((pair[0] as usize) << 4) | (pair[1] as usize >> 4)) + 1;
+            let len = (pair[1] as usize & 0x0f) + LZ_MIN_MATCH;
+            src += 2;
+
+            if dist > dst {
+                return Err(FrameError::Compression);
+            }
+            if dst + len > out.len() {
+                return Err(FrameError::OutOfRange);
+            }
+            // Byte by byte, the match may overlap what it is writing
+            for i in dst..dst + len {
+                out[i] = out[i - dist];
+            }
+            dst += len;
+        }
+    }
+
+    Ok(dst)
+}
+
+/// Longest match for `input[pos..]` in the window behind it, as (distance, length).
+fn lzss_match(input: &[u8], pos: usize) -> (usize, usize) {
+    let start = pos.saturating_sub(LZ_WINDOW);
+    let max = core::cmp::min(LZ_MAX_MATCH, input.len() - pos);
+    let mut best = (0, 0);
+
+    for cand in (start..pos).rev() {
+        let len = (0..max)
+            .take_while(|i| input[cand + i] == input[pos + i])
+            .count();
+        if len > best.1 {
+            best = (pos - cand, len);
+            if len == max {
+                break;
+            }
+        }
+    }
+
+    best
+}
+
+fn lzss_compress(input: &[u8], out: &mut [u8]) -> (usize, usize) {
+    let mut src = 0;
+    let mut dst = 0;
+    let mut flags_at = 0;
+    let mut bit = 0;
+
+    while src < input.len() {
+        if bit == 0 {
+            // A new flag byte plus at least one literal
+            if dst + 2 > out.len() {
+                break;
+            }
+            flags_at = dst;
+            out[flags_at] = 0;
+            dst += 1;
+            bit = 8;
+        }
+
+        let (dist, len) = lzss_match(input, src);
+        if len >= LZ_MIN_MATCH && dst + 2 <= out.len() {
+            let d = dist - 1;
+            out[dst] = (d >> 4) as u8;
+            out[dst + 1] = ((d << 4) as u8) | (len - LZ_MIN_MATCH) as u8;
+            dst += 2;
+            src += len;
+        } else if dst < out.len() {
+            out[flags_at] |= 1 << (bit - 1);
+            out[dst] = input[src];
+            dst += 1;
+            src += 1;
+        } else {
+            break;
+        }
+        bit -= 1;
+    }
+
+    (src, dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const ALL: [Compression; 3] = [Compression::None, Compression::Rle, Compression::Lzss];
+
+    /// Compress `input` in pieces of at most `out_len` bytes and check each
+    /// one decompresses back, returns the compressed size.
+    fn roundtrip(compression: Compression, input: &[u8], out_len: usize) -> usize {
+        let mut pos = 0;
+        let mut total = 0;
+        while pos < input.len() {
+            let mut out = vec![0; out_len];
+            let (used, written) = compress(compression, &input[pos..], &mut out);
+            assert!(used > 0 && written <= out_len);
+
+            let mut back = vec![0; used];
+            assert_eq!(
+                decompress(compression, &out[..written], &mut back),
+                Ok(used)
+            );
+            assert_eq!(back, input[pos..pos + used]);
+            pos += used;
+            total += written;
+        }
+        total
+    }
+
+    /// Deterministic noise.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut seed = 12345u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flags() {
+        for compression in ALL {
+            assert_eq!(
+                Compression::from_flags(compression.flags()),
+                Ok(compression)
+            );
+        }
+        assert_eq!(Compression::from_flags(0x04 | 1), Ok(Compression::Rle));
+        assert_eq!(Compression::from_flags(3), Err(FrameError::Compression));
+    }
+
+    #[test]
+    fn roundtrips() {
+        let white = vec![0xff; 15000];
+        let mut dashboard = white.clone();
+        for (i, b) in dashboard.iter_mut().enumerate() {
+            if (i / 50) % 7 == 0 {
+                *b = (i as u8) | 0xf0;
+            }
+            if i % 333 < 20 {
+                *b = 0;
+            }
+        }
+        let noise = noise(15000);
+        let small = [1, 2, 3, 1, 2, 3, 1, 2, 3, 1, 2, 3, 4, 4, 4, 4, 5];
+
+        for input in [&white[..], &dashboard, &noise, &small, &[7]] {
+            for compression in ALL {
+                for out_len in [2, 3, 17, 1004] {
+                    roundtrip(compression, input, out_len);
+                }
+            }
+        }
+        // Mostly white frames are what compression is for
+        assert!(roundtrip(Compression::Rle, &white, 1004) < 300);
+        assert!(roundtrip(Compression::Lzss, &white, 1004) < 2000);
+        assert!(roundtrip(Compression::Lzss, &dashboard, 1004) < 15000 / 2);
+    }
+
+    #[test]
+    fn rle_stream() {
+        let mut out = [0; 16];
+        let (used, n) = compress(Compression::Rle, &[5, 5, 5, 1, 2], &mut out);
+        assert_eq!((used, &out[..n]), (5, &[0x81, 5, 0x01, 1, 2][..]));
+
+        // Longest run and longest literal
+        let (used, n) = compress(Compression::Rle, &[9; 200], &mut out);
+        assert_eq!((used, &out[..n]), (200, &[0xff, 9, 0xc5, 9][..]));
+        let mut out = [0; 300];
+        let input: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let (used, n) = compress(Compression::Rle, &input, &mut out);
+        assert_eq!((used, n), (200, 202));
+        assert_eq!((out[0], out[129]), (0x7f, 71));
+    }
+
+    #[test]
+    fn lzss_overlapping_references() {
+        let mut out = [0; 16];
+        // "a", then 8 bytes from 1 back
+        assert_eq!(
+            decompress(Compression::Lzss, &[0x80, b'a', 0x00, 0x05], &mut out),
+            Ok(9)
+        );
+        assert_eq!(&out[..9], b"aaaaaaaaa");
+        // "ab", then 7 bytes from 2 back
+        assert_eq!(
+            decompress(Compression::Lzss, &[0xc0, b'a', b'b', 0x00, 0x14], &mut out),
+            Ok(9)
+        );
+        assert_eq!(&out[..9], b"ababababa");
+
+        // The compressor emits them too
+        let input = b"xyxyxyxyxyxyxyxyxyxy";
+        let mut packed = [0; 32];
+        let (used, n) = compress(Compression::Lzss, input, &mut packed);
+        assert_eq!(used, input.len());
+        assert!(n < 8, "{:?}", &packed[..n]);
+        assert_eq!(
+            decompress(Compression::Lzss, &packed[..n], &mut out[..]),
+            Err(FrameError::OutOfRange)
+        );
+        let mut back = [0; 20];
+        assert_eq!(
+            decompress(Compression::Lzss, &packed[..n], &mut back),
+            Ok(20)
+        );
+        assert_eq!(&back, input);
+    }
+
+    #[test]
+    fn truncated_streams() {
+        let mut out = [0; 64];
+        // RLE literal run and repeat cut short
+        assert_eq!(
+            decompress(Compression::Rle, &[0x05, 1, 2], &mut out),
+            Err(FrameError::Compression)
+        );
+        assert_eq!(
+            decompress(Compression::Rle, &[0x85], &mut out),
+            Err(FrameError::Compression)
+        );
+        // LZSS back-reference cut in half, or reaching before the chunk
+        assert_eq!(
+            decompress(Compression::Lzss, &[0x80, b'a', 0x00], &mut out),
+            Err(FrameError::Compression)
+        );
+        assert_eq!(
+            decompress(Compression::Lzss, &[0x00, 0x00, 0x10], &mut out),
+            Err(FrameError::Compression)
+        );
+
+        // A stream cut between items decodes what is there, the chunk CRC
+        // is what catches it
+        let input = noise(40);
+        let mut packed = [0; 64];
+        let (_, n) = compress(Compression::Lzss, &input, &mut packed);
+        assert_eq!(
+            decompress(Compression::Lzss, &packed[..n - 1], &mut out),
+            Ok(39)
+        );
+        assert_eq!(decompress(Compression::Lzss, &[], &mut out), Ok(0));
+    }
+
+    #[test]
+    fn output_too_small() {
+        let mut out = [0; 4];
+        assert_eq!(
+            decompress(Compression::None, b"hello", &mut out),
+            Err(FrameError::OutOfRange)
+        );
+        assert_eq!(
+            decompress(Compression::Rle, &[0xff, 1], &mut out),
+            Err(FrameError::OutOfRange)
+        );
+        assert_eq!(
+            decompress(Compression::Lzss, &[0x80, b'a', 0x00, 0x05], &mut out),
+            Err(FrameError::OutOfRange)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn decompress_never_panics(
+            input in prop::collection::vec(any::<u8>(), 0..300),
+            out_len in 0usize..600,
+        ) {
+            let mut out = vec![0; out_len];
+            for compression in ALL {
+                if let Ok(n) = decompress(compression, &input, &mut out) {
+                    prop_assert!(n <= out_len);
+                }
+            }
+        }
+
+        /// Runs of few values, so both back-references and literals show up.
+        #[test]
+        fn compress_roundtrip(
+            input in prop::collection::vec(
+                (0u8..4, 1usize..40).prop_map(|(v, n)| vec![v * 85; n]),
+                1..60,
+            ),
+            out_len in 2usize..300,
+        ) {
+            let input = input.concat();
+            for compression in ALL {
+                roundtrip(compression, &input, out_len);
+            }
+        }
+
+        #[test]
+        fn compress_roundtrip_noise(
+            input in prop::collection::vec(any::<u8>(), 1..500),
+            out_len in 2usize..300,
+        ) {
+            for compression in ALL {
+                roundtrip(compression, &input, out_len);
+            }
+        }
+    }
+}
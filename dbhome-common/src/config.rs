@@ -0,0 +1,629 @@
+//! Key/value settings kept in a flash partition.
+//!
+//! Every save writes a whole record in the next sector of the partition, so
+//! erases are spread over all of them and the previous record stays intact
+//! until the new one is complete. Loading picks the valid record with the
+//! highest sequence number, a torn or corrupted one is skipped. Records are
+//! little-endian:
+//!
+//! | offset | size | field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 4    | magic `DBCF`                            |
+//! | 4      | 4    | sequence number                         |
+//! | 8      | 2    | settings version                        |
+//! | 10     | 2    | payload length                          |
+//! | 12     | 4    | CRC32 of bytes 0 to 12 and the payload  |
+//!
+//! The payload is the entries, each a key length byte, the key, a value
+//! length byte and the value. A record written by another settings version
+//! is handed to a migration function before use.
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+use heapless::{String, Vec};
+
+use crate::frame::CRC32;
+
+pub const MAGIC: [u8; 4] = *b"DBCF";
+pub const HEADER_LEN: usize = 16;
+pub const KEY_LEN: usize = 16;
+pub const VALUE_LEN: usize = 64;
+pub const MAX_ENTRIES: usize = 32;
+/// Longest payload, every entry full.
+pub const MAX_PAYLOAD_LEN: usize = MAX_ENTRIES * (2 + KEY_LEN + VALUE_LEN);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    KeyTooLong,
+    ValueTooLong,
+    Full,
+    /// The partition is not made of whole sectors able to hold a record.
+    Partition,
+    Flash(NorFlashErrorKind),
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigError::KeyTooLong => f.write_str("key too long"),
+            ConfigError::ValueTooLong => f.write_str("value too long"),
+            ConfigError::Full => f.write_str("too many entries"),
+            ConfigError::Partition => f.write_str("bad partition"),
+            ConfigError::Flash(kind) => write!(f, "flash error {:?}", kind),
+        }
+    }
+}
+
+impl core::error::Error for ConfigError {}
+
+fn flash_error<E: NorFlashError>(e: E) -> ConfigError {
+    ConfigError::Flash(e.kind())
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigMap {
+    entries: Vec<(String<KEY_LEN>, String<VALUE_LEN>), MAX_ENTRIES>,
+}
+
+impl ConfigMap {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.as_str() == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let value = String::try_from(value).map_err(|_| ConfigError::ValueTooLong)?;
+        if let Some(entry) = self.entries.iter_mut().find(|(k, _)| k.as_str() == key) {
+            entry.1 = value;
+            return Ok(());
+        }
+        let key = String::try_from(key).map_err(|_| ConfigError::KeyTooLong)?;
+        self.entries
+            .push((key, value))
+            .map_err(|_| ConfigError::Full)
+    }
+
+    /// Returns whether the key was set.
+    pub fn remove(&mut self, key: &str) -> bool {
+        match self.entries.iter().position(|(k, _)| k.as_str() == key) {
+            Some(idx) => {
+                self.entries.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Move the value of `old` to `new`, for migrations.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), ConfigError> {
+        let Some(idx) = self.entries.iter().position(|(k, _)| k.as_str() == old) else {
+            return Ok(());
+        };
+        let (_, value) = self.entries.remove(idx);
+        self.set(new, &value)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&str, &str) -> bool) {
+        self.entries.retain(|(k, v)| keep(k, v));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the payload length.
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for (k, v) in self.entries.iter() {
+            for field in [k.as_bytes(), v.as_bytes()] {
+                buf[len] = field.len() as u8;
+                buf[len + 1..len + 1 + field.len()].copy_from_slice(field);
+                len += 1 + field.len();
+            }
+        }
+        len
+    }
+
+    fn decode(mut payload: &[u8]) -> Option<Self> {
+        let mut map = Self::new();
+        while !payload.is_empty() {
+            let key = take_field(&mut payload)?;
+            let value = take_field(&mut payload)?;
+            map.set(key, value).ok()?;
+        }
+        Some(map)
+    }
+}
+
+/// Length prefixed string at the start of `payload`, which moves past it.
+fn take_field<'a>(payload: &mut &'a [u8]) -> Option<&'a str> {
+    let (len, rest) = payload.split_first()?;
+    let field = rest.get(..*len as usize)?;
+    *payload = &rest[*len as usize..];
+    core::str::from_utf8(field).ok()
+}
+
+/// Settings read from flash.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Loaded {
+    pub map: ConfigMap,
+    /// Settings version of the record, before migration.
+    pub version: u16,
+}
+
+/// Where the records go, `len` bytes of `flash` from `offset`.
+pub struct Store<F> {
+    flash: F,
+    offset: u32,
+    sectors: u32,
+    /// Sector and sequence number of the newest record.
+    last: Option<(u32, u32)>,
+}
+
+fn get_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn get_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// Checksum of a record, without the CRC field.
+fn record_crc(header: &[u8], payload: &[u8]) -> u32 {
+    let mut digest = CRC32.digest();
+    digest.update(&header[..12]);
+    digest.update(payload);
+    digest.finalize()
+}
+
+impl<F: NorFlash> Store<F> {
+    pub fn new(flash: F, offset: u32, len: u32) -> Result<Self, ConfigError> {
+        let sector = F::ERASE_SIZE as u32;
+        let fits = sector as usize >= HEADER_LEN + MAX_PAYLOAD_LEN + F::WRITE_SIZE;
+        if len < sector || !offset.is_multiple_of(sector) || !len.is_multiple_of(sector) || !fits {
+            return Err(ConfigError::Partition);
+        }
+        Ok(Self {
+            flash,
+            offset,
+            sectors: len / sector,
+            last: None,
+        })
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    fn sector_addr(&self, sector: u32) -> u32 {
+        self.offset + sector * F::ERASE_SIZE as u32
+    }
+
+    /// Read the record of `sector` in `header` and `payload`, returns the
+    /// payload length when it is intact.
+    fn read_record(
+        &mut self,
+        sector: u32,
+        header: &mut [u8; HEADER_LEN],
+        payload: &mut [u8; MAX_PAYLOAD_LEN],
+    ) -> Result<Option<usize>, ConfigError> {
+        let addr = self.sector_addr(sector);
+        self.flash.read(addr, header).map_err(flash_error)?;
+        let len = get_u16(header, 10) as usize;
+        if header[..4] != MAGIC || len > MAX_PAYLOAD_LEN {
+            return Ok(None);
+        }
+        // Reads are whole words, the record is padded
+        let padded = len.next_multiple_of(F::READ_SIZE);
+        self.flash
+            .read(addr + HEADER_LEN as u32, &mut payload[..padded])
+            .map_err(flash_error)?;
+        let intact = record_crc(header, &payload[..len]) == get_u32(header, 12);
+        Ok(intact.then_some(len))
+    }
+
+    /// The newest intact record, `None` on a blank partition or when every
+    /// record is damaged.
+    ///
+    /// Settings written by another version than `version` go through
+    /// `migrate` with the version they were written by.
+    pub fn load(
+        &mut self,
+        version: u16,
+        migrate: impl FnOnce(u16, &mut ConfigMap),
+    ) -> Result<Option<Loaded>, ConfigError> {
+        let mut header = [0; HEADER_LEN];
+        let mut payload = [0; MAX_PAYLOAD_LEN];
+        let mut newest: Option<(u32, u32)> = None;
+
+        for sector in 0..self.sectors {
+            if self
+                .read_record(sector, &mut header, &mut payload)?
+                .is_none()
+            {
+                continue;
+            }
+            let seq = get_u32(&header, 4);
+            // Sequence numbers wrap, a newer one is less than half the range ahead
+            if newest.is_none_or(|(_, s)| (seq.wrapping_sub(s) as i32) > 0) {
+                newest = Some((sector, seq));
+            }
+        }
+        self.last = newest;
+
+        let Some((sector, _)) = newest else {
+            return Ok(None);
+        };
+        let Some(len) = self.read_record(sector, &mut header, &mut payload)? else {
+            return Ok(None);
+        };
+        let Some(mut map) = ConfigMap::decode(&payload[..len]) else {
+            return Ok(None);
+        };
+        let stored = get_u16(&header, 8);
+        if stored != version {
+            migrate(stored, &mut map);
+        }
+        Ok(Some(Loaded {
+            map,
+            version: stored,
+        }))
+    }
+
+    /// Write `map` as a new record in the sector after the newest one.
+    pub fn save(&mut self, map: &ConfigMap, version: u16) -> Result<(), ConfigError> {
+        let (sector, seq) = match self.last {
+            Some((sector, seq)) => ((sector + 1) % self.sectors, seq.wrapping_add(1)),
+            None => (0, 1),
+        };
+
+        let mut record = [0xff; HEADER_LEN + MAX_PAYLOAD_LEN + 8];
+        let len = map.encode(&mut record[HEADER_LEN..]);
+        record[..4].copy_from_slice(&MAGIC);
+        record[4..8].copy_from_slice(&seq.to_le_bytes());
+        record[8..10].copy_from_slice(&version.to_le_bytes());
+        record[10..12].copy_from_slice(&(len as u16).to_le_bytes());
+        let crc = record_crc(&record[..HEADER_LEN], &record[HEADER_LEN..HEADER_LEN + len]);
+        record[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        let addr = self.sector_addr(sector);
+        let end = (HEADER_LEN + len).next_multiple_of(F::WRITE_SIZE);
+        self.flash
+            .erase(addr, addr + F::ERASE_SIZE as u32)
+            .map_err(flash_error)?;
+        self.flash
+            .write(addr, &record[..end])
+            .map_err(flash_error)?;
+        self.last = Some((sector, seq));
+        Ok(())
+    }
+
+    /// Erase every record, the next load finds none.
+    pub fn erase(&mut self) -> Result<(), ConfigError> {
+        let end = self.sector_addr(self.sectors);
+        self.flash.erase(self.offset, end).map_err(flash_error)?;
+        self.last = None;
+        Ok(())
+    }
+}
+
+/// NOR flash in RAM, for host tests and tools.
+///
+/// Like the real thing, erasing sets bytes to `0xff` and writing can only
+/// clear bits.
+pub struct MemFlash<const N: usize> {
+    pub data: [u8; N],
+    /// Writes still allowed before failing, to simulate a power loss.
+    pub writes_left: Option<usize>,
+}
+
+impl<const N: usize> Default for MemFlash<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MemFlash<N> {
+    pub const fn new() -> Self {
+        Self {
+            data: [0xff; N],
+            writes_left: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemFlashError(NorFlashErrorKind);
+
+impl NorFlashError for MemFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        self.0
+    }
+}
+
+impl<const N: usize> embedded_storage::nor_flash::ErrorType for MemFlash<N> {
+    type Error = MemFlashError;
+}
+
+impl<const N: usize> MemFlash<N> {
+    fn check(&self, offset: u32, len: usize, align: usize) -> Result<(), MemFlashError> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(MemFlashError(NorFlashErrorKind::NotAligned));
+        }
+        if offset + len > N {
+            return Err(MemFlashError(NorFlashErrorKind::OutOfBounds));
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> ReadNorFlash for MemFlash<N> {
+    const READ_SIZE: usize = 4;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> NorFlash for MemFlash<N> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+        self.data[from as usize..to as usize].fill(0xff);
+        Ok(())
+    }
+
+    /// With a power loss pending only the first half of the data lands.
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        let len = match self.writes_left {
+            Some(0) => bytes.len() / 2,
+            _ => bytes.len(),
+        };
+        let offset = offset as usize;
+        for (cell, b) in self.data[offset..offset + len].iter_mut().zip(bytes) {
+            *cell &= *b;
+        }
+        match self.writes_left.as_mut() {
+            Some(0) => Err(MemFlashError(NorFlashErrorKind::Other)),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::ToString;
+
+    use super::*;
+
+    const SECTORS: usize = 4;
+    const LEN: usize = SECTORS * 4096;
+    type Flash = MemFlash<LEN>;
+
+    fn store(flash: Flash) -> Store<Flash> {
+        Store::new(flash, 0, LEN as u32).unwrap()
+    }
+
+    /// The store a restart would find, with a copy of the flash.
+    fn reopen(store: &mut Store<Flash>) -> Store<Flash> {
+        let flash = MemFlash {
+            data: store.flash().data,
+            writes_left: None,
+        };
+        self::store(flash)
+    }
+
+    fn map(pairs: &[(&str, &str)]) -> ConfigMap {
+        let mut map = ConfigMap::new();
+        for (key, value) in pairs {
+            map.set(key, value).unwrap();
+        }
+        map
+    }
+
+    fn load(store: &mut Store<Flash>) -> Option<Loaded> {
+        store
+            .load(1, |version, _| panic!("migrating {version}"))
+            .unwrap()
+    }
+
+    #[test]
+    fn map_limits() {
+        let mut map = ConfigMap::new();
+        assert_eq!(
+            map.set(&"k".repeat(KEY_LEN + 1), "v"),
+            Err(ConfigError::KeyTooLong)
+        );
+        assert_eq!(
+            map.set("k", &"v".repeat(VALUE_LEN + 1)),
+            Err(ConfigError::ValueTooLong)
+        );
+        for i in 0..MAX_ENTRIES {
+            map.set(&i.to_string(), "v").unwrap();
+        }
+        assert_eq!(map.set("full", "v"), Err(ConfigError::Full));
+        // Replacing needs no room
+        map.set("0", "w").unwrap();
+        assert_eq!(map.get("0"), Some("w"));
+        assert!(map.remove("0"));
+        assert!(!map.remove("0"));
+        map.rename("1", "one").unwrap();
+        assert_eq!((map.get("1"), map.get("one")), (None, Some("v")));
+    }
+
+    #[test]
+    fn blank() {
+        let mut store = store(Flash::new());
+        assert_eq!(load(&mut store), None);
+        store.save(&map(&[("a", "1")]), 1).unwrap();
+        store.erase().unwrap();
+        assert_eq!(load(&mut reopen(&mut store)), None);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut store = store(Flash::new());
+        let saved = map(&[
+            ("wifi.0.ssid", "café"),
+            ("empty", ""),
+            ("n", &"v".repeat(64)),
+        ]);
+        store.save(&saved, 1).unwrap();
+        let loaded = load(&mut reopen(&mut store)).unwrap();
+        assert_eq!(
+            loaded,
+            Loaded {
+                map: saved,
+                version: 1
+            }
+        );
+    }
+
+    #[test]
+    fn wear_levelling() {
+        let mut store = store(Flash::new());
+        for i in 0..SECTORS + 2 {
+            store.save(&map(&[("a", &i.to_string())]), 1).unwrap();
+        }
+        // Each save went to the next sector, wrapping to the first
+        for sector in 0..SECTORS {
+            let at = sector * 4096;
+            assert_eq!(store.flash().data[at..at + 4], MAGIC);
+            let seq = get_u32(&store.flash().data, at + 4);
+            let expected = match sector {
+                0 | 1 => sector + 5,
+                _ => sector + 1,
+            };
+            assert_eq!(seq as usize, expected, "sector {sector}");
+        }
+
+        let mut store = reopen(&mut store);
+        assert_eq!(load(&mut store).unwrap().map.get("a"), Some("5"));
+        // Saving after a restart carries on in the next sector
+        store.save(&map(&[("a", "6")]), 1).unwrap();
+        assert_eq!(get_u32(&store.flash().data, 2 * 4096 + 4), 7);
+        assert_eq!(
+            load(&mut reopen(&mut store)).unwrap().map.get("a"),
+            Some("6")
+        );
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let mut store = store(Flash::new());
+        store.last = Some((0, u32::MAX - 1));
+        for value in ["old", "older", "newest"] {
+            store.save(&map(&[("a", value)]), 1).unwrap();
+        }
+        // Sequence numbers u32::MAX, 0 and 1
+        let mut store = reopen(&mut store);
+        assert_eq!(load(&mut store).unwrap().map.get("a"), Some("newest"));
+        assert_eq!(store.last, Some((3, 1)));
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut store = store(Flash::new());
+        store.save(&map(&[("a", "old")]), 1).unwrap();
+        store.save(&map(&[("a", "new")]), 1).unwrap();
+        // A flipped bit in the payload, then in the header
+        store.flash().data[4096 + HEADER_LEN + 1] ^= 0x01;
+        assert_eq!(
+            load(&mut reopen(&mut store)).unwrap().map.get("a"),
+            Some("old")
+        );
+        store.flash().data[4096 + HEADER_LEN + 1] ^= 0x01;
+        store.flash().data[4096 + 9] ^= 0x80;
+        assert_eq!(
+            load(&mut reopen(&mut store)).unwrap().map.get("a"),
+            Some("old")
+        );
+        // A length past the largest record
+        store.flash().data[4096 + 9] ^= 0x80;
+        store.flash().data[4096 + 10..4096 + 12].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(
+            load(&mut reopen(&mut store)).unwrap().map.get("a"),
+            Some("old")
+        );
+
+        store.flash().data[..4].copy_from_slice(b"XXXX");
+        assert_eq!(load(&mut reopen(&mut store)), None);
+    }
+
+    #[test]
+    fn torn_write() {
+        let mut store = store(Flash::new());
+        store.save(&map(&[("a", "old")]), 1).unwrap();
+        // Power lost halfway through the next record
+        store.flash().writes_left = Some(0);
+        let long = "v".repeat(60);
+        assert_eq!(
+            store.save(&map(&[("a", &long), ("b", &long)]), 1),
+            Err(ConfigError::Flash(NorFlashErrorKind::Other))
+        );
+        assert_eq!(store.flash().data[4096..4100], MAGIC);
+
+        let mut store = reopen(&mut store);
+        assert_eq!(load(&mut store).unwrap().map.get("a"), Some("old"));
+        // The next save skips the torn record, which stays the oldest
+        store.save(&map(&[("a", "new")]), 1).unwrap();
+        assert_eq!(
+            load(&mut reopen(&mut store)).unwrap().map.get("a"),
+            Some("new")
+        );
+    }
+
+    #[test]
+    fn migration() {
+        let mut store = store(Flash::new());
+        store.save(&map(&[("ssid", "home")]), 0).unwrap();
+        let mut from = None;
+        let loaded = store
+            .load(1, |version, map| {
+                from = Some(version);
+                map.rename("ssid", "wifi.ssid").unwrap();
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, Some(0));
+        assert_eq!(loaded.version, 0);
+        assert_eq!(loaded.map, map(&[("wifi.ssid", "home")]));
+    }
+
+    #[test]
+    fn partition() {
+        assert!(Store::new(Flash::new(), 0, 4096).is_ok());
+        for (offset, len) in [(100, 4096), (0, 1000), (0, 0), (4096, 4097)] {
+            assert_eq!(
+                Store::new(Flash::new(), offset, len).err(),
+                Some(ConfigError::Partition),
+                "{offset} {len}"
+            );
+        }
+    }
+}
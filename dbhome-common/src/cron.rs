@@ -0,0 +1,290 @@
+//! Cron style timetables, `minute hour day month weekday` in local time.
+//!
+//! Fields take `*`, numbers, ranges `a-b`, steps `*/n` or `a-b/n` and lists
+//! of those separated by `,`. Weekdays count from 0, Sunday, 7 is Sunday
+//! too. As in cron, when both the day and the weekday are restricted either
+//! one matching is enough.
+use crate::tz::DateTime;
+
+/// Fields of a timetable.
+const FIELDS: usize = 5;
+/// How far [`Cron::next_after`] looks, long enough for February 29th.
+const DAYS_AHEAD: i64 = 366 * 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CronError {
+    /// Not five fields.
+    Fields,
+    BadField,
+}
+
+impl core::fmt::Display for CronError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            CronError::Fields => "expected 5 fields",
+            CronError::BadField => "bad field",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for CronError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u32,
+    /// Bit 1 is the 1st.
+    days: u32,
+    /// Bit 1 is January.
+    months: u16,
+    /// Bit 0 is Sunday.
+    weekdays: u8,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Bits `min..=max` set by `field`, and whether it was `*`.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<(u64, bool), CronError> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| CronError::BadField)?),
+            None => (part, 1),
+        };
+        let (lo, hi) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((lo, hi)) => (
+                    lo.parse().map_err(|_| CronError::BadField)?,
+                    hi.parse().map_err(|_| CronError::BadField)?,
+                ),
+                None => {
+                    let at = range.parse().map_err(|_| CronError::BadField)?;
+                    // `a/n` runs from a to the end
+                    match part.contains('/') {
+                        true => (at, max),
+                        false => (at, at),
+                    }
+                }
+            },
+        };
+        if step == 0 || lo < min || hi > max || lo > hi {
+            return Err(CronError::BadField);
+        }
+        for v in (lo..=hi).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok((bits, field == "*"))
+}
+
+fn has(bits: u64, v: u8) -> bool {
+    bits & (1 << v) != 0
+}
+
+impl Cron {
+    pub fn parse(spec: &str) -> Result<Self, CronError> {
+        let mut fields = spec.split_ascii_whitespace();
+        let mut next = || fields.next().ok_or(CronError::Fields);
+        let (minutes, _) = parse_field(next()?, 0, 59)?;
+        let (hours, _) = parse_field(next()?, 0, 23)?;
+        let (days, any_day) = parse_field(next()?, 1, 31)?;
+        let (months, _) = parse_field(next()?, 1, 12)?;
+        let (weekdays, any_weekday) = parse_field(next()?, 0, 7)?;
+        if fields.next().is_some() {
+            return Err(CronError::Fields);
+        }
+
+        Ok(Self {
+            minutes,
+            hours: hours as u32,
+            days: days as u32,
+            months: months as u16,
+            // 7 is another Sunday
+            weekdays: ((weekdays | weekdays >> 7) & 0x7f) as u8,
+            any_day,
+            any_weekday,
+        })
+    }
+
+    fn day_matches(&self, t: &DateTime) -> bool {
+        if !has(self.months as u64, t.month) {
+            return false;
+        }
+        let day = has(self.days as u64, t.day);
+        let weekday = has(self.weekdays as u64, t.weekday);
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// Whether the minute of `t` is in the timetable.
+    pub fn matches(&self, t: &DateTime) -> bool {
+        self.day_matches(t) && has(self.hours as u64, t.hour) && has(self.minutes, t.minute)
+    }
+
+    /// First minute in the timetable strictly after `t`, `None` when it
+    /// never comes, like the 31st of February.
+    pub fn next_after(&self, t: &DateTime) -> Option<DateTime> {
+        let start = DateTime::from_unix(t.to_unix() - t.second as i64 + 60);
+        let first_day = start.to_unix().div_euclid(86_400);
+
+        for day in first_day..first_day + DAYS_AHEAD {
+            let midnight = DateTime::from_unix(day * 86_400);
+            if !self.day_matches(&midnight) {
+                continue;
+            }
+            let (h0, m0) = match day == first_day {
+                true => (start.hour, start.minute),
+                false => (0, 0),
+            };
+            for hour in (h0..24).filter(|h| has(self.hours as u64, *h)) {
+                let from = if hour == h0 { m0 } else { 0 };
+                if let Some(minute) = (from..60).find(|m| has(self.minutes, *m)) {
+                    return Some(DateTime {
+                        hour,
+                        minute,
+                        ..midnight
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Split a crontab style line into its timetable, the first five fields, and
+/// the command after them. `None` without a command.
+pub fn split_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    let mut end = 0;
+    for _ in 0..FIELDS {
+        let rest = &line[end..];
+        let start = end + rest.len() - rest.trim_start().len();
+        end = line[start..]
+            .find(char::is_whitespace)
+            .map_or(line.len(), |len| start + len);
+    }
+    let command = line[end..].trim_start();
+    (!command.is_empty()).then_some((&line[..end], command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        let t = DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            weekday: 0,
+        };
+        // Fills in the weekday
+        DateTime::from_unix(t.to_unix())
+    }
+
+    fn next(spec: &str, t: &DateTime) -> Option<std::string::String> {
+        let next = Cron::parse(spec).unwrap().next_after(t)?;
+        assert_eq!(next.second, 0, "{spec}");
+        Some(format!("{next}"))
+    }
+
+    #[test]
+    fn next_after() {
+        // A Saturday
+        let t = at(2026, 10, 17, 14, 3, 22);
+        for (spec, expected) in [
+            ("0 3 * * *", "2026-10-18 03:00:00"),
+            ("*/15 * * * *", "2026-10-17 14:15:00"),
+            ("* * * * *", "2026-10-17 14:04:00"),
+            ("4 14 * * *", "2026-10-17 14:04:00"),
+            ("3 14 * * *", "2026-10-18 14:03:00"),
+            ("0 23 * * 1-5", "2026-10-19 23:00:00"),
+            ("0 9 * * 0", "2026-10-18 09:00:00"),
+            ("0 9 * * 7", "2026-10-18 09:00:00"),
+            ("30 8 1 * *", "2026-11-01 08:30:00"),
+            ("0 0 1 1 *", "2027-01-01 00:00:00"),
+            ("0 0 29 2 *", "2028-02-29 00:00:00"),
+            ("0,30 9-17/4 * * *", "2026-10-17 17:00:00"),
+            ("5/20 * * * *", "2026-10-17 14:05:00"),
+        ] {
+            assert_eq!(next(spec, &t).as_deref(), Some(expected), "{spec}");
+        }
+        assert_eq!(next("0 0 31 2 *", &t), None);
+    }
+
+    #[test]
+    fn edges() {
+        // 2100 is not a leap year
+        assert_eq!(
+            next("0 0 29 2 *", &at(2097, 3, 1, 0, 0, 0)).as_deref(),
+            Some("2104-02-29 00:00:00")
+        );
+        assert_eq!(
+            next("59 23 31 12 *", &at(2026, 12, 31, 23, 59, 0)).as_deref(),
+            Some("2027-12-31 23:59:00")
+        );
+        // Restricting both the day and the weekday takes either
+        let t = at(2026, 10, 17, 14, 3, 22);
+        assert_eq!(
+            next("0 12 20 * 1", &t).as_deref(),
+            Some("2026-10-19 12:00:00")
+        );
+        assert_eq!(
+            next("0 12 18 * 1", &t).as_deref(),
+            Some("2026-10-18 12:00:00")
+        );
+    }
+
+    #[test]
+    fn matches() {
+        let cron = Cron::parse("0 3 * * *").unwrap();
+        assert!(cron.matches(&at(2026, 1, 1, 3, 0, 45)));
+        assert!(!cron.matches(&at(2026, 1, 1, 3, 1, 0)));
+        assert!(!cron.matches(&at(2026, 1, 1, 4, 0, 0)));
+    }
+
+    #[test]
+    fn errors() {
+        for spec in ["", "* * * *", "* * * * * *"] {
+            assert_eq!(Cron::parse(spec), Err(CronError::Fields), "{spec}");
+        }
+        for spec in [
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "1,,2 * * * *",
+        ] {
+            assert_eq!(Cron::parse(spec), Err(CronError::BadField), "{spec}");
+        }
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            split_line("0 3 * * * epd refresh"),
+            Some(("0 3 * * *", "epd refresh"))
+        );
+        assert_eq!(
+            split_line("  0  3 * *\t*   text 10 10 \"a  b\" "),
+            Some(("0  3 * *\t*", "text 10 10 \"a  b\""))
+        );
+        assert_eq!(split_line("0 3 * * *"), None);
+        assert_eq!(split_line("0 3 * * * "), None);
+        assert_eq!(split_line("0 3 *"), None);
+        assert_eq!(split_line(""), None);
+    }
+}
@@ -0,0 +1,391 @@
+//! Tiny DHCP server for the fallback access point.
+//!
+//! It hands out the addresses after its own in its /24, one per client
+//! hardware address. With few clients at a time leases never expire, when
+//! the pool is full the oldest one is given to the new client.
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+/// Addresses handed out.
+pub const POOL_LEN: usize = 8;
+/// Replies are at least as long as a BOOTP message.
+pub const MIN_REPLY_LEN: usize = 300;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS: usize = 240;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_ADDR: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DhcpError {
+    Short,
+    /// Not a client message of Ethernet addresses.
+    NotRequest,
+    BadOption,
+    BufferTooSmall,
+}
+
+impl core::fmt::Display for DhcpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            DhcpError::Short => "message too short",
+            DhcpError::NotRequest => "not a client request",
+            DhcpError::BadOption => "bad option",
+            DhcpError::BufferTooSmall => "buffer too small",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for DhcpError {}
+
+/// Options of a client message that matter here.
+#[derive(Default)]
+struct Options {
+    message_type: Option<u8>,
+    requested: Option<[u8; 4]>,
+    server_id: Option<[u8; 4]>,
+}
+
+fn addr(value: &[u8]) -> Result<[u8; 4], DhcpError> {
+    value.try_into().map_err(|_| DhcpError::BadOption)
+}
+
+fn parse_options(mut rest: &[u8]) -> Result<Options, DhcpError> {
+    let mut options = Options::default();
+    loop {
+        match rest {
+            [] | [OPT_END, ..] => return Ok(options),
+            [OPT_PAD, tail @ ..] => rest = tail,
+            [code, len, tail @ ..] => {
+                let value = tail.get(..*len as usize).ok_or(DhcpError::BadOption)?;
+                match *code {
+                    OPT_MESSAGE_TYPE => options.message_type = value.first().copied(),
+                    OPT_REQUESTED_ADDR => options.requested = Some(addr(value)?),
+                    OPT_SERVER_ID => options.server_id = Some(addr(value)?),
+                    _ => {}
+                }
+                rest = &tail[*len as usize..];
+            }
+            [_] => return Err(DhcpError::BadOption),
+        }
+    }
+}
+
+pub struct Server {
+    addr: [u8; 4],
+    lease_secs: u32,
+    /// Hardware address given each address of the pool.
+    leases: [Option<[u8; 6]>; POOL_LEN],
+    /// Lease given away next when the pool is full.
+    oldest: usize,
+}
+
+impl Server {
+    /// Server at `addr`, the host part of the pool follows it.
+    pub const fn new(addr: [u8; 4], lease_secs: u32) -> Self {
+        Self {
+            addr,
+            lease_secs,
+            leases: [None; POOL_LEN],
+            oldest: 0,
+        }
+    }
+
+    fn pool_addr(&self, idx: usize) -> [u8; 4] {
+        let [a, b, c, d] = self.addr;
+        [a, b, c, d.wrapping_add(1 + idx as u8)]
+    }
+
+    fn lease_of(&self, mac: &[u8; 6]) -> Option<usize> {
+        self.leases.iter().position(|l| l.as_ref() == Some(mac))
+    }
+
+    fn lease_for(&mut self, mac: &[u8; 6]) -> usize {
+        if let Some(idx) = self.lease_of(mac) {
+            return idx;
+        }
+        let idx = match self.leases.iter().position(|l| l.is_none()) {
+            Some(idx) => idx,
+            None => {
+                let idx = self.oldest;
+                self.oldest = (self.oldest + 1) % POOL_LEN;
+                idx
+            }
+        };
+        self.leases[idx] = Some(*mac);
+        idx
+    }
+
+    /// Reply to the client message `msg` in `buf`, returns its length or
+    /// `None` when there is nothing to answer. Replies go to the broadcast
+    /// address, the client has none yet.
+    pub fn handle(&mut self, msg: &[u8], buf: &mut [u8]) -> Result<Option<usize>, DhcpError> {
+        let header = msg.get(..OPTIONS).ok_or(DhcpError::Short)?;
+        if header[0] != 1 || header[1] != 1 || header[2] != 6 || header[236..] != MAGIC_COOKIE {
+            return Err(DhcpError::NotRequest);
+        }
+        let mac: [u8; 6] = header[28..34].try_into().unwrap();
+        let options = parse_options(&msg[OPTIONS..])?;
+
+        let (reply_type, yiaddr) = match options.message_type {
+            Some(DISCOVER) => {
+                let idx = self.lease_for(&mac);
+                (OFFER, Some(self.pool_addr(idx)))
+            }
+            Some(REQUEST) => {
+                if options.server_id.is_some_and(|id| id != self.addr) {
+                    // The client picked another server
+                    if let Some(idx) = self.lease_of(&mac) {
+                        self.leases[idx] = None;
+                    }
+                    return Ok(None);
+                }
+                let requested = options
+                    .requested
+                    .unwrap_or(header[12..16].try_into().unwrap());
+                let leased = self.lease_of(&mac).map(|idx| self.pool_addr(idx));
+                match leased == Some(requested) {
+                    true => (ACK, leased),
+                    false => (NAK, None),
+                }
+            }
+            Some(RELEASE) => {
+                if let Some(idx) = self.lease_of(&mac) {
+                    self.leases[idx] = None;
+                }
+                return Ok(None);
+            }
+            _ => return Ok(None),
+        };
+
+        let out = buf
+            .get_mut(..MIN_REPLY_LEN)
+            .ok_or(DhcpError::BufferTooSmall)?;
+        out.fill(0);
+        out[0] = 2;
+        out[1] = 1;
+        out[2] = 6;
+        // Transaction id, then flags
+        out[4..8].copy_from_slice(&header[4..8]);
+        out[10..12].copy_from_slice(&header[10..12]);
+        out[16..20].copy_from_slice(&yiaddr.unwrap_or_default());
+        out[20..24].copy_from_slice(&self.addr);
+        // Relay agent and client hardware address
+        out[24..44].copy_from_slice(&header[24..44]);
+        out[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut at = OPTIONS;
+        let mut put = |code: u8, value: &[u8]| {
+            out[at] = code;
+            out[at + 1] = value.len() as u8;
+            out[at + 2..at + 2 + value.len()].copy_from_slice(value);
+            at += 2 + value.len();
+        };
+        put(OPT_MESSAGE_TYPE, &[reply_type]);
+        put(OPT_SERVER_ID, &self.addr);
+        if reply_type != NAK {
+            put(OPT_LEASE_TIME, &self.lease_secs.to_be_bytes());
+            put(OPT_SUBNET_MASK, &[255, 255, 255, 0]);
+            put(OPT_ROUTER, &self.addr);
+            put(OPT_DNS, &self.addr);
+        }
+        out[at] = OPT_END;
+        Ok(Some(MIN_REPLY_LEN))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    const AP: [u8; 4] = [192, 168, 4, 1];
+
+    fn client(mac: u8, kind: u8, requested: Option<[u8; 4]>, server: Option<[u8; 4]>) -> Vec<u8> {
+        let mut msg = std::vec![0; OPTIONS];
+        msg[..3].copy_from_slice(&[1, 1, 6]);
+        msg[4..8].copy_from_slice(&[9, 8, 7, mac]);
+        // Broadcast flag
+        msg[10] = 0x80;
+        msg[28..34].copy_from_slice(&[2, 0, 0, 0, 0, mac]);
+        msg[236..].copy_from_slice(&MAGIC_COOKIE);
+        msg.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, kind]);
+        if let Some(addr) = requested {
+            msg.extend_from_slice(&[OPT_REQUESTED_ADDR, 4]);
+            msg.extend_from_slice(&addr);
+        }
+        if let Some(addr) = server {
+            msg.extend_from_slice(&[OPT_SERVER_ID, 4]);
+            msg.extend_from_slice(&addr);
+        }
+        msg.extend_from_slice(&[OPT_PAD, OPT_END]);
+        msg
+    }
+
+    fn option(reply: &[u8], code: u8) -> Option<&[u8]> {
+        let mut at = OPTIONS;
+        while reply[at] != OPT_END {
+            let len = reply[at + 1] as usize;
+            if reply[at] == code {
+                return Some(&reply[at + 2..at + 2 + len]);
+            }
+            at += 2 + len;
+        }
+        None
+    }
+
+    /// Reply type and offered address.
+    fn handle(server: &mut Server, msg: &[u8]) -> Option<(u8, [u8; 4])> {
+        let mut buf = [0; 400];
+        let len = server.handle(msg, &mut buf).unwrap()?;
+        assert_eq!(len, MIN_REPLY_LEN);
+        let kind = option(&buf, OPT_MESSAGE_TYPE).unwrap()[0];
+        Some((kind, buf[16..20].try_into().unwrap()))
+    }
+
+    #[test]
+    fn offer() {
+        let mut server = Server::new(AP, 3600);
+        let mut buf = [0; 400];
+        server
+            .handle(&client(1, DISCOVER, None, None), &mut buf)
+            .unwrap();
+        assert_eq!(buf[..3], [2, 1, 6]);
+        assert_eq!(buf[4..8], [9, 8, 7, 1]);
+        assert_eq!(buf[10], 0x80);
+        assert_eq!(buf[16..24], [192, 168, 4, 2, 192, 168, 4, 1]);
+        assert_eq!(buf[28..34], [2, 0, 0, 0, 0, 1]);
+        assert_eq!(option(&buf, OPT_MESSAGE_TYPE), Some(&[OFFER][..]));
+        assert_eq!(option(&buf, OPT_SERVER_ID), Some(&AP[..]));
+        assert_eq!(option(&buf, OPT_SUBNET_MASK), Some(&[255, 255, 255, 0][..]));
+        assert_eq!(option(&buf, OPT_ROUTER), Some(&AP[..]));
+        assert_eq!(option(&buf, OPT_DNS), Some(&AP[..]));
+        assert_eq!(
+            option(&buf, OPT_LEASE_TIME),
+            Some(&3600u32.to_be_bytes()[..])
+        );
+
+        // The same client keeps its address, another one gets the next
+        let offer = handle(&mut server, &client(1, DISCOVER, None, None));
+        assert_eq!(offer, Some((OFFER, [192, 168, 4, 2])));
+        let offer = handle(&mut server, &client(2, DISCOVER, None, None));
+        assert_eq!(offer, Some((OFFER, [192, 168, 4, 3])));
+    }
+
+    #[test]
+    fn request() {
+        let mut server = Server::new(AP, 3600);
+        handle(&mut server, &client(1, DISCOVER, None, None));
+        let ack = handle(
+            &mut server,
+            &client(1, REQUEST, Some([192, 168, 4, 2]), Some(AP)),
+        );
+        assert_eq!(ack, Some((ACK, [192, 168, 4, 2])));
+
+        // A wrong address, or none offered, is refused without options
+        let mut buf = [0; 400];
+        let msg = client(1, REQUEST, Some([192, 168, 4, 9]), None);
+        server.handle(&msg, &mut buf).unwrap();
+        assert_eq!(option(&buf, OPT_MESSAGE_TYPE), Some(&[NAK][..]));
+        assert_eq!(buf[16..20], [0; 4]);
+        assert_eq!(option(&buf, OPT_LEASE_TIME), None);
+        let nak = handle(
+            &mut server,
+            &client(5, REQUEST, Some([192, 168, 4, 2]), None),
+        );
+        assert_eq!(nak, Some((NAK, [0; 4])));
+
+        // Another server picked: nothing to say and the lease is dropped
+        let msg = client(1, REQUEST, Some([10, 0, 0, 5]), Some([10, 0, 0, 1]));
+        assert_eq!(handle(&mut server, &msg), None);
+        let offer = handle(&mut server, &client(3, DISCOVER, None, None));
+        assert_eq!(offer, Some((OFFER, [192, 168, 4, 2])));
+    }
+
+    #[test]
+    fn release() {
+        let mut server = Server::new(AP, 3600);
+        handle(&mut server, &client(1, DISCOVER, None, None));
+        handle(&mut server, &client(2, DISCOVER, None, None));
+        assert_eq!(handle(&mut server, &client(1, RELEASE, None, None)), None);
+        let offer = handle(&mut server, &client(3, DISCOVER, None, None));
+        assert_eq!(offer, Some((OFFER, [192, 168, 4, 2])));
+        // Unknown message types are ignored
+        assert_eq!(handle(&mut server, &client(3, 8, None, None)), None);
+    }
+
+    #[test]
+    fn full_pool() {
+        let mut server = Server::new(AP, 3600);
+        for mac in 1..=POOL_LEN as u8 {
+            handle(&mut server, &client(mac, DISCOVER, None, None));
+        }
+        // The oldest leases go first, and their holders lose them
+        let offer = handle(&mut server, &client(100, DISCOVER, None, None));
+        assert_eq!(offer, Some((OFFER, [192, 168, 4, 2])));
+        let offer = handle(&mut server, &client(101, DISCOVER, None, None));
+        assert_eq!(offer, Some((OFFER, [192, 168, 4, 3])));
+        let nak = handle(
+            &mut server,
+            &client(1, REQUEST, Some([192, 168, 4, 2]), None),
+        );
+        assert_eq!(nak, Some((NAK, [0; 4])));
+        let ack = handle(
+            &mut server,
+            &client(3, REQUEST, Some([192, 168, 4, 4]), None),
+        );
+        assert_eq!(ack, Some((ACK, [192, 168, 4, 4])));
+    }
+
+    #[test]
+    fn bad_messages() {
+        let mut server = Server::new(AP, 3600);
+        let mut buf = [0; 400];
+        let msg = client(1, DISCOVER, None, None);
+        assert_eq!(
+            server.handle(&msg[..OPTIONS - 1], &mut buf),
+            Err(DhcpError::Short)
+        );
+        let mut reply = msg.clone();
+        reply[0] = 2;
+        assert_eq!(server.handle(&reply, &mut buf), Err(DhcpError::NotRequest));
+        let mut cookie = msg.clone();
+        cookie[239] = 0;
+        assert_eq!(server.handle(&cookie, &mut buf), Err(DhcpError::NotRequest));
+        assert_eq!(
+            server.handle(&msg, &mut buf[..MIN_REPLY_LEN - 1]),
+            Err(DhcpError::BufferTooSmall)
+        );
+
+        // Options running past the end, or of the wrong length
+        let mut options = msg[..OPTIONS].to_vec();
+        options.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, DISCOVER, 12, 9, 1]);
+        assert_eq!(server.handle(&options, &mut buf), Err(DhcpError::BadOption));
+        options.truncate(OPTIONS + 4);
+        assert_eq!(server.handle(&options, &mut buf), Err(DhcpError::BadOption));
+        let mut options = msg[..OPTIONS].to_vec();
+        options.extend_from_slice(&[OPT_SERVER_ID, 3, 1, 2, 3, OPT_END]);
+        assert_eq!(server.handle(&options, &mut buf), Err(DhcpError::BadOption));
+        // Without an end option the options stop with the message
+        let mut options = msg[..OPTIONS].to_vec();
+        options.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, DISCOVER]);
+        assert!(server.handle(&options, &mut buf).unwrap().is_some());
+    }
+}
@@ -0,0 +1,204 @@
+//! Grey scale to black and white conversion.
+//!
+//! Works in place on 8 bit grey pixels, row-major, so it needs no allocation
+//! and runs the same on the host tool and on the device. After [`dither`]
+//! every pixel is either 0 or 255 and [`pack`] turns it into a frame.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    /// Pixels brighter than the level are white.
+    Threshold(u8),
+    FloydSteinberg,
+    Atkinson,
+    /// Ordered dithering with an 8x8 Bayer matrix.
+    Bayer,
+}
+
+#[rustfmt::skip]
+const BAYER8: [[u8; 8]; 8] = [
+    [ 0, 32,  8, 40,  2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44,  4, 36, 14, 46,  6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [ 3, 35, 11, 43,  1, 33,  9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47,  7, 39, 13, 45,  5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Apply `v' = 255 * (v / 255) ^ gamma`, below 1 lightens the image.
+pub fn gamma(pixels: &mut [u8], gamma: f32) {
+    let mut lut = [0; 256];
+    for (v, out) in lut.iter_mut().enumerate() {
+        *out = (libm::powf(v as f32 / 255.0, gamma) * 255.0 + 0.5) as u8;
+    }
+    for p in pixels.iter_mut() {
+        *p = lut[*p as usize];
+    }
+}
+
+/// Stretch the levels so the darkest pixel is black and the brightest white.
+pub fn stretch_contrast(pixels: &mut [u8]) {
+    let min = pixels.iter().copied().min().unwrap_or(0) as u32;
+    let max = pixels.iter().copied().max().unwrap_or(255) as u32;
+    if max <= min {
+        return;
+    }
+    for p in pixels.iter_mut() {
+        *p = ((*p as u32 - min) * 255 / (max - min)) as u8;
+    }
+}
+
+/// Add `err` to the pixel at (x, y), if it is inside the image.
+fn spread(pixels: &mut [u8], width: usize, x: usize, dx: isize, y: usize, err: i16) {
+    let Some(x) = x.checked_add_signed(dx).filter(|x| *x < width) else {
+        return;
+    };
+    if let Some(p) = pixels.get_mut(y * width + x) {
+        *p = (*p as i16 + err).clamp(0, 255) as u8;
+    }
+}
+
+/// Reduce `pixels`, `width` pixels per row, to pure black and white.
+pub fn dither(pixels: &mut [u8], width: usize, method: Method) {
+    if width == 0 {
+        return;
+    }
+    let height = pixels.len() / width;
+
+    for y in 0..height {
+        for x in 0..width {
+            let idx = y * width + x;
+            let old = pixels[idx];
+            let new = match method {
+                Method::Threshold(level) => old > level,
+                Method::Bayer => old as u16 * 64 > (BAYER8[y % 8][x % 8] as u16 * 2 + 1) * 128,
+                Method::FloydSteinberg | Method::Atkinson => old >= 128,
+            };
+            pixels[idx] = if new { 255 } else { 0 };
+
+            let err = old as i16 - pixels[idx] as i16;
+            match method {
+                Method::FloydSteinberg => {
+                    spread(pixels, width, x, 1, y, err * 7 / 16);
+                    spread(pixels, width, x, -1, y + 1, err * 3 / 16);
+                    spread(pixels, width, x, 0, y + 1, err * 5 / 16);
+                    spread(pixels, width, x, 1, y + 1, err / 16);
+                }
+                Method::Atkinson => {
+                    let err = err / 8;
+                    spread(pixels, width, x, 1, y, err);
+                    spread(pixels, width, x, 2, y, err);
+                    spread(pixels, width, x, -1, y + 1, err);
+                    spread(pixels, width, x, 0, y + 1, err);
+                    spread(pixels, width, x, 1, y + 1, err);
+                    spread(pixels, width, x, 0, y + 2, err);
+                }
+                Method::Threshold(_) | Method::Bayer => {}
+            }
+        }
+    }
+}
+
+/// Pack dithered pixels in 1bpp rows, MSB first, a set bit is white.
+///
+/// Rows are padded to a whole byte; `out` needs `width.div_ceil(8) * height` bytes.
+pub fn pack(pixels: &[u8], width: usize, out: &mut [u8]) {
+    if width == 0 {
+        return;
+    }
+    let stride = width.div_ceil(8);
+    out.fill(0);
+
+    for (y, row) in pixels.chunks(width).enumerate() {
+        for (x, p) in row.iter().enumerate() {
+            if *p >= 128 {
+                if let Some(b) = out.get_mut(y * stride + x / 8) {
+                    *b |= 0x80 >> (x % 8);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Four rows of a 16 step ramp from black to white.
+    fn ramp() -> [u8; 64] {
+        core::array::from_fn(|i| (i % 16 * 17) as u8)
+    }
+
+    fn packed(mut pixels: [u8; 64], width: usize, method: Method) -> [u8; 8] {
+        dither(&mut pixels, width, method);
+        assert!(pixels.iter().all(|p| *p == 0 || *p == 255));
+        let mut out = [0; 8];
+        pack(&pixels, width, &mut out);
+        out
+    }
+
+    #[test]
+    fn golden_ramp() {
+        let golden = [
+            (
+                Method::Threshold(127),
+                [0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff],
+            ),
+            (
+                Method::FloydSteinberg,
+                [0x02, 0xbf, 0x09, 0x6f, 0x05, 0x5f, 0x12, 0xf7],
+            ),
+            (
+                Method::Atkinson,
+                [0x01, 0xbf, 0x04, 0xff, 0x06, 0x5f, 0x01, 0xdf],
+            ),
+            (
+                Method::Bayer,
+                [0x0a, 0xff, 0x05, 0x5f, 0x2a, 0xbf, 0x01, 0x57],
+            ),
+        ];
+        for (method, expected) in golden {
+            assert_eq!(packed(ramp(), 16, method), expected, "{method:?}");
+        }
+    }
+
+    #[test]
+    fn mid_grey_is_a_checkerboard() {
+        for method in [Method::FloydSteinberg, Method::Bayer] {
+            assert_eq!(
+                packed([128; 64], 8, method),
+                [0xaa, 0x55, 0xaa, 0x55, 0xaa, 0x55, 0xaa, 0x55],
+                "{method:?}"
+            );
+        }
+        for method in [Method::FloydSteinberg, Method::Atkinson, Method::Bayer] {
+            assert_eq!(packed([0; 64], 8, method), [0; 8]);
+            assert_eq!(packed([255; 64], 8, method), [0xff; 8]);
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let mut px = [10, 60, 110];
+        stretch_contrast(&mut px);
+        assert_eq!(px, [0, 127, 255]);
+        let mut flat = [42; 3];
+        stretch_contrast(&mut flat);
+        assert_eq!(flat, [42; 3]);
+
+        let mut px = [0, 64, 128, 255];
+        gamma(&mut px, 0.5);
+        assert_eq!(px, [0, 128, 181, 255]);
+    }
+
+    #[test]
+    fn pack_pads_rows() {
+        let px = [0, 255, 255, 0, 255, 0, 0, 255, 255, 255];
+        let mut out = [0xff; 4];
+        pack(&px, 10, &mut out);
+        assert_eq!(out, [0b0110_1001, 0b1100_0000, 0, 0]);
+        pack(&px, 5, &mut out);
+        assert_eq!(out, [0b0110_1000, 0b0011_1000, 0, 0]);
+    }
+}
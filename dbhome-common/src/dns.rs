@@ -0,0 +1,371 @@
+//! Minimal DNS message handling, enough to answer and send mDNS A queries
+//! and to answer every query of the captive portal.
+
+pub const MDNS_ADDR: [u8; 4] = [224, 0, 0, 251];
+pub const MDNS_PORT: u16 = 5353;
+pub const DNS_PORT: u16 = 53;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+
+const HEADER_LEN: usize = 12;
+/// Top bit of the class: cache flush in answers, unicast response in questions.
+const CLASS_FLAG: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+/// Pointer to the name of the first question, right after the header.
+const FIRST_NAME: u16 = 0xc000 | HEADER_LEN as u16;
+/// Compression pointers followed before giving up on a name.
+const MAX_JUMPS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnsError {
+    Short,
+    BadName,
+    BufferTooSmall,
+}
+
+impl core::fmt::Display for DnsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            DnsError::Short => "message too short",
+            DnsError::BadName => "bad name",
+            DnsError::BufferTooSmall => "buffer too small",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for DnsError {}
+
+fn get_u16(msg: &[u8], at: usize) -> Result<u16, DnsError> {
+    match msg.get(at..at + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(DnsError::Short),
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, data: &[u8]) -> Result<(), DnsError> {
+        let end = self.len + data.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(DnsError::BufferTooSmall)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn put_u16(&mut self, value: u16) -> Result<(), DnsError> {
+        self.put(&value.to_be_bytes())
+    }
+
+    fn put_name(&mut self, name: &str) -> Result<(), DnsError> {
+        for label in name.split('.').filter(|l| !l.is_empty()) {
+            if label.len() > 63 {
+                return Err(DnsError::BadName);
+            }
+            self.put(&[label.len() as u8])?;
+            self.put(label.as_bytes())?;
+        }
+        self.put(&[0])
+    }
+
+    /// Copy the name at `pos` of `msg` without its compression pointers,
+    /// which would point elsewhere in the reply. Returns the position right
+    /// after the name in `msg`.
+    fn put_name_from(&mut self, msg: &[u8], pos: usize) -> Result<usize, DnsError> {
+        let mut at = pos;
+        let mut next = None;
+        let mut jumps = 0;
+        loop {
+            let len = *msg.get(at).ok_or(DnsError::Short)? as usize;
+            if len & 0xc0 == 0xc0 {
+                next.get_or_insert(at + 2);
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return Err(DnsError::BadName);
+                }
+                at = (get_u16(msg, at)? & 0x3fff) as usize;
+                continue;
+            }
+            if len & 0xc0 != 0 {
+                return Err(DnsError::BadName);
+            }
+            let label = msg.get(at..at + 1 + len).ok_or(DnsError::Short)?;
+            self.put(label)?;
+            at += 1 + len;
+            if len == 0 {
+                return Ok(next.unwrap_or(at));
+            }
+        }
+    }
+}
+
+/// Compare the name at `pos` with the dotted `name`, ignoring case.
+///
+/// Returns whether it matches and the position right after the name.
+pub fn name_eq(msg: &[u8], pos: usize, name: &str) -> Result<(bool, usize), DnsError> {
+    let mut labels = name.split('.').filter(|l| !l.is_empty());
+    let mut equal = true;
+    let mut at = pos;
+    let mut next = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *msg.get(at).ok_or(DnsError::Short)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let ptr = (get_u16(msg, at)? & 0x3fff) as usize;
+            next.get_or_insert(at + 2);
+            jumps += 1;
+            if jumps > MAX_JUMPS {
+                return Err(DnsError::BadName);
+            }
+            at = ptr;
+            continue;
+        }
+        if len & 0xc0 != 0 {
+            return Err(DnsError::BadName);
+        }
+
+        at += 1;
+        if len == 0 {
+            equal &= labels.next().is_none();
+            return Ok((equal, next.unwrap_or(at)));
+        }
+
+        let label = msg.get(at..at + len).ok_or(DnsError::Short)?;
+        equal &= labels
+            .next()
+            .is_some_and(|l| l.as_bytes().eq_ignore_ascii_case(label));
+        at += len;
+    }
+}
+
+/// Skip the name at `pos`, returning the position right after it.
+fn skip_name(msg: &[u8], pos: usize) -> Result<usize, DnsError> {
+    name_eq(msg, pos, "").map(|(_, next)| next)
+}
+
+/// Build a one question query for the A record of `name`.
+pub fn encode_query(id: u16, name: &str, buf: &mut [u8]) -> Result<usize, DnsError> {
+    let mut w = Writer { buf, len: 0 };
+    w.put_u16(id)?;
+    w.put_u16(0)?;
+    w.put_u16(1)?;
+    w.put(&[0; 6])?;
+    w.put_name(name)?;
+    w.put_u16(TYPE_A)?;
+    w.put_u16(CLASS_IN)?;
+    Ok(w.len)
+}
+
+/// Id of a query asking the A record of `name`, `None` if it does not.
+pub fn query_for(msg: &[u8], name: &str) -> Result<Option<u16>, DnsError> {
+    let flags = get_u16(msg, 2)?;
+    if flags & FLAG_RESPONSE != 0 {
+        return Ok(None);
+    }
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..get_u16(msg, 4)? {
+        let (equal, next) = name_eq(msg, pos, name)?;
+        let qtype = get_u16(msg, next)?;
+        let qclass = get_u16(msg, next + 2)? & !CLASS_FLAG;
+        pos = next + 4;
+
+        if equal && (qtype == TYPE_A || qtype == TYPE_ANY) && qclass == CLASS_IN {
+            return Ok(Some(get_u16(msg, 0)?));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Build an authoritative answer binding `name` to `addr`.
+pub fn encode_response(
+    id: u16,
+    name: &str,
+    addr: [u8; 4],
+    ttl: u32,
+    buf: &mut [u8],
+) -> Result<usize, DnsError> {
+    let mut w = Writer { buf, len: 0 };
+    w.put_u16(id)?;
+    w.put_u16(FLAG_RESPONSE | FLAG_AUTHORITATIVE)?;
+    w.put_u16(0)?;
+    w.put_u16(1)?;
+    w.put(&[0; 4])?;
+    w.put_name(name)?;
+    w.put_u16(TYPE_A)?;
+    w.put_u16(CLASS_IN | CLASS_FLAG)?;
+    w.put(&ttl.to_be_bytes())?;
+    w.put_u16(4)?;
+    w.put(&addr)?;
+    Ok(w.len)
+}
+
+/// Address of `name` from the answers of a response, if it has one.
+pub fn parse_response(msg: &[u8], name: &str) -> Result<Option<[u8; 4]>, DnsError> {
+    let flags = get_u16(msg, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Ok(None);
+    }
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..get_u16(msg, 4)? {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let records =
+        get_u16(msg, 6)? as usize + get_u16(msg, 8)? as usize + get_u16(msg, 10)? as usize;
+    for _ in 0..records {
+        let (equal, next) = name_eq(msg, pos, name)?;
+        let rtype = get_u16(msg, next)?;
+        let rlen = get_u16(msg, next + 8)? as usize;
+        let data = msg
+            .get(next + 10..next + 10 + rlen)
+            .ok_or(DnsError::Short)?;
+        pos = next + 10 + rlen;
+
+        if equal && rtype == TYPE_A && rlen == 4 {
+            return Ok(Some([data[0], data[1], data[2], data[3]]));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Answer a query with `addr` whatever the name, for a captive portal
+/// where every name leads to the device. Queries for another type than A
+/// get an empty answer. `None` when `query` is not one.
+pub fn encode_catch_all(
+    query: &[u8],
+    addr: [u8; 4],
+    ttl: u32,
+    buf: &mut [u8],
+) -> Result<Option<usize>, DnsError> {
+    let flags = get_u16(query, 2)?;
+    if flags & FLAG_RESPONSE != 0 || get_u16(query, 4)? == 0 {
+        return Ok(None);
+    }
+    let next = skip_name(query, HEADER_LEN)?;
+    let qtype = get_u16(query, next)?;
+    let qclass = get_u16(query, next + 2)?;
+    let answer = (qtype == TYPE_A || qtype == TYPE_ANY) && qclass & !CLASS_FLAG == CLASS_IN;
+
+    let mut w = Writer { buf, len: 0 };
+    w.put(&query[..2])?;
+    w.put_u16(FLAG_RESPONSE | FLAG_AUTHORITATIVE | (flags & FLAG_RECURSION_DESIRED))?;
+    w.put_u16(1)?;
+    w.put_u16(answer as u16)?;
+    w.put(&[0; 4])?;
+    w.put_name_from(query, HEADER_LEN)?;
+    w.put_u16(qtype)?;
+    w.put_u16(qclass)?;
+    if answer {
+        w.put_u16(FIRST_NAME)?;
+        w.put_u16(TYPE_A)?;
+        w.put_u16(CLASS_IN)?;
+        w.put(&ttl.to_be_bytes())?;
+        w.put_u16(4)?;
+        w.put(&addr)?;
+    }
+    Ok(Some(w.len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AP: [u8; 4] = [192, 168, 4, 1];
+
+    fn catch_all(query: &[u8]) -> std::vec::Vec<u8> {
+        let mut buf = [0; 128];
+        let len = encode_catch_all(query, AP, 60, &mut buf).unwrap().unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn catch_all_answers() {
+        let mut query = [0; 64];
+        let len = encode_query(0x1234, "connectivitycheck.gstatic.com", &mut query).unwrap();
+        query[2] = 0x01;
+        let query = &mut query[..len];
+
+        let reply = catch_all(query);
+        assert_eq!(reply[..4], [0x12, 0x34, 0x85, 0x00]);
+        assert_eq!(reply[6..8], [0, 1]);
+        assert_eq!(reply[HEADER_LEN..len], query[HEADER_LEN..]);
+        assert_eq!(
+            parse_response(&reply, "connectivitycheck.gstatic.com"),
+            Ok(Some(AP))
+        );
+
+        // AAAA gets the question back without an answer
+        query[len - 3] = 28;
+        let reply = catch_all(query);
+        assert_eq!(reply.len(), len);
+        assert_eq!(reply[6..8], [0, 0]);
+        assert_eq!(
+            parse_response(&reply, "connectivitycheck.gstatic.com"),
+            Ok(None)
+        );
+
+        // Responses and empty queries are not answered
+        let response = catch_all(&query[..len]);
+        assert_eq!(encode_catch_all(&response, AP, 60, &mut [0; 128]), Ok(None));
+        query[5] = 0;
+        assert_eq!(encode_catch_all(query, AP, 60, &mut [0; 128]), Ok(None));
+    }
+
+    #[test]
+    fn compressed_question() {
+        // "www" then a pointer to "example.com" after the question
+        let mut query = std::vec![0x43, 0x21, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(&[3, b'w', b'w', b'w', 0xc0, 22, 0, 1, 0, 1]);
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        let reply = catch_all(&query);
+        assert_eq!(reply[6..8], [0, 1]);
+        assert_eq!(parse_response(&reply, "www.example.com"), Ok(Some(AP)));
+
+        // A pointer loop or one past the end
+        query[17] = 12;
+        assert_eq!(
+            encode_catch_all(&query, AP, 60, &mut [0; 128]),
+            Err(DnsError::BadName)
+        );
+        query[17] = 200;
+        assert_eq!(
+            encode_catch_all(&query, AP, 60, &mut [0; 128]),
+            Err(DnsError::Short)
+        );
+    }
+
+    #[test]
+    fn catch_all_errors() {
+        let mut query = [0; 64];
+        let len = encode_query(1, "example.com", &mut query).unwrap();
+        let reply_len = catch_all(&query[..len]).len();
+        assert_eq!(
+            encode_catch_all(&query[..len], AP, 60, &mut [0; 64][..reply_len - 1]),
+            Err(DnsError::BufferTooSmall)
+        );
+        assert_eq!(
+            encode_catch_all(&query[..5], AP, 60, &mut [0; 128]),
+            Err(DnsError::Short)
+        );
+        // Cut in the question type
+        assert_eq!(
+            encode_catch_all(&query[..len - 3], AP, 60, &mut [0; 128]),
+            Err(DnsError::Short)
+        );
+    }
+}
@@ -0,0 +1,881 @@
+//! Framebuffer transfer protocol over UDP.
+//!
+//! Every datagram starts with a 20 byte little-endian header:
+//!
+//! | offset | size | field                                                 |
+//! |--------|------|-------------------------------------------------------|
+//! | 0      | 4    | magic `EPDF`                                          |
+//! | 4      | 1    | protocol version                                      |
+//! | 5      | 1    | packet kind                                           |
+//! | 6      | 1    | flags, compression of a Data payload                  |
+//! | 7      | 1    | error code, only meaningful in Nack                   |
+//! | 8      | 2    | frame id                                              |
+//! | 10     | 2    | chunk index (Data) or chunk count (Commit, Ack)       |
+//! | 12     | 4    | byte offset (Data) or frame length (Commit)           |
+//! | 16     | 4    | CRC32 of the payload (Data) or of the frame (Commit)  |
+//!
+//! A frame is sent as a sequence of Data chunks closed by a Commit. The
+//! device answers the Commit with an Ack once every chunk arrived and the
+//! frame CRC matches, otherwise with a Nack followed by a little-endian u64
+//! bitmap of the chunks to send again.
+//!
+//! The first clients used a bare 8 byte header instead, `i32` offset and
+//! `u32` size, both little-endian, where a negative offset displays the
+//! frame. It is still accepted, see [`LegacyPacket`]; errors on both paths
+//! are answered with a Nack.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use crate::compress::{self, Compression};
+
+pub const MAGIC: [u8; 4] = *b"EPDF";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 20;
+/// Missing chunks are reported in a u64 bitmap.
+pub const MAX_CHUNKS: usize = 64;
+/// Largest datagram the device accepts.
+pub const MAX_PACKET_LEN: usize = 1024;
+pub const MAX_PAYLOAD_LEN: usize = MAX_PACKET_LEN - HEADER_LEN;
+
+/// Same CRC as zlib.crc32, so host tools can use whatever they have at hand.
+pub const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+pub fn crc32(data: &[u8]) -> u32 {
+    CRC32.checksum(data)
+}
+
+const KIND_DATA: u8 = 0;
+const KIND_COMMIT: u8 = 1;
+const KIND_ACK: u8 = 2;
+const KIND_NACK: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// Packet shorter than its header says.
+    Short,
+    BadMagic,
+    Version,
+    Kind,
+    /// Payload does not match the chunk CRC.
+    ChunkCrc,
+    /// Chunk index, offset or length outside of the frame.
+    OutOfRange,
+    /// Commit received while some chunks are still missing.
+    Missing,
+    /// Every chunk arrived but the frame CRC does not match.
+    FrameCrc,
+    /// Commit for a frame that was never started.
+    UnknownFrame,
+    /// Output buffer too small to encode the packet.
+    BufferTooSmall,
+    /// Size in the header differs from the payload length.
+    SizeMismatch,
+    /// Unknown compression or corrupted compressed payload.
+    Compression,
+}
+
+impl FrameError {
+    pub fn code(self) -> u8 {
+        match self {
+            FrameError::Short => 1,
+            FrameError::BadMagic => 2,
+            FrameError::Version => 3,
+            FrameError::Kind => 4,
+            FrameError::ChunkCrc => 5,
+            FrameError::OutOfRange => 6,
+            FrameError::Missing => 7,
+            FrameError::FrameCrc => 8,
+            FrameError::UnknownFrame => 9,
+            FrameError::BufferTooSmall => 10,
+            FrameError::SizeMismatch => 11,
+            FrameError::Compression => 12,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            1 => FrameError::Short,
+            2 => FrameError::BadMagic,
+            3 => FrameError::Version,
+            4 => FrameError::Kind,
+            5 => FrameError::ChunkCrc,
+            6 => FrameError::OutOfRange,
+            7 => FrameError::Missing,
+            8 => FrameError::FrameCrc,
+            9 => FrameError::UnknownFrame,
+            10 => FrameError::BufferTooSmall,
+            11 => FrameError::SizeMismatch,
+            12 => FrameError::Compression,
+            _ => return None,
+        })
+    }
+}
+
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            FrameError::Short => "packet too short",
+            FrameError::BadMagic => "bad magic",
+            FrameError::Version => "unsupported version",
+            FrameError::Kind => "unknown packet kind",
+            FrameError::ChunkCrc => "chunk CRC mismatch",
+            FrameError::OutOfRange => "chunk out of frame",
+            FrameError::Missing => "chunks missing",
+            FrameError::FrameCrc => "frame CRC mismatch",
+            FrameError::UnknownFrame => "unknown frame",
+            FrameError::BufferTooSmall => "buffer too small",
+            FrameError::SizeMismatch => "size mismatch",
+            FrameError::Compression => "bad compression",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for FrameError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Packet<'a> {
+    Data {
+        frame_id: u16,
+        chunk: u16,
+        /// Offset of the decompressed payload in the frame.
+        offset: u32,
+        compression: Compression,
+        payload: &'a [u8],
+    },
+    Commit {
+        frame_id: u16,
+        chunks: u16,
+        len: u32,
+        crc: u32,
+    },
+    Ack {
+        frame_id: u16,
+        chunks: u16,
+        crc: u32,
+    },
+    Nack {
+        frame_id: u16,
+        error: FrameError,
+        missing: u64,
+    },
+}
+
+fn get_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn get_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn put_u16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], at: usize, value: u32) {
+    buf[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl<'a> Packet<'a> {
+    pub fn decode(buf: &'a [u8]) -> Result<Self, FrameError> {
+        if buf.len() < HEADER_LEN {
+            return Err(FrameError::Short);
+        }
+        if buf[..4] != MAGIC {
+            return Err(FrameError::BadMagic);
+        }
+        if buf[4] != VERSION {
+            return Err(FrameError::Version);
+        }
+
+        let frame_id = get_u16(buf, 8);
+        let chunk = get_u16(buf, 10);
+        let offset = get_u32(buf, 12);
+        let crc = get_u32(buf, 16);
+        let body = &buf[HEADER_LEN..];
+
+        match buf[5] {
+            KIND_DATA => {
+                if crc32(body) != crc {
+                    return Err(FrameError::ChunkCrc);
+                }
+                Ok(Packet::Data {
+                    frame_id,
+                    chunk,
+                    offset,
+                    compression: Compression::from_flags(buf[6])?,
+                    payload: body,
+                })
+            }
+            KIND_COMMIT => Ok(Packet::Commit {
+                frame_id,
+                chunks: chunk,
+                len: offset,
+                crc,
+            }),
+            KIND_ACK => Ok(Packet::Ack {
+                frame_id,
+                chunks: chunk,
+                crc,
+            }),
+            KIND_NACK => {
+                if body.len() < 8 {
+                    return Err(FrameError::Short);
+                }
+                let missing = u64::from_le_bytes(body[..8].try_into().unwrap());
+                let error = FrameError::from_code(buf[7]).ok_or(FrameError::Kind)?;
+                Ok(Packet::Nack {
+                    frame_id,
+                    error,
+                    missing,
+                })
+            }
+            _ => Err(FrameError::Kind),
+        }
+    }
+
+    /// Write the packet into `buf`, returning the number of bytes used.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, FrameError> {
+        let body_len = match self {
+            Packet::Data { payload, .. } => payload.len(),
+            Packet::Nack { .. } => 8,
+            _ => 0,
+        };
+        let len = HEADER_LEN + body_len;
+        if buf.len() < len {
+            return Err(FrameError::BufferTooSmall);
+        }
+
+        buf[..HEADER_LEN].fill(0);
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4] = VERSION;
+
+        match *self {
+            Packet::Data {
+                frame_id,
+                chunk,
+                offset,
+                compression,
+                payload,
+            } => {
+                buf[5] = KIND_DATA;
+                buf[6] = compression.flags();
+                put_u16(buf, 8, frame_id);
+                put_u16(buf, 10, chunk);
+                put_u32(buf, 12, offset);
+                put_u32(buf, 16, crc32(payload));
+                buf[HEADER_LEN..len].copy_from_slice(payload);
+            }
+            Packet::Commit {
+                frame_id,
+                chunks,
+                len,
+                crc,
+            } => {
+                buf[5] = KIND_COMMIT;
+                put_u16(buf, 8, frame_id);
+                put_u16(buf, 10, chunks);
+                put_u32(buf, 12, len);
+                put_u32(buf, 16, crc);
+            }
+            Packet::Ack {
+                frame_id,
+                chunks,
+                crc,
+            } => {
+                buf[5] = KIND_ACK;
+                put_u16(buf, 8, frame_id);
+                put_u16(buf, 10, chunks);
+                put_u32(buf, 16, crc);
+            }
+            Packet::Nack {
+                frame_id,
+                error,
+                missing,
+            } => {
+                buf[5] = KIND_NACK;
+                buf[7] = error.code();
+                put_u16(buf, 8, frame_id);
+                buf[HEADER_LEN..len].copy_from_slice(&missing.to_le_bytes());
+            }
+        }
+
+        Ok(len)
+    }
+}
+
+/// Nack answering a datagram that could not be decoded, carrying the frame
+/// id and chunk of the offending packet when its header is readable.
+pub fn error_reply(buf: &[u8], error: FrameError) -> Packet<'static> {
+    if buf.len() < HEADER_LEN || buf[..4] != MAGIC {
+        return Packet::Nack {
+            frame_id: 0,
+            error,
+            missing: 0,
+        };
+    }
+
+    let chunk = get_u16(buf, 10) as usize;
+    Packet::Nack {
+        frame_id: get_u16(buf, 8),
+        error,
+        missing: if buf[5] == KIND_DATA && chunk < MAX_CHUNKS {
+            1 << chunk
+        } else {
+            0
+        },
+    }
+}
+
+pub const LEGACY_HEADER_LEN: usize = 8;
+
+/// Packet of the original header-less protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyPacket<'a> {
+    Chunk { offset: usize, payload: &'a [u8] },
+    Display,
+}
+
+impl<'a> LegacyPacket<'a> {
+    /// Decode a datagram, checking the chunk fits a frame of `frame_len` bytes.
+    pub fn decode(buf: &'a [u8], frame_len: usize) -> Result<Self, FrameError> {
+        if buf.len() < LEGACY_HEADER_LEN {
+            return Err(FrameError::Short);
+        }
+
+        let offset = get_u32(buf, 0) as i32;
+        let size = get_u32(buf, 4) as usize;
+        if offset < 0 {
+            return Ok(LegacyPacket::Display);
+        }
+
+        let payload = &buf[LEGACY_HEADER_LEN..];
+        if payload.len() < size {
+            return Err(FrameError::Short);
+        }
+        if payload.len() != size {
+            return Err(FrameError::SizeMismatch);
+        }
+
+        let offset = offset as usize;
+        if offset + size > frame_len {
+            return Err(FrameError::OutOfRange);
+        }
+
+        Ok(LegacyPacket::Chunk { offset, payload })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, FrameError> {
+        let (offset, payload): (i32, &[u8]) = match *self {
+            LegacyPacket::Chunk { offset, payload } => (offset as i32, payload),
+            LegacyPacket::Display => (-1, &[]),
+        };
+        let len = LEGACY_HEADER_LEN + payload.len();
+        if buf.len() < len {
+            return Err(FrameError::BufferTooSmall);
+        }
+
+        put_u32(buf, 0, offset as u32);
+        put_u32(buf, 4, payload.len() as u32);
+        buf[LEGACY_HEADER_LEN..len].copy_from_slice(payload);
+        Ok(len)
+    }
+}
+
+/// Bitmap with the first `chunks` bits set.
+pub fn chunk_mask(chunks: u16) -> u64 {
+    if chunks as usize >= MAX_CHUNKS {
+        u64::MAX
+    } else {
+        (1 << chunks) - 1
+    }
+}
+
+/// What the receiver should do after handling a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// Send the packet back to the peer.
+    Reply(Packet<'static>),
+    /// The frame is complete and verified: send the Ack and display it.
+    Complete(Packet<'static>),
+}
+
+/// Receiver side of the protocol, collects the chunks of one frame at a time.
+pub struct Assembler {
+    frame_id: Option<u16>,
+    received: u64,
+    completed: Option<(u16, u16, u32)>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    pub const fn new() -> Self {
+        Self {
+            frame_id: None,
+            received: 0,
+            completed: None,
+        }
+    }
+
+    /// Bitmap of the chunks received so far for the current frame.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn handle(&mut self, frame: &mut [u8], packet: &Packet) -> Action {
+        match *packet {
+            Packet::Data {
+                frame_id,
+                chunk,
+                offset,
+                compression,
+                payload,
+            } => {
+                if self.frame_id != Some(frame_id) {
+                    self.frame_id = Some(frame_id);
+                    self.received = 0;
+                }
+
+                let start = offset as usize;
+                let ret = match frame.get_mut(start..) {
+                    Some(out) if (chunk as usize) < MAX_CHUNKS => {
+                        compress::decompress(compression, payload, out)
+                    }
+                    _ => Err(FrameError::OutOfRange),
+                };
+                if let Err(error) = ret {
+                    return Action::Reply(Packet::Nack {
+                        frame_id,
+                        error,
+                        missing: 1 << (chunk as usize % MAX_CHUNKS),
+                    });
+                }
+
+                self.received |= 1 << chunk;
+                Action::None
+            }
+            Packet::Commit {
+                frame_id,
+                chunks,
+                len,
+                crc,
+            } => {
+                let mask = chunk_mask(chunks);
+
+                // Our Ack got lost, the frame is already on the panel
+                if self.completed == Some((frame_id, chunks, crc)) {
+                    return Action::Reply(Packet::Ack {
+                        frame_id,
+                        chunks,
+                        crc,
+                    });
+                }
+
+                let nack = |error| {
+                    Action::Reply(Packet::Nack {
+                        frame_id,
+                        error,
+                        missing: mask,
+                    })
+                };
+
+                if self.frame_id != Some(frame_id) {
+                    return nack(FrameError::UnknownFrame);
+                }
+                if chunks as usize > MAX_CHUNKS || len as usize > frame.len() {
+                    return nack(FrameError::OutOfRange);
+                }
+
+                let missing = mask & !self.received;
+                if missing != 0 {
+                    return Action::Reply(Packet::Nack {
+                        frame_id,
+                        error: FrameError::Missing,
+                        missing,
+                    });
+                }
+
+                if crc32(&frame[..len as usize]) != crc {
+                    self.received = 0;
+                    return nack(FrameError::FrameCrc);
+                }
+
+                self.frame_id = None;
+                self.received = 0;
+                self.completed = Some((frame_id, chunks, crc));
+                Action::Complete(Packet::Ack {
+                    frame_id,
+                    chunks,
+                    crc,
+                })
+            }
+            Packet::Ack { .. } | Packet::Nack { .. } => Action::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn roundtrip(packet: Packet) {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let n = packet.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf[..n]), Ok(packet));
+    }
+
+    fn data(chunk: u16, offset: u32, payload: &[u8]) -> Packet<'_> {
+        Packet::Data {
+            frame_id: 7,
+            chunk,
+            offset,
+            compression: Compression::None,
+            payload,
+        }
+    }
+
+    #[test]
+    fn crc_matches_zlib() {
+        assert_eq!(crc32(b"hello"), 0x3610_a686);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn header_layout() {
+        let mut buf = [0; 32];
+        let packet = Packet::Data {
+            frame_id: 0x0102,
+            chunk: 0x0304,
+            offset: 0x0506_0708,
+            compression: Compression::Lzss,
+            payload: b"hello",
+        };
+        assert_eq!(packet.encode(&mut buf), Ok(25));
+        assert_eq!(
+            buf[..HEADER_LEN],
+            [
+                b'E', b'P', b'D', b'F', VERSION, KIND_DATA, 2, 0, 0x02, 0x01, 0x04, 0x03, 0x08,
+                0x07, 0x06, 0x05, 0x86, 0xa6, 0x10, 0x36
+            ]
+        );
+        assert_eq!(&buf[HEADER_LEN..25], b"hello");
+    }
+
+    #[test]
+    fn encode_decode() {
+        roundtrip(data(3, 3000, &[0xaa; MAX_PAYLOAD_LEN]));
+        roundtrip(data(0, 0, &[]));
+        roundtrip(Packet::Commit {
+            frame_id: 9,
+            chunks: 15,
+            len: 15000,
+            crc: 0xdead_beef,
+        });
+        roundtrip(Packet::Ack {
+            frame_id: 9,
+            chunks: 15,
+            crc: 0xdead_beef,
+        });
+        roundtrip(Packet::Nack {
+            frame_id: 9,
+            error: FrameError::Missing,
+            missing: 1 << 63 | 1 << 3,
+        });
+
+        let mut small = [0; HEADER_LEN + 3];
+        assert_eq!(
+            data(0, 0, b"four").encode(&mut small),
+            Err(FrameError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut buf = [0; 64];
+        let n = data(1, 1000, b"payload").encode(&mut buf).unwrap();
+        assert_eq!(
+            Packet::decode(&buf[..HEADER_LEN - 1]),
+            Err(FrameError::Short)
+        );
+
+        let mut bad = buf;
+        bad[0] = b'X';
+        assert_eq!(Packet::decode(&bad[..n]), Err(FrameError::BadMagic));
+        let mut bad = buf;
+        bad[4] = VERSION + 1;
+        assert_eq!(Packet::decode(&bad[..n]), Err(FrameError::Version));
+        let mut bad = buf;
+        bad[5] = 9;
+        assert_eq!(Packet::decode(&bad[..n]), Err(FrameError::Kind));
+        let mut bad = buf;
+        bad[n - 1] ^= 1;
+        assert_eq!(Packet::decode(&bad[..n]), Err(FrameError::ChunkCrc));
+        let mut bad = buf;
+        bad[6] = 3;
+        assert_eq!(Packet::decode(&bad[..n]), Err(FrameError::Compression));
+        // Truncated payload no longer matches its CRC
+        assert_eq!(Packet::decode(&buf[..n - 1]), Err(FrameError::ChunkCrc));
+
+        let nack = Packet::Nack {
+            frame_id: 1,
+            error: FrameError::FrameCrc,
+            missing: 0,
+        };
+        let n = nack.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf[..n - 1]), Err(FrameError::Short));
+        buf[7] = 0xee;
+        assert_eq!(Packet::decode(&buf[..n]), Err(FrameError::Kind));
+    }
+
+    #[test]
+    fn error_codes() {
+        for code in 0..=u8::MAX {
+            if let Some(error) = FrameError::from_code(code) {
+                assert_eq!(error.code(), code);
+            }
+        }
+    }
+
+    #[test]
+    fn error_reply_names_the_chunk() {
+        let mut buf = [0; 64];
+        let n = data(5, 0, b"x").encode(&mut buf).unwrap();
+        assert_eq!(
+            error_reply(&buf[..n], FrameError::ChunkCrc),
+            Packet::Nack {
+                frame_id: 7,
+                error: FrameError::ChunkCrc,
+                missing: 1 << 5,
+            }
+        );
+        assert_eq!(
+            error_reply(b"junk", FrameError::Short),
+            Packet::Nack {
+                frame_id: 0,
+                error: FrameError::Short,
+                missing: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn legacy_packets() {
+        let mut buf = [0; 32];
+        let chunk = LegacyPacket::Chunk {
+            offset: 100,
+            payload: b"abc",
+        };
+        let n = chunk.encode(&mut buf).unwrap();
+        assert_eq!(buf[..n], [100, 0, 0, 0, 3, 0, 0, 0, b'a', b'b', b'c']);
+        assert_eq!(LegacyPacket::decode(&buf[..n], 15000), Ok(chunk));
+        assert_eq!(
+            LegacyPacket::decode(&buf[..n], 102),
+            Err(FrameError::OutOfRange)
+        );
+        assert_eq!(
+            LegacyPacket::decode(&buf[..n - 1], 15000),
+            Err(FrameError::Short)
+        );
+        buf[n] = b'd';
+        assert_eq!(
+            LegacyPacket::decode(&buf[..n + 1], 15000),
+            Err(FrameError::SizeMismatch)
+        );
+
+        let n = LegacyPacket::Display.encode(&mut buf).unwrap();
+        assert_eq!(
+            LegacyPacket::decode(&buf[..n], 15000),
+            Ok(LegacyPacket::Display)
+        );
+    }
+
+    #[test]
+    fn chunk_masks() {
+        assert_eq!(chunk_mask(0), 0);
+        assert_eq!(chunk_mask(3), 0b111);
+        assert_eq!(chunk_mask(64), u64::MAX);
+        assert_eq!(chunk_mask(u16::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn assemble_with_retransmit() {
+        let image: Vec<u8> = (0..15000u32).map(|i| (i * 7) as u8).collect();
+        let mut frame = vec![0; 15000];
+        let mut assembler = Assembler::new();
+        let chunks: Vec<&[u8]> = image.chunks(1000).collect();
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            if i != 3 {
+                let packet = data(i as u16, i as u32 * 1000, chunk);
+                assert_eq!(assembler.handle(&mut frame, &packet), Action::None);
+            }
+        }
+        let commit = Packet::Commit {
+            frame_id: 7,
+            chunks: 15,
+            len: 15000,
+            crc: crc32(&image),
+        };
+        assert_eq!(
+            assembler.handle(&mut frame, &commit),
+            Action::Reply(Packet::Nack {
+                frame_id: 7,
+                error: FrameError::Missing,
+                missing: 1 << 3,
+            })
+        );
+
+        assembler.handle(&mut frame, &data(3, 3000, chunks[3]));
+        let ack = Packet::Ack {
+            frame_id: 7,
+            chunks: 15,
+            crc: crc32(&image),
+        };
+        assert_eq!(assembler.handle(&mut frame, &commit), Action::Complete(ack));
+        assert_eq!(frame, image);
+        // The Ack got lost and the Commit is sent again
+        assert_eq!(assembler.handle(&mut frame, &commit), Action::Reply(ack));
+    }
+
+    #[test]
+    fn assemble_errors() {
+        let mut frame = vec![0; 100];
+        let mut assembler = Assembler::new();
+        let commit = |frame_id, crc| Packet::Commit {
+            frame_id,
+            chunks: 1,
+            len: 4,
+            crc,
+        };
+        let nack = |error, missing| {
+            Action::Reply(Packet::Nack {
+                frame_id: 7,
+                error,
+                missing,
+            })
+        };
+
+        assert_eq!(
+            assembler.handle(&mut frame, &commit(7, 0)),
+            nack(FrameError::UnknownFrame, 1)
+        );
+        assert_eq!(
+            assembler.handle(&mut frame, &data(2, 98, b"abc")),
+            nack(FrameError::OutOfRange, 1 << 2)
+        );
+        assert_eq!(
+            assembler.handle(&mut frame, &data(64, 0, b"abc")),
+            nack(FrameError::OutOfRange, 1)
+        );
+
+        assembler.handle(&mut frame, &data(0, 0, b"abcd"));
+        assert_eq!(assembler.received(), 1);
+        assert_eq!(
+            assembler.handle(&mut frame, &commit(7, 0)),
+            nack(FrameError::FrameCrc, 1)
+        );
+        // A bad frame CRC drops what was received
+        assert_eq!(assembler.received(), 0);
+
+        // Chunks of another frame start over
+        assembler.handle(&mut frame, &data(0, 0, b"abcd"));
+        let other = Packet::Data {
+            frame_id: 8,
+            chunk: 1,
+            offset: 4,
+            compression: Compression::None,
+            payload: b"e",
+        };
+        assembler.handle(&mut frame, &other);
+        assert_eq!(assembler.received(), 1 << 1);
+    }
+
+    proptest! {
+        #[test]
+        fn decode_never_panics(buf in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = Packet::decode(&buf);
+            let _ = LegacyPacket::decode(&buf, 1000);
+        }
+
+        /// Random bodies behind a valid header reach every packet kind.
+        #[test]
+        fn decode_header_never_panics(
+            kind in 0u8..6,
+            flags in any::<u8>(),
+            rest in prop::collection::vec(any::<u8>(), 0..40),
+        ) {
+            let mut buf = std::vec::Vec::from(MAGIC);
+            buf.extend([VERSION, kind, flags]);
+            buf.extend(rest);
+            let _ = Packet::decode(&buf);
+        }
+
+        #[test]
+        fn data_roundtrip(
+            frame_id in any::<u16>(),
+            chunk in any::<u16>(),
+            offset in any::<u32>(),
+            payload in prop::collection::vec(any::<u8>(), 0..MAX_PAYLOAD_LEN),
+        ) {
+            roundtrip(Packet::Data {
+                frame_id,
+                chunk,
+                offset,
+                compression: Compression::Lzss,
+                payload: &payload,
+            });
+        }
+
+        /// Any corrupted payload byte is caught by the chunk CRC.
+        #[test]
+        fn corrupted_data_is_rejected(
+            payload in prop::collection::vec(any::<u8>(), 1..200),
+            at in any::<usize>(),
+            flip in 1u8..,
+        ) {
+            let mut buf = [0; MAX_PACKET_LEN];
+            let n = data(0, 0, &payload).encode(&mut buf).unwrap();
+            buf[HEADER_LEN + at % payload.len()] ^= flip;
+            prop_assert_eq!(Packet::decode(&buf[..n]), Err(FrameError::ChunkCrc));
+        }
+
+        #[test]
+        fn assembler_never_panics(
+            packets in prop::collection::vec(
+                (0u8..2, 0u16..70, any::<u32>(), prop::collection::vec(any::<u8>(), 0..32)),
+                0..20,
+            ),
+        ) {
+            let mut frame = [0; 100];
+            let mut assembler = Assembler::new();
+            for (kind, chunk, offset, payload) in &packets {
+                let packet = match kind {
+                    0 => Packet::Data {
+                        frame_id: 1,
+                        chunk: *chunk,
+                        offset: *offset % 128,
+                        compression: Compression::from_flags(payload.first().map_or(0, |b| b % 3))
+                            .unwrap(),
+                        payload,
+                    },
+                    _ => Packet::Commit {
+                        frame_id: 1,
+                        chunks: *chunk,
+                        len: *offset % 128,
+                        crc: *offset,
+                    },
+                };
+                let _ = assembler.handle(&mut frame, &packet);
+            }
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Just enough HTTP/1.1 for the captive configuration page: one request per
+//! connection, a body only with `Content-Length` and urlencoded forms.
+use core::fmt::Write;
+
+use heapless::String;
+
+/// Largest request taken, headers and body, the size of the receive buffer.
+pub const MAX_REQUEST_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpError {
+    /// More bytes are needed, the headers or the body are not all there.
+    Incomplete,
+    BadRequest,
+    /// A decoded value does not fit.
+    TooLong,
+}
+
+impl core::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            HttpError::Incomplete => "incomplete request",
+            HttpError::BadRequest => "bad request",
+            HttpError::TooLong => "value too long",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for HttpError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: Method,
+    /// Target without the query string.
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub host: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Parse the request at the start of `buf`, [`HttpError::Incomplete`]
+    /// until its headers and body have all been received. A body that would
+    /// end past [`MAX_REQUEST_LEN`] is a [`HttpError::BadRequest`].
+    pub fn parse(buf: &'a [u8]) -> Result<Self, HttpError> {
+        let head_len = buf
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or(HttpError::Incomplete)?;
+        let head = core::str::from_utf8(&buf[..head_len]).map_err(|_| HttpError::BadRequest)?;
+        let mut lines = head.split("\r\n");
+
+        let mut words = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            return Err(HttpError::BadRequest);
+        };
+        if !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+            return Err(HttpError::BadRequest);
+        }
+        let method = match method {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            _ => Method::Other,
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+
+        let mut host = None;
+        let mut body_len = 0;
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(HttpError::BadRequest)?;
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("host") {
+                host = Some(value);
+            } else if name.eq_ignore_ascii_case("content-length") {
+                body_len = value.parse().map_err(|_| HttpError::BadRequest)?;
+            }
+        }
+
+        let body_start = head_len + 4;
+        let body_end = body_start
+            .checked_add(body_len)
+            .filter(|end| *end <= MAX_REQUEST_LEN)
+            .ok_or(HttpError::BadRequest)?;
+        let body = buf.get(body_start..body_end).ok_or(HttpError::Incomplete)?;
+        Ok(Self {
+            method,
+            path,
+            query,
+            host,
+            body,
+        })
+    }
+}
+
+/// Raw value of the field `name` of an urlencoded form, see [`url_decode`].
+pub fn form_value<'a>(form: &'a str, name: &str) -> Option<&'a str> {
+    form.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn hex_digit(b: u8) -> Result<u8, HttpError> {
+    match b {
+        b'0'..=b'9' => Ok(b - b'0'),
+        b'a'..=b'f' => Ok(b - b'a' + 10),
+        b'A'..=b'F' => Ok(b - b'A' + 10),
+        _ => Err(HttpError::BadRequest),
+    }
+}
+
+/// Decode `%XX` escapes and `+` for spaces.
+pub fn url_decode<const N: usize>(value: &str) -> Result<String<N>, HttpError> {
+    let mut bytes: heapless::Vec<u8, N> = heapless::Vec::new();
+    let mut rest = value.as_bytes();
+    while let [b, tail @ ..] = rest {
+        let (decoded, tail) = match (b, tail) {
+            (b'+', _) => (b' ', tail),
+            (b'%', [hi, lo, tail @ ..]) => (hex_digit(*hi)? << 4 | hex_digit(*lo)?, tail),
+            (b'%', _) => return Err(HttpError::BadRequest),
+            _ => (*b, tail),
+        };
+        bytes.push(decoded).map_err(|_| HttpError::TooLong)?;
+        rest = tail;
+    }
+    String::from_utf8(bytes).map_err(|_| HttpError::BadRequest)
+}
+
+/// Status line and headers of a response with a `len` bytes body.
+pub fn write_head(
+    w: &mut impl Write,
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    len: usize,
+) -> core::fmt::Result {
+    write!(
+        w,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status, reason, content_type, len
+    )
+}
+
+/// A whole response sending the client to `location`.
+pub fn write_redirect(w: &mut impl Write, location: &str) -> core::fmt::Result {
+    write!(
+        w,
+        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\
+         Connection: close\r\n\r\n",
+        location
+    )
+}
+
+/// Text escaped for HTML content and attribute values.
+pub struct Escaped<'a>(pub &'a str);
+
+impl core::fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                _ => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::format;
+
+    use super::*;
+
+    #[test]
+    fn requests() {
+        let raw = b"GET /generate_204?x=1 HTTP/1.1\r\nHost: clients3.google.com\r\n\
+                    Accept: */*\r\n\r\n";
+        assert_eq!(
+            Request::parse(raw),
+            Ok(Request {
+                method: Method::Get,
+                path: "/generate_204",
+                query: Some("x=1"),
+                host: Some("clients3.google.com"),
+                body: b"",
+            })
+        );
+
+        let raw = b"POST /save HTTP/1.1\r\ncontent-length: 7\r\n\r\nssid=ab";
+        for end in 0..raw.len() {
+            assert_eq!(
+                Request::parse(&raw[..end]),
+                Err(HttpError::Incomplete),
+                "{end}"
+            );
+        }
+        let request = Request::parse(raw).unwrap();
+        assert_eq!(
+            (request.method, request.body),
+            (Method::Post, &b"ssid=ab"[..])
+        );
+        // Whatever follows the body is left
+        assert_eq!(
+            Request::parse(&[&raw[..], b"more"].concat()).unwrap().body,
+            b"ssid=ab"
+        );
+
+        let request = Request::parse(b"DELETE / HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!((request.method, request.host), (Method::Other, None));
+    }
+
+    #[test]
+    fn bad_requests() {
+        for raw in [
+            &b"GET\r\n\r\n"[..],
+            b"GET / FTP\r\n\r\n",
+            b"GET nowhere HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"GET / HTTP/1.0\r\nbad\r\n\r\n",
+            b"GET / HTTP/1.0\r\nContent-Length: x\r\n\r\n",
+            b"GET / HTTP/1.0\r\nContent-Length: -1\r\n\r\n",
+            b"GET /\xff HTTP/1.0\r\n\r\n",
+        ] {
+            assert_eq!(
+                Request::parse(raw),
+                Err(HttpError::BadRequest),
+                "{}",
+                raw.escape_ascii()
+            );
+        }
+    }
+
+    #[test]
+    fn body_length() {
+        let head = "POST /save HTTP/1.1\r\nContent-Length: ";
+        let with_len = |len: usize| format!("{head}{len}\r\n\r\n");
+        // Three digit lengths, then the blank line
+        let body_start = head.len() + 3 + 4;
+        let fits = with_len(MAX_REQUEST_LEN - body_start);
+        assert_eq!(fits.len(), body_start);
+        // A body filling the buffer waits for the rest
+        assert_eq!(Request::parse(fits.as_bytes()), Err(HttpError::Incomplete));
+        for len in [
+            MAX_REQUEST_LEN - body_start + 1,
+            MAX_REQUEST_LEN,
+            usize::MAX,
+        ] {
+            assert_eq!(
+                Request::parse(with_len(len).as_bytes()),
+                Err(HttpError::BadRequest),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn forms() {
+        assert_eq!(url_decode::<16>("a+b%20c%2Bd%C3%A9").unwrap(), "a b c+dé");
+        assert_eq!(url_decode::<4>("abcd").unwrap(), "abcd");
+        assert_eq!(url_decode::<4>("abcde"), Err(HttpError::TooLong));
+        for bad in ["%4", "%zz", "%ff", "a%"] {
+            assert_eq!(url_decode::<8>(bad), Err(HttpError::BadRequest), "{bad}");
+        }
+        assert_eq!(form_value("a=1&b=&c=3", "a"), Some("1"));
+        assert_eq!(form_value("a=1&b=&c=3", "b"), Some(""));
+        assert_eq!(form_value("a=1&b=&c=3", "d"), None);
+        assert_eq!(form_value("a&b=2", "a"), None);
+    }
+
+    #[test]
+    fn responses() {
+        let mut head = String::<160>::new();
+        write_head(&mut head, 200, "OK", "text/html", 42).unwrap();
+        assert_eq!(
+            head,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 42\r\n\
+             Cache-Control: no-store\r\nConnection: close\r\n\r\n"
+        );
+        let mut redirect = String::<160>::new();
+        write_redirect(&mut redirect, "http://192.168.4.1/").unwrap();
+        assert_eq!(
+            redirect,
+            "HTTP/1.1 302 Found\r\nLocation: http://192.168.4.1/\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(
+            format!("{}", Escaped("<a href=\"x\">&'é")),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;é"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! IPv4 settings of the station: an address from DHCP or a static one.
+//!
+//! Addresses are written dotted, the static address with its prefix length
+//! as in `192.168.1.50/24`, DNS servers separated by commas.
+use core::str::FromStr;
+
+use heapless::Vec;
+
+/// DNS servers kept, as many as embassy-net takes.
+pub const MAX_DNS: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ipv4Error {
+    BadAddress,
+    BadPrefix,
+    /// The address is the network or broadcast address of its subnet.
+    NotHost,
+    GatewayOutside,
+    TooManyDns,
+    BadMode,
+}
+
+impl core::fmt::Display for Ipv4Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            Ipv4Error::BadAddress => "bad address",
+            Ipv4Error::BadPrefix => "bad prefix length",
+            Ipv4Error::NotHost => "not a host address",
+            Ipv4Error::GatewayOutside => "gateway outside the subnet",
+            Ipv4Error::TooManyDns => "too many DNS servers",
+            Ipv4Error::BadMode => "mode is dhcp or static",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for Ipv4Error {}
+
+/// Dotted quad, without leading zeros that could be read as octal.
+pub fn parse_addr(s: &str) -> Result<[u8; 4], Ipv4Error> {
+    let mut addr = [0; 4];
+    let mut parts = s.split('.');
+    for byte in addr.iter_mut() {
+        let part = parts.next().ok_or(Ipv4Error::BadAddress)?;
+        let digits_ok = (1..=3).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_digit());
+        if !digits_ok || (part.len() > 1 && part.starts_with('0')) {
+            return Err(Ipv4Error::BadAddress);
+        }
+        *byte = part.parse().map_err(|_| Ipv4Error::BadAddress)?;
+    }
+    match parts.next() {
+        Some(_) => Err(Ipv4Error::BadAddress),
+        None => Ok(addr),
+    }
+}
+
+/// Address and prefix length of its subnet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    pub addr: [u8; 4],
+    pub prefix: u8,
+}
+
+impl Cidr {
+    /// Parse `a.b.c.d/n`, the address must be a host of the subnet.
+    pub fn parse(s: &str) -> Result<Self, Ipv4Error> {
+        let (addr, prefix) = s.split_once('/').ok_or(Ipv4Error::BadPrefix)?;
+        let addr = parse_addr(addr)?;
+        let prefix = match prefix.parse::<u8>() {
+            Ok(p) if (1..=32).contains(&p) && !prefix.starts_with('+') => p,
+            _ => return Err(Ipv4Error::BadPrefix),
+        };
+        let cidr = Self { addr, prefix };
+        // /31 and /32 have no network nor broadcast address
+        let host = u32::from_be_bytes(addr) & !cidr.mask();
+        if prefix < 31 && (host == 0 || host == !cidr.mask()) {
+            return Err(Ipv4Error::NotHost);
+        }
+        Ok(cidr)
+    }
+
+    pub fn mask(&self) -> u32 {
+        u32::MAX << (32 - self.prefix as u32)
+    }
+
+    pub fn contains(&self, addr: [u8; 4]) -> bool {
+        let mask = self.mask();
+        u32::from_be_bytes(addr) & mask == u32::from_be_bytes(self.addr) & mask
+    }
+}
+
+/// Comma separated DNS servers, empty for none.
+pub fn parse_dns(s: &str) -> Result<Vec<[u8; 4], MAX_DNS>, Ipv4Error> {
+    let mut servers = Vec::new();
+    if s.is_empty() {
+        return Ok(servers);
+    }
+    for server in s.split(',') {
+        let addr = parse_addr(server.trim())?;
+        servers.push(addr).map_err(|_| Ipv4Error::TooManyDns)?;
+    }
+    Ok(servers)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Dhcp,
+    Static,
+}
+
+impl FromStr for Mode {
+    type Err = Ipv4Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dhcp" => Ok(Mode::Dhcp),
+            "static" => Ok(Mode::Static),
+            _ => Err(Ipv4Error::BadMode),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StaticConfig {
+    pub address: Cidr,
+    pub gateway: Option<[u8; 4]>,
+    pub dns: Vec<[u8; 4], MAX_DNS>,
+}
+
+impl StaticConfig {
+    /// Parse the settings of a static address, `gateway` and `dns` may be
+    /// empty. The gateway must be another host of the subnet.
+    pub fn parse(address: &str, gateway: &str, dns: &str) -> Result<Self, Ipv4Error> {
+        let address = Cidr::parse(address)?;
+        let gateway = match gateway {
+            "" => None,
+            g => {
+                let g = parse_addr(g)?;
+                if !address.contains(g) || g == address.addr {
+                    return Err(Ipv4Error::GatewayOutside);
+                }
+                Some(g)
+            }
+        };
+        Ok(Self {
+            address,
+            gateway,
+            dns: parse_dns(dns)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_addr("192.168.1.5"), Ok([192, 168, 1, 5]));
+        assert_eq!(parse_addr("0.0.0.0"), Ok([0; 4]));
+        for bad in [
+            "192.168.1",
+            "1.2.3.4.5",
+            "1.2.3.256",
+            "01.2.3.4",
+            "1.2.3.00",
+            "1.2.3.+4",
+            "1.2.3.-4",
+            "1..3.4",
+            "1.2.3.4.",
+            " 1.2.3.4",
+            "a.b.c.d",
+            "",
+        ] {
+            assert_eq!(parse_addr(bad), Err(Ipv4Error::BadAddress), "{bad}");
+        }
+    }
+
+    #[test]
+    fn cidrs() {
+        let cidr = Cidr::parse("10.0.0.7/8").unwrap();
+        assert_eq!((cidr.addr, cidr.prefix), ([10, 0, 0, 7], 8));
+        assert_eq!(cidr.mask(), 0xff00_0000);
+        assert!(cidr.contains([10, 9, 9, 9]));
+        assert!(!cidr.contains([11, 0, 0, 1]));
+
+        for (s, e) in [
+            ("10.0.0.7", Ipv4Error::BadPrefix),
+            ("10.0.0.7/0", Ipv4Error::BadPrefix),
+            ("10.0.0.7/33", Ipv4Error::BadPrefix),
+            ("10.0.0.7/+24", Ipv4Error::BadPrefix),
+            ("10.0.0.7/", Ipv4Error::BadPrefix),
+            ("10.0.0.256/24", Ipv4Error::BadAddress),
+            ("10.0.0.0/24", Ipv4Error::NotHost),
+            ("10.0.0.255/24", Ipv4Error::NotHost),
+            ("10.0.0.4/30", Ipv4Error::NotHost),
+        ] {
+            assert_eq!(Cidr::parse(s), Err(e), "{s}");
+        }
+        // Point to point and single host subnets use every address
+        for s in ["10.0.0.0/31", "10.0.0.1/31", "10.0.0.0/32", "0.0.0.1/1"] {
+            assert!(Cidr::parse(s).is_ok(), "{s}");
+        }
+        assert_eq!(Cidr::parse("10.0.0.1/32").unwrap().mask(), u32::MAX);
+    }
+
+    #[test]
+    fn dns_servers() {
+        assert!(parse_dns("").unwrap().is_empty());
+        assert_eq!(
+            parse_dns("1.1.1.1, 8.8.8.8").unwrap(),
+            [[1, 1, 1, 1], [8, 8, 8, 8]]
+        );
+        assert_eq!(parse_dns("1.1.1.1,2.2.2.2,3.3.3.3").unwrap().len(), MAX_DNS);
+        assert_eq!(
+            parse_dns("1.1.1.1,2.2.2.2,3.3.3.3,4.4.4.4"),
+            Err(Ipv4Error::TooManyDns)
+        );
+        assert_eq!(parse_dns("1.1.1.1,"), Err(Ipv4Error::BadAddress));
+    }
+
+    #[test]
+    fn static_config() {
+        assert_eq!("dhcp".parse(), Ok(Mode::Dhcp));
+        assert_eq!("static".parse(), Ok(Mode::Static));
+        assert_eq!("Static".parse::<Mode>(), Err(Ipv4Error::BadMode));
+
+        let config = StaticConfig::parse("192.168.1.50/24", "192.168.1.1", "192.168.1.1").unwrap();
+        assert_eq!(config.address.addr, [192, 168, 1, 50]);
+        assert_eq!(config.gateway, Some([192, 168, 1, 1]));
+        assert_eq!(config.dns, [[192, 168, 1, 1]]);
+        let config = StaticConfig::parse("192.168.1.50/24", "", "").unwrap();
+        assert_eq!((config.gateway, config.dns.len()), (None, 0));
+
+        for gateway in ["192.168.2.1", "192.168.1.50"] {
+            assert_eq!(
+                StaticConfig::parse("192.168.1.50/24", gateway, ""),
+                Err(Ipv4Error::GatewayOutside),
+                "{gateway}"
+            );
+        }
+        assert_eq!(
+            StaticConfig::parse("192.168.1.50/24", "192.168.1.x", ""),
+            Err(Ipv4Error::BadAddress)
+        );
+        assert_eq!(
+            StaticConfig::parse("192.168.1.50", "", ""),
+            Err(Ipv4Error::BadPrefix)
+        );
+    }
+}
@@ -0,0 +1,41 @@
+#![cfg_attr(not(test), no_std)]
+//! Types shared between the dbhome-epd firmware and the host tools.
+pub mod compress;
+pub mod config;
+pub mod cron;
+pub mod dhcp;
+pub mod dither;
+pub mod dns;
+pub mod frame;
+pub mod http;
+pub mod ipv4;
+pub mod portal;
+pub mod provision;
+pub mod reply;
+pub mod roam;
+pub mod sntp;
+pub mod tz;
+
+pub const EPD_WIDTH: usize = 400;
+pub const EPD_HEIGHT: usize = 300;
+/// Bytes in a 1bpp frame, MSB is the leftmost pixel and a set bit is white.
+pub const FRAME_LEN: usize = EPD_WIDTH * EPD_HEIGHT / 8;
+
+/// UDP port receiving framebuffer uploads.
+pub const FRAME_PORT: u16 = 23000;
+/// TCP port of the text command session.
+pub const CONTROL_PORT: u16 = 20000;
+/// Name the device answers to over mDNS, as `<name>.local`.
+pub const DEFAULT_HOSTNAME: &str = "dbhome-epd";
+/// Longest device name, short enough for `<name>-setup` to be an SSID.
+pub const HOSTNAME_LEN: usize = 24;
+
+/// Whether `name` can be a device name: ASCII letters, digits and `-`, not
+/// at either end.
+pub fn valid_hostname(name: &str) -> bool {
+    let chars_ok = name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    (1..=HOSTNAME_LEN).contains(&name.len())
+        && chars_ok
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
@@ -0,0 +1,285 @@
+//! Configuration page of the fallback access point.
+//!
+//! When no network can be joined the panel opens an access point where every
+//! DNS name leads to it, so phones show this page as a captive portal. The
+//! form sets the credentials of a network and the device name.
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::http::{self, Escaped, HttpError};
+use crate::provision::{self, Credentials, PASSWORD_LEN, SSID_LEN};
+use crate::{valid_hostname, HOSTNAME_LEN};
+
+pub const HTTP_PORT: u16 = 80;
+/// Address of the device on its access point, a /24.
+pub const AP_ADDR: [u8; 4] = [192, 168, 4, 1];
+/// Where every other path is redirected.
+pub const HOME: &str = "http://192.168.4.1/";
+/// Path the form is posted to.
+pub const SAVE_PATH: &str = "/save";
+/// Room for the page, with a full scan of SSIDs that all need escaping.
+pub const PAGE_LEN: usize = 4096;
+/// Longest message shown on the page.
+pub const MESSAGE_LEN: usize = 128;
+/// Length of the WPA2 passphrase of the access point.
+pub const AP_PASSWORD_LEN: usize = 12;
+/// Characters of the passphrase, without look-alikes such as `l`, `1`, `o` and `0`.
+const AP_PASSWORD_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormError {
+    Http(HttpError),
+    Missing,
+    BadCredentials,
+    BadHostname,
+}
+
+impl core::fmt::Display for FormError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FormError::Http(e) => write!(f, "{}", e),
+            FormError::Missing => f.write_str("missing field"),
+            FormError::BadCredentials => {
+                f.write_str("bad network name or password (8 to 64 characters)")
+            }
+            FormError::BadHostname => f.write_str("bad device name (letters, digits and -)"),
+        }
+    }
+}
+
+impl core::error::Error for FormError {}
+
+impl From<HttpError> for FormError {
+    fn from(e: HttpError) -> Self {
+        FormError::Http(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Form {
+    pub credentials: Credentials,
+    pub hostname: String<HOSTNAME_LEN>,
+}
+
+fn field<const N: usize>(form: &str, name: &str) -> Result<String<N>, FormError> {
+    let value = http::form_value(form, name).ok_or(FormError::Missing)?;
+    Ok(http::url_decode(value)?)
+}
+
+impl Form {
+    /// Parse the urlencoded body of the form.
+    pub fn parse(body: &[u8]) -> Result<Self, FormError> {
+        let form = core::str::from_utf8(body).map_err(|_| HttpError::BadRequest)?;
+        let ssid: String<SSID_LEN> = field(form, "ssid").map_err(|_| FormError::BadCredentials)?;
+        let password: String<PASSWORD_LEN> =
+            field(form, "password").map_err(|_| FormError::BadCredentials)?;
+        let hostname: String<HOSTNAME_LEN> =
+            field(form, "hostname").map_err(|_| FormError::BadHostname)?;
+        if !valid_hostname(&hostname) {
+            return Err(FormError::BadHostname);
+        }
+        Ok(Self {
+            credentials: Credentials::new(&ssid, &password)
+                .map_err(|_| FormError::BadCredentials)?,
+            hostname,
+        })
+    }
+}
+
+/// What the page shows.
+pub struct Page<'a> {
+    pub hostname: &'a str,
+    /// Networks in range, a scan value of [`provision`].
+    pub scan: &'a [u8],
+    /// Outcome of the last submission.
+    pub message: Option<&'a str>,
+}
+
+/// Write the page HTML.
+pub fn render(w: &mut impl Write, page: &Page) -> core::fmt::Result {
+    write!(
+        w,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\">\
+         <title>{0} setup</title><style>body{{font-family:sans-serif;margin:2em}}\
+         input{{display:block;margin:.3em 0 1em;width:100%}}</style></head>\
+         <body><h1>{0}</h1>",
+        Escaped(page.hostname)
+    )?;
+    if let Some(message) = page.message {
+        write!(w, "<p><b>{}</b></p>", Escaped(message))?;
+    }
+    write!(
+        w,
+        "<form method=\"post\" action=\"{}\">\
+         <label>Network<input name=\"ssid\" list=\"networks\" required maxlength=\"{}\"></label>\
+         <datalist id=\"networks\">",
+        SAVE_PATH, SSID_LEN
+    )?;
+    for network in provision::networks(page.scan).flatten() {
+        write!(w, "<option value=\"{}\">", Escaped(network.ssid))?;
+    }
+    write!(
+        w,
+        "</datalist><label>Password<input name=\"password\" type=\"password\" maxlength=\"{}\">\
+         </label><label>Device name<input name=\"hostname\" value=\"{}\" required \
+         maxlength=\"{}\"></label><input type=\"submit\" value=\"Save\"></form></body></html>",
+        PASSWORD_LEN,
+        Escaped(page.hostname),
+        HOSTNAME_LEN
+    )
+}
+
+/// A fresh passphrase for the access point, drawn from `random`.
+///
+/// It is shown on the panel, so it is short and avoids characters that are
+/// hard to tell apart on an e-paper font.
+pub fn ap_password(mut random: impl FnMut() -> u32) -> String<AP_PASSWORD_LEN> {
+    let mut password = String::new();
+    for _ in 0..AP_PASSWORD_LEN {
+        let c = AP_PASSWORD_CHARS[random() as usize % AP_PASSWORD_CHARS.len()];
+        // Cannot fail, there is room for every character.
+        let _ = password.push(c as char);
+    }
+    password
+}
+
+struct QrEscaped<'a>(&'a str);
+
+impl core::fmt::Display for QrEscaped<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for c in self.0.chars() {
+            if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+                f.write_char('\\')?;
+            }
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the `WIFI:` payload phones join a WPA2 network from when they scan
+/// it as a QR code.
+pub fn wifi_qr(w: &mut impl Write, ssid: &str, password: &str) -> core::fmt::Result {
+    write!(
+        w,
+        "WIFI:T:WPA;S:{};P:{};;",
+        QrEscaped(ssid),
+        QrEscaped(password)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+    use crate::provision::{encode_network, Network, MAX_NETWORKS, SCAN_LEN};
+
+    fn scan(ssids: &[&str]) -> std::vec::Vec<u8> {
+        let mut scan = [0; SCAN_LEN];
+        let mut len = 0;
+        for ssid in ssids {
+            let network = Network {
+                ssid,
+                rssi: -50,
+                secured: true,
+            };
+            len = encode_network(&network, &mut scan, len).unwrap();
+        }
+        scan[..len].to_vec()
+    }
+
+    #[test]
+    fn forms() {
+        let form = Form::parse(b"ssid=My+Home&password=hunter%2122&hostname=kitchen-2").unwrap();
+        assert_eq!(form.credentials.ssid, "My Home");
+        assert_eq!(form.credentials.password, "hunter!22");
+        assert_eq!(form.hostname, "kitchen-2");
+        assert!(Form::parse(b"hostname=a&password=&ssid=open").is_ok());
+
+        for (body, e) in [
+            (
+                &b"ssid=x&password=short&hostname=a"[..],
+                FormError::BadCredentials,
+            ),
+            (b"password=&hostname=a", FormError::BadCredentials),
+            (b"ssid=x&password=%zz&hostname=a", FormError::BadCredentials),
+            (b"ssid=x&password=&hostname=-a", FormError::BadHostname),
+            (b"ssid=x&password=", FormError::BadHostname),
+            (b"\xff", FormError::Http(HttpError::BadRequest)),
+        ] {
+            assert_eq!(Form::parse(body), Err(e), "{}", body.escape_ascii());
+        }
+    }
+
+    #[test]
+    fn page() {
+        let scan = scan(&["home", "<evil>"]);
+        let page = Page {
+            hostname: "dbhome-epd",
+            scan: &scan,
+            message: Some("Saved & joining"),
+        };
+        let mut out = String::new();
+        render(&mut out, &page).unwrap();
+        assert!(out.starts_with("<!DOCTYPE html>"));
+        assert!(out.contains("<title>dbhome-epd setup</title>"));
+        assert!(out.contains("<option value=\"home\"><option value=\"&lt;evil&gt;\">"));
+        assert!(!out.contains("<evil>"));
+        assert!(out.contains("<p><b>Saved &amp; joining</b></p>"));
+        assert!(out.contains("action=\"/save\""));
+        assert!(out.contains("name=\"hostname\" value=\"dbhome-epd\""));
+
+        let mut out = String::new();
+        render(
+            &mut out,
+            &Page {
+                message: None,
+                ..page
+            },
+        )
+        .unwrap();
+        assert!(!out.contains("<p>"));
+    }
+
+    #[test]
+    fn passwords() {
+        let mut n = 0;
+        let password = ap_password(|| {
+            n += 1;
+            n - 1
+        });
+        assert_eq!(password, "abcdefghjkmn");
+        let password = ap_password(|| u32::MAX);
+        assert_eq!(password.len(), AP_PASSWORD_LEN);
+        assert!(password.bytes().all(|c| AP_PASSWORD_CHARS.contains(&c)));
+    }
+
+    #[test]
+    fn qr_payload() {
+        let mut out = String::new();
+        wifi_qr(&mut out, "kitchen-setup", "abcdefghjkmn").unwrap();
+        assert_eq!(out, "WIFI:T:WPA;S:kitchen-setup;P:abcdefghjkmn;;");
+
+        let mut out = String::new();
+        wifi_qr(&mut out, r#"a;b,c:d"e\f"#, "x").unwrap();
+        assert_eq!(out, r#"WIFI:T:WPA;S:a\;b\,c\:d\"e\\f;P:x;;"#);
+    }
+
+    #[test]
+    fn worst_page_fits() {
+        let ssid = "\"".repeat(SSID_LEN);
+        let scan = scan(&[ssid.as_str(); MAX_NETWORKS]);
+        let hostname = "h".repeat(HOSTNAME_LEN);
+        let message = "<".repeat(MESSAGE_LEN);
+        let page = Page {
+            hostname: &hostname,
+            scan: &scan,
+            message: Some(&message),
+        };
+        let mut out = heapless::String::<PAGE_LEN>::new();
+        render(&mut out, &page).unwrap();
+    }
+}
@@ -0,0 +1,387 @@
+//! Values of the BLE Wi-Fi provisioning characteristics.
+//!
+//! A phone writes the SSID and password characteristics, writes
+//! [`Command::Connect`] to the control one and then reads the status
+//! characteristic until it is connected. Values longer than one ATT packet
+//! are written and read in pieces at increasing offsets.
+use heapless::String;
+
+pub const SSID_LEN: usize = 32;
+pub const PASSWORD_LEN: usize = 64;
+/// Networks kept in the scan characteristic.
+pub const MAX_NETWORKS: usize = 10;
+/// Each network is its signal, whether it is secured, the SSID length and
+/// the SSID.
+pub const SCAN_LEN: usize = MAX_NETWORKS * (3 + SSID_LEN);
+/// State then the IPv4 address, zero until one is assigned.
+pub const STATUS_LEN: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProvisionError {
+    Short,
+    TooLong,
+    /// A write starting past the end of what was written so far.
+    BadOffset,
+    BadCommand,
+    BadState,
+    /// Empty or overlong SSID, or a password WPA2 does not allow.
+    BadCredentials,
+}
+
+impl core::fmt::Display for ProvisionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            ProvisionError::Short => "value too short",
+            ProvisionError::TooLong => "value too long",
+            ProvisionError::BadOffset => "bad offset",
+            ProvisionError::BadCommand => "bad command",
+            ProvisionError::BadState => "bad state",
+            ProvisionError::BadCredentials => "bad credentials",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for ProvisionError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub ssid: String<SSID_LEN>,
+    pub password: String<PASSWORD_LEN>,
+}
+
+impl Credentials {
+    /// An empty password joins an open network.
+    pub fn new(ssid: &str, password: &str) -> Result<Self, ProvisionError> {
+        let valid_password = password.is_empty() || (8..=PASSWORD_LEN).contains(&password.len());
+        if ssid.is_empty() || !valid_password {
+            return Err(ProvisionError::BadCredentials);
+        }
+        Ok(Self {
+            ssid: String::try_from(ssid).map_err(|_| ProvisionError::BadCredentials)?,
+            password: String::try_from(password).map_err(|_| ProvisionError::BadCredentials)?,
+        })
+    }
+}
+
+/// A characteristic value written in pieces.
+pub struct Field<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for Field<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Field<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Write `data` at `offset`, the value ends after it. A write at offset
+    /// 0 starts a new value.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), ProvisionError> {
+        if offset > self.len {
+            return Err(ProvisionError::BadOffset);
+        }
+        let end = offset + data.len();
+        self.buf
+            .get_mut(offset..end)
+            .ok_or(ProvisionError::TooLong)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn as_str(&self) -> Result<&str, ProvisionError> {
+        core::str::from_utf8(self.as_bytes()).map_err(|_| ProvisionError::BadCredentials)
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// Part of `value` from `offset` copied in `out`, for reads in pieces.
+/// Returns the bytes copied.
+pub fn read_at(value: &[u8], offset: usize, out: &mut [u8]) -> usize {
+    let rest = value.get(offset..).unwrap_or_default();
+    let n = core::cmp::min(rest.len(), out.len());
+    out[..n].copy_from_slice(&rest[..n]);
+    n
+}
+
+/// Written to the control characteristic, one byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Join the network of the SSID and password characteristics.
+    Connect = 1,
+    /// Refresh the scan characteristic.
+    Scan = 2,
+    /// Drop the credentials and disconnect.
+    Forget = 3,
+}
+
+impl Command {
+    pub fn decode(value: &[u8]) -> Result<Self, ProvisionError> {
+        match value {
+            [1] => Ok(Command::Connect),
+            [2] => Ok(Command::Scan),
+            [3] => Ok(Command::Forget),
+            [] => Err(ProvisionError::Short),
+            _ => Err(ProvisionError::BadCommand),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// No credentials to use.
+    Idle = 0,
+    Scanning = 1,
+    Connecting = 2,
+    Connected = 3,
+    /// The last attempt to join failed, new credentials are needed.
+    Failed = 4,
+}
+
+pub fn encode_status(state: State, addr: Option<[u8; 4]>) -> [u8; STATUS_LEN] {
+    let mut value = [0; STATUS_LEN];
+    value[0] = state as u8;
+    value[1..].copy_from_slice(&addr.unwrap_or_default());
+    value
+}
+
+pub fn decode_status(value: &[u8]) -> Result<(State, Option<[u8; 4]>), ProvisionError> {
+    let [state, a, b, c, d] = value else {
+        return Err(ProvisionError::Short);
+    };
+    let state = match state {
+        0 => State::Idle,
+        1 => State::Scanning,
+        2 => State::Connecting,
+        3 => State::Connected,
+        4 => State::Failed,
+        _ => return Err(ProvisionError::BadState),
+    };
+    let addr = [*a, *b, *c, *d];
+    Ok((state, (addr != [0; 4]).then_some(addr)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Network<'a> {
+    pub ssid: &'a str,
+    /// dBm.
+    pub rssi: i8,
+    pub secured: bool,
+}
+
+/// Append `network` to the scan value in `buf` of `len` bytes, returns the
+/// new length.
+pub fn encode_network(
+    network: &Network,
+    buf: &mut [u8],
+    len: usize,
+) -> Result<usize, ProvisionError> {
+    let ssid = network.ssid.as_bytes();
+    if ssid.len() > SSID_LEN {
+        return Err(ProvisionError::TooLong);
+    }
+    let end = len + 3 + ssid.len();
+    let out = buf.get_mut(len..end).ok_or(ProvisionError::TooLong)?;
+    out[0] = network.rssi as u8;
+    out[1] = network.secured as u8;
+    out[2] = ssid.len() as u8;
+    out[3..].copy_from_slice(ssid);
+    Ok(end)
+}
+
+/// Networks of a scan value.
+pub fn networks(value: &[u8]) -> impl Iterator<Item = Result<Network<'_>, ProvisionError>> {
+    let mut rest = value;
+    core::iter::from_fn(move || {
+        let [rssi, secured, len, tail @ ..] = rest else {
+            return match rest.is_empty() {
+                true => None,
+                false => {
+                    rest = &[];
+                    Some(Err(ProvisionError::Short))
+                }
+            };
+        };
+        let Some(ssid) = tail.get(..*len as usize) else {
+            rest = &[];
+            return Some(Err(ProvisionError::Short));
+        };
+        let network = core::str::from_utf8(ssid)
+            .map(|ssid| Network {
+                ssid,
+                rssi: *rssi as i8,
+                secured: *secured != 0,
+            })
+            .map_err(|_| ProvisionError::BadCredentials);
+        rest = &tail[*len as usize..];
+        Some(network)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let mut field = Field::<SSID_LEN>::new();
+        assert_eq!(field.as_str(), Ok(""));
+        field.write(0, b"my-home-").unwrap();
+        field.write(8, b"network").unwrap();
+        assert_eq!(field.as_str(), Ok("my-home-network"));
+        // A write ends the value
+        field.write(3, b"X").unwrap();
+        assert_eq!(field.as_str(), Ok("my-X"));
+        assert_eq!(field.write(10, b"a"), Err(ProvisionError::BadOffset));
+
+        field.write(0, &[b'a'; SSID_LEN]).unwrap();
+        assert_eq!(field.write(SSID_LEN, b"a"), Err(ProvisionError::TooLong));
+        assert_eq!(
+            field.write(1, &[b'a'; SSID_LEN]),
+            Err(ProvisionError::TooLong)
+        );
+        field.write(0, &[0xff]).unwrap();
+        assert_eq!(field.as_str(), Err(ProvisionError::BadCredentials));
+        field.clear();
+        assert_eq!(field.as_bytes(), b"");
+    }
+
+    #[test]
+    fn credentials() {
+        assert!(Credentials::new("open", "").is_ok());
+        assert!(Credentials::new("n", &"p".repeat(PASSWORD_LEN)).is_ok());
+        for (ssid, password) in [
+            ("net", "short"),
+            ("", "password1"),
+            (&*"x".repeat(SSID_LEN + 1), "password1"),
+            ("n", &*"p".repeat(PASSWORD_LEN + 1)),
+        ] {
+            assert_eq!(
+                Credentials::new(ssid, password),
+                Err(ProvisionError::BadCredentials),
+                "{ssid} {password}"
+            );
+        }
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::decode(&[1]), Ok(Command::Connect));
+        assert_eq!(Command::decode(&[2]), Ok(Command::Scan));
+        assert_eq!(Command::decode(&[3]), Ok(Command::Forget));
+        assert_eq!(Command::decode(&[]), Err(ProvisionError::Short));
+        assert_eq!(Command::decode(&[0]), Err(ProvisionError::BadCommand));
+        assert_eq!(Command::decode(&[1, 1]), Err(ProvisionError::BadCommand));
+    }
+
+    #[test]
+    fn status() {
+        let value = encode_status(State::Connected, Some([192, 168, 1, 9]));
+        assert_eq!(value, [3, 192, 168, 1, 9]);
+        assert_eq!(
+            decode_status(&value),
+            Ok((State::Connected, Some([192, 168, 1, 9])))
+        );
+        for state in [
+            State::Idle,
+            State::Scanning,
+            State::Connecting,
+            State::Failed,
+        ] {
+            assert_eq!(
+                decode_status(&encode_status(state, None)),
+                Ok((state, None))
+            );
+        }
+        assert_eq!(
+            decode_status(&[5, 0, 0, 0, 0]),
+            Err(ProvisionError::BadState)
+        );
+        assert_eq!(decode_status(&[1]), Err(ProvisionError::Short));
+        assert_eq!(decode_status(&[1; 6]), Err(ProvisionError::Short));
+    }
+
+    #[test]
+    fn scan() {
+        let found = [
+            Network {
+                ssid: "home",
+                rssi: -40,
+                secured: true,
+            },
+            Network {
+                ssid: "café",
+                rssi: -87,
+                secured: false,
+            },
+        ];
+        let mut buf = [0; SCAN_LEN];
+        let mut len = 0;
+        for network in &found {
+            len = encode_network(network, &mut buf, len).unwrap();
+        }
+        assert_eq!(len, 3 + 4 + 3 + 5);
+        assert_eq!(buf[..3], [-40i8 as u8, 1, 4]);
+        let decoded: Vec<_> = networks(&buf[..len]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(decoded, found);
+
+        assert_eq!(
+            networks(&buf[..len - 1]).last(),
+            Some(Err(ProvisionError::Short))
+        );
+        assert_eq!(
+            networks(&buf[..2]).collect::<Vec<_>>(),
+            [Err(ProvisionError::Short)]
+        );
+        assert_eq!(networks(&[]).count(), 0);
+
+        let mut small = [0; 8];
+        let network = Network {
+            ssid: "toolong",
+            rssi: 0,
+            secured: false,
+        };
+        assert_eq!(
+            encode_network(&network, &mut small, 0),
+            Err(ProvisionError::TooLong)
+        );
+        let network = Network {
+            ssid: &"x".repeat(SSID_LEN + 1),
+            ..network
+        };
+        assert_eq!(
+            encode_network(&network, &mut buf, 0),
+            Err(ProvisionError::TooLong)
+        );
+    }
+
+    #[test]
+    fn reads() {
+        let mut out = [0; 4];
+        assert_eq!(read_at(b"abcdefg", 0, &mut out), 4);
+        assert_eq!(&out, b"abcd");
+        assert_eq!(read_at(b"abcdefg", 4, &mut out), 3);
+        assert_eq!(&out[..3], b"efg");
+        assert_eq!(read_at(b"abc", 3, &mut out), 0);
+        assert_eq!(read_at(b"abc", 9, &mut out), 0);
+    }
+}
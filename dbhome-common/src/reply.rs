@@ -0,0 +1,222 @@
+//! Reply format of the command session.
+//!
+//! Every reply carries a three digit status code, FTP/SMTP style: 2xx is
+//! success, 5xx a failure. In text mode, the default, each payload line is
+//! prefixed by the code and a separator, `-` while more lines follow and a
+//! space on the last one:
+//!
+//! ```text
+//! 200-0 192.168.1.20:51234 12s
+//! 200 1 192.168.1.21:40022 3s
+//! ```
+//!
+//! In JSON mode the reply is a single line object,
+//! `{"code":200,"ok":true,"msg":"..."}`.
+
+use core::fmt::Write as _;
+
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// The line could not be split in a command.
+    BadSyntax,
+    BadArgs,
+    UnknownCommand,
+    /// The command was understood but did not succeed.
+    Failed,
+}
+
+impl Status {
+    pub fn code(self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::BadSyntax => 500,
+            Status::BadArgs => 501,
+            Status::UnknownCommand => 502,
+            Status::Failed => 550,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            200 => Some(Status::Ok),
+            500 => Some(Status::BadSyntax),
+            501 => Some(Status::BadArgs),
+            502 => Some(Status::UnknownCommand),
+            550 => Some(Status::Failed),
+            _ => None,
+        }
+    }
+
+    /// Text used when a reply has no payload.
+    fn text(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::BadSyntax => "Bad syntax",
+            Status::BadArgs => "Bad args",
+            Status::UnknownCommand => "Unknown command",
+            Status::Failed => "Failed",
+        }
+    }
+}
+
+/// Whether a code reports success.
+pub fn is_ok(code: u16) -> bool {
+    (200..300).contains(&code)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Serialize)]
+struct JsonReply<'a> {
+    code: u16,
+    ok: bool,
+    msg: &'a str,
+}
+
+/// Writes formatted text in a byte buffer.
+struct Cursor<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl core::fmt::Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Encode a reply, returning its length or `None` if `buf` is too small.
+pub fn encode(format: Format, status: Status, msg: &str, buf: &mut [u8]) -> Option<usize> {
+    match format {
+        Format::Text => {
+            let mut w = Cursor { buf, len: 0 };
+            let msg = if msg.is_empty() { status.text() } else { msg };
+            let mut lines = msg.lines().peekable();
+            while let Some(line) = lines.next() {
+                let sep = if lines.peek().is_some() { '-' } else { ' ' };
+                writeln!(w, "{}{}{}", status.code(), sep, line).ok()?;
+            }
+            Some(w.len)
+        }
+        Format::Json => {
+            let reply = JsonReply {
+                code: status.code(),
+                ok: is_ok(status.code()),
+                msg,
+            };
+            let len = serde_json_core::to_slice(&reply, buf).ok()?;
+            let nl = buf.get_mut(len)?;
+            *nl = b'\n';
+            Some(len + 1)
+        }
+    }
+}
+
+/// Code of a text reply once its last line arrived, `None` until then.
+pub fn complete(reply: &str) -> Option<u16> {
+    let last = reply.strip_suffix('\n')?.rsplit('\n').next()?;
+    match last.as_bytes().get(3) {
+        Some(b' ') | None => last.get(..3)?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Payload lines of a text reply, without their code.
+pub fn lines(reply: &str) -> impl Iterator<Item = &str> {
+    reply.lines().map(|l| l.get(4..).unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(status: Status, msg: &str) -> String {
+        let mut buf = [0; 128];
+        let n = encode(Format::Text, status, msg, &mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn text_lines() {
+        assert_eq!(text(Status::Ok, "Update"), "200 Update\n");
+        assert_eq!(text(Status::Ok, ""), "200 OK\n");
+        assert_eq!(text(Status::UnknownCommand, ""), "502 Unknown command\n");
+        assert_eq!(
+            text(Status::Ok, "0 a 1s\n1 b 2s\n2 c 3s"),
+            "200-0 a 1s\n200-1 b 2s\n200 2 c 3s\n"
+        );
+        // Blank lines are kept, a trailing newline and a \r are not
+        assert_eq!(text(Status::Failed, "a\r\n\nb\n"), "550-a\n550-\n550 b\n");
+    }
+
+    #[test]
+    fn json() {
+        let mut buf = [0; 128];
+        let n = encode(
+            Format::Json,
+            Status::BadArgs,
+            "say \"hi\"\nback\\",
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(
+            core::str::from_utf8(&buf[..n]).unwrap(),
+            "{\"code\":501,\"ok\":false,\"msg\":\"say \\\"hi\\\"\\nback\\\\\"}\n"
+        );
+        let n = encode(Format::Json, Status::Ok, "", &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"{\"code\":200,\"ok\":true,\"msg\":\"\"}\n");
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0; 64];
+        // "200 OK\n" is 7 bytes, the JSON one 32
+        assert_eq!(encode(Format::Text, Status::Ok, "", &mut buf[..7]), Some(7));
+        assert_eq!(encode(Format::Text, Status::Ok, "", &mut buf[..6]), None);
+        assert_eq!(
+            encode(Format::Text, Status::Ok, "a\nb", &mut buf[..11]),
+            None
+        );
+        assert_eq!(
+            encode(Format::Json, Status::Ok, "", &mut buf[..32]),
+            Some(32)
+        );
+        // Room for the object but not its newline
+        assert_eq!(encode(Format::Json, Status::Ok, "", &mut buf[..31]), None);
+        assert_eq!(encode(Format::Json, Status::Ok, "", &mut buf[..10]), None);
+    }
+
+    #[test]
+    fn reply_complete() {
+        assert_eq!(complete(""), None);
+        assert_eq!(complete("200 O"), None);
+        assert_eq!(complete("200-a\n"), None);
+        assert_eq!(complete("200-a\n200 b"), None);
+        assert_eq!(complete("200 OK\n"), Some(200));
+        assert_eq!(complete("550-a\n550-\n550 b\n"), Some(550));
+        assert_eq!(complete("200\n"), Some(200));
+        // Too short or not a code
+        assert_eq!(complete("20\n"), None);
+        assert_eq!(complete("abc ok\n"), None);
+        // The last full line decides, a reply running past it is not done
+        assert_eq!(complete("200 a\n200-b\n"), None);
+        assert_eq!(complete("200 a\n200 b\n"), Some(200));
+        assert_eq!(complete("200 a\n2"), None);
+
+        let reply = "200-0 a 1s\n200-\n200 2 c 3s\n";
+        assert_eq!(lines(reply).collect::<Vec<_>>(), ["0 a 1s", "", "2 c 3s"]);
+    }
+}
@@ -0,0 +1,300 @@
+//! Which known Wi-Fi network to join, and how long to wait after a failure.
+//!
+//! Known networks are joined by priority, then by signal among those of the
+//! same priority. The network in use is only left for a higher priority one
+//! or one [`ROAM_MARGIN`] dB stronger, so two similar access points do not
+//! make it hop back and forth.
+use heapless::Vec;
+
+use crate::provision::Credentials;
+
+pub const MAX_KNOWN: usize = 4;
+/// Networks heard weaker than this are not worth joining, in dBm.
+pub const MIN_RSSI: i8 = -90;
+/// Extra signal another network needs to replace the current one, in dB.
+pub const ROAM_MARGIN: i16 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoamError {
+    Full,
+}
+
+impl core::fmt::Display for RoamError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RoamError::Full => f.write_str("too many networks"),
+        }
+    }
+}
+
+impl core::error::Error for RoamError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Known {
+    pub credentials: Credentials,
+    /// Higher is preferred.
+    pub priority: u8,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KnownNetworks {
+    networks: Vec<Known, MAX_KNOWN>,
+}
+
+impl KnownNetworks {
+    pub const fn new() -> Self {
+        Self {
+            networks: Vec::new(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Known> {
+        self.networks.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    pub fn get(&self, ssid: &str) -> Option<&Known> {
+        self.networks.iter().find(|k| k.credentials.ssid == ssid)
+    }
+
+    /// Add a network, or replace the one with the same SSID.
+    pub fn add(&mut self, credentials: Credentials, priority: u8) -> Result<(), RoamError> {
+        let known = Known {
+            credentials,
+            priority,
+        };
+        match self
+            .networks
+            .iter_mut()
+            .find(|k| k.credentials.ssid == known.credentials.ssid)
+        {
+            Some(k) => *k = known,
+            None => self.networks.push(known).map_err(|_| RoamError::Full)?,
+        }
+        Ok(())
+    }
+
+    /// Returns whether the network was known.
+    pub fn forget(&mut self, ssid: &str) -> bool {
+        match self
+            .networks
+            .iter()
+            .position(|k| k.credentials.ssid == ssid)
+        {
+            Some(idx) => {
+                self.networks.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Network to join among the `heard` SSIDs and their signal in dBm, an
+    /// SSID can be heard from several access points.
+    ///
+    /// `current` is the network in use. A scan can miss it, so when it was
+    /// not heard it counts as heard at [`MIN_RSSI`]: kept over weak networks
+    /// of its priority, left for a fair signal or a higher priority.
+    pub fn pick<'a, 'b>(
+        &'a self,
+        heard: impl IntoIterator<Item = (&'b str, i8)> + Clone,
+        current: Option<&str>,
+    ) -> Option<&'a Known> {
+        let mut best: Option<(&Known, (u8, i16))> = None;
+        for known in self.networks.iter() {
+            let is_current = current == Some(known.credentials.ssid.as_str());
+            let rssi = heard
+                .clone()
+                .into_iter()
+                .filter(|(ssid, _)| *ssid == known.credentials.ssid)
+                .map(|(_, rssi)| rssi)
+                .max();
+            let rssi = match (rssi, is_current) {
+                (None, true) => MIN_RSSI as i16,
+                (Some(rssi), _) if rssi >= MIN_RSSI => rssi as i16,
+                _ => continue,
+            };
+            let score = match is_current {
+                true => (known.priority, rssi + ROAM_MARGIN),
+                false => (known.priority, rssi),
+            };
+            // On a tie the current network or the first added wins
+            if best.is_none_or(|(b, s)| score > s || (score == s && is_current && b != known)) {
+                best = Some((known, score));
+            }
+        }
+        best.map(|(known, _)| known)
+    }
+}
+
+/// Exponential backoff with jitter, in milliseconds.
+///
+/// Each failure doubles the delay up to a maximum, the actual delay is
+/// randomly between half of it and all of it so devices failing together
+/// do not retry together.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    min: u32,
+    max: u32,
+    failures: u32,
+}
+
+impl Backoff {
+    pub const fn new(min: u32, max: u32) -> Self {
+        Self {
+            min,
+            max,
+            failures: 0,
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Record a failure, returns the delay before the next attempt.
+    /// `random` is any random number.
+    pub fn failed(&mut self, random: u32) -> u32 {
+        let factor = 1u32.checked_shl(self.failures).unwrap_or(u32::MAX);
+        let delay = self.min.saturating_mul(factor).min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        delay / 2 + random % (delay - delay / 2 + 1)
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::ToString;
+
+    use super::*;
+
+    fn known(list: &[(&str, u8)]) -> KnownNetworks {
+        let mut networks = KnownNetworks::new();
+        for (ssid, priority) in list {
+            let credentials = Credentials::new(ssid, "").unwrap();
+            networks.add(credentials, *priority).unwrap();
+        }
+        networks
+    }
+
+    fn pick<'a>(
+        networks: &'a KnownNetworks,
+        heard: &[(&str, i8)],
+        current: Option<&str>,
+    ) -> Option<&'a str> {
+        networks
+            .pick(heard.iter().copied(), current)
+            .map(|k| k.credentials.ssid.as_str())
+    }
+
+    #[test]
+    fn priority_then_signal() {
+        let networks = known(&[("a", 0), ("b", 0), ("c", 1)]);
+        assert_eq!(
+            pick(&networks, &[("a", -70), ("b", -50), ("x", -20)], None),
+            Some("b")
+        );
+        // An SSID heard twice counts its best access point
+        assert_eq!(
+            pick(&networks, &[("a", -70), ("b", -50), ("a", -40)], None),
+            Some("a")
+        );
+        assert_eq!(pick(&networks, &[("a", -30), ("c", -80)], None), Some("c"));
+        // Too weak to join
+        assert_eq!(pick(&networks, &[("c", -95), ("a", -89)], None), Some("a"));
+        assert_eq!(pick(&networks, &[("c", -95)], None), None);
+        assert_eq!(pick(&networks, &[], None), None);
+        // On a tie the first added
+        assert_eq!(pick(&networks, &[("b", -60), ("a", -60)], None), Some("a"));
+    }
+
+    #[test]
+    fn roaming() {
+        let networks = known(&[("a", 0), ("b", 0), ("c", 1)]);
+        let current = Some("a");
+        assert_eq!(
+            pick(&networks, &[("a", -60), ("b", -55)], current),
+            Some("a")
+        );
+        assert_eq!(
+            pick(&networks, &[("a", -60), ("b", -50)], current),
+            Some("a")
+        );
+        assert_eq!(
+            pick(&networks, &[("a", -60), ("b", -49)], current),
+            Some("b")
+        );
+        // A scan can miss the network in use, it is then scored as barely
+        // heard: kept over weak networks, not over a fair signal
+        assert_eq!(pick(&networks, &[], current), Some("a"));
+        assert_eq!(pick(&networks, &[("b", -80)], current), Some("a"));
+        assert_eq!(pick(&networks, &[("b", -79)], current), Some("b"));
+        assert_eq!(pick(&networks, &[("b", -30)], current), Some("b"));
+        // nor over a higher priority one
+        let higher = known(&[("a", 0), ("c", 1)]);
+        assert_eq!(pick(&higher, &[("c", -40)], current), Some("c"));
+        assert_eq!(pick(&higher, &[("c", -95)], current), Some("a"));
+        assert_eq!(
+            pick(&networks, &[("a", -30), ("c", -85)], current),
+            Some("c")
+        );
+        assert_eq!(
+            pick(&networks, &[("a", -60), ("c", -40)], Some("c")),
+            Some("c")
+        );
+        // Not known any more
+        assert_eq!(pick(&networks, &[("b", -70)], Some("x")), Some("b"));
+    }
+
+    #[test]
+    fn add_and_forget() {
+        let mut networks = known(&[("a", 0)]);
+        networks
+            .add(Credentials::new("a", "password1").unwrap(), 5)
+            .unwrap();
+        assert_eq!(networks.iter().count(), 1);
+        assert_eq!(networks.get("a").unwrap().priority, 5);
+        assert_eq!(networks.get("a").unwrap().credentials.password, "password1");
+        for i in 1..MAX_KNOWN {
+            let credentials = Credentials::new(&i.to_string(), "").unwrap();
+            networks.add(credentials, 0).unwrap();
+        }
+        let extra = Credentials::new("z", "").unwrap();
+        assert_eq!(networks.add(extra.clone(), 0), Err(RoamError::Full));
+        assert!(networks.forget("a"));
+        assert!(!networks.forget("a"));
+        assert_eq!(networks.add(extra, 0), Ok(()));
+    }
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new(1000, 60_000);
+        for delay in [1000, 2000, 4000, 8000, 16_000, 32_000, 60_000, 60_000] {
+            // Lands on the top of the range
+            assert_eq!(backoff.failed(delay - delay / 2), delay);
+        }
+        assert_eq!(backoff.failures(), 8);
+        backoff.reset();
+        assert_eq!(backoff.failures(), 0);
+        assert_eq!(backoff.failed(0), 500);
+        assert_eq!(backoff.failed(0), 1000);
+
+        for random in (0..5000).map(|r| r * 7919) {
+            let mut backoff = Backoff::new(1000, 60_000);
+            assert!((500..=1000).contains(&backoff.failed(random)));
+        }
+        // No overflow however long it fails
+        let mut backoff = Backoff::new(1000, 60_000);
+        for _ in 0..100 {
+            assert!(backoff.failed(u32::MAX) <= 60_000);
+        }
+        assert!(Backoff::new(u32::MAX, u32::MAX).failed(u32::MAX) >= u32::MAX / 2);
+    }
+}
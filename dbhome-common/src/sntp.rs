@@ -0,0 +1,197 @@
+//! SNTP client messages (RFC 4330), enough to set the clock from a server.
+//!
+//! Times are microseconds since the Unix epoch, NTP timestamps are 32.32
+//! fixed point seconds since 1900.
+
+pub const NTP_PORT: u16 = 123;
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds from 1900 to 1970.
+const UNIX_OFFSET: u64 = 2_208_988_800;
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+const ORIGIN_AT: usize = 24;
+const TRANSMIT_AT: usize = 40;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SntpError {
+    Short,
+    /// Not a server reply, or a reply to another request.
+    Unexpected,
+    /// Kiss-o'-death, the server asks to back off.
+    Denied,
+    /// The server has no time to give.
+    Unsynchronized,
+}
+
+impl core::fmt::Display for SntpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            SntpError::Short => "message too short",
+            SntpError::Unexpected => "unexpected message",
+            SntpError::Denied => "denied by server",
+            SntpError::Unsynchronized => "server unsynchronized",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for SntpError {}
+
+pub fn to_ntp(unix_micros: u64) -> u64 {
+    let secs = unix_micros / 1_000_000 + UNIX_OFFSET;
+    let frac = ((unix_micros % 1_000_000) << 32) / 1_000_000;
+    (secs << 32) | frac
+}
+
+/// Timestamps from 1968 on are read as such, later ones as the next era
+/// started in 2036. Times before 1970 are read as the epoch.
+pub fn from_ntp(ts: u64) -> u64 {
+    let secs = ts >> 32;
+    let secs = match secs & 0x8000_0000 {
+        0 => secs + (1 << 32),
+        _ => secs,
+    };
+    let micros = ((ts & 0xffff_ffff) * 1_000_000) >> 32;
+    match secs.checked_sub(UNIX_OFFSET) {
+        Some(secs) => secs * 1_000_000 + micros,
+        None => 0,
+    }
+}
+
+/// Client request carrying `cookie` as its transmit time, the server echoes
+/// it back so the reply can be matched.
+pub fn encode_request(cookie: u64) -> [u8; PACKET_LEN] {
+    let mut msg = [0; PACKET_LEN];
+    msg[0] = (VERSION << 3) | MODE_CLIENT;
+    msg[TRANSMIT_AT..TRANSMIT_AT + 8].copy_from_slice(&cookie.to_be_bytes());
+    msg
+}
+
+fn get_u64(msg: &[u8], at: usize) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&msg[at..at + 8]);
+    u64::from_be_bytes(b)
+}
+
+/// Server transmit time of the reply to the request sent with `cookie`.
+pub fn decode_reply(msg: &[u8], cookie: u64) -> Result<u64, SntpError> {
+    if msg.len() < PACKET_LEN {
+        return Err(SntpError::Short);
+    }
+    let leap = msg[0] >> 6;
+    let version = (msg[0] >> 3) & 0x7;
+    let mode = msg[0] & 0x7;
+    let stratum = msg[1];
+
+    if mode != MODE_SERVER || !(1..=4).contains(&version) || get_u64(msg, ORIGIN_AT) != cookie {
+        return Err(SntpError::Unexpected);
+    }
+    if stratum == 0 {
+        return Err(SntpError::Denied);
+    }
+    let transmit = get_u64(msg, TRANSMIT_AT);
+    if leap == LEAP_UNSYNCHRONIZED || transmit == 0 {
+        return Err(SntpError::Unsynchronized);
+    }
+    Ok(from_ntp(transmit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2036-02-07 06:28:16 UTC, when the 32 bit NTP seconds wrap.
+    const ERA_1: u64 = (1 << 32) - UNIX_OFFSET;
+    const COOKIE: u64 = 0x1122_3344_5566_7788;
+    /// 2026-10-17 00:00:00.123456 UTC.
+    const NOW: u64 = 1_792_195_200_123_456;
+
+    #[test]
+    fn ntp_round_trip() {
+        for micros in [
+            0,
+            NOW,
+            (ERA_1 - 1) * 1_000_000 + 999_999,
+            ERA_1 * 1_000_000,
+            ERA_1 * 1_000_000 + 500_000,
+            // 2100-01-01
+            4_102_444_800_000_000,
+        ] {
+            // The 32 bit fraction is finer than a microsecond, truncation
+            // loses at most one
+            assert!(from_ntp(to_ntp(micros)).abs_diff(micros) <= 1, "{micros}");
+        }
+
+        assert_eq!(to_ntp(0), UNIX_OFFSET << 32);
+        assert_eq!(to_ntp(500_000), (UNIX_OFFSET << 32) | 0x8000_0000);
+        // The new era starts back at 0
+        assert_eq!(to_ntp(ERA_1 * 1_000_000), 0);
+        assert_eq!(from_ntp(0), ERA_1 * 1_000_000);
+        assert_eq!(from_ntp(1 << 32), (ERA_1 + 1) * 1_000_000);
+        // The last second before the wrap, and 1968 which is still read
+        // in the first era, before the epoch
+        assert_eq!(from_ntp(0xffff_ffff << 32), (ERA_1 - 1) * 1_000_000);
+        assert_eq!(from_ntp(0x8000_0000 << 32), 0);
+    }
+
+    fn reply(transmit: u64) -> [u8; PACKET_LEN] {
+        let mut msg = [0; PACKET_LEN];
+        // No leap warning, version 4, server
+        msg[0] = 0x24;
+        msg[1] = 2;
+        msg[ORIGIN_AT..ORIGIN_AT + 8].copy_from_slice(&COOKIE.to_be_bytes());
+        msg[TRANSMIT_AT..TRANSMIT_AT + 8].copy_from_slice(&transmit.to_be_bytes());
+        msg
+    }
+
+    #[test]
+    fn request() {
+        let msg = encode_request(COOKIE);
+        assert_eq!(msg[0], 0x23);
+        assert_eq!(msg[TRANSMIT_AT..], COOKIE.to_be_bytes());
+        assert!(msg[1..TRANSMIT_AT].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn decode() {
+        let ok = reply(to_ntp(NOW));
+        assert_eq!(decode_reply(&ok, COOKIE), Ok(from_ntp(to_ntp(NOW))));
+        // Longer datagrams carry extensions, they are ignored
+        let mut long = [0; PACKET_LEN + 20];
+        long[..PACKET_LEN].copy_from_slice(&ok);
+        assert!(decode_reply(&long, COOKIE).is_ok());
+
+        assert_eq!(
+            decode_reply(&ok[..PACKET_LEN - 1], COOKIE),
+            Err(SntpError::Short)
+        );
+        assert_eq!(decode_reply(&[], COOKIE), Err(SntpError::Short));
+        assert_eq!(decode_reply(&ok, COOKIE + 1), Err(SntpError::Unexpected));
+
+        let with = |at: usize, byte: u8| {
+            let mut msg = ok;
+            msg[at] = byte;
+            decode_reply(&msg, COOKIE)
+        };
+        // Client and broadcast modes, versions 0 and 5
+        assert_eq!(with(0, 0x23), Err(SntpError::Unexpected));
+        assert_eq!(with(0, 0x25), Err(SntpError::Unexpected));
+        assert_eq!(with(0, 0x04), Err(SntpError::Unexpected));
+        assert_eq!(with(0, 0x2c), Err(SntpError::Unexpected));
+        // Version 3 servers are still around
+        assert!(with(0, 0x1c).is_ok());
+
+        assert_eq!(with(1, 0), Err(SntpError::Denied));
+        // Leap indicator 3, alarm
+        assert_eq!(with(0, 0xe4), Err(SntpError::Unsynchronized));
+        // A leap second warning is still a valid time
+        assert!(with(0, 0x64).is_ok());
+        assert_eq!(
+            decode_reply(&reply(0), COOKIE),
+            Err(SntpError::Unsynchronized)
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! Calendar dates and POSIX `TZ` rules, to show local time without a
+//! timezone database.
+//!
+//! A rule like `CET-1CEST,M3.5.0,M10.5.0/3` gives the standard name and
+//! offset west of UTC, then the summer name and when it starts and ends. Only
+//! the `Mm.w.d` form of the dates is understood, it is what every zone uses.
+use heapless::String;
+
+/// Longest zone name kept, `<+0330>` style names included.
+pub const NAME_LEN: usize = 8;
+/// Zone offsets are within a day of UTC.
+const MAX_OFFSET_HOURS: i32 = 24;
+/// Change times may spill over the following days, as POSIX allows.
+const MAX_RULE_HOURS: i32 = 167;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TzError {
+    BadName,
+    BadOffset,
+    BadRule,
+}
+
+impl core::fmt::Display for TzError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            TzError::BadName => "bad zone name",
+            TzError::BadOffset => "bad offset",
+            TzError::BadRule => "bad rule",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for TzError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 0 is Sunday.
+    pub weekday: u8,
+}
+
+pub const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+pub const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+pub fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let y = year as i64 - (month <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400 + (month <= 2) as i64) as i32;
+    (year, month, day)
+}
+
+fn weekday(days: i64) -> u8 {
+    // 1970-01-01 was a Thursday
+    (days + 4).rem_euclid(7) as u8
+}
+
+fn days_in_month(year: i32, month: u8) -> u8 {
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    pub fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(86_400);
+        let rem = secs.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+            weekday: weekday(days),
+        }
+    }
+
+    pub fn to_unix(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * 86_400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Day `weekday` of week `week` of `month`, week 5 being the last one, at
+/// `time` seconds past midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rule {
+    month: u8,
+    week: u8,
+    weekday: u8,
+    time: i32,
+}
+
+impl Rule {
+    /// Local time of the change in `year`, as Unix seconds.
+    fn local_in(&self, year: i32) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        let mut day = 1
+            + (self.weekday as i64 - weekday(first) as i64).rem_euclid(7)
+            + (self.week as i64 - 1) * 7;
+        while day > days_in_month(year, self.month) as i64 {
+            day -= 7;
+        }
+        (first + day - 1) * 86_400 + self.time as i64
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Dst {
+    name: String<NAME_LEN>,
+    /// Seconds east of UTC.
+    offset: i32,
+    start: Rule,
+    end: Rule,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tz {
+    name: String<NAME_LEN>,
+    /// Seconds east of UTC.
+    offset: i32,
+    dst: Option<Dst>,
+}
+
+impl Default for Tz {
+    fn default() -> Self {
+        Self::utc()
+    }
+}
+
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char, err: TzError) -> Result<(), TzError> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(err),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let end = self.rest.find(|c| !f(c)).unwrap_or(self.rest.len());
+        let (taken, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        taken
+    }
+
+    fn name(&mut self) -> Result<String<NAME_LEN>, TzError> {
+        let name = if self.eat('<') {
+            let name = self.take_while(|c| c != '>');
+            if !self.eat('>') {
+                return Err(TzError::BadName);
+            }
+            name
+        } else {
+            self.take_while(|c| c.is_ascii_alphabetic())
+        };
+        if name.len() < 3 {
+            return Err(TzError::BadName);
+        }
+        String::try_from(name).map_err(|_| TzError::BadName)
+    }
+
+    fn number(&mut self, err: TzError) -> Result<i32, TzError> {
+        self.take_while(|c| c.is_ascii_digit())
+            .parse()
+            .map_err(|_| err)
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds, at most `max_hours` hours.
+    fn time(&mut self, err: TzError, max_hours: i32) -> Result<i32, TzError> {
+        let sign = match self.peek() {
+            Some('-') => -1,
+            _ => 1,
+        };
+        if !self.eat('-') {
+            self.eat('+');
+        }
+        let hours = Some(self.number(err)?).filter(|h| *h <= max_hours);
+        let mut secs = hours.and_then(|h| h.checked_mul(3600)).ok_or(err)?;
+        for unit in [60, 1] {
+            if !self.eat(':') {
+                break;
+            }
+            match self.number(err)? {
+                n @ 0..=59 => secs += n * unit,
+                _ => return Err(err),
+            }
+        }
+        Ok(sign * secs)
+    }
+
+    /// `Mm.w.d[/time]`, changing at 02:00 when no time is given.
+    fn rule(&mut self) -> Result<Rule, TzError> {
+        if !self.eat('M') {
+            return Err(TzError::BadRule);
+        }
+        let month = self.number(TzError::BadRule)?;
+        self.expect('.', TzError::BadRule)?;
+        let week = self.number(TzError::BadRule)?;
+        self.expect('.', TzError::BadRule)?;
+        let weekday = self.number(TzError::BadRule)?;
+        let time = match self.eat('/') {
+            true => self.time(TzError::BadRule, MAX_RULE_HOURS)?,
+            false => 2 * 3600,
+        };
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || !(0..=6).contains(&weekday) {
+            return Err(TzError::BadRule);
+        }
+        Ok(Rule {
+            month: month as u8,
+            week: week as u8,
+            weekday: weekday as u8,
+            time,
+        })
+    }
+}
+
+impl Tz {
+    pub fn utc() -> Self {
+        Self {
+            name: String::try_from("UTC").unwrap(),
+            offset: 0,
+            dst: None,
+        }
+    }
+
+    pub fn parse(rule: &str) -> Result<Self, TzError> {
+        let mut cur = Cursor { rest: rule };
+        let name = cur.name()?;
+        // POSIX offsets count west of UTC
+        let offset = -cur.time(TzError::BadOffset, MAX_OFFSET_HOURS)?;
+        if cur.rest.is_empty() {
+            return Ok(Self {
+                name,
+                offset,
+                dst: None,
+            });
+        }
+
+        let dst_name = cur.name()?;
+        let dst_offset = match cur.peek() {
+            Some(',') => offset + 3600,
+            _ => -cur.time(TzError::BadOffset, MAX_OFFSET_HOURS)?,
+        };
+        cur.expect(',', TzError::BadRule)?;
+        let start = cur.rule()?;
+        cur.expect(',', TzError::BadRule)?;
+        let end = cur.rule()?;
+        if !cur.rest.is_empty() {
+            return Err(TzError::BadRule);
+        }
+
+        Ok(Self {
+            name,
+            offset,
+            dst: Some(Dst {
+                name: dst_name,
+                offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Offset east of UTC in seconds and zone name in effect at `unix`.
+    pub fn offset_at(&self, unix: i64) -> (i32, &str) {
+        let Some(dst) = &self.dst else {
+            return (self.offset, &self.name);
+        };
+
+        // Summer time starts on standard time and ends on summer time
+        let year = DateTime::from_unix(unix + self.offset as i64).year;
+        let start = dst.start.local_in(year) - self.offset as i64;
+        let end = dst.end.local_in(year) - dst.offset as i64;
+        let summer = match start < end {
+            true => start <= unix && unix < end,
+            // Southern hemisphere, summer spans the new year
+            false => unix >= start || unix < end,
+        };
+        match summer {
+            true => (dst.offset, &dst.name),
+            false => (self.offset, &self.name),
+        }
+    }
+
+    /// Local time at `unix` and the zone name in effect.
+    pub fn local(&self, unix: i64) -> (DateTime, &str) {
+        let (offset, name) = self.offset_at(unix);
+        (DateTime::from_unix(unix + offset as i64), name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(year: i32, month: u8, day: u8, hour: u8, minute: u8) -> i64 {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second: 0,
+            weekday: 0,
+        }
+        .to_unix()
+    }
+
+    #[test]
+    fn civil() {
+        for secs in [
+            -31_536_000_000,
+            -1,
+            0,
+            951_782_400,
+            1_700_000_000,
+            253_402_300_799,
+        ] {
+            assert_eq!(DateTime::from_unix(secs).to_unix(), secs);
+        }
+        let leap = DateTime::from_unix(951_782_400);
+        assert_eq!(format!("{}", leap), "2000-02-29 00:00:00");
+        assert_eq!(DateTime::from_unix(0).weekday, 4);
+        assert_eq!(DateTime::from_unix(utc(2026, 10, 17, 0, 0)).weekday, 6);
+    }
+
+    #[test]
+    fn summer_time() {
+        let berlin = Tz::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(berlin.offset_at(utc(2026, 3, 29, 1, 0) - 1), (3600, "CET"));
+        assert_eq!(berlin.offset_at(utc(2026, 3, 29, 1, 0)), (7200, "CEST"));
+        assert_eq!(
+            berlin.offset_at(utc(2026, 10, 25, 1, 0) - 1),
+            (7200, "CEST")
+        );
+        assert_eq!(berlin.offset_at(utc(2026, 10, 25, 1, 0)), (3600, "CET"));
+
+        // Southern hemisphere, summer over the new year
+        let sydney = Tz::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(sydney.offset_at(utc(2026, 1, 1, 0, 0)), (11 * 3600, "AEDT"));
+        assert_eq!(sydney.offset_at(utc(2026, 4, 4, 16, 0)).0, 10 * 3600);
+        assert_eq!(sydney.offset_at(utc(2026, 10, 3, 16, 0)).0, 11 * 3600);
+
+        let india = Tz::parse("<+0530>-5:30").unwrap();
+        assert_eq!(india.offset_at(0), (19_800, "+0530"));
+        assert_eq!(Tz::parse("UTC0").unwrap(), Tz::utc());
+    }
+
+    #[test]
+    fn bounds() {
+        assert!(Tz::parse("XXX-24").is_ok());
+        assert!(Tz::parse("CET-1CEST,M3.5.0/167,M10.5.0/-167").is_ok());
+        for (rule, err) in [
+            ("XXX25", TzError::BadOffset),
+            ("XXX-2147483647", TzError::BadOffset),
+            ("XXX99999999999", TzError::BadOffset),
+            ("XXX1:60", TzError::BadOffset),
+            ("XXX1:00:60", TzError::BadOffset),
+            ("CET-1CEST-2147483647,M3.5.0,M10.5.0", TzError::BadOffset),
+            ("CET-1CEST,M3.5.0/168,M10.5.0", TzError::BadRule),
+            ("CET-1CEST,M3.5.0/2147483647,M10.5.0", TzError::BadRule),
+            ("CET-1CEST,M13.5.0,M10.5.0", TzError::BadRule),
+            ("CET-1CEST,M3.5.0", TzError::BadRule),
+            ("CET-1CEST,M3.5.0,M10.5.0x", TzError::BadRule),
+            ("U0", TzError::BadName),
+            ("", TzError::BadName),
+        ] {
+            assert_eq!(Tz::parse(rule), Err(err), "{rule}");
+        }
+    }
+}
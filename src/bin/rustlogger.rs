@@ -4,44 +4,90 @@
 #![no_std]
 #![no_main]
 
+use bleps::{
+    ad_structure::{
+        create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
+    },
+    async_attribute_server::AttributeServer,
+    asynch::Ble,
+    attribute_server::NotificationData,
+    gatt,
+};
+use core::fmt::Write as _;
 use core::str::from_utf8;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::dns::DnsQueryType;
 use embassy_net::udp::{PacketMetadata, UdpSocket};
-use embassy_net::{tcp::TcpSocket, Stack, StackResources};
+use embassy_net::{
+    tcp::TcpSocket, DhcpConfig, IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StackResources,
+    StaticConfigV4,
+};
 
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::{Duration, Timer};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
+use embassy_time::{with_timeout, Delay, Duration, Instant, Timer};
 
+use embedded_hal_bus::spi::{ExclusiveDevice, NoDelay};
 use embedded_io_async::Write;
 use esp_alloc as _;
 use esp_backtrace as _;
 use esp_hal::{
     dma::*,
     dma_buffers,
+    gpio::{Input, Level, Output, Pull},
+    peripherals::BT,
     prelude::*,
     rng::Rng,
     spi::{
-        master::{Config, Spi},
+        master::{Config, Spi, SpiDmaBus},
         SpiBitOrder, SpiMode,
     },
+    time,
     timer::timg::TimerGroup,
+    Async,
 };
 use esp_println::{print, println};
+use esp_storage::FlashStorage;
 use esp_wifi::{
+    ble::controller::BleConnector,
     config::PowerSaveMode,
     init,
     wifi::{
-        ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiStaDevice,
-        WifiState,
+        AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, WifiApDevice,
+        WifiController, WifiDevice, WifiEvent, WifiStaDevice, WifiState,
     },
     EspWifiController,
 };
 use heapless::{String, Vec};
 
+use dbhome_common::{
+    config::Store,
+    dhcp, dns,
+    frame::{self, Action, Assembler, LegacyPacket, Packet},
+    http::{self, HttpError, Method},
+    portal::{self, Form, Page, AP_PASSWORD_LEN},
+    provision::{Credentials, Network, State, MAX_NETWORKS, SCAN_LEN, SSID_LEN},
+    reply::{self, Format, Status},
+    roam::Backoff,
+    sntp, CONTROL_PORT, DEFAULT_HOSTNAME, FRAME_LEN, FRAME_PORT, HOSTNAME_LEN,
+};
+
 use rustlogger::{
+    clock::Clock,
+    config::{self as settings, ConfigMgr},
+    dashboard::{self, Dashboard, LogMgr, SetMgr, WidgetMgr},
+    dispatcher::{Dispatcher, Reply},
     epd4in2::EPDMgr,
     leds::LedsMgr,
-    proto_parser::{reply_err, reply_ok, ParserMgr},
+    line_framer::LineFramer,
+    proto_parser::{ParserMgr, LINE_LEN},
+    provision::{Provisioner, Request},
+    qr::{draw_wifi_setup, QrMgr},
+    schedule::{Schedule, ScheduleMgr},
+    sessions::{session_cmd, SessionTable},
+    system::SystemMgr,
+    text::TextMgr,
+    wifi::{Link, LinkState, NetMgr, WifiMgr},
 };
 
 // When you are okay with using a nightly compiler it's better to use https://docs.rs/static_cell/2.1.0/static_cell/macro.make_static.html
@@ -54,11 +100,53 @@ macro_rules! mk_static {
     }};
 }
 
-const SSID: &str = env!("SSID");
-const PASSWORD: &str = env!("PASSWORD");
+type EpdSpi = ExclusiveDevice<SpiDmaBus<'static, Async>, Output<'static>, NoDelay>;
+type Epd = EPDMgr<EpdSpi, Input<'static>, Output<'static>, Output<'static>, Delay>;
+type SharedEpd = Mutex<CriticalSectionRawMutex, Epd>;
+type SharedDashboard = Mutex<CriticalSectionRawMutex, Dashboard>;
+type SharedSchedule = Mutex<CriticalSectionRawMutex, Schedule>;
+type SharedSettings = Mutex<CriticalSectionRawMutex, settings::Config<FlashStorage>>;
+
+/// Built in credentials, used when none are saved. Without either they are
+/// provisioned over BLE.
+const SSID: Option<&str> = option_env!("SSID");
+const PASSWORD: Option<&str> = option_env!("PASSWORD");
+
+/// Time between scans looking for a better network while connected.
+const ROAM_PERIOD: Duration = Duration::from_secs(300);
+/// Delay before retrying to join, doubling after each failure.
+const BACKOFF_MIN_MS: u32 = 2_000;
+const BACKOFF_MAX_MS: u32 = 300_000;
+/// Failed attempts in a row before opening the setup access point.
+const AP_AFTER_FAILURES: u32 = 5;
+/// How long the BLE provisioning service stays up without a network.
+const PROVISION_WINDOW: Duration = Duration::from_secs(600);
+/// Left to the phone to read the address once joined, before it closes.
+const PROVISION_LINGER: Duration = Duration::from_secs(10);
+/// Lease time given to clients of the setup access point.
+const AP_LEASE_SECS: u32 = 3600;
+
+const NTP_SERVER: &str = "pool.ntp.org";
+/// Time between SNTP syncs, and between attempts after a failed one.
+const SYNC_PERIOD: Duration = Duration::from_secs(3600);
+const SYNC_RETRY: Duration = Duration::from_secs(30);
+
+/// Control clients served at the same time.
+const SESSIONS: usize = 3;
+/// A session quiet for this long is closed to free its slot.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Sender id of the scheduled commands, after the sessions.
+const SCHEDULER: usize = SESSIONS;
+const SENDERS: usize = SESSIONS + 1;
 
-static PROTO_PARSE: Channel<CriticalSectionRawMutex, String<128>, 2> = Channel::new();
-static PROTO_RET: Channel<CriticalSectionRawMutex, String<64>, 2> = Channel::new();
+static PROTO_PARSE: Channel<CriticalSectionRawMutex, (usize, Vec<u8, LINE_LEN>), SENDERS> =
+    Channel::new();
+/// Replies, one channel per sender so they never cross.
+static PROTO_RET: [Channel<CriticalSectionRawMutex, (Status, Reply), 1>; SENDERS] =
+    [const { Channel::new() }; SENDERS];
+static SESSION_TABLE: SessionTable<SESSIONS> = SessionTable::new();
+static PROVISIONER: Provisioner = Provisioner::new();
+static LINK: Link = Link::new();
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) -> ! {
@@ -71,25 +159,87 @@ async fn main(spawner: Spawner) -> ! {
 
     esp_alloc::heap_allocator!(72 * 1024);
 
+    let store = Store::new(
+        FlashStorage::new(),
+        settings::PARTITION_OFFSET,
+        settings::PARTITION_LEN,
+    );
+    let settings = match store {
+        Ok(store) => settings::Config::load(store),
+        Err(e) => {
+            println!("Settings partition: {}, using the defaults", e);
+            settings::Config::unsaved()
+        }
+    };
+    let settings = &*mk_static!(SharedSettings, Mutex::new(settings));
+    let (provisioning, power, spi_mhz, udp_port, tcp_port, hostname, ipv4) = {
+        let mut settings = settings.lock().await;
+        let mut networks = settings.networks();
+        let built_in = SSID.and_then(|ssid| Credentials::new(ssid, PASSWORD.unwrap_or("")).ok());
+        if let (true, Some(credentials)) = (networks.is_empty(), built_in) {
+            // In RAM only, until the settings are next saved
+            let _ = networks.add(credentials, 0);
+            let _ = settings.set_networks(&networks);
+        }
+        let power = match settings.get("wifi.power") {
+            Some("none") => PowerSaveMode::None,
+            Some("min") => PowerSaveMode::Minimum,
+            _ => PowerSaveMode::Maximum,
+        };
+        (
+            networks.is_empty(),
+            power,
+            settings.parse::<u32>("spi.mhz").unwrap_or(4),
+            settings.parse::<u16>("net.udp_port").unwrap_or(FRAME_PORT),
+            settings
+                .parse::<u16>("net.tcp_port")
+                .unwrap_or(CONTROL_PORT),
+            settings
+                .parse::<String<HOSTNAME_LEN>>("net.hostname")
+                .unwrap_or_else(default_hostname),
+            settings.ipv4(),
+        )
+    };
+    let hostname = mk_static!(String<HOSTNAME_LEN>, hostname).as_str();
+    let mut ap_ssid: String<SSID_LEN> = String::new();
+    let _ = write!(ap_ssid, "{}-setup", hostname);
+
     let timg0 = TimerGroup::new(peripherals.TIMG0);
 
+    let rng = Rng::new(peripherals.RNG);
     let init = &*mk_static!(
         EspWifiController<'static>,
-        init(
-            timg0.timer0,
-            Rng::new(peripherals.RNG),
-            peripherals.RADIO_CLK,
-        )
-        .unwrap()
+        init(timg0.timer0, rng, peripherals.RADIO_CLK).unwrap()
     );
 
     let wifi = peripherals.WIFI;
-    let (wifi_interface, controller) =
-        esp_wifi::wifi::new_with_mode(&init, wifi, WifiStaDevice).unwrap();
+    let (ap_interface, wifi_interface, controller) =
+        esp_wifi::wifi::new_ap_sta(&init, wifi).unwrap();
 
     let timg1 = TimerGroup::new(peripherals.TIMG1);
     esp_hal_embassy::init(timg1.timer0);
-    let config = embassy_net::Config::dhcpv4(Default::default());
+    let mut dhcp = DhcpConfig::default();
+    dhcp.hostname = hostname.try_into().ok();
+    let config = match ipv4 {
+        Ok(Some(ipv4)) => embassy_net::Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(
+                Ipv4Address::from_bytes(&ipv4.address.addr),
+                ipv4.address.prefix,
+            ),
+            gateway: ipv4.gateway.map(|g| Ipv4Address::from_bytes(&g)),
+            dns_servers: ipv4
+                .dns
+                .iter()
+                .map(|d| Ipv4Address::from_bytes(d))
+                .collect(),
+        }),
+        Ok(None) => embassy_net::Config::dhcpv4(dhcp),
+        Err(e) => {
+            println!("Static address settings: {}, using DHCP", e);
+            embassy_net::Config::dhcpv4(dhcp)
+        }
+    };
+    let dhcp = !matches!(ipv4, Ok(Some(_)));
 
     let seed = 1234; // very random, very secure seed
 
@@ -99,7 +249,22 @@ async fn main(spawner: Spawner) -> ! {
         Stack::new(
             wifi_interface,
             config,
-            mk_static!(StackResources<3>, StackResources::<3>::new()),
+            mk_static!(StackResources<8>, StackResources::<8>::new()),
+            seed
+        )
+    );
+    // Setup access point, only up when no network can be joined
+    let ap_config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::from_bytes(&portal::AP_ADDR), 24),
+        gateway: None,
+        dns_servers: Vec::new(),
+    });
+    let ap_stack = &*mk_static!(
+        Stack<WifiDevice<'_, WifiApDevice>>,
+        Stack::new(
+            ap_interface,
+            ap_config,
+            mk_static!(StackResources<4>, StackResources::<4>::new()),
             seed
         )
     );
@@ -118,7 +283,7 @@ async fn main(spawner: Spawner) -> ! {
     let spi = Spi::new_with_config(
         peripherals.SPI2,
         Config {
-            frequency: 4.MHz(),
+            frequency: spi_mhz.MHz(),
             mode: SpiMode::Mode0,
             read_bit_order: SpiBitOrder::MSBFirst,
             write_bit_order: SpiBitOrder::MSBFirst,
@@ -127,69 +292,474 @@ async fn main(spawner: Spawner) -> ! {
     .with_sck(sclk)
     .with_mosi(mosi)
     .with_miso(miso)
-    .with_cs(cs)
     .with_dma(dma_channel.configure(false, DmaPriority::Priority0))
     .with_buffers(dma_rx_buf, dma_tx_buf)
     .into_async();
 
-    let mut leds = LedsMgr::new(peripherals.GPIO3, peripherals.GPIO4, peripherals.GPIO5);
-    let epd = EPDMgr::new(spi, peripherals.GPIO6, peripherals.GPIO7, peripherals.GPIO8);
+    let leds = LedsMgr::new(
+        Output::new(peripherals.GPIO3, Level::Low),
+        Output::new(peripherals.GPIO4, Level::Low),
+        Output::new(peripherals.GPIO5, Level::Low),
+    );
+    let spi = ExclusiveDevice::new_no_delay(spi, Output::new(cs, Level::High)).unwrap();
+    let epd = &*mk_static!(
+        SharedEpd,
+        Mutex::new(EPDMgr::new(
+            spi,
+            Input::new(peripherals.GPIO6, Pull::Up),
+            Output::new(peripherals.GPIO7, Level::Low),
+            Output::new(peripherals.GPIO8, Level::Low),
+            Delay,
+        ))
+    );
 
-    spawner.spawn(connection(controller)).ok();
+    if provisioning {
+        println!("No Wi-Fi credentials, provisioning over BLE");
+        spawner.spawn(ble_task(init, peripherals.BT, hostname)).ok();
+    }
+    spawner
+        .spawn(connection(
+            controller, stack, power, settings, epd, rng, ap_ssid,
+        ))
+        .ok();
     spawner.spawn(net_task(&stack)).ok();
-    spawner.spawn(listener_task(&stack)).ok();
-    spawner.spawn(epd_task(&stack, epd)).ok();
+    spawner.spawn(ap_net_task(ap_stack)).ok();
+    spawner.spawn(portal_task(ap_stack, settings)).ok();
+    spawner.spawn(captive_dns_task(ap_stack)).ok();
+    spawner.spawn(dhcp_server_task(ap_stack)).ok();
+    for id in 0..SESSIONS {
+        spawner.spawn(listener_task(&stack, id, tcp_port)).ok();
+    }
+    spawner.spawn(epd_task(&stack, epd, udp_port)).ok();
+    spawner.spawn(mdns_task(&stack, hostname)).ok();
+
+    let dashboard = &*mk_static!(SharedDashboard, Mutex::new(Dashboard::new()));
+    let clock = &*mk_static!(Clock, Clock::new());
+    if let Some(tz) = settings.lock().await.get("time.tz") {
+        if let Err(e) = clock.set_tz(tz) {
+            println!("Timezone {}: {}", tz, e);
+        }
+    }
+    spawner.spawn(sntp_task(&stack, clock)).ok();
+    spawner.spawn(clock_task(clock, dashboard, epd)).ok();
+    let schedule = settings.lock().await.schedule();
+    let schedule = &*mk_static!(SharedSchedule, Mutex::new(schedule));
+    spawner.spawn(schedule_task(clock, schedule)).ok();
+
+    let mut dispatcher = Dispatcher::new()
+        .register(leds)
+        .register(epd)
+        .register(TextMgr::new(epd))
+        .register(QrMgr::new(epd))
+        .register(WidgetMgr::new(dashboard, epd))
+        .register(SetMgr::new(dashboard, epd))
+        .register(LogMgr::new(dashboard, epd))
+        .register(WifiMgr::new(stack, &LINK, &PROVISIONER, settings))
+        .register(NetMgr::new(stack, &LINK, hostname, dhcp))
+        .register(SystemMgr::new())
+        .register(clock)
+        .register(ScheduleMgr::new(schedule, clock, settings))
+        .register(ConfigMgr::new(settings))
+        .register(&SESSION_TABLE);
 
     let in_chan = PROTO_PARSE.dyn_receiver();
-    let out_chan = PROTO_RET.dyn_sender();
 
     loop {
-        let pkg = ParserMgr::new(in_chan.receive().await);
-        let reply = match pkg.cmd.as_str() {
-            "led" => leds.cmd(pkg),
-            _ => Err("Invalid Command"),
+        let (session, line) = in_chan.receive().await;
+        let mut payload = Reply::new();
+        let status = match ParserMgr::parse(&line) {
+            Ok(pkg) => dispatcher.dispatch(pkg, &mut payload).await,
+            Err(e) => {
+                let _ = payload.push_str(e.as_str());
+                Status::BadSyntax
+            }
         };
+        PROTO_RET[session].send((status, payload)).await;
+    }
+}
 
-        let ret = match reply {
-            Ok(e) => reply_ok(e),
-            Err(e) => reply_err(e),
-        };
-        out_chan.send(ret).await;
+fn default_hostname() -> String<HOSTNAME_LEN> {
+    // Fits, see `HOSTNAME_LEN`
+    String::try_from(DEFAULT_HOSTNAME).unwrap()
+}
+
+/// Station configuration, with the setup access point `ap`, its SSID and
+/// passphrase, when given.
+fn wifi_config(
+    credentials: Option<&Credentials>,
+    ap: Option<(&String<SSID_LEN>, &String<AP_PASSWORD_LEN>)>,
+) -> Configuration {
+    let mut client = ClientConfiguration::default();
+    if let Some(c) = credentials {
+        client.ssid = c.ssid.clone();
+        client.password = c.password.clone();
+    }
+    match ap {
+        Some((ssid, password)) => Configuration::Mixed(
+            client,
+            AccessPointConfiguration {
+                ssid: ssid.clone(),
+                // Fits, see `AP_PASSWORD_LEN`
+                password: String::try_from(password.as_str()).unwrap(),
+                auth_method: AuthMethod::WPA2Personal,
+                ..Default::default()
+            },
+        ),
+        None => Configuration::Client(client),
+    }
+}
+
+/// Scan and publish the results, returns the SSIDs heard and their signal.
+async fn scan(
+    controller: &mut WifiController<'static>,
+) -> Vec<(String<SSID_LEN>, i8), MAX_NETWORKS> {
+    match controller.scan_n_async::<MAX_NETWORKS>().await {
+        Ok((found, _)) => {
+            PROVISIONER.set_networks(found.iter().map(|ap| Network {
+                ssid: &ap.ssid,
+                rssi: ap.signal_strength,
+                secured: ap.auth_method.is_some_and(|a| a != AuthMethod::None),
+            }));
+            found
+                .iter()
+                .map(|ap| (ap.ssid.clone(), ap.signal_strength))
+                .collect()
+        }
+        Err(e) => {
+            println!("Scan failed: {e:?}");
+            Vec::new()
+        }
+    }
+}
+
+fn strongest(heard: &[(String<SSID_LEN>, i8)], ssid: &str) -> Option<i8> {
+    heard
+        .iter()
+        .filter(|(s, _)| s == ssid)
+        .map(|(_, rssi)| *rssi)
+        .max()
+}
+
+/// Wait before the next attempt, or until a request comes.
+async fn retry(backoff: &mut Backoff, rng: &mut Rng) -> Option<Request> {
+    let at = Instant::now() + Duration::from_millis(backoff.failed(rng.random()) as u64);
+    LINK.update(|l| {
+        l.failures = backoff.failures();
+        l.retry_at = Some(at);
+    });
+    let request = match select(Timer::at(at), PROVISIONER.request()).await {
+        Either::First(()) => None,
+        Either::Second(request) => Some(request),
+    };
+    LINK.update(|l| l.retry_at = None);
+    request
+}
+
+/// Save provisioned credentials that work, preferred over the others.
+async fn save_network(settings: &SharedSettings, credentials: Credentials) {
+    let mut settings = settings.lock().await;
+    let saved = settings
+        .prefer_network(credentials)
+        .and_then(|_| settings.save_networks());
+    if let Err(e) = saved {
+        println!("Saving wifi credentials: {}", e);
+    }
+}
+
+async fn forget_network(settings: &SharedSettings, ssid: &str) {
+    let mut settings = settings.lock().await;
+    let mut networks = settings.networks();
+    if networks.forget(ssid) {
+        let forgotten = settings
+            .set_networks(&networks)
+            .and_then(|_| settings.save_networks());
+        if let Err(e) = forgotten {
+            println!("Forgetting wifi credentials: {}", e);
+        }
+    }
+}
+
+/// Look for a better known network than `current`, leave it when there is
+/// one so the next attempt joins that one.
+async fn roam(controller: &mut WifiController<'static>, settings: &SharedSettings, current: &str) {
+    let heard = scan(controller).await;
+    LINK.update(|l| l.rssi = strongest(&heard, current));
+    let networks = settings.lock().await.networks();
+    let heard_iter = heard.iter().map(|(ssid, rssi)| (ssid.as_str(), *rssi));
+    if let Some(better) = networks
+        .pick(heard_iter, Some(current))
+        .filter(|k| k.credentials.ssid != current)
+    {
+        println!("Roaming to {}", better.credentials.ssid);
+        let _ = controller.disconnect_async().await;
     }
 }
 
+/// Joins and roams between the saved networks, and opens the setup access
+/// point when none can be joined.
+///
+/// The access point takes a fresh WPA2 passphrase every time it opens,
+/// shown on the panel with a QR code to join it. Anyone who can read the
+/// panel can join and set the credentials on the portal, which has no
+/// login of its own, so it only runs while no network can be joined.
 #[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>) {
-    let _ = controller.set_power_saving(PowerSaveMode::Maximum);
+async fn connection(
+    mut controller: WifiController<'static>,
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    power: PowerSaveMode,
+    settings: &'static SharedSettings,
+    epd: &'static SharedEpd,
+    mut rng: Rng,
+    ap_ssid: String<SSID_LEN>,
+) {
+    let _ = controller.set_power_saving(power);
     println!("Device capabilities: {:?}", controller.capabilities());
+    let mut backoff = Backoff::new(BACKOFF_MIN_MS, BACKOFF_MAX_MS);
+    // Provisioned over BLE, saved once they work
+    let mut provisioned: Option<Credentials> = None;
+    // Credentials of the current configuration
+    let mut joined: Option<Credentials> = None;
+    let mut ap_on = false;
+    let mut ap_password = String::new();
     loop {
-        match esp_wifi::wifi::wifi_state() {
-            WifiState::StaConnected => {
-                // wait until we're no longer connected
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                Timer::after(Duration::from_millis(5000)).await
-            }
-            _ => {}
-        }
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.try_into().unwrap(),
-                password: PASSWORD.try_into().unwrap(),
-                ..Default::default()
-            });
-            controller.set_configuration(&client_config).unwrap();
+            // Started even without credentials, scans need it
+            controller
+                .set_configuration(&wifi_config(None, None))
+                .unwrap();
             println!("Starting wifi");
             controller.start_async().await.unwrap();
             println!("Wifi started!");
         }
-        println!("About to connect...");
 
-        match controller.connect_async().await {
-            Ok(_) => println!("Wifi connected!"),
-            Err(e) => {
-                println!("Failed to connect to wifi: {e:?}");
-                Timer::after(Duration::from_millis(5000)).await
+        let connected = matches!(esp_wifi::wifi::wifi_state(), WifiState::StaConnected);
+        let networks = settings.lock().await.networks();
+        // Open the access point when stuck, close it once joined
+        let want_ap =
+            !connected && (networks.is_empty() || backoff.failures() >= AP_AFTER_FAILURES);
+        if want_ap != ap_on {
+            ap_on = want_ap;
+            println!("Setup access point {}", if ap_on { "up" } else { "down" });
+            if ap_on {
+                ap_password = portal::ap_password(|| rng.random());
+                let mut epd = epd.lock().await;
+                let Ok(()) = draw_wifi_setup(&mut *epd, &ap_ssid, &ap_password);
+                epd.display_frame().await;
+            }
+            let config = wifi_config(joined.as_ref(), ap_on.then_some((&ap_ssid, &ap_password)));
+            controller.set_configuration(&config).unwrap();
+        }
+
+        let request = if connected {
+            let addressed = async {
+                loop {
+                    if let Some(config) = stack.config_v4() {
+                        let addr = config.address.address().0;
+                        LINK.update(|l| {
+                            l.addressed_at.get_or_insert_with(Instant::now);
+                        });
+                        PROVISIONER.set_status(State::Connected, Some(addr));
+                        break;
+                    }
+                    Timer::after(Duration::from_millis(500)).await;
+                }
+                PROVISIONER.request().await
+            };
+            match select3(
+                controller.wait_for_event(WifiEvent::StaDisconnected),
+                addressed,
+                Timer::after(ROAM_PERIOD),
+            )
+            .await
+            {
+                Either3::First(()) => {
+                    println!("Wifi disconnected");
+                    LINK.update(|l| l.addressed_at = None);
+                    None
+                }
+                Either3::Second(request) => Some(request),
+                Either3::Third(()) => {
+                    if let Some(current) = LINK.get().ssid {
+                        roam(&mut controller, settings, &current).await;
+                    }
+                    None
+                }
+            }
+        } else {
+            let target = match provisioned.clone() {
+                Some(credentials) => Some((credentials, None)),
+                None if networks.is_empty() => None,
+                None => {
+                    PROVISIONER.set_status(State::Scanning, None);
+                    let heard = scan(&mut controller).await;
+                    let heard_iter = heard.iter().map(|(ssid, rssi)| (ssid.as_str(), *rssi));
+                    networks.pick(heard_iter, None).map(|k| {
+                        let rssi = strongest(&heard, &k.credentials.ssid);
+                        (k.credentials.clone(), rssi)
+                    })
+                }
+            };
+
+            match target {
+                None if networks.is_empty() => {
+                    PROVISIONER.set_status(State::Idle, None);
+                    LINK.update(|l| *l = LinkState::default());
+                    backoff.reset();
+                    Some(PROVISIONER.request().await)
+                }
+                None => {
+                    println!("No known network in range");
+                    retry(&mut backoff, &mut rng).await
+                }
+                Some((credentials, rssi)) => {
+                    LINK.update(|l| {
+                        l.ssid = Some(credentials.ssid.clone());
+                        l.rssi = rssi;
+                    });
+                    joined = Some(credentials.clone());
+                    let ap = ap_on.then_some((&ap_ssid, &ap_password));
+                    let config = wifi_config(joined.as_ref(), ap);
+                    controller.set_configuration(&config).unwrap();
+                    println!("Connecting to {}...", credentials.ssid);
+                    PROVISIONER.set_status(State::Connecting, None);
+                    match controller.connect_async().await {
+                        Ok(()) => {
+                            println!("Wifi connected!");
+                            backoff.reset();
+                            LINK.update(|l| l.failures = 0);
+                            if let Some(credentials) = provisioned.take() {
+                                save_network(settings, credentials).await;
+                            }
+                            None
+                        }
+                        Err(e) => {
+                            println!("Failed to connect to wifi: {e:?}");
+                            if provisioned.take().is_some() {
+                                PROVISIONER.set_status(State::Failed, None);
+                            }
+                            retry(&mut backoff, &mut rng).await
+                        }
+                    }
+                }
+            }
+        };
+
+        let current = LINK.get().ssid;
+        let leave = match request {
+            None => false,
+            Some(Request::Scan) => {
+                PROVISIONER.set_status(State::Scanning, None);
+                scan(&mut controller).await;
+                false
+            }
+            Some(Request::Connect(credentials)) => {
+                println!("Wifi credentials provisioned");
+                provisioned = Some(credentials);
+                backoff.reset();
+                true
+            }
+            Some(Request::Forget) => {
+                if let Some(ssid) = &current {
+                    forget_network(settings, ssid).await;
+                }
+                true
+            }
+            Some(Request::Reload) => {
+                backoff.reset();
+                let networks = settings.lock().await.networks();
+                current.is_some_and(|ssid| networks.get(&ssid).is_none())
+            }
+        };
+        if leave && matches!(esp_wifi::wifi::wifi_state(), WifiState::StaConnected) {
+            let _ = controller.disconnect_async().await;
+        }
+    }
+}
+
+/// GATT server of the Wi-Fi provisioning, see `dbhome_common::provision`.
+///
+/// There is no pairing: anyone in range can read the scan results and the
+/// address, and make the panel join or forget a network, and the password
+/// crosses the air in clear. So the service only runs at boot without any
+/// network, and closes once one is joined or after [`PROVISION_WINDOW`].
+#[embassy_executor::task]
+async fn ble_task(init: &'static EspWifiController<'static>, bt: BT, name: &'static str) {
+    let now = || time::now().duration_since_epoch().to_millis();
+    let mut connector = BleConnector::new(init, bt);
+    let closes_at = Instant::now() + PROVISION_WINDOW;
+
+    loop {
+        let mut ble = Ble::new(&mut connector, now);
+        let _ = ble.init().await;
+        let _ = ble.cmd_set_le_advertising_parameters().await;
+        let adv = create_advertising_data(&[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            AdStructure::CompleteLocalName(name),
+        ])
+        .unwrap();
+        let _ = ble.cmd_set_le_advertising_data(adv).await;
+        let _ = ble.cmd_set_le_advertise_enable(true).await;
+        println!("BLE advertising");
+
+        let mut ssid = |offset: usize, data: &[u8]| PROVISIONER.write_ssid(offset, data);
+        let mut password = |offset: usize, data: &[u8]| PROVISIONER.write_password(offset, data);
+        let mut control = |_offset: usize, data: &[u8]| PROVISIONER.write_control(data);
+        let mut status = |offset: usize, data: &mut [u8]| PROVISIONER.read_status(offset, data);
+        let mut scan = |offset: usize, data: &mut [u8]| PROVISIONER.read_scan(offset, data);
+
+        gatt!([service {
+            uuid: "6462e000-8d6a-4d1e-9f3b-a3c1e2f0b001",
+            characteristics: [
+                characteristic {
+                    uuid: "6462e001-8d6a-4d1e-9f3b-a3c1e2f0b001",
+                    write: ssid,
+                },
+                characteristic {
+                    uuid: "6462e002-8d6a-4d1e-9f3b-a3c1e2f0b001",
+                    write: password,
+                },
+                characteristic {
+                    uuid: "6462e003-8d6a-4d1e-9f3b-a3c1e2f0b001",
+                    write: control,
+                },
+                characteristic {
+                    name: "status",
+                    uuid: "6462e004-8d6a-4d1e-9f3b-a3c1e2f0b001",
+                    notify: true,
+                    read: status,
+                },
+                characteristic {
+                    uuid: "6462e005-8d6a-4d1e-9f3b-a3c1e2f0b001",
+                    read: scan,
+                },
+            ],
+        },]);
+
+        let mut rng = bleps::no_rng::NoRng;
+        let mut srv = AttributeServer::new(&mut ble, &mut gatt_attributes, &mut rng);
+        let mut notifier = || async {
+            let status = PROVISIONER.status_changed().await;
+            NotificationData::new(status_handle, &status)
+        };
+        let window = select(Timer::at(closes_at), async {
+            PROVISIONER.connected().await;
+            Timer::after(PROVISION_LINGER).await;
+        });
+        let closed = match select(srv.run(&mut notifier), window).await {
+            Either::First(Ok(())) => None,
+            Either::First(Err(e)) => {
+                println!("BLE Err: {:?}", e);
+                None
             }
+            Either::Second(Either::First(())) => Some("timed out"),
+            Either::Second(Either::Second(())) => Some("network joined"),
+        };
+        if let Some(why) = closed {
+            drop(srv);
+            // The reset stops advertising and drops a phone still connected
+            let _ = ble.init().await;
+            println!("BLE provisioning closed, {}", why);
+            return;
         }
     }
 }
@@ -200,10 +770,213 @@ async fn net_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
 }
 
 #[embassy_executor::task]
-async fn listener_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
-    let mut rx_buffer = [0; 4096];
-    let mut tx_buffer = [0; 4096];
-    let mut tmp_buffer = [0; 1024];
+async fn ap_net_task(stack: &'static Stack<WifiDevice<'static, WifiApDevice>>) {
+    stack.run().await
+}
+
+/// Page of a portal response in `body`, its head in `head`.
+fn portal_page(
+    head: &mut impl core::fmt::Write,
+    body: &mut String<{ portal::PAGE_LEN }>,
+    hostname: &str,
+    message: Option<&str>,
+) -> core::fmt::Result {
+    let mut scan = [0; SCAN_LEN];
+    let len = PROVISIONER.read_scan(0, &mut scan);
+    let page = Page {
+        hostname,
+        scan: &scan[..len],
+        message,
+    };
+    portal::render(body, &page)?;
+    http::write_head(head, 200, "OK", "text/html; charset=utf-8", body.len())
+}
+
+/// Save the networks and device name of the portal form, returns what to
+/// tell the user.
+async fn portal_save(settings: &SharedSettings, body: &[u8]) -> String<{ portal::MESSAGE_LEN }> {
+    let mut message = String::new();
+    let form = match Form::parse(body) {
+        Ok(form) => form,
+        Err(e) => {
+            let _ = write!(message, "Not saved: {}", e);
+            return message;
+        }
+    };
+    let mut settings = settings.lock().await;
+    let saved = settings
+        .prefer_network(form.credentials.clone())
+        .and_then(|_| settings.set("net.hostname", &form.hostname))
+        .and_then(|_| settings.save());
+    let _ = match saved {
+        Ok(()) => write!(
+            message,
+            "Saved, joining {}. The device name applies after a restart.",
+            form.credentials.ssid
+        ),
+        Err(e) => write!(message, "Not saved: {}", e),
+    };
+    message
+}
+
+/// Captive configuration page of the setup access point, see
+/// `dbhome_common::portal`.
+#[embassy_executor::task]
+async fn portal_task(
+    stack: &'static Stack<WifiDevice<'static, WifiApDevice>>,
+    settings: &'static SharedSettings,
+) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut request = [0; http::MAX_REQUEST_LEN];
+    let mut head: String<160> = String::new();
+    let mut body: String<{ portal::PAGE_LEN }> = String::new();
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(portal::HTTP_PORT).await {
+            println!("portal accept error: {:?}", e);
+            continue;
+        }
+
+        let mut len = 0;
+        let parsed = loop {
+            match socket.read(&mut request[len..]).await {
+                Ok(0) | Err(_) => break None,
+                Ok(n) => len += n,
+            }
+            match http::Request::parse(&request[..len]) {
+                Err(HttpError::Incomplete) if len < request.len() => continue,
+                parsed => break Some(parsed),
+            }
+        };
+
+        head.clear();
+        body.clear();
+        let hostname = settings.lock().await.parse("net.hostname");
+        let hostname = hostname.unwrap_or_else(default_hostname);
+        let mut saved = false;
+        let written = match parsed {
+            None => {
+                socket.abort();
+                continue;
+            }
+            Some(Ok(r)) if r.path == "/" && r.method == Method::Get => {
+                portal_page(&mut head, &mut body, &hostname, None)
+            }
+            Some(Ok(r)) if r.path == portal::SAVE_PATH && r.method == Method::Post => {
+                let message = portal_save(settings, r.body).await;
+                saved = message.starts_with("Saved");
+                portal_page(&mut head, &mut body, &hostname, Some(&message))
+            }
+            // Captive portal checks and anything else land on the page
+            Some(Ok(_)) => http::write_redirect(&mut head, portal::HOME),
+            Some(Err(_)) => http::write_head(&mut head, 400, "Bad Request", "text/plain", 0),
+        };
+        if written.is_err() {
+            head.clear();
+            body.clear();
+            let _ = http::write_head(&mut head, 500, "Internal Server Error", "text/plain", 0);
+        }
+
+        let sent = async {
+            socket.write_all(head.as_bytes()).await?;
+            socket.write_all(body.as_bytes()).await?;
+            socket.flush().await
+        };
+        if let Err(e) = sent.await {
+            println!("portal write error: {:?}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+        if saved {
+            PROVISIONER.post(Request::Reload);
+        }
+    }
+}
+
+/// Answers every DNS query on the setup access point with its own address.
+#[embassy_executor::task]
+async fn captive_dns_task(stack: &'static Stack<WifiDevice<'static, WifiApDevice>>) {
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 512];
+    let mut tmp_buffer = [0; 512];
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(dns::DNS_PORT).unwrap();
+
+    loop {
+        let Ok((n, sender)) = socket.recv_from(&mut tmp_buffer).await else {
+            continue;
+        };
+        let mut reply = [0; 512];
+        let Ok(Some(len)) =
+            dns::encode_catch_all(&tmp_buffer[..n], portal::AP_ADDR, 60, &mut reply)
+        else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&reply[..len], sender).await {
+            println!("captive DNS send Err: {:?}", e);
+        }
+    }
+}
+
+/// Hands out addresses on the setup access point, see `dbhome_common::dhcp`.
+#[embassy_executor::task]
+async fn dhcp_server_task(stack: &'static Stack<WifiDevice<'static, WifiApDevice>>) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut tmp_buffer = [0; 576];
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut server = dhcp::Server::new(portal::AP_ADDR, AP_LEASE_SECS);
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(dhcp::SERVER_PORT).unwrap();
+    let clients = IpEndpoint::new(Ipv4Address::BROADCAST.into(), dhcp::CLIENT_PORT);
+
+    loop {
+        let Ok((n, _)) = socket.recv_from(&mut tmp_buffer).await else {
+            continue;
+        };
+        let mut reply = [0; dhcp::MIN_REPLY_LEN];
+        match server.handle(&tmp_buffer[..n], &mut reply) {
+            Ok(Some(len)) => {
+                if let Err(e) = socket.send_to(&reply[..len], clients).await {
+                    println!("DHCP send Err: {:?}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => println!("DHCP: {}", e),
+        }
+    }
+}
+
+#[embassy_executor::task(pool_size = SESSIONS)]
+async fn listener_task(
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    id: usize,
+    port: u16,
+) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut framer: LineFramer<LINE_LEN> = LineFramer::new();
+    let mut out = [0; 1024];
 
     loop {
         if stack.is_link_up() {
@@ -224,42 +997,74 @@ async fn listener_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>
     loop {
         let mut socket = TcpSocket::new(&stack, &mut rx_buffer, &mut tx_buffer);
         socket.set_timeout(Some(embassy_time::Duration::from_secs(60)));
-        if let Err(e) = socket.accept(20000).await {
+        if let Err(e) = socket.accept(port).await {
             println!("accept error: {:?}", e);
             continue;
         }
-        loop {
-            let n = match socket.read(&mut tmp_buffer).await {
-                Ok(0) => {
+        if let Some(peer) = socket.remote_endpoint() {
+            println!("session {} from {}", id, peer);
+            SESSION_TABLE.open(id, peer);
+        }
+        framer.reset();
+        let mut format = Format::Text;
+        'session: loop {
+            let n = match with_timeout(IDLE_TIMEOUT, socket.read(framer.spare())).await {
+                Ok(Ok(0)) => {
                     println!("read EOF");
                     break;
                 }
-                Ok(n) => n,
-                Err(e) => {
+                Ok(Ok(n)) => n,
+                Ok(Err(e)) => {
                     println!("read error: {:?}", e);
                     break;
                 }
+                Err(_) => {
+                    println!("session {} idle", id);
+                    break;
+                }
             };
+            framer.filled(n);
 
-            println!("rxd {}", from_utf8(&tmp_buffer[..n]).unwrap());
-
-            PROTO_PARSE
-                .send(String::from_utf8(Vec::from_slice(&tmp_buffer[..n]).unwrap()).unwrap())
-                .await;
+            // Several commands may come in one read, reply to each in order
+            while let Some(line) = framer.next_line() {
+                let (status, msg) = match line {
+                    Ok(line) if line.trim_ascii().is_empty() => continue,
+                    Ok(line) => {
+                        println!("rxd {}", from_utf8(line).unwrap_or("<binary>"));
+                        let local = ParserMgr::parse(line)
+                            .ok()
+                            .and_then(|pkg| session_cmd(&pkg, &mut format));
+                        match local {
+                            Some((status, msg)) => (status, Reply::try_from(msg).unwrap()),
+                            None => {
+                                PROTO_PARSE.send((id, Vec::from_slice(line).unwrap())).await;
+                                PROTO_RET[id].receive().await
+                            }
+                        }
+                    }
+                    Err(e) => (Status::BadSyntax, Reply::try_from(e.as_str()).unwrap()),
+                };
 
-            let ret_str = PROTO_RET.receive().await;
-            if let Err(e) = socket.write_all(ret_str.as_bytes()).await {
-                println!("write error: {:?}", e);
-                break;
+                let n = reply::encode(format, status, &msg, &mut out)
+                    .or_else(|| reply::encode(format, Status::Failed, "Reply too long", &mut out))
+                    .unwrap_or(0);
+                if let Err(e) = socket.write_all(&out[..n]).await {
+                    println!("write error: {:?}", e);
+                    break 'session;
+                }
             }
         }
+        SESSION_TABLE.close(id);
+        socket.close();
+        let _ = socket.flush().await;
     }
 }
 
 #[embassy_executor::task]
 async fn epd_task(
     stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
-    mut epd: EPDMgr<'static>,
+    epd: &'static SharedEpd,
+    port: u16,
 ) {
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
@@ -283,7 +1088,7 @@ async fn epd_task(
         Timer::after(Duration::from_millis(500)).await;
     }
 
-    epd.init().await;
+    epd.lock().await.init().await;
 
     let mut udp_socket = UdpSocket::new(
         stack,
@@ -292,25 +1097,49 @@ async fn epd_task(
         &mut tx_meta,
         &mut tx_buffer,
     );
+    let mut assembler = Assembler::new();
     loop {
-        udp_socket.bind(23000).unwrap();
+        udp_socket.bind(port).unwrap();
         loop {
             match udp_socket.recv_from(&mut tmp_buffer).await {
                 Ok((n, sender)) => {
-                    let mut field: [u8; 4] = [0; 4];
-                    field.copy_from_slice(&tmp_buffer[..4]);
-                    let offset = i32::from_ne_bytes(field);
-                    field.copy_from_slice(&tmp_buffer[4..8]);
-                    let size = u32::from_ne_bytes(field);
-
-                    print!("{}: {:?} {} ", sender, n, offset);
+                    if tmp_buffer[..n].starts_with(&frame::MAGIC) {
+                        let action = match Packet::decode(&tmp_buffer[..n]) {
+                            Ok(packet) => assembler.handle(epd.lock().await.buffer_mut(), &packet),
+                            Err(e) => {
+                                println!("{}: frame error {:?}", sender, e);
+                                Action::Reply(frame::error_reply(&tmp_buffer[..n], e))
+                            }
+                        };
 
-                    if offset < 0 {
-                        epd.display_frame().await;
+                        match action {
+                            Action::None => {}
+                            Action::Reply(reply) => send_packet(&udp_socket, &reply, sender).await,
+                            Action::Complete(ack) => {
+                                send_packet(&udp_socket, &ack, sender).await;
+                                epd.lock().await.display_frame().await;
+                            }
+                        }
                         continue;
                     }
 
-                    epd.update_frame(&tmp_buffer[8..n], offset as usize, size as usize);
+                    print!("{}: {:?} ", sender, n);
+
+                    let ret = match LegacyPacket::decode(&tmp_buffer[..n], FRAME_LEN) {
+                        Ok(LegacyPacket::Display) => {
+                            epd.lock().await.display_frame().await;
+                            Ok(())
+                        }
+                        Ok(LegacyPacket::Chunk { offset, payload }) => {
+                            epd.lock().await.update_frame(payload, offset)
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    if let Err(e) = ret {
+                        println!("frame error {:?}", e);
+                        send_packet(&udp_socket, &frame::error_reply(&[], e), sender).await;
+                    }
                 }
                 Err(e) => {
                     println!("UDP Err: {:?}", e);
@@ -320,3 +1149,220 @@ async fn epd_task(
         }
     }
 }
+
+#[embassy_executor::task]
+async fn mdns_task(
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    hostname: &'static str,
+) {
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 512];
+    let mut tmp_buffer = [0; 512];
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+
+    loop {
+        if stack.is_link_up() {
+            break;
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    }
+
+    loop {
+        if stack.config_v4().is_some() {
+            break;
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    }
+
+    let mut name: String<{ HOSTNAME_LEN + 6 }> = String::new();
+    let _ = write!(name, "{}.local", hostname);
+
+    let group = Ipv4Address::from_bytes(&dns::MDNS_ADDR);
+    if let Err(e) = stack.join_multicast_group(group).await {
+        println!("mDNS join error: {:?}", e);
+    }
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(dns::MDNS_PORT).unwrap();
+
+    loop {
+        let (n, sender) = match socket.recv_from(&mut tmp_buffer).await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("mDNS Err: {:?}", e);
+                continue;
+            }
+        };
+
+        let id = match dns::query_for(&tmp_buffer[..n], &name) {
+            Ok(Some(id)) => id,
+            _ => continue,
+        };
+        let Some(config) = stack.config_v4() else {
+            continue;
+        };
+
+        let mut reply = [0; 64];
+        let addr = config.address.address().0;
+        let Ok(len) = dns::encode_response(id, &name, addr, 120, &mut reply) else {
+            continue;
+        };
+
+        // One-shot queries come from another port and expect a direct answer
+        let to = if sender.port == dns::MDNS_PORT {
+            IpEndpoint::new(group.into(), dns::MDNS_PORT)
+        } else {
+            sender
+        };
+        if let Err(e) = socket.send_to(&reply[..len], to).await {
+            println!("mDNS send Err: {:?}", e);
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn sntp_task(
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    clock: &'static Clock,
+) {
+    let mut rx_buffer = [0; 128];
+    let mut tx_buffer = [0; 128];
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+
+    loop {
+        if stack.config_v4().is_some() {
+            break;
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    }
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).unwrap();
+
+    loop {
+        let wait = match sntp_sync(stack, &socket, clock).await {
+            Ok(()) => {
+                if let Some(now) = clock.local() {
+                    println!("SNTP synced, {}", now);
+                }
+                SYNC_PERIOD
+            }
+            Err(e) => {
+                println!("SNTP Err: {}", e);
+                SYNC_RETRY
+            }
+        };
+        Timer::after(wait).await;
+    }
+}
+
+async fn sntp_sync(
+    stack: &Stack<WifiDevice<'static, WifiStaDevice>>,
+    socket: &UdpSocket<'_>,
+    clock: &Clock,
+) -> Result<(), &'static str> {
+    let addrs = stack
+        .dns_query(NTP_SERVER, DnsQueryType::A)
+        .await
+        .map_err(|_| "DNS failed")?;
+    let server = *addrs.first().ok_or("DNS failed")?;
+
+    // Our uptime is only a cookie to match the reply
+    let sent = Instant::now();
+    let cookie = sntp::to_ntp(sent.as_micros());
+    socket
+        .send_to(&sntp::encode_request(cookie), (server, sntp::NTP_PORT))
+        .await
+        .map_err(|_| "send failed")?;
+
+    let mut buffer = [0; 64];
+    loop {
+        let (n, _) = with_timeout(Duration::from_secs(5), socket.recv_from(&mut buffer))
+            .await
+            .map_err(|_| "timeout")?
+            .map_err(|_| "recv failed")?;
+        match sntp::decode_reply(&buffer[..n], cookie) {
+            Ok(server_time) => {
+                // The server answered about halfway through the round trip
+                let now = Instant::now();
+                let half_rtt = (now - sent).as_micros() / 2;
+                clock.sync(server_time + half_rtt, now);
+                return Ok(());
+            }
+            Err(sntp::SntpError::Unexpected) => continue,
+            Err(sntp::SntpError::Denied) => return Err("denied by server"),
+            Err(_) => return Err("bad reply"),
+        }
+    }
+}
+
+/// Feed the dashboard clocks, waking at the start of every minute.
+#[embassy_executor::task]
+async fn clock_task(
+    clock: &'static Clock,
+    dash: &'static SharedDashboard,
+    epd: &'static SharedEpd,
+) {
+    loop {
+        let Some(now) = clock.local() else {
+            Timer::after(Duration::from_secs(1)).await;
+            continue;
+        };
+        if dash.lock().await.set_time(now) {
+            if let Err(e) = dashboard::redraw(dash, epd).await {
+                println!("clock redraw Err: {}", e);
+            }
+        }
+        Timer::after(Duration::from_secs(60 - now.second as u64)).await;
+    }
+}
+
+/// Run the commands of the timetable, checking at the start of every minute.
+#[embassy_executor::task]
+async fn schedule_task(clock: &'static Clock, schedule: &'static SharedSchedule) {
+    loop {
+        let Some(now) = clock.local() else {
+            Timer::after(Duration::from_secs(1)).await;
+            continue;
+        };
+        let due = schedule.lock().await.due(&now);
+        for command in due {
+            println!("scheduled: {}", command);
+            let line = Vec::from_slice(command.as_bytes()).unwrap();
+            PROTO_PARSE.send((SCHEDULER, line)).await;
+            let (status, msg) = PROTO_RET[SCHEDULER].receive().await;
+            if status != Status::Ok {
+                println!("scheduled Err: {} {}", status.code(), msg);
+            }
+        }
+        Timer::after(Duration::from_secs(60 - now.second as u64)).await;
+    }
+}
+
+async fn send_packet(socket: &UdpSocket<'_>, packet: &Packet<'_>, to: IpEndpoint) {
+    let mut buffer = [0; frame::HEADER_LEN + 8];
+    let n = match packet.encode(&mut buffer) {
+        Ok(n) => n,
+        Err(e) => {
+            println!("encode error: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.send_to(&buffer[..n], to).await {
+        println!("UDP send Err: {:?}", e);
+    }
+}
@@ -0,0 +1,315 @@
+//! Logged samples and the chart drawing them.
+//!
+//! Each named series keeps its last [`SERIES_LEN`] samples in a ring, the
+//! oldest one is dropped when a new one comes in.
+use core::fmt::Write as _;
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use heapless::{Deque, String, Vec};
+
+use crate::dashboard::KEY_LEN;
+use crate::dispatcher::CmdError;
+
+pub const SERIES_LEN: usize = 96;
+pub const MAX_SERIES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Seconds since boot.
+    pub time: u32,
+    pub value: f32,
+}
+
+pub struct Series {
+    name: String<KEY_LEN>,
+    samples: Deque<Sample, SERIES_LEN>,
+}
+
+impl Series {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Oldest first.
+    pub fn samples(&self) -> impl DoubleEndedIterator<Item = &Sample> + Clone {
+        self.samples.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn last(&self) -> Option<&Sample> {
+        self.samples.back()
+    }
+}
+
+#[derive(Default)]
+pub struct SeriesStore {
+    series: Vec<Series, MAX_SERIES>,
+}
+
+impl SeriesStore {
+    pub const fn new() -> Self {
+        Self { series: Vec::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Series> {
+        self.series.iter().find(|s| s.name.as_str() == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Series> {
+        self.series.iter()
+    }
+
+    /// Append to the series `name`, creating it on its first sample.
+    pub fn push(&mut self, name: &str, sample: Sample) -> Result<(), CmdError> {
+        let idx = match self.series.iter().position(|s| s.name.as_str() == name) {
+            Some(idx) => idx,
+            None => {
+                let series = Series {
+                    name: String::try_from(name).map_err(|_| CmdError::BadArgs("Name too long"))?,
+                    samples: Deque::new(),
+                };
+                self.series.push(series).map_err(|_| "Too many series")?;
+                self.series.len() - 1
+            }
+        };
+
+        let samples = &mut self.series[idx].samples;
+        if samples.is_full() {
+            samples.pop_front();
+        }
+        let _ = samples.push_back(sample);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), CmdError> {
+        let idx = self
+            .series
+            .iter()
+            .position(|s| s.name.as_str() == name)
+            .ok_or(CmdError::BadArgs("Unknown series"))?;
+        self.series.swap_remove(idx);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChartStyle {
+    Line,
+    Bars,
+}
+
+/// Narrowest bar drawn, older values are left out to keep them this wide.
+const MIN_BAR_WIDTH: u32 = 2;
+
+fn label(value: f32) -> String<12> {
+    let mut s = String::new();
+    let _ = write!(s, "{:.1}", value);
+    s
+}
+
+/// Plot the last `values` that fit in `area`, scaled between their extremes.
+///
+/// The maximum and minimum are written left of the vertical axis.
+pub fn draw_chart<D, I>(
+    target: &mut D,
+    area: Rectangle,
+    values: I,
+    style: ChartStyle,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+    I: Iterator<Item = f32> + Clone,
+{
+    let (min, max) = values
+        .clone()
+        .fold((f32::MAX, f32::MIN), |(lo, hi), v| (lo.min(v), hi.max(v)));
+    let count = values.clone().count();
+    let (lo, hi) = match count {
+        0 => (0.0, 1.0),
+        _ if max > min => (min, max),
+        // A flat line sits in the middle
+        _ => (min - 1.0, max + 1.0),
+    };
+
+    let (lo_text, hi_text) = (label(lo), label(hi));
+    let char_width = FONT_6X10.character_size.width;
+    let margin = core::cmp::max(lo_text.len(), hi_text.len()) as u32 * char_width + 3;
+    if area.size.width <= margin + 2 || area.size.height < 2 {
+        return Ok(());
+    }
+
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+    let left = area.top_left.x + margin as i32;
+    let top = area.top_left.y;
+    let bottom = top + area.size.height as i32 - 1;
+    let right = area.top_left.x + area.size.width as i32 - 1;
+
+    let top_right = TextStyleBuilder::new()
+        .alignment(Alignment::Right)
+        .baseline(Baseline::Top)
+        .build();
+    let bottom_right = TextStyleBuilder::new()
+        .alignment(Alignment::Right)
+        .baseline(Baseline::Bottom)
+        .build();
+    Text::with_text_style(&hi_text, Point::new(left - 3, top), small, top_right).draw(target)?;
+    Text::with_text_style(&lo_text, Point::new(left - 3, bottom), small, bottom_right)
+        .draw(target)?;
+
+    Line::new(Point::new(left, top), Point::new(left, bottom))
+        .into_styled(stroke)
+        .draw(target)?;
+    Line::new(Point::new(left, bottom), Point::new(right, bottom))
+        .into_styled(stroke)
+        .draw(target)?;
+
+    // Plot inside the axes
+    let x0 = left + 1;
+    let width = (right - x0 + 1) as u32;
+    let height = (bottom - top) as u32;
+    let y_of = |v: f32| bottom - 1 - ((v - lo) / (hi - lo) * (height - 1) as f32) as i32;
+
+    let shown = match style {
+        ChartStyle::Line => core::cmp::min(count, width as usize),
+        ChartStyle::Bars => core::cmp::min(count, (width / MIN_BAR_WIDTH) as usize),
+    };
+    let values = values.skip(count - shown);
+
+    match style {
+        ChartStyle::Line => {
+            let step = (width - 1) as f32 / core::cmp::max(shown, 2).saturating_sub(1) as f32;
+            let mut prev: Option<Point> = None;
+            for (i, v) in values.enumerate() {
+                let p = Point::new(x0 + (i as f32 * step) as i32, y_of(v));
+                match prev {
+                    Some(prev) => Line::new(prev, p).into_styled(stroke).draw(target)?,
+                    None => Pixel(p, BinaryColor::On).draw(target)?,
+                }
+                prev = Some(p);
+            }
+        }
+        ChartStyle::Bars => {
+            let bar = width / core::cmp::max(shown, 1) as u32;
+            let fill = PrimitiveStyle::with_fill(BinaryColor::On);
+            for (i, v) in values.enumerate() {
+                let x = x0 + (i as u32 * bar) as i32;
+                let y = y_of(v);
+                // One pixel gap between bars
+                Rectangle::with_corners(
+                    Point::new(x, y),
+                    Point::new(x + bar as i32 - 2, bottom - 1),
+                )
+                .into_styled(fill)
+                .draw(target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Canvas;
+
+    fn wave(n: usize) -> impl Iterator<Item = f32> + Clone {
+        (0..n).map(|i| 20.0 + 5.0 * (i as f32 / 4.0).sin())
+    }
+
+    fn render(values: impl Iterator<Item = f32> + Clone, style: ChartStyle) -> Canvas {
+        let mut canvas = Canvas::new(160, 80);
+        let area = Rectangle::new(Point::new(4, 4), Size::new(152, 72));
+        let Ok(()) = draw_chart(&mut canvas, area, values, style);
+        canvas
+    }
+
+    #[test]
+    fn golden_line() {
+        render(wave(40), ChartStyle::Line).assert_golden("chart_line");
+    }
+
+    #[test]
+    fn golden_bars() {
+        render(wave(24), ChartStyle::Bars).assert_golden("chart_bars");
+    }
+
+    #[test]
+    fn golden_flat_and_empty() {
+        render([3.0; 10].into_iter(), ChartStyle::Line).assert_golden("chart_flat");
+        render(core::iter::empty(), ChartStyle::Bars).assert_golden("chart_empty");
+    }
+
+    #[test]
+    fn keeps_the_last_values_that_fit() {
+        // 128 pixels right of the axis, bars at least 2 pixels wide
+        let values = (0..100).map(|i| (i % 7) as f32);
+        let shown = (100 - 64..100).map(|i| (i % 7) as f32);
+        let mut canvas = Canvas::new(160, 80);
+        let mut expected = Canvas::new(160, 80);
+        let area = Rectangle::new(Point::new(4, 4), Size::new(150, 72));
+        let Ok(()) = draw_chart(&mut canvas, area, values, ChartStyle::Bars);
+        let Ok(()) = draw_chart(&mut expected, area, shown, ChartStyle::Bars);
+        assert!(canvas.to_png() == expected.to_png());
+    }
+
+    #[test]
+    fn too_small_draws_nothing() {
+        let mut canvas = Canvas::new(40, 40);
+        let area = Rectangle::new(Point::zero(), Size::new(20, 40));
+        let Ok(()) = draw_chart(&mut canvas, area, wave(10), ChartStyle::Line);
+        assert_eq!(canvas.ink(), None);
+    }
+
+    #[test]
+    fn series_ring() {
+        let mut store = SeriesStore::new();
+        for i in 0..SERIES_LEN as u32 + 5 {
+            store
+                .push(
+                    "temp",
+                    Sample {
+                        time: i,
+                        value: i as f32,
+                    },
+                )
+                .unwrap();
+        }
+        let temp = store.get("temp").unwrap();
+        assert_eq!(temp.len(), SERIES_LEN);
+        assert_eq!(temp.samples().next().unwrap().time, 5);
+        assert_eq!(temp.last().unwrap().time, SERIES_LEN as u32 + 4);
+
+        let sample = Sample {
+            time: 0,
+            value: 1.0,
+        };
+        for name in ["a", "b", "c"] {
+            store.push(name, sample).unwrap();
+        }
+        assert_eq!(
+            store.push("d", sample),
+            Err(CmdError::Failed("Too many series"))
+        );
+        assert_eq!(
+            store.push("a_name_much_too_long", sample),
+            Err(CmdError::BadArgs("Name too long"))
+        );
+        store.remove("a").unwrap();
+        store.push("d", sample).unwrap();
+        assert_eq!(store.remove("a"), Err(CmdError::BadArgs("Unknown series")));
+    }
+}
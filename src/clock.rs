@@ -0,0 +1,180 @@
+//! Wall clock time, set over SNTP and shown in the configured timezone.
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use dbhome_common::tz::{DateTime, Tz};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::String;
+
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
+use crate::proto_parser::{ParserMgr, ARG_LEN};
+
+/// POSIX `TZ` rule used until another one is set.
+pub const DEFAULT_TZ: &str = "UTC0";
+
+struct State {
+    /// Unix time at boot in microseconds, known once synced.
+    boot: Option<u64>,
+    synced_at: Option<Instant>,
+    tz: Tz,
+    rule: String<ARG_LEN>,
+}
+
+pub struct Clock {
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(State {
+                boot: None,
+                synced_at: None,
+                tz: Tz::utc(),
+                rule: String::try_from(DEFAULT_TZ).unwrap(),
+            })),
+        }
+    }
+
+    /// Record that it was `unix_micros` at `at`.
+    pub fn sync(&self, unix_micros: u64, at: Instant) {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            state.boot = Some(unix_micros.saturating_sub(at.as_micros()));
+            state.synced_at = Some(at);
+        });
+    }
+
+    /// Unix time in seconds, once synced.
+    pub fn unix(&self) -> Option<i64> {
+        self.state.lock(|state| {
+            let boot = state.borrow().boot?;
+            Some(((boot + Instant::now().as_micros()) / 1_000_000) as i64)
+        })
+    }
+
+    /// Local time, once synced.
+    pub fn local(&self) -> Option<DateTime> {
+        let unix = self.unix()?;
+        Some(self.state.lock(|state| state.borrow().tz.local(unix).0))
+    }
+
+    pub fn set_tz(&self, rule: &str) -> Result<(), &'static str> {
+        let tz = Tz::parse(rule).map_err(|_| "Bad timezone rule")?;
+        let rule = String::try_from(rule).map_err(|_| "Bad timezone rule")?;
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            state.tz = tz;
+            state.rule = rule;
+        });
+        Ok(())
+    }
+}
+
+impl CommandHandler for &Clock {
+    fn name(&self) -> &'static str {
+        "time"
+    }
+
+    fn usage(&self) -> &'static str {
+        "time [tz [rule]]"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        match pkg.args.as_slice() {
+            [] => {
+                let unix = self.unix().ok_or("Not synced")?;
+                self.state.lock(|state| {
+                    let state = state.borrow();
+                    let (local, zone) = state.tz.local(unix);
+                    write!(reply, "{} {}", local, zone)?;
+                    if let Some(at) = state.synced_at {
+                        let age = Instant::now().saturating_duration_since(at);
+                        write!(reply, ", synced {}s ago", age.as_secs())?;
+                    }
+                    Ok(())
+                })
+            }
+            [tz] if tz.as_str() == "tz" => self
+                .state
+                .lock(|state| reply.write_str(&state.borrow().rule)),
+            [tz, rule] if tz.as_str() == "tz" => {
+                self.set_tz(rule).map_err(CmdError::BadArgs)?;
+                reply.write_str(rule)
+            }
+            _ => return Err(WRONG_ARGS),
+        }
+        .map_err(|_| REPLY_TOO_LONG)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+
+    fn run(mut clock: &Clock, line: &str) -> Result<Reply, CmdError> {
+        let mut reply = Reply::new();
+        let pkg = ParserMgr::parse(line.as_bytes()).unwrap();
+        block_on(clock.handle(pkg, &mut reply)).map(|()| reply)
+    }
+
+    /// 2026-10-17 00:00:00 UTC.
+    const SAT: u64 = 1_792_195_200;
+
+    #[test]
+    fn sync() {
+        let clock = Clock::new();
+        assert_eq!(clock.unix(), None);
+        assert!(clock.local().is_none());
+
+        // Microseconds since boot are taken off, the clock keeps running
+        let now = Instant::now();
+        clock.sync(SAT * 1_000_000 + 999_999, now);
+        assert_eq!(clock.unix(), Some(SAT as i64));
+        let local = clock.local().unwrap();
+        assert_eq!((local.year, local.month, local.day), (2026, 10, 17));
+        assert_eq!(local.weekday, 6);
+
+        clock.set_tz("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(clock.local().unwrap().hour, 2);
+        assert_eq!(clock.set_tz("nope"), Err("Bad timezone rule"));
+        assert_eq!(clock.local().unwrap().hour, 2);
+    }
+
+    #[test]
+    fn command() {
+        let clock = Clock::new();
+        assert_eq!(run(&clock, "time"), Err(CmdError::Failed("Not synced")));
+        assert_eq!(run(&clock, "time tz").unwrap(), DEFAULT_TZ);
+
+        clock.sync(SAT * 1_000_000, Instant::now());
+        assert_eq!(
+            run(&clock, "time").unwrap(),
+            "2026-10-17 00:00:00 UTC, synced 0s ago"
+        );
+
+        let rule = "EST5EDT,M3.2.0,M11.1.0";
+        assert_eq!(run(&clock, &format!("time tz {rule}")).unwrap(), rule);
+        assert_eq!(run(&clock, "time tz").unwrap(), rule);
+        assert_eq!(
+            run(&clock, "time").unwrap(),
+            "2026-10-16 20:00:00 EDT, synced 0s ago"
+        );
+
+        assert_eq!(
+            run(&clock, "time tz CET"),
+            Err(CmdError::BadArgs("Bad timezone rule"))
+        );
+        assert_eq!(run(&clock, "time tz").unwrap(), rule);
+        assert_eq!(run(&clock, "time zone"), Err(WRONG_ARGS));
+        assert_eq!(run(&clock, "time tz a b"), Err(WRONG_ARGS));
+    }
+}
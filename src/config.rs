@@ -0,0 +1,632 @@
+//! Device settings, kept in the `config` flash partition.
+//!
+//! `config set` only changes the settings in RAM, `config save` writes them
+//! and they apply at the next boot.
+use core::fmt::Write as _;
+use core::str::FromStr;
+
+use dbhome_common::config::{ConfigMap, Store, KEY_LEN, VALUE_LEN};
+use dbhome_common::cron::{self, Cron};
+use dbhome_common::ipv4::{self, Cidr, Ipv4Error, Mode, StaticConfig};
+use dbhome_common::provision::{Credentials, PASSWORD_LEN, SSID_LEN};
+use dbhome_common::roam::{KnownNetworks, MAX_KNOWN};
+use dbhome_common::tz::Tz;
+use dbhome_common::{valid_hostname, DEFAULT_HOSTNAME};
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embedded_storage::nor_flash::NorFlash;
+use heapless::String;
+use log::{info, warn};
+
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
+use crate::proto_parser::{ParserMgr, MAX_ARGS};
+use crate::schedule::{self, Schedule};
+
+/// Version of the settings layout, bumped when keys change meaning.
+pub const VERSION: u16 = 2;
+/// Partition of the settings, see `partitions.csv`.
+pub const PARTITION_OFFSET: u32 = 0x3f_0000;
+pub const PARTITION_LEN: u32 = 0x1_0000;
+
+/// Known keys and their values when not set. Saved networks are also kept
+/// as `wifi.<n>.ssid`, `wifi.<n>.password` and `wifi.<n>.prio`, the
+/// timetable as `sched.<n>` crontab lines.
+pub const DEFAULTS: [(&str, &str); 10] = [
+    ("wifi.power", "max"),
+    ("net.hostname", DEFAULT_HOSTNAME),
+    ("net.mode", "dhcp"),
+    ("net.address", ""),
+    ("net.gateway", ""),
+    ("net.dns", ""),
+    ("net.udp_port", "23000"),
+    ("net.tcp_port", "20000"),
+    ("spi.mhz", "4"),
+    ("time.tz", "UTC0"),
+];
+
+/// Field of a saved network key.
+fn network_field(key: &str) -> Option<&str> {
+    let (idx, field) = key.strip_prefix("wifi.")?.split_once('.')?;
+    (idx.parse::<usize>().ok()? < MAX_KNOWN).then_some(field)
+}
+
+fn network_key(idx: usize, field: &str) -> String<KEY_LEN> {
+    let mut key = String::new();
+    // At most `wifi.9.password`
+    let _ = write!(key, "wifi.{}.{}", idx, field);
+    key
+}
+
+/// Whether `key` holds a timetable entry.
+fn is_schedule_key(key: &str) -> bool {
+    key.strip_prefix("sched.")
+        .and_then(|idx| idx.parse::<usize>().ok())
+        .is_some_and(|idx| idx < schedule::MAX_ENTRIES)
+}
+
+fn schedule_key(idx: usize) -> String<KEY_LEN> {
+    let mut key = String::new();
+    let _ = write!(key, "sched.{}", idx);
+    key
+}
+
+fn validate(key: &str, value: &str) -> Result<(), &'static str> {
+    let valid = match key {
+        "wifi.power" => matches!(value, "none" | "min" | "max"),
+        "net.hostname" => valid_hostname(value),
+        "net.mode" => value.parse::<Mode>().is_ok(),
+        "net.address" => value.is_empty() || Cidr::parse(value).is_ok(),
+        "net.gateway" => value.is_empty() || ipv4::parse_addr(value).is_ok(),
+        "net.dns" => ipv4::parse_dns(value).is_ok(),
+        "net.udp_port" | "net.tcp_port" => value.parse::<u16>().is_ok_and(|p| p != 0),
+        "spi.mhz" => value.parse::<u32>().is_ok_and(|f| (1..=20).contains(&f)),
+        "time.tz" => Tz::parse(value).is_ok(),
+        _ if is_schedule_key(key) => {
+            cron::split_line(value).is_some_and(|(spec, _)| Cron::parse(spec).is_ok())
+        }
+        _ => match network_field(key) {
+            Some("ssid") => !value.is_empty() && value.len() <= SSID_LEN,
+            Some("password") => value.is_empty() || (8..=PASSWORD_LEN).contains(&value.len()),
+            Some("prio") => value.parse::<u8>().is_ok(),
+            _ => return Err("Unknown key"),
+        },
+    };
+    valid.then_some(()).ok_or("Bad value")
+}
+
+/// Bring settings of an older `version` up to date, what is no longer valid
+/// is dropped when loading.
+fn migrate(version: u16, map: &mut ConfigMap) {
+    info!("Migrating settings from version {}", version);
+    if version < 2 {
+        // A single network became a list
+        let _ = map.rename("wifi.ssid", "wifi.0.ssid");
+        let _ = map.rename("wifi.password", "wifi.0.password");
+    }
+}
+
+const NO_PARTITION: &str = "No settings partition";
+
+/// Write `map` as the saved settings.
+fn write<F: NorFlash>(store: &mut Option<Store<F>>, map: &ConfigMap) -> Result<(), &'static str> {
+    let store = store.as_mut().ok_or(NO_PARTITION)?;
+    store.save(map, VERSION).map_err(|e| {
+        warn!("Saving settings: {}", e);
+        "Flash error"
+    })
+}
+
+pub struct Config<F> {
+    /// `None` when the partition is unusable, the settings stay in RAM.
+    store: Option<Store<F>>,
+    map: ConfigMap,
+    /// What is in flash, for [`Config::save_only`].
+    saved: ConfigMap,
+}
+
+impl<F: NorFlash> Config<F> {
+    /// Read the saved settings, the defaults are used when there are none.
+    /// Values that are not valid, say written by another firmware, are
+    /// dropped so their default applies.
+    pub fn load(mut store: Store<F>) -> Self {
+        let mut map = match store.load(VERSION, migrate) {
+            Ok(Some(loaded)) => loaded.map,
+            Ok(None) => {
+                info!("No saved settings, using defaults");
+                ConfigMap::new()
+            }
+            Err(e) => {
+                warn!("Loading settings: {}", e);
+                ConfigMap::new()
+            }
+        };
+        map.retain(|key, value| match validate(key, value) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Dropping setting {}: {}", key, e);
+                false
+            }
+        });
+        Self {
+            store: Some(store),
+            saved: map.clone(),
+            map,
+        }
+    }
+
+    /// The defaults, for a partition that cannot be used. Nothing can be
+    /// saved.
+    pub const fn unsaved() -> Self {
+        Self {
+            store: None,
+            map: ConfigMap::new(),
+            saved: ConfigMap::new(),
+        }
+    }
+
+    /// Value of a known key, its default when not set.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let default = match DEFAULTS.iter().find(|(k, _)| *k == key) {
+            Some((_, default)) => default,
+            None if is_schedule_key(key) => "",
+            None => network_field(key).map(|_| "")?,
+        };
+        Some(self.map.get(key).unwrap_or(default))
+    }
+
+    /// Parsed value of a known key, its default when the value does not
+    /// parse.
+    pub fn parse<T: FromStr>(&self, key: &str) -> Option<T> {
+        let parsed = self.get(key)?.parse().ok();
+        parsed.or_else(|| {
+            warn!("Setting {} does not parse, using its default", key);
+            let (_, default) = DEFAULTS.iter().find(|(k, _)| *k == key)?;
+            default.parse().ok()
+        })
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        validate(key, value)?;
+        self.map.set(key, value).map_err(|_| "Too many settings")
+    }
+
+    /// Static IPv4 settings, `None` for DHCP. The keys are only checked one
+    /// by one when set, so they can still disagree.
+    pub fn ipv4(&self) -> Result<Option<StaticConfig>, Ipv4Error> {
+        let key = |key| self.get(key).unwrap_or_default();
+        match key("net.mode").parse()? {
+            Mode::Dhcp => Ok(None),
+            Mode::Static => {
+                StaticConfig::parse(key("net.address"), key("net.gateway"), key("net.dns"))
+                    .map(Some)
+            }
+        }
+    }
+
+    /// Saved networks, those with bad credentials are left out.
+    pub fn networks(&self) -> KnownNetworks {
+        let mut networks = KnownNetworks::new();
+        for idx in 0..MAX_KNOWN {
+            let Some(ssid) = self.map.get(&network_key(idx, "ssid")) else {
+                continue;
+            };
+            let password = self.map.get(&network_key(idx, "password"));
+            let priority = self.map.get(&network_key(idx, "prio"));
+            if let Ok(credentials) = Credentials::new(ssid, password.unwrap_or_default()) {
+                let priority = priority.and_then(|p| p.parse().ok()).unwrap_or(0);
+                let _ = networks.add(credentials, priority);
+            }
+        }
+        networks
+    }
+
+    /// Add or replace a network, preferred over the others. In RAM like
+    /// [`Config::set`].
+    pub fn prefer_network(&mut self, credentials: Credentials) -> Result<(), &'static str> {
+        let mut networks = self.networks();
+        let priority = networks
+            .iter()
+            .map(|k| k.priority)
+            .max()
+            .map_or(0, |max| max.saturating_add(1));
+        networks
+            .add(credentials, priority)
+            .map_err(|_| "Too many networks")?;
+        self.set_networks(&networks)
+    }
+
+    /// Replace the saved networks, in RAM like [`Config::set`].
+    pub fn set_networks(&mut self, networks: &KnownNetworks) -> Result<(), &'static str> {
+        self.map.retain(|key, _| network_field(key).is_none());
+        for (idx, known) in networks.iter().enumerate() {
+            let mut priority = String::<3>::new();
+            let _ = write!(priority, "{}", known.priority);
+            let c = &known.credentials;
+            for (field, value) in [
+                ("ssid", &c.ssid[..]),
+                ("password", &c.password),
+                ("prio", &priority),
+            ] {
+                self.map
+                    .set(&network_key(idx, field), value)
+                    .map_err(|_| "Too many settings")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Timetable entries, those that no longer parse are left out.
+    pub fn schedule(&self) -> Schedule {
+        let mut schedule = Schedule::new();
+        for idx in 0..schedule::MAX_ENTRIES {
+            let Some((spec, command)) = self.map.get(&schedule_key(idx)).and_then(cron::split_line)
+            else {
+                continue;
+            };
+            if let Err(e) = schedule.add(spec, command) {
+                warn!("Schedule entry {}: {}", idx, e);
+            }
+        }
+        schedule
+    }
+
+    /// Replace the timetable, in RAM like [`Config::set`].
+    pub fn set_schedule(&mut self, schedule: &Schedule) -> Result<(), &'static str> {
+        let mut lines = heapless::Vec::<String<VALUE_LEN>, { schedule::MAX_ENTRIES }>::new();
+        for entry in schedule.entries() {
+            let mut line = String::new();
+            write!(line, "{} {}", entry.spec(), entry.command()).map_err(|_| "Entry too long")?;
+            // Fits, the schedule holds at most as many entries
+            let _ = lines.push(line);
+        }
+        self.map.retain(|key, _| !is_schedule_key(key));
+        for (idx, line) in lines.iter().enumerate() {
+            self.map
+                .set(&schedule_key(idx), line)
+                .map_err(|_| "Too many settings")?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<(), &'static str> {
+        write(&mut self.store, &self.map)?;
+        self.saved.clone_from(&self.map);
+        Ok(())
+    }
+
+    /// Save only the keys matching `only`, other edits stay in RAM until the
+    /// next [`Config::save`].
+    pub fn save_only(&mut self, only: impl Fn(&str) -> bool) -> Result<(), &'static str> {
+        let mut saved = self.saved.clone();
+        saved.retain(|key, _| !only(key));
+        for (key, value) in self.map.iter().filter(|(key, _)| only(key)) {
+            saved.set(key, value).map_err(|_| "Too many settings")?;
+        }
+        write(&mut self.store, &saved)?;
+        self.saved = saved;
+        Ok(())
+    }
+
+    /// Save the networks alone, see [`Config::save_only`].
+    pub fn save_networks(&mut self) -> Result<(), &'static str> {
+        self.save_only(|key| network_field(key).is_some())
+    }
+
+    /// Save the timetable alone, see [`Config::save_only`].
+    pub fn save_schedule(&mut self) -> Result<(), &'static str> {
+        self.save_only(is_schedule_key)
+    }
+
+    /// Back to the defaults, in RAM and in flash.
+    pub fn reset(&mut self) -> Result<(), &'static str> {
+        self.map.clear();
+        self.saved.clear();
+        let store = self.store.as_mut().ok_or(NO_PARTITION)?;
+        store.erase().map_err(|e| {
+            warn!("Erasing settings: {}", e);
+            "Flash error"
+        })
+    }
+}
+
+/// `config` command, reads and edits the settings.
+pub struct ConfigMgr<'a, M: RawMutex, F> {
+    config: &'a Mutex<M, Config<F>>,
+}
+
+impl<'a, M: RawMutex, F> ConfigMgr<'a, M, F> {
+    pub fn new(config: &'a Mutex<M, Config<F>>) -> Self {
+        Self { config }
+    }
+}
+
+fn write_value(reply: &mut Reply, key: &str, value: &str) -> core::fmt::Result {
+    // Passwords are never shown
+    match network_field(key) == Some("password") && !value.is_empty() {
+        true => reply.write_str("***"),
+        false => reply.write_str(value),
+    }
+}
+
+impl<M: RawMutex, F: NorFlash> CommandHandler for ConfigMgr<'_, M, F> {
+    fn name(&self) -> &'static str {
+        "config"
+    }
+
+    fn usage(&self) -> &'static str {
+        "config list [prefix] | config get <key> | config set <key> <value> | config save | config reset"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let mut config = self.config.lock().await;
+        let args: heapless::Vec<&str, MAX_ARGS> = pkg.args.iter().map(|a| a.as_str()).collect();
+        match args.as_slice() {
+            [] | ["list"] | ["list", _] => {
+                let prefix = args.get(1).copied().unwrap_or_default();
+                let keys = DEFAULTS.iter().filter(|(key, _)| key.starts_with(prefix));
+                for (i, (key, _)) in keys.enumerate() {
+                    if i > 0 {
+                        reply.push('\n').map_err(|_| REPLY_TOO_LONG)?;
+                    }
+                    write!(reply, "{}=", key).map_err(|_| REPLY_TOO_LONG)?;
+                    write_value(reply, key, config.get(key).unwrap_or_default())
+                        .map_err(|_| REPLY_TOO_LONG)?;
+                }
+                Ok(())
+            }
+            ["get", key] => {
+                let value = config.get(key).ok_or(CmdError::BadArgs("Unknown key"))?;
+                write_value(reply, key, value).map_err(|_| REPLY_TOO_LONG)
+            }
+            ["set", key, value] => {
+                validate(key, value).map_err(CmdError::BadArgs)?;
+                config.set(key, value)?;
+                reply
+                    .push_str("Set, save to keep")
+                    .map_err(|_| REPLY_TOO_LONG)
+            }
+            ["save"] => {
+                config.save()?;
+                reply
+                    .push_str("Saved, restart to apply")
+                    .map_err(|_| REPLY_TOO_LONG)
+            }
+            ["reset"] => {
+                config.reset()?;
+                reply
+                    .push_str("Reset, restart to apply")
+                    .map_err(|_| REPLY_TOO_LONG)
+            }
+            _ => Err(WRONG_ARGS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dbhome_common::config::MemFlash;
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    const LEN: u32 = 4 * 4096;
+    type Flash = MemFlash<{ LEN as usize }>;
+
+    fn load(flash: &mut Flash) -> Config<&mut Flash> {
+        Config::load(Store::new(flash, 0, LEN).unwrap())
+    }
+
+    /// Write a record of `version` as an older firmware would.
+    fn write(flash: &mut Flash, version: u16, pairs: &[(&str, &str)]) {
+        let mut map = ConfigMap::new();
+        for (key, value) in pairs {
+            map.set(key, value).unwrap();
+        }
+        let mut store = Store::new(flash, 0, LEN).unwrap();
+        store.save(&map, version).unwrap();
+    }
+
+    fn run<F: NorFlash>(
+        mgr: &mut ConfigMgr<'_, NoopRawMutex, F>,
+        line: &str,
+    ) -> Result<Reply, CmdError> {
+        let mut reply = Reply::new();
+        let pkg = ParserMgr::parse(line.as_bytes()).unwrap();
+        block_on(mgr.handle(pkg, &mut reply)).map(|()| reply)
+    }
+
+    #[test]
+    fn command() {
+        let mut flash = Flash::new();
+        {
+            let config = Mutex::new(load(&mut flash));
+            let mut mgr = ConfigMgr::new(&config);
+            assert_eq!(run(&mut mgr, "config get spi.mhz").unwrap(), "4");
+            assert_eq!(
+                run(&mut mgr, "config set spi.mhz 99"),
+                Err(CmdError::BadArgs("Bad value"))
+            );
+            assert_eq!(
+                run(&mut mgr, "config set nope 1"),
+                Err(CmdError::BadArgs("Unknown key"))
+            );
+            run(&mut mgr, "config set wifi.0.ssid home").unwrap();
+            run(&mut mgr, "config set wifi.0.password hunter222").unwrap();
+            run(&mut mgr, "config set net.tcp_port 2323").unwrap();
+            assert_eq!(run(&mut mgr, "config get wifi.0.password").unwrap(), "***");
+            assert_eq!(
+                run(&mut mgr, "config list net.").unwrap(),
+                "net.hostname=dbhome-epd\nnet.mode=dhcp\nnet.address=\nnet.gateway=\n\
+                 net.dns=\nnet.udp_port=23000\nnet.tcp_port=2323"
+            );
+            assert_eq!(
+                run(&mut mgr, "config save").unwrap(),
+                "Saved, restart to apply"
+            );
+            // More words than any form takes
+            for line in [
+                "config set a b c d e f g h i",
+                "config get",
+                "config list a b",
+                "config save now",
+            ] {
+                assert_eq!(run(&mut mgr, line), Err(WRONG_ARGS), "{line}");
+            }
+        }
+
+        let config = Mutex::new(load(&mut flash));
+        {
+            let config = block_on(config.lock());
+            assert_eq!(config.get("wifi.0.password"), Some("hunter222"));
+            assert_eq!(config.parse::<u16>("net.tcp_port"), Some(2323));
+        }
+        run(&mut ConfigMgr::new(&config), "config reset").unwrap();
+        drop(config);
+        assert_eq!(load(&mut flash).get("net.tcp_port"), Some("20000"));
+    }
+
+    #[test]
+    fn bad_values_are_dropped() {
+        let mut flash = Flash::new();
+        write(
+            &mut flash,
+            VERSION,
+            &[
+                ("spi.mhz", "999"),
+                ("net.udp_port", "nope"),
+                ("net.hostname", "-bad-"),
+                ("net.tcp_port", "2323"),
+                ("bogus", "1"),
+            ],
+        );
+        let config = load(&mut flash);
+        assert_eq!(config.parse::<u32>("spi.mhz"), Some(4));
+        assert_eq!(config.parse::<u16>("net.udp_port"), Some(23000));
+        assert_eq!(config.get("net.hostname"), Some(DEFAULT_HOSTNAME));
+        assert_eq!(config.parse::<u16>("net.tcp_port"), Some(2323));
+        assert_eq!(config.get("bogus"), None);
+        assert_eq!(config.parse::<u16>("bogus"), None);
+    }
+
+    #[test]
+    fn migrates_one_network() {
+        let mut flash = Flash::new();
+        write(
+            &mut flash,
+            1,
+            &[
+                ("wifi.ssid", "home"),
+                ("wifi.password", "hunter222"),
+                ("spi.mhz", "8"),
+            ],
+        );
+        let config = load(&mut flash);
+        let networks = config.networks();
+        assert_eq!(
+            networks.get("home").unwrap().credentials.password,
+            "hunter222"
+        );
+        assert_eq!(config.get("spi.mhz"), Some("8"));
+        assert_eq!(config.get("wifi.ssid"), None);
+    }
+
+    #[test]
+    fn networks() {
+        let mut flash = Flash::new();
+        let mut config = load(&mut flash);
+        let mut networks = KnownNetworks::new();
+        networks.add(Credentials::new("a", "").unwrap(), 3).unwrap();
+        networks
+            .add(Credentials::new("b", "password1").unwrap(), 0)
+            .unwrap();
+        config.set_networks(&networks).unwrap();
+        config.save().unwrap();
+        assert_eq!(config.networks(), networks);
+
+        let mut fewer = networks.clone();
+        fewer.forget("a");
+        config.set_networks(&fewer).unwrap();
+        assert_eq!(config.get("wifi.1.ssid"), Some(""));
+        drop(config);
+        // Not saved
+        assert_eq!(load(&mut flash).networks(), networks);
+    }
+
+    #[test]
+    fn preferred() {
+        let mut flash = Flash::new();
+        let mut config = load(&mut flash);
+        let credentials = |ssid| Credentials::new(ssid, "").unwrap();
+        config.prefer_network(credentials("a")).unwrap();
+        config.prefer_network(credentials("b")).unwrap();
+        let networks = config.networks();
+        assert_eq!(networks.get("a").unwrap().priority, 0);
+        assert_eq!(networks.get("b").unwrap().priority, 1);
+        assert_eq!(
+            networks.pick([("a", -40), ("b", -80)], None).unwrap(),
+            networks.get("b").unwrap()
+        );
+
+        let mut networks = KnownNetworks::new();
+        networks.add(credentials("c"), u8::MAX).unwrap();
+        config.set_networks(&networks).unwrap();
+        config.prefer_network(credentials("d")).unwrap();
+        assert_eq!(config.networks().get("d").unwrap().priority, u8::MAX);
+    }
+
+    #[test]
+    fn save_networks() {
+        let mut flash = Flash::new();
+        let mut config = load(&mut flash);
+        config.set("spi.mhz", "8").unwrap();
+        config
+            .prefer_network(Credentials::new("home", "hunter222").unwrap())
+            .unwrap();
+        config.save_networks().unwrap();
+        // Saving them again keeps what is already saved
+        config.set("net.tcp_port", "2323").unwrap();
+        config.save_only(|key| key == "net.tcp_port").unwrap();
+        config.save_networks().unwrap();
+        drop(config);
+
+        let config = load(&mut flash);
+        assert!(config.networks().get("home").is_some());
+        assert_eq!(config.get("net.tcp_port"), Some("2323"));
+        assert_eq!(config.get("spi.mhz"), Some("4"));
+    }
+
+    #[test]
+    fn unsaved() {
+        let mut config = Config::<Flash>::unsaved();
+        assert_eq!(config.get("time.tz"), Some("UTC0"));
+        config.set("time.tz", "CET-1").unwrap();
+        assert_eq!(config.get("time.tz"), Some("CET-1"));
+        assert_eq!(config.save(), Err(NO_PARTITION));
+        assert_eq!(config.reset(), Err(NO_PARTITION));
+    }
+
+    #[test]
+    fn ipv4() {
+        let mut config = Config::<Flash>::unsaved();
+        assert_eq!(config.ipv4(), Ok(None));
+        // Each key is checked alone, the whole only when read
+        config.set("net.mode", "static").unwrap();
+        assert_eq!(config.ipv4(), Err(Ipv4Error::BadPrefix));
+        assert_eq!(config.set("net.address", "10.0.0.0/24"), Err("Bad value"));
+        assert_eq!(config.set("net.gateway", "10.0.0.300"), Err("Bad value"));
+        config.set("net.address", "10.0.0.9/24").unwrap();
+        config.set("net.gateway", "10.1.0.1").unwrap();
+        assert_eq!(config.ipv4(), Err(Ipv4Error::GatewayOutside));
+
+        config.set("net.gateway", "10.0.0.1").unwrap();
+        config.set("net.dns", "10.0.0.1, 9.9.9.9").unwrap();
+        let ipv4 = config.ipv4().unwrap().unwrap();
+        assert_eq!(ipv4.address, Cidr::parse("10.0.0.9/24").unwrap());
+        assert_eq!(ipv4.gateway, Some([10, 0, 0, 1]));
+        assert_eq!(ipv4.dns, [[10, 0, 0, 1], [9, 9, 9, 9]]);
+
+        config.set("net.mode", "dhcp").unwrap();
+        assert_eq!(config.ipv4(), Ok(None));
+        assert_eq!(config.set("net.mode", "auto"), Err("Bad value"));
+    }
+}
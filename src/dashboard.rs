@@ -0,0 +1,1031 @@
+//! Widgets laid out on a grid and fed with values over the command session,
+//! so the panel can show live data without a host rendering every frame.
+//!
+//! The panel is split in [`GRID_COLS`] x [`GRID_ROWS`] cells, a widget spans
+//! one or more of them. Only widgets whose value changed are drawn again,
+//! and only their area of the panel is refreshed.
+use core::convert::Infallible;
+use core::fmt::Write as _;
+
+use dbhome_common::tz::{DateTime, MONTHS, WEEKDAYS};
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embassy_time::Instant;
+use embedded_graphics::{
+    geometry::{AnchorX, AnchorY},
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoTextStyle,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Circle, Line, Polyline, PrimitiveStyle, Rectangle, Triangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use heapless::{Deque, String, Vec};
+
+use crate::chart::{draw_chart, ChartStyle, Sample, SeriesStore};
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
+use crate::proto_parser::{ParserMgr, ARG_LEN};
+use crate::qr::draw_qr_in;
+use crate::text::{draw_text, text_height};
+
+pub const GRID_COLS: u32 = 4;
+pub const GRID_ROWS: u32 = 3;
+pub const MAX_WIDGETS: usize = 12;
+pub const KEY_LEN: usize = 16;
+/// Samples kept by a sparkline.
+pub const SPARK_LEN: usize = 32;
+
+/// Blank margin inside a widget cell.
+const GUTTER: u32 = 4;
+const CAPTION_HEIGHT: u32 = 12;
+
+pub const ICONS: [&str; 4] = ["sun", "cloud", "rain", "alert"];
+
+/// Framebuffer that can refresh part of itself on the glass.
+#[allow(async_fn_in_trait)]
+pub trait Panel: DrawTarget<Color = BinaryColor, Error = Infallible> + OriginDimensions {
+    async fn refresh_region(&mut self, area: Rectangle) -> Result<(), &'static str>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Free text, wrapped in the cell.
+    Label,
+    /// Value in a large font under its key.
+    Number,
+    /// Percentage, 0 to 100.
+    Bar,
+    /// One of [`ICONS`].
+    Icon,
+    /// Trend of the last [`SPARK_LEN`] numeric values.
+    Sparkline,
+    /// Rows of `name=value`, separated by `;`.
+    Table,
+    /// Line chart of the logged series with the widget key.
+    Chart,
+    /// Same as [`Kind::Chart`], one bar per sample.
+    Bars,
+    /// QR code of the value, as large as the cell allows.
+    Qr,
+    /// Local time and date, redrawn every minute.
+    Clock,
+}
+
+impl Kind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "label" => Some(Kind::Label),
+            "number" => Some(Kind::Number),
+            "bar" => Some(Kind::Bar),
+            "icon" => Some(Kind::Icon),
+            "spark" => Some(Kind::Sparkline),
+            "table" => Some(Kind::Table),
+            "chart" => Some(Kind::Chart),
+            "bars" => Some(Kind::Bars),
+            "qr" => Some(Kind::Qr),
+            "clock" => Some(Kind::Clock),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Label => "label",
+            Kind::Number => "number",
+            Kind::Bar => "bar",
+            Kind::Icon => "icon",
+            Kind::Sparkline => "spark",
+            Kind::Table => "table",
+            Kind::Chart => "chart",
+            Kind::Bars => "bars",
+            Kind::Qr => "qr",
+            Kind::Clock => "clock",
+        }
+    }
+}
+
+pub struct Widget {
+    key: String<KEY_LEN>,
+    kind: Kind,
+    /// Position and span, in grid cells.
+    cells: Rectangle,
+    value: String<ARG_LEN>,
+    history: Deque<f32, SPARK_LEN>,
+    dirty: bool,
+}
+
+impl Widget {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Update from a `set` command, `false` if nothing changed.
+    fn set(&mut self, value: &str) -> Result<bool, CmdError> {
+        match self.kind {
+            Kind::Bar => {
+                let pct: f32 = value
+                    .parse()
+                    .map_err(|_| CmdError::BadArgs("Not a number"))?;
+                if !(0.0..=100.0).contains(&pct) {
+                    return Err(CmdError::BadArgs("Out of range"));
+                }
+            }
+            Kind::Icon if !ICONS.contains(&value) => return Err(CmdError::BadArgs("Unknown icon")),
+            Kind::Chart | Kind::Bars => return Err(CmdError::BadArgs("Charts take logged values")),
+            Kind::Clock => return Err(CmdError::BadArgs("Clocks show the time")),
+            Kind::Sparkline => {
+                let sample: f32 = value
+                    .parse()
+                    .map_err(|_| CmdError::BadArgs("Not a number"))?;
+                if self.history.is_full() {
+                    self.history.pop_front();
+                }
+                let _ = self.history.push_back(sample);
+                // Every sample moves the line, even when the value repeats
+                self.dirty = true;
+            }
+            _ => {}
+        }
+
+        if self.value.as_str() != value {
+            self.value =
+                String::try_from(value).map_err(|_| CmdError::BadArgs("Value too long"))?;
+            self.dirty = true;
+        }
+        Ok(self.dirty)
+    }
+}
+
+/// Pixel area of a span of grid cells on a panel of `size`.
+pub fn cell_area(cells: Rectangle, size: Size) -> Rectangle {
+    let cw = size.width / GRID_COLS;
+    let ch = size.height / GRID_ROWS;
+    Rectangle::new(
+        Point::new(cells.top_left.x * cw as i32, cells.top_left.y * ch as i32),
+        Size::new(cells.size.width * cw, cells.size.height * ch),
+    )
+}
+
+/// Whether cells `start` to `start + len` are inside a grid of `cells`.
+fn within(start: u32, len: u32, cells: u32) -> bool {
+    start.checked_add(len).is_some_and(|end| end <= cells)
+}
+
+/// Smallest rectangle holding both.
+fn union(a: Option<Rectangle>, b: Rectangle) -> Rectangle {
+    let Some(a) = a else {
+        return b;
+    };
+    let (Some(a_end), Some(b_end)) = (a.bottom_right(), b.bottom_right()) else {
+        return a;
+    };
+    let top_left = a.top_left.component_min(b.top_left);
+    let bottom_right = a_end.component_max(b_end);
+    Rectangle::with_corners(top_left, bottom_right)
+}
+
+#[derive(Default)]
+pub struct Dashboard {
+    widgets: Vec<Widget, MAX_WIDGETS>,
+    /// Area of removed widgets, blanked on the next render.
+    erased: Option<Rectangle>,
+    series: SeriesStore,
+    /// Local time shown by clocks, unknown until the clock is synced.
+    time: Option<DateTime>,
+}
+
+impl Dashboard {
+    pub const fn new() -> Self {
+        Self {
+            widgets: Vec::new(),
+            erased: None,
+            series: SeriesStore::new(),
+            time: None,
+        }
+    }
+
+    pub fn widgets(&self) -> &[Widget] {
+        &self.widgets
+    }
+
+    /// Place a widget, replacing the one with the same key.
+    pub fn add(&mut self, key: &str, kind: Kind, cells: Rectangle) -> Result<(), CmdError> {
+        if cells.is_zero_sized() {
+            return Err(CmdError::BadArgs("Empty widget"));
+        }
+        let (Ok(col), Ok(row)) = (
+            u32::try_from(cells.top_left.x),
+            u32::try_from(cells.top_left.y),
+        ) else {
+            return Err(CmdError::BadArgs("Outside the grid"));
+        };
+        if !within(col, cells.size.width, GRID_COLS) || !within(row, cells.size.height, GRID_ROWS) {
+            return Err(CmdError::BadArgs("Outside the grid"));
+        }
+
+        let _ = self.remove(key);
+        let widget = Widget {
+            key: String::try_from(key).map_err(|_| CmdError::BadArgs("Key too long"))?,
+            kind,
+            cells,
+            value: String::new(),
+            history: Deque::new(),
+            dirty: true,
+        };
+        self.widgets
+            .push(widget)
+            .map_err(|_| CmdError::Failed("Too many widgets"))
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<(), CmdError> {
+        let idx = self
+            .widgets
+            .iter()
+            .position(|w| w.key.as_str() == key)
+            .ok_or(CmdError::BadArgs("Unknown widget"))?;
+        let widget = self.widgets.swap_remove(idx);
+        self.erased = Some(union(self.erased, widget.cells));
+        Ok(())
+    }
+
+    /// Set the value of the widget `key`, returns whether it needs drawing.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, CmdError> {
+        self.widgets
+            .iter_mut()
+            .find(|w| w.key.as_str() == key)
+            .ok_or(CmdError::BadArgs("Unknown widget"))?
+            .set(value)
+    }
+
+    pub fn series(&self) -> &SeriesStore {
+        &self.series
+    }
+
+    /// Log a sample, returns whether a chart needs drawing.
+    pub fn log(&mut self, name: &str, sample: Sample) -> Result<bool, CmdError> {
+        self.series.push(name, sample)?;
+
+        let mut dirty = false;
+        for w in self.widgets.iter_mut() {
+            if matches!(w.kind, Kind::Chart | Kind::Bars) && w.key.as_str() == name {
+                w.dirty = true;
+                dirty = true;
+            }
+        }
+        Ok(dirty)
+    }
+
+    /// Forget a series, its charts are drawn empty.
+    pub fn clear_series(&mut self, name: &str) -> Result<(), CmdError> {
+        self.series.remove(name)?;
+        for w in self.widgets.iter_mut() {
+            if matches!(w.kind, Kind::Chart | Kind::Bars) && w.key.as_str() == name {
+                w.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Give the local time to clocks, returns whether one needs drawing.
+    ///
+    /// Clocks show minutes, seconds alone do not make them dirty.
+    pub fn set_time(&mut self, time: DateTime) -> bool {
+        let shown = |t: &DateTime| (t.year, t.month, t.day, t.hour, t.minute);
+        if self.time.as_ref().map(shown) == Some(shown(&time)) {
+            return false;
+        }
+        self.time = Some(time);
+
+        let mut dirty = false;
+        for w in self.widgets.iter_mut().filter(|w| w.kind == Kind::Clock) {
+            w.dirty = true;
+            dirty = true;
+        }
+        dirty
+    }
+
+    /// Draw the widgets that changed, returning the pixel area touched.
+    pub fn render<D>(&mut self, target: &mut D) -> Result<Option<Rectangle>, D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor> + OriginDimensions,
+    {
+        let size = target.size();
+        let mut area = None;
+
+        if let Some(cells) = self.erased.take() {
+            // Widgets under the blanked area are drawn again on top of it
+            for w in self.widgets.iter_mut() {
+                if w.cells.intersection(&cells).size != Size::zero() {
+                    w.dirty = true;
+                }
+            }
+            let erased = cell_area(cells, size);
+            erased
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+                .draw(target)?;
+            area = Some(erased);
+        }
+
+        for w in self.widgets.iter_mut().filter(|w| w.dirty) {
+            let bounds = cell_area(w.cells, size);
+            draw_widget(target, w, bounds, &self.series, self.time.as_ref())?;
+            w.dirty = false;
+            area = Some(union(area, bounds));
+        }
+        Ok(area)
+    }
+}
+
+fn draw_widget<D>(
+    target: &mut D,
+    w: &Widget,
+    bounds: Rectangle,
+    series: &SeriesStore,
+    time: Option<&DateTime>,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor> + OriginDimensions,
+{
+    bounds
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+        .draw(target)?;
+
+    let inner = bounds.offset(-(GUTTER as i32));
+    // Whatever overflows is cut at the gutter instead of spilling on a neighbour
+    let target = &mut target.clipped(&inner);
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+    let top = TextStyleBuilder::new().baseline(Baseline::Top).build();
+
+    // Everything but labels and icons shows what it is about
+    let body = match w.kind {
+        Kind::Label | Kind::Icon | Kind::Qr | Kind::Clock => inner,
+        _ => {
+            Text::with_text_style(&w.key, inner.top_left, small, top).draw(target)?;
+            inner.resized_height(
+                inner.size.height.saturating_sub(CAPTION_HEIGHT),
+                AnchorY::Bottom,
+            )
+        }
+    };
+    let center = body.center();
+
+    match w.kind {
+        Kind::Label => {
+            let mut cell = target.cropped(&inner);
+            draw_text(
+                &mut cell,
+                &FONT_10X20,
+                Point::zero(),
+                &w.value,
+                Alignment::Left,
+                false,
+            )?;
+        }
+        Kind::Number => {
+            // Centered in the body, a value too wide wraps around the middle
+            let mut cell = target.cropped(&body);
+            let size = body.size;
+            let anchor = Point::new(size.width as i32 / 2, 0);
+            let height = text_height(&FONT_10X20, size.width, anchor, &w.value, Alignment::Center);
+            let top = anchor + Point::new(0, (size.height as i32 - height as i32) / 2);
+            draw_text(
+                &mut cell,
+                &FONT_10X20,
+                top,
+                &w.value,
+                Alignment::Center,
+                false,
+            )?;
+        }
+        Kind::Bar => {
+            // Values are checked by set, this keeps the fill in the bar anyway
+            let pct = w
+                .value
+                .parse::<f32>()
+                .ok()
+                .filter(|p| !p.is_nan())
+                .map_or(0.0, |p| p.clamp(0.0, 100.0));
+            let bar = Rectangle::with_center(center, Size::new(body.size.width, 16));
+            bar.into_styled(stroke).draw(target)?;
+            let filled = (bar.size.width as f32 * pct / 100.0) as u32;
+            bar.resized_width(filled, AnchorX::Left)
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(target)?;
+        }
+        Kind::Icon => draw_icon(target, &w.value, body)?,
+        Kind::Qr => draw_qr_in(target, &w.value, body)?,
+        Kind::Clock => {
+            let big = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+            let centered = TextStyleBuilder::new()
+                .alignment(Alignment::Center)
+                .baseline(Baseline::Bottom)
+                .build();
+            let mut hm: String<8> = String::new();
+            let mut date: String<16> = String::new();
+            match time {
+                Some(t) => {
+                    let _ = write!(hm, "{:02}:{:02}", t.hour, t.minute);
+                    let _ = write!(
+                        date,
+                        "{} {} {}",
+                        WEEKDAYS[t.weekday as usize],
+                        t.day,
+                        MONTHS[t.month as usize - 1]
+                    );
+                }
+                None => {
+                    let _ = hm.push_str("--:--");
+                }
+            }
+            Text::with_text_style(&hm, center + Point::new(0, 6), big, centered).draw(target)?;
+            let below = TextStyleBuilder::new()
+                .alignment(Alignment::Center)
+                .baseline(Baseline::Top)
+                .build();
+            Text::with_text_style(&date, center + Point::new(0, 8), small, below).draw(target)?;
+        }
+        Kind::Sparkline => {
+            let (min, max) = w
+                .history
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+            let span = if max > min { max - min } else { 1.0 };
+            let step = body.size.width as f32 / (SPARK_LEN - 1) as f32;
+            let bottom = body.top_left.y + body.size.height as i32 - 1;
+
+            let mut points: Vec<Point, SPARK_LEN> = Vec::new();
+            for (i, v) in w.history.iter().enumerate() {
+                let x = body.top_left.x + (i as f32 * step) as i32;
+                let y = bottom - ((v - min) / span * (body.size.height - 1) as f32) as i32;
+                let _ = points.push(Point::new(x, y));
+            }
+            Polyline::new(&points).into_styled(stroke).draw(target)?;
+        }
+        Kind::Chart | Kind::Bars => {
+            let style = match w.kind {
+                Kind::Bars => ChartStyle::Bars,
+                _ => ChartStyle::Line,
+            };
+            let values = series
+                .get(&w.key)
+                .into_iter()
+                .flat_map(|s| s.samples())
+                .map(|s| s.value);
+            draw_chart(target, body, values, style)?;
+        }
+        Kind::Table => {
+            let right = TextStyleBuilder::new()
+                .alignment(Alignment::Right)
+                .baseline(Baseline::Top)
+                .build();
+            let mut cell = target.cropped(&body);
+            let right_edge = body.size.width as i32 - 1;
+            let mut y = 0;
+            for row in w.value.split(';').filter(|r| !r.is_empty()) {
+                let (name, value) = row.split_once('=').unwrap_or((row, ""));
+                Text::with_text_style(name, Point::new(0, y), small, top).draw(&mut cell)?;
+                Text::with_text_style(value, Point::new(right_edge, y), small, right)
+                    .draw(&mut cell)?;
+                y += CAPTION_HEIGHT as i32;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn draw_icon<D>(target: &mut D, name: &str, area: Rectangle) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let size = core::cmp::min(area.size.width, area.size.height);
+    let c = area.center();
+    let r = size as i32 / 4;
+    let fill = PrimitiveStyle::with_fill(BinaryColor::On);
+    let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 3);
+
+    let cloud = |target: &mut D, c: Point| -> Result<(), D::Error> {
+        Circle::with_center(c + Point::new(-r / 2, 0), r as u32 + 2)
+            .into_styled(fill)
+            .draw(target)?;
+        Circle::with_center(c + Point::new(r / 3, -r / 4), r as u32 * 3 / 2)
+            .into_styled(fill)
+            .draw(target)?;
+        Rectangle::with_center(
+            c + Point::new(0, r / 3),
+            Size::new(r as u32 * 2, r as u32 / 2),
+        )
+        .into_styled(fill)
+        .draw(target)
+    };
+
+    match name {
+        "sun" => {
+            Circle::with_center(c, r as u32 * 2)
+                .into_styled(fill)
+                .draw(target)?;
+            for (dx, dy) in [
+                (1, 0),
+                (1, 1),
+                (0, 1),
+                (-1, 1),
+                (-1, 0),
+                (-1, -1),
+                (0, -1),
+                (1, -1),
+            ] {
+                let ray = Point::new(dx, dy);
+                Line::new(c + ray * (r + r / 3), c + ray * (r * 2 - r / 4))
+                    .into_styled(stroke)
+                    .draw(target)?;
+            }
+        }
+        "cloud" => cloud(target, c)?,
+        "rain" => {
+            cloud(target, c - Point::new(0, r / 2))?;
+            for dx in [-r / 2, 0, r / 2] {
+                let top = c + Point::new(dx, r / 2);
+                Line::new(top, top + Point::new(-r / 4, r / 2))
+                    .into_styled(stroke)
+                    .draw(target)?;
+            }
+        }
+        "alert" => {
+            Triangle::new(
+                c + Point::new(0, -r),
+                c + Point::new(r, r),
+                c + Point::new(-r, r),
+            )
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 2))
+            .draw(target)?;
+            Line::new(c + Point::new(0, -r / 3), c + Point::new(0, r / 3))
+                .into_styled(stroke)
+                .draw(target)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Draw what changed and refresh only that part of the panel.
+pub async fn redraw<M: RawMutex, P: Panel>(
+    dash: &Mutex<M, Dashboard>,
+    panel: &Mutex<M, P>,
+) -> Result<(), &'static str> {
+    let mut panel = panel.lock().await;
+    let Ok(area) = dash.lock().await.render(&mut *panel);
+    match area {
+        Some(area) => panel.refresh_region(area).await,
+        None => Ok(()),
+    }
+}
+
+fn parse_cell(arg: Option<&String<ARG_LEN>>) -> Result<u32, CmdError> {
+    arg.and_then(|a| a.parse().ok()).ok_or(WRONG_ARGS)
+}
+
+/// `widget` command, places and removes widgets.
+pub struct WidgetMgr<'a, M: RawMutex, P> {
+    dash: &'a Mutex<M, Dashboard>,
+    panel: &'a Mutex<M, P>,
+}
+
+impl<'a, M: RawMutex, P> WidgetMgr<'a, M, P> {
+    pub fn new(dash: &'a Mutex<M, Dashboard>, panel: &'a Mutex<M, P>) -> Self {
+        Self { dash, panel }
+    }
+}
+
+impl<M: RawMutex, P: Panel> CommandHandler for WidgetMgr<'_, M, P> {
+    fn name(&self) -> &'static str {
+        "widget"
+    }
+
+    fn usage(&self) -> &'static str {
+        "widget add <key> <label|number|bar|icon|spark|table|chart|bars|qr|clock> <col> <row> [w] [h] | widget del <key> | widget list"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let args = pkg.args.as_slice();
+        match args.first().map(|a| a.as_str()) {
+            Some("add") => {
+                let key = args.get(1).ok_or(WRONG_ARGS)?;
+                let kind = args
+                    .get(2)
+                    .and_then(|k| Kind::from_name(k))
+                    .ok_or(CmdError::BadArgs("Unknown widget kind"))?;
+                let col = parse_cell(args.get(3))?;
+                let row = parse_cell(args.get(4))?;
+                let w = args.get(5).map_or(Ok(1), |_| parse_cell(args.get(5)))?;
+                let h = args.get(6).map_or(Ok(1), |_| parse_cell(args.get(6)))?;
+                if w < 1 || h < 1 {
+                    return Err(WRONG_ARGS);
+                }
+                if !within(col, w, GRID_COLS) || !within(row, h, GRID_ROWS) {
+                    return Err(CmdError::BadArgs("Outside the grid"));
+                }
+
+                // Both fit the grid, far from overflowing a coordinate
+                let cells = Rectangle::new(Point::new(col as i32, row as i32), Size::new(w, h));
+                self.dash.lock().await.add(key, kind, cells)?;
+            }
+            Some("del") => {
+                let key = args.get(1).ok_or(WRONG_ARGS)?;
+                self.dash.lock().await.remove(key)?;
+            }
+            Some("list") => {
+                let dash = self.dash.lock().await;
+                for w in dash.widgets() {
+                    if !reply.is_empty() {
+                        reply.push('\n').map_err(|_| REPLY_TOO_LONG)?;
+                    }
+                    write!(reply, "{} {} {}", w.key(), w.kind().name(), w.value())
+                        .map_err(|_| REPLY_TOO_LONG)?;
+                }
+                return Ok(());
+            }
+            _ => return Err(WRONG_ARGS),
+        }
+
+        redraw(self.dash, self.panel)
+            .await
+            .map_err(CmdError::Failed)
+    }
+}
+
+/// `set <key> <value>`, feeds a widget.
+pub struct SetMgr<'a, M: RawMutex, P> {
+    dash: &'a Mutex<M, Dashboard>,
+    panel: &'a Mutex<M, P>,
+}
+
+impl<'a, M: RawMutex, P> SetMgr<'a, M, P> {
+    pub fn new(dash: &'a Mutex<M, Dashboard>, panel: &'a Mutex<M, P>) -> Self {
+        Self { dash, panel }
+    }
+}
+
+impl<M: RawMutex, P: Panel> CommandHandler for SetMgr<'_, M, P> {
+    fn name(&self) -> &'static str {
+        "set"
+    }
+
+    fn usage(&self) -> &'static str {
+        "set <key> <value>"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let [key, value] = pkg.args.as_slice() else {
+            return Err(WRONG_ARGS);
+        };
+
+        if self.dash.lock().await.set(key, value)? {
+            redraw(self.dash, self.panel).await?;
+            reply.push_str("Updated").map_err(|_| REPLY_TOO_LONG)
+        } else {
+            reply.push_str("Unchanged").map_err(|_| REPLY_TOO_LONG)
+        }
+    }
+}
+
+/// `log` command, records samples of named series for the charts.
+pub struct LogMgr<'a, M: RawMutex, P> {
+    dash: &'a Mutex<M, Dashboard>,
+    panel: &'a Mutex<M, P>,
+}
+
+impl<'a, M: RawMutex, P> LogMgr<'a, M, P> {
+    pub fn new(dash: &'a Mutex<M, Dashboard>, panel: &'a Mutex<M, P>) -> Self {
+        Self { dash, panel }
+    }
+}
+
+impl<M: RawMutex, P: Panel> CommandHandler for LogMgr<'_, M, P> {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn usage(&self) -> &'static str {
+        "log <name> <value> | log show <name> [n] | log clear <name> | log list"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let args = pkg.args.as_slice();
+        match args {
+            [list] if list.as_str() == "list" => {
+                let dash = self.dash.lock().await;
+                for s in dash.series().iter() {
+                    if !reply.is_empty() {
+                        reply.push('\n').map_err(|_| REPLY_TOO_LONG)?;
+                    }
+                    write!(reply, "{} {}", s.name(), s.len()).map_err(|_| REPLY_TOO_LONG)?;
+                    if let Some(last) = s.last() {
+                        write!(reply, " {}", last.value).map_err(|_| REPLY_TOO_LONG)?;
+                    }
+                }
+                Ok(())
+            }
+            [show, name, rest @ ..] if show.as_str() == "show" => {
+                let n: usize = match rest.first() {
+                    Some(n) => n.parse().map_err(|_| WRONG_ARGS)?,
+                    None => 10,
+                };
+                let dash = self.dash.lock().await;
+                let series = dash
+                    .series()
+                    .get(name)
+                    .ok_or(CmdError::BadArgs("Unknown series"))?;
+                for s in series.samples().skip(series.len().saturating_sub(n)) {
+                    if !reply.is_empty() {
+                        reply.push('\n').map_err(|_| REPLY_TOO_LONG)?;
+                    }
+                    write!(reply, "{} {}", s.time, s.value).map_err(|_| REPLY_TOO_LONG)?;
+                }
+                Ok(())
+            }
+            [clear, name] if clear.as_str() == "clear" => {
+                self.dash.lock().await.clear_series(name)?;
+                redraw(self.dash, self.panel)
+                    .await
+                    .map_err(CmdError::Failed)
+            }
+            [name, value] => {
+                let sample = Sample {
+                    time: Instant::now().as_secs() as u32,
+                    value: value
+                        .parse()
+                        .map_err(|_| CmdError::BadArgs("Not a number"))?,
+                };
+                if self.dash.lock().await.log(name, sample)? {
+                    redraw(self.dash, self.panel).await?;
+                }
+                Ok(())
+            }
+            _ => Err(WRONG_ARGS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::mock::Canvas;
+    use std::format;
+
+    fn cells(col: i32, row: i32, w: u32, h: u32) -> Rectangle {
+        Rectangle::new(Point::new(col, row), Size::new(w, h))
+    }
+
+    #[test]
+    fn add_checks_the_grid() {
+        let mut dash = Dashboard::new();
+        assert_eq!(dash.add("a", Kind::Label, cells(0, 0, 4, 3)), Ok(()));
+        assert_eq!(dash.add("b", Kind::Label, cells(3, 2, 1, 1)), Ok(()));
+        for bad in [
+            cells(0, 0, 5, 1),
+            cells(3, 0, 2, 1),
+            cells(0, 2, 1, 2),
+            cells(-1, 0, 1, 1),
+            cells(i32::MAX, 0, 1, 1),
+            cells(1, 1, u32::MAX, 1),
+            cells(1, 1, 1, u32::MAX),
+        ] {
+            assert_eq!(
+                dash.add("c", Kind::Label, bad),
+                Err(CmdError::BadArgs("Outside the grid")),
+                "{bad:?}"
+            );
+        }
+        assert_eq!(
+            dash.add("c", Kind::Label, cells(0, 0, 0, 1)),
+            Err(CmdError::BadArgs("Empty widget"))
+        );
+        assert_eq!(dash.widgets().len(), 2);
+    }
+
+    fn run(mgr: &mut impl CommandHandler, line: &str) -> Result<Reply, CmdError> {
+        let mut reply = Reply::new();
+        let pkg = ParserMgr::parse(line.as_bytes()).unwrap();
+        block_on(mgr.handle(pkg, &mut reply)).map(|()| reply)
+    }
+
+    #[test]
+    fn widget_command() {
+        let dash = Mutex::<NoopRawMutex, _>::new(Dashboard::new());
+        let panel = Mutex::new(Canvas::new(400, 300));
+        let mut widgets = WidgetMgr::new(&dash, &panel);
+        let mut set = SetMgr::new(&dash, &panel);
+
+        run(&mut widgets, "widget add temp number 1 1 2").unwrap();
+        assert_eq!(run(&mut widgets, "widget list").unwrap(), "temp number ");
+        // Only the cells of the widget are refreshed
+        assert_eq!(
+            block_on(panel.lock()).refreshed,
+            [cells(100, 100, 200, 100)]
+        );
+
+        assert_eq!(run(&mut set, "set temp 21.5").unwrap(), "Updated");
+        assert_eq!(run(&mut set, "set temp 21.5").unwrap(), "Unchanged");
+        assert_eq!(
+            run(&mut set, "set nope 1"),
+            Err(CmdError::BadArgs("Unknown widget"))
+        );
+
+        for line in [
+            "widget add x label 4 0",
+            "widget add x label 0 0 5",
+            "widget add x label 2 0 4294967295",
+            "widget add x label 0 2 1 4294967295",
+        ] {
+            assert_eq!(
+                run(&mut widgets, line),
+                Err(CmdError::BadArgs("Outside the grid")),
+                "{line}"
+            );
+        }
+        for line in [
+            "widget add x label -1 0",
+            "widget add x label 0 0 0",
+            "widget add x label",
+            "widget add x label 99999999999 0",
+        ] {
+            assert_eq!(run(&mut widgets, line), Err(WRONG_ARGS), "{line}");
+        }
+        assert_eq!(
+            run(&mut widgets, "widget add x dial 0 0"),
+            Err(CmdError::BadArgs("Unknown widget kind"))
+        );
+
+        run(&mut widgets, "widget del temp").unwrap();
+        assert_eq!(run(&mut widgets, "widget list").unwrap(), "");
+        assert_eq!(
+            run(&mut widgets, "widget del temp"),
+            Err(CmdError::BadArgs("Unknown widget"))
+        );
+    }
+
+    /// Pixels of the whole grid on a 200 x 100 panel, rows round down.
+    fn grid() -> Rectangle {
+        cell_area(cells(0, 0, GRID_COLS, GRID_ROWS), Size::new(200, 100))
+    }
+
+    /// Every widget kind alone on a 200 x 100 panel, over the whole grid.
+    fn render_one(kind: Kind, values: &[&str]) -> Canvas {
+        let mut dash = Dashboard::new();
+        let key = kind.name();
+        dash.add(key, kind, cells(0, 0, GRID_COLS, GRID_ROWS))
+            .unwrap();
+        for (time, v) in values.iter().enumerate() {
+            match kind {
+                Kind::Chart | Kind::Bars => {
+                    let value = v.parse().unwrap();
+                    let time = time as u32 * 60;
+                    dash.log(key, Sample { time, value }).unwrap();
+                }
+                Kind::Clock => {}
+                _ => {
+                    dash.set(key, v).unwrap();
+                }
+            }
+        }
+        dash.set_time(DateTime {
+            year: 2026,
+            month: 10,
+            day: 17,
+            hour: 19,
+            minute: 35,
+            second: 12,
+            weekday: 6,
+        });
+
+        let mut canvas = Canvas::new(200, 100);
+        let Ok(area) = dash.render(&mut canvas);
+        assert_eq!(area, Some(grid()));
+        canvas
+    }
+
+    #[test]
+    fn golden_widgets() {
+        let series = ["3", "5", "4", "8", "6", "9", "7", "2"];
+        let samples: &[(Kind, &[&str])] = &[
+            (
+                Kind::Label,
+                &["Long labels wrap on words, the rest is cut at the bottom of cell"],
+            ),
+            (Kind::Number, &["21.5"]),
+            (Kind::Bar, &["42"]),
+            (Kind::Icon, &["rain"]),
+            (Kind::Sparkline, &series),
+            (
+                Kind::Table,
+                &["in=21.5;out=-3.2;humidity=45%;wind=12 km/h;rain=0"],
+            ),
+            (Kind::Chart, &series),
+            (Kind::Bars, &series),
+            (Kind::Qr, &["https://example.com"]),
+            (Kind::Clock, &[]),
+        ];
+        let inner = grid().offset(-(GUTTER as i32));
+        for (kind, values) in samples {
+            let canvas = render_one(*kind, values);
+            canvas.assert_golden(&format!("widget_{}", kind.name()));
+
+            let ink = canvas.ink().unwrap();
+            assert!(
+                inner.contains(ink.top_left) && inner.contains(ink.bottom_right().unwrap()),
+                "{kind:?} inks {ink:?}"
+            );
+        }
+
+        // Wider than the body, wrapped around the middle
+        render_one(Kind::Number, &["1234567890123456789012345"])
+            .assert_golden("widget_number_wrap");
+    }
+
+    #[test]
+    fn bar_is_clamped() {
+        let full = render_one(Kind::Bar, &["100"]);
+        let mut dash = Dashboard::new();
+        dash.add("bar", Kind::Bar, cells(0, 0, GRID_COLS, GRID_ROWS))
+            .unwrap();
+        // Only a stored value can be out of range, set refuses it
+        assert_eq!(
+            dash.set("bar", "150"),
+            Err(CmdError::BadArgs("Out of range"))
+        );
+        for value in ["150", "-20", "NaN"] {
+            dash.widgets[0].value = String::try_from(value).unwrap();
+            dash.widgets[0].dirty = true;
+            let mut canvas = Canvas::new(200, 100);
+            let Ok(_) = dash.render(&mut canvas);
+            let expected = match value {
+                "150" => full.ink(),
+                _ => render_one(Kind::Bar, &["0"]).ink(),
+            };
+            assert_eq!(canvas.ink(), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn set_refreshes_one_cell() {
+        let dash = Mutex::<NoopRawMutex, _>::new(Dashboard::new());
+        let panel = Mutex::new(Canvas::new(400, 300));
+        let mut widgets = WidgetMgr::new(&dash, &panel);
+        let mut set = SetMgr::new(&dash, &panel);
+
+        run(&mut widgets, "widget add note label 0 0").unwrap();
+        run(&mut widgets, "widget add temp number 1 0").unwrap();
+        run(&mut widgets, "widget add rows table 0 1 2").unwrap();
+        run(&mut set, "set temp 21.5").unwrap();
+        run(&mut set, "set rows a=1;b=2").unwrap();
+
+        let note = cells(0, 0, 100, 100);
+        let outside = |canvas: &Canvas| {
+            (0..300)
+                .flat_map(|y| (0..400).map(move |x| (x, y)))
+                .filter(|&(x, y)| !note.contains(Point::new(x as i32, y as i32)))
+                .map(|(x, y)| canvas.pixel(x, y))
+                .collect::<std::vec::Vec<_>>()
+        };
+        let before = outside(&block_on(panel.lock()));
+        block_on(panel.lock()).refreshed.clear();
+
+        // Far more text than the cell holds
+        run(
+            &mut set,
+            "set note \"a long note that would run over the number and the table\"",
+        )
+        .unwrap();
+
+        let canvas = block_on(panel.lock());
+        assert_eq!(canvas.refreshed, [note]);
+        assert_eq!(outside(&canvas), before);
+        let ink = canvas.ink().unwrap();
+        assert!(ink.top_left.x < 100 && ink.top_left.y < 100);
+        let note_ink = (0..100)
+            .flat_map(|y| (0..100).map(move |x| (x, y)))
+            .any(|(x, y)| canvas.pixel(x, y));
+        assert!(note_ink);
+        // Nothing in the gutter
+        let inner = note.offset(-(GUTTER as i32));
+        for y in 0..100 {
+            for x in 0..100 {
+                if !inner.contains(Point::new(x as i32, y as i32)) {
+                    assert!(!canvas.pixel(x, y), "({x}, {y})");
+                }
+            }
+        }
+    }
+}
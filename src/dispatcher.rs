@@ -0,0 +1,284 @@
+//! Routing of parsed command lines to the subsystem handling them.
+//!
+//! Handlers are chained at compile time by [`Dispatcher::register`], so the
+//! registry needs no allocation and `handle` can stay a plain async fn.
+use dbhome_common::reply::Status;
+use heapless::String;
+
+use crate::proto_parser::ParserMgr;
+
+/// Payload room in a reply, status codes are added when it is encoded.
+pub const REPLY_LEN: usize = 256;
+pub type Reply = String<REPLY_LEN>;
+
+/// Why a command did not run, the message is sent back as the reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CmdError {
+    /// Missing, unknown or out of range arguments.
+    BadArgs(&'static str),
+    /// The command was understood but did not succeed.
+    Failed(&'static str),
+}
+
+/// The usual answer to arguments that do not match the usage.
+pub const WRONG_ARGS: CmdError = CmdError::BadArgs("Wrong args");
+pub const REPLY_TOO_LONG: CmdError = CmdError::Failed("Reply too long");
+
+impl CmdError {
+    pub fn status(self) -> Status {
+        match self {
+            CmdError::BadArgs(_) => Status::BadArgs,
+            CmdError::Failed(_) => Status::Failed,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CmdError::BadArgs(msg) | CmdError::Failed(msg) => msg,
+        }
+    }
+}
+
+/// Errors of the drivers and managers below are failures to carry out the command.
+impl From<&'static str> for CmdError {
+    fn from(msg: &'static str) -> Self {
+        CmdError::Failed(msg)
+    }
+}
+
+impl core::fmt::Display for CmdError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A subsystem reachable from the command session.
+#[allow(async_fn_in_trait)]
+pub trait CommandHandler {
+    /// First word of the command line, e.g. `led`.
+    fn name(&self) -> &'static str;
+    /// One line synopsis shown by `help <name>`.
+    fn usage(&self) -> &'static str;
+    /// Run the command, writing its output in `reply`.
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError>;
+}
+
+/// Chain of handlers built by the dispatcher.
+#[allow(async_fn_in_trait)]
+pub trait CommandSet {
+    /// `None` when no handler in the set has that name.
+    async fn dispatch(&mut self, pkg: ParserMgr, reply: &mut Reply)
+        -> Option<Result<(), CmdError>>;
+    fn usage(&self, name: &str) -> Option<&'static str>;
+    fn names(&self, reply: &mut Reply);
+}
+
+impl CommandSet for () {
+    async fn dispatch(
+        &mut self,
+        _pkg: ParserMgr,
+        _reply: &mut Reply,
+    ) -> Option<Result<(), CmdError>> {
+        None
+    }
+
+    fn usage(&self, _name: &str) -> Option<&'static str> {
+        None
+    }
+
+    fn names(&self, _reply: &mut Reply) {}
+}
+
+impl<H: CommandHandler, T: CommandSet> CommandSet for (H, T) {
+    async fn dispatch(
+        &mut self,
+        pkg: ParserMgr,
+        reply: &mut Reply,
+    ) -> Option<Result<(), CmdError>> {
+        if pkg.cmd.as_str() == self.0.name() {
+            return Some(self.0.handle(pkg, reply).await);
+        }
+        self.1.dispatch(pkg, reply).await
+    }
+
+    fn usage(&self, name: &str) -> Option<&'static str> {
+        if name == self.0.name() {
+            return Some(self.0.usage());
+        }
+        self.1.usage(name)
+    }
+
+    fn names(&self, reply: &mut Reply) {
+        // Tail first, so names come out in registration order
+        self.1.names(reply);
+        let _ = reply.push_str(self.0.name());
+        let _ = reply.push(' ');
+    }
+}
+
+pub struct Dispatcher<S> {
+    handlers: S,
+}
+
+impl Default for Dispatcher<()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dispatcher<()> {
+    pub fn new() -> Self {
+        Self { handlers: () }
+    }
+}
+
+impl<S: CommandSet> Dispatcher<S> {
+    pub fn register<H: CommandHandler>(self, handler: H) -> Dispatcher<(H, S)> {
+        Dispatcher {
+            handlers: (handler, self.handlers),
+        }
+    }
+
+    /// Run the command line, `help` is answered here from the registered handlers.
+    ///
+    /// On failure `reply` holds the error message instead of the output.
+    pub async fn dispatch(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Status {
+        let ret = if pkg.cmd.as_str() == "help" {
+            self.help(pkg, reply)
+        } else {
+            match self.handlers.dispatch(pkg, reply).await {
+                Some(ret) => ret.map_err(|e| (e.status(), e.as_str())),
+                None => Err((Status::UnknownCommand, "Invalid Command")),
+            }
+        };
+
+        match ret {
+            Ok(()) => Status::Ok,
+            Err((status, msg)) => {
+                reply.clear();
+                let _ = reply.push_str(msg);
+                status
+            }
+        }
+    }
+
+    fn help(&self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), (Status, &'static str)> {
+        match pkg.args.first() {
+            Some(name) => {
+                let usage = self
+                    .handlers
+                    .usage(name)
+                    .ok_or((Status::BadArgs, "Invalid Command"))?;
+                reply
+                    .push_str(usage)
+                    .map_err(|_| (REPLY_TOO_LONG.status(), REPLY_TOO_LONG.as_str()))
+            }
+            None => {
+                self.handlers.names(reply);
+                let _ = reply.push_str("help");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    struct Echo;
+
+    impl CommandHandler for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn usage(&self) -> &'static str {
+            "echo <word>"
+        }
+
+        async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+            let word = pkg.args.first().ok_or(CmdError::BadArgs("Missing word"))?;
+            reply.push_str(word).map_err(|_| REPLY_TOO_LONG)
+        }
+    }
+
+    struct Broken;
+
+    impl CommandHandler for Broken {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        fn usage(&self) -> &'static str {
+            "broken"
+        }
+
+        async fn handle(&mut self, _pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+            let _ = reply.push_str("partial output");
+            Err("Out of order".into())
+        }
+    }
+
+    fn run<S: CommandSet>(dispatcher: &mut Dispatcher<S>, line: &str) -> (Status, Reply) {
+        let mut reply = Reply::new();
+        let pkg = ParserMgr::parse(line.as_bytes()).unwrap();
+        let status = block_on(dispatcher.dispatch(pkg, &mut reply));
+        (status, reply)
+    }
+
+    #[test]
+    fn routes_by_name() {
+        let mut dispatcher = Dispatcher::new().register(Echo).register(Broken);
+        assert_eq!(
+            run(&mut dispatcher, "echo hi"),
+            (Status::Ok, "hi".try_into().unwrap())
+        );
+        assert_eq!(
+            run(&mut dispatcher, "echo"),
+            (Status::BadArgs, "Missing word".try_into().unwrap())
+        );
+        assert_eq!(
+            run(&mut dispatcher, "nope"),
+            (
+                Status::UnknownCommand,
+                "Invalid Command".try_into().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn error_replaces_output() {
+        let mut dispatcher = Dispatcher::new().register(Broken);
+        assert_eq!(
+            run(&mut dispatcher, "broken"),
+            (Status::Failed, "Out of order".try_into().unwrap())
+        );
+    }
+
+    #[test]
+    fn help() {
+        let mut dispatcher = Dispatcher::new().register(Echo).register(Broken);
+        assert_eq!(
+            run(&mut dispatcher, "help"),
+            (Status::Ok, "echo broken help".try_into().unwrap())
+        );
+        assert_eq!(
+            run(&mut dispatcher, "help echo"),
+            (Status::Ok, "echo <word>".try_into().unwrap())
+        );
+        assert_eq!(
+            run(&mut dispatcher, "help nope"),
+            (Status::BadArgs, "Invalid Command".try_into().unwrap())
+        );
+
+        let mut empty = Dispatcher::new();
+        assert_eq!(
+            run(&mut empty, "help"),
+            (Status::Ok, "help".try_into().unwrap())
+        );
+        assert_eq!(run(&mut empty, "echo hi").0, Status::UnknownCommand);
+    }
+}
@@ -1,99 +1,186 @@
-use embassy_time::{Duration, Timer};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::{delay::DelayNs, digital::Wait, spi::SpiDevice};
 
-use esp_hal::{
-    gpio::{GpioPin, Input, Level, Output, Pull},
-    spi::master::SpiDmaBus,
-    Async,
-};
-
-//use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
-use esp_println::println;
+use dbhome_common::frame::FrameError;
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
 
+use crate::dashboard::Panel;
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
 use crate::epd4in2_cmd::Command;
 use crate::epd4in2_const::*;
 use crate::proto_parser::ParserMgr;
 
-pub const EPD_WIDTH: usize = 400;
-pub const EPD_HEIGHT: usize = 300;
+pub use dbhome_common::{EPD_HEIGHT, EPD_WIDTH};
+
+/// Quick refreshes allowed in a row before a full one is forced to clear ghosting.
+pub const QUICK_REFRESH_LIMIT: u16 = 10;
+
+/// Waveform used to refresh the panel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefreshMode {
+    /// Slow, flashing refresh that leaves no ghosting.
+    Full,
+    /// Single phase refresh, fast but leaves some ghosting behind.
+    Quick,
+}
+
+/// Window rejected by [`EPDMgr::update_region`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionError {
+    /// Zero width or height.
+    Empty,
+    /// The window does not fit the panel.
+    OutOfRange,
+}
+
+impl RegionError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegionError::Empty => "empty region",
+            RegionError::OutOfRange => "region out of range",
+        }
+    }
+}
 
-//framebuffer: [u8; EPD_WIDTH as usize * EPD_HEIGHT as usize / 8],
-pub struct EPDMgr<'d> {
-    busy: Input<'d>,
-    rst: Output<'d>,
-    dc: Output<'d>,
-    channel: SpiDmaBus<'d, Async>,
+impl core::fmt::Display for RegionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 1bpp framebuffer, one bit per pixel, MSB is the leftmost pixel.
+/// A set bit is white, a cleared bit is black.
+pub struct EPDMgr<SPI, BUSY, RST, DC, DELAY> {
+    busy: BUSY,
+    rst: RST,
+    dc: DC,
+    channel: SPI,
+    delay: DELAY,
+    mode: RefreshMode,
+    lut: Option<RefreshMode>,
+    quick_count: u16,
+    quick_limit: u16,
     payload: [u8; EPD_WIDTH * EPD_HEIGHT / 8],
 }
 
-impl<'d> EPDMgr<'d> {
-    pub fn new(
-        channel: SpiDmaBus<'d, Async>,
-        busy: GpioPin<6>,
-        rst: GpioPin<7>,
-        dc: GpioPin<8>,
-    ) -> Self {
+impl<SPI, BUSY, RST, DC, DELAY> EPDMgr<SPI, BUSY, RST, DC, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    RST: OutputPin,
+    DC: OutputPin,
+    DELAY: DelayNs,
+{
+    /// Busy pin is expected to be already configured as input with pull-up,
+    /// rst and dc as outputs driven low.
+    pub fn new(channel: SPI, busy: BUSY, rst: RST, dc: DC, delay: DELAY) -> Self {
         Self {
             channel,
-            busy: Input::new(busy, Pull::Up).into(),
-            rst: Output::new(rst, Level::Low).into(),
-            dc: Output::new(dc, Level::Low).into(),
+            busy,
+            rst,
+            dc,
+            delay,
+            mode: RefreshMode::Full,
+            lut: None,
+            quick_count: 0,
+            quick_limit: QUICK_REFRESH_LIMIT,
             payload: [0xff; EPD_WIDTH * EPD_HEIGHT / 8],
         }
     }
 
-    fn transfer(&mut self, data: u8) {
+    /// Raw framebuffer as it is sent to the panel.
+    pub fn buffer(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.payload
+    }
+
+    /// Give back the bus, pins and delay.
+    pub fn release(self) -> (SPI, BUSY, RST, DC, DELAY) {
+        (self.channel, self.busy, self.rst, self.dc, self.delay)
+    }
+
+    async fn transfer(&mut self, data: u8) {
         let mut buffer = [0; 1];
-        self.channel.transfer(&mut buffer, &[data]).unwrap();
+        self.channel.transfer(&mut buffer, &[data]).await.unwrap();
     }
     async fn reset(&mut self) {
-        self.rst.set_low();
-        Timer::after(Duration::from_millis(200)).await;
-        self.rst.set_high();
-        Timer::after(Duration::from_millis(200)).await;
+        self.rst.set_low().unwrap();
+        self.delay.delay_ms(200).await;
+        self.rst.set_high().unwrap();
+        self.delay.delay_ms(200).await;
     }
     async fn wait_idle(&mut self) {
         self.send_command(Command::GetStatus).await;
-        loop {
-            //LOW: busy, HIGH: idle
-            if self.busy.is_high() {
-                break;
-            }
-            Timer::after(Duration::from_millis(100)).await;
-        }
+        //LOW: busy, HIGH: idle
+        self.busy.wait_for_high().await.unwrap();
     }
     async fn send_command(&mut self, cmd: Command) {
-        self.dc.set_low();
-        self.transfer(cmd.address());
+        self.dc.set_low().unwrap();
+        self.transfer(cmd.address()).await;
     }
     async fn send_data(&mut self, data: u8) {
-        self.dc.set_high();
-        self.transfer(data);
+        self.dc.set_high().unwrap();
+        self.transfer(data).await;
     }
-    async fn set_lut(&mut self) {
-        self.send_command(Command::LutForVcom).await; //vcom
-        for i in LUT_VCOM0.iter() {
+    async fn send_lut(&mut self, cmd: Command, lut: &[u8]) {
+        self.send_command(cmd).await;
+        for i in lut.iter() {
             self.send_data(*i).await;
         }
-
-        self.send_command(Command::LutWhiteToWhite).await; //ww --
-        for i in LUT_WW.iter() {
-            self.send_data(*i).await;
+    }
+    async fn set_lut(&mut self, mode: RefreshMode) {
+        if self.lut == Some(mode) {
+            return;
         }
 
-        self.send_command(Command::LutBlackToWhite).await; //bw r
-        for i in LUT_BW.iter() {
-            self.send_data(*i).await;
+        match mode {
+            RefreshMode::Full => {
+                self.send_lut(Command::LutForVcom, &LUT_VCOM0).await; //vcom
+                self.send_lut(Command::LutWhiteToWhite, &LUT_WW).await; //ww --
+                self.send_lut(Command::LutBlackToWhite, &LUT_BW).await; //bw r
+                self.send_lut(Command::LutWhiteToBlack, &LUT_BB).await; //wb w
+                self.send_lut(Command::LutBlackToBlack, &LUT_WB).await; //bb b
+            }
+            RefreshMode::Quick => {
+                self.send_lut(Command::LutForVcom, &LUT_VCOM0_QUICK).await;
+                self.send_lut(Command::LutWhiteToWhite, &LUT_WW_QUICK).await;
+                self.send_lut(Command::LutBlackToWhite, &LUT_BW_QUICK).await;
+                self.send_lut(Command::LutWhiteToBlack, &LUT_BB_QUICK).await;
+                self.send_lut(Command::LutBlackToBlack, &LUT_WB_QUICK).await;
+            }
         }
+        self.lut = Some(mode);
+    }
 
-        self.send_command(Command::LutWhiteToBlack).await; //wb w
-        for i in LUT_BB.iter() {
-            self.send_data(*i).await;
+    /// Pick the waveform for the next refresh, honouring the quick refresh limit.
+    fn next_refresh(&mut self, wanted: RefreshMode) -> RefreshMode {
+        if wanted == RefreshMode::Quick
+            && (self.quick_limit == 0 || self.quick_count < self.quick_limit)
+        {
+            self.quick_count += 1;
+            return RefreshMode::Quick;
         }
 
-        self.send_command(Command::LutBlackToBlack).await; //bb b
-        for i in LUT_WB.iter() {
-            self.send_data(*i).await;
-        }
+        self.quick_count = 0;
+        RefreshMode::Full
+    }
+
+    pub fn refresh_mode(&self) -> RefreshMode {
+        self.mode
+    }
+
+    pub fn set_refresh_mode(&mut self, mode: RefreshMode) {
+        self.mode = mode;
+        self.quick_count = 0;
+    }
+
+    /// Force a full refresh after `limit` quick ones, 0 never forces it.
+    pub fn set_quick_limit(&mut self, limit: u16) {
+        self.quick_limit = limit;
     }
 
     pub async fn init(&mut self) {
@@ -131,44 +218,609 @@ impl<'d> EPDMgr<'d> {
         self.send_command(Command::VcomAndDataIntervalSetting).await;
         self.send_data(0x97).await;
 
-        self.set_lut().await;
+        self.lut = None;
+        self.set_lut(self.mode).await;
     }
 
     pub async fn display_frame(&mut self) {
+        let mode = self.next_refresh(self.mode);
+        self.refresh_frame(mode).await;
+    }
+
+    /// Full refresh now whatever the mode, e.g. to clear ghosting at night.
+    pub async fn display_frame_full(&mut self) {
+        self.quick_count = 0;
+        self.refresh_frame(RefreshMode::Full).await;
+    }
+
+    async fn refresh_frame(&mut self, mode: RefreshMode) {
+        self.set_lut(mode).await;
+
         self.send_command(Command::DataStartTransmission1).await;
         for _ in 0..self.payload.len() {
             self.send_data(0xff).await;
         }
-        Timer::after(Duration::from_millis(2)).await;
+        self.delay.delay_ms(2).await;
 
         self.send_command(Command::DataStartTransmission2).await;
         for idx in 0..self.payload.len() {
             self.send_data(self.payload[idx]).await;
         }
-        Timer::after(Duration::from_millis(2)).await;
+        self.delay.delay_ms(2).await;
 
         self.send_command(Command::DisplayRefresh).await;
-        Timer::after(Duration::from_millis(100)).await;
+        self.delay.delay_ms(100).await;
 
         self.wait_idle().await;
     }
 
-    pub fn update_frame(&mut self, chunk: &[u8], offset: usize, size: usize) {
-        if offset + size > self.payload.len() {
-            println!("Frame overflow");
+    /// Push only the window (x, y, w, h) of the framebuffer and refresh it with
+    /// the quick LUT, the rest of the panel is left untouched.
+    /// When a full refresh is due the whole frame is refreshed instead.
+    ///
+    /// The controller addresses columns in bytes, so x is rounded down and
+    /// x + w rounded up to a multiple of 8.
+    pub async fn update_region(
+        &mut self,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> Result<(), RegionError> {
+        if w == 0 || h == 0 {
+            return Err(RegionError::Empty);
+        }
+        let fits = |start: usize, len: usize, max: usize| {
+            start.checked_add(len).is_some_and(|end| end <= max)
+        };
+        if !fits(x, w, EPD_WIDTH) || !fits(y, h, EPD_HEIGHT) {
+            return Err(RegionError::OutOfRange);
+        }
+
+        let x_start = x & !0x07;
+        let x_end = (x + w + 7) & !0x07;
+        let y_end = y + h;
+
+        if self.next_refresh(RefreshMode::Quick) == RefreshMode::Full {
+            self.refresh_frame(RefreshMode::Full).await;
+            return Ok(());
+        }
+
+        self.set_lut(RefreshMode::Quick).await;
+        // Keep the border floating, or it flashes with every partial refresh
+        self.send_command(Command::VcomAndDataIntervalSetting).await;
+        self.send_data(0xf7).await;
+
+        self.send_command(Command::PartialIn).await;
+        self.send_command(Command::PartialWindow).await;
+        self.send_data((x_start >> 8) as u8).await;
+        self.send_data((x_start & 0xf8) as u8).await;
+        self.send_data(((x_end - 1) >> 8) as u8).await;
+        self.send_data(((x_end - 1) & 0xf8) as u8 | 0x07).await;
+        self.send_data((y >> 8) as u8).await;
+        self.send_data((y & 0xff) as u8).await;
+        self.send_data(((y_end - 1) >> 8) as u8).await;
+        self.send_data(((y_end - 1) & 0xff) as u8).await;
+        // Gates scan both inside and outside of the window
+        self.send_data(0x01).await;
+
+        self.send_command(Command::DataStartTransmission2).await;
+        for row in y..y_end {
+            let line = row * EPD_WIDTH / 8;
+            for idx in (line + x_start / 8)..(line + x_end / 8) {
+                self.send_data(self.payload[idx]).await;
+            }
+        }
+        self.delay.delay_ms(2).await;
+
+        self.send_command(Command::DisplayRefresh).await;
+        self.delay.delay_ms(100).await;
+        self.wait_idle().await;
+
+        self.send_command(Command::PartialOut).await;
+        self.send_command(Command::VcomAndDataIntervalSetting).await;
+        self.send_data(0x97).await;
+
+        Ok(())
+    }
+
+    /// Copy a received chunk at `offset` in the framebuffer.
+    pub fn update_frame(&mut self, chunk: &[u8], offset: usize) -> Result<(), FrameError> {
+        let end = offset
+            .checked_add(chunk.len())
+            .ok_or(FrameError::OutOfRange)?;
+        if end > self.payload.len() {
+            return Err(FrameError::OutOfRange);
+        }
+
+        self.payload[offset..end].copy_from_slice(chunk);
+        Ok(())
+    }
+
+    pub async fn cmd(&mut self, pkg: ParserMgr) -> Result<&'static str, CmdError> {
+        match pkg.args.first().map(|a| a.as_str()) {
+            None | Some("refresh") => match pkg.args.get(1).map(|a| a.as_str()) {
+                None => {
+                    self.display_frame().await;
+                    Ok("Update")
+                }
+                Some("full") => {
+                    self.display_frame_full().await;
+                    Ok("Update")
+                }
+                _ => Err(WRONG_ARGS),
+            },
+            Some("mode") => match pkg.args.get(1).map(|a| a.as_str()) {
+                Some("full") => {
+                    self.set_refresh_mode(RefreshMode::Full);
+                    Ok("Full")
+                }
+                Some("quick") => {
+                    self.set_refresh_mode(RefreshMode::Quick);
+                    Ok("Quick")
+                }
+                None => match self.refresh_mode() {
+                    RefreshMode::Full => Ok("Full"),
+                    RefreshMode::Quick => Ok("Quick"),
+                },
+                _ => Err(WRONG_ARGS),
+            },
+            Some("limit") => match pkg.args.get(1).map(|a| a.parse::<u16>()) {
+                Some(Ok(n)) => {
+                    self.set_quick_limit(n);
+                    Ok("Limit")
+                }
+                _ => Err(WRONG_ARGS),
+            },
+            _ => Err(WRONG_ARGS),
+        }
+    }
+}
+
+/// The panel is shared with the frame task, commands take the lock.
+impl<M, SPI, BUSY, RST, DC, DELAY> CommandHandler for &Mutex<M, EPDMgr<SPI, BUSY, RST, DC, DELAY>>
+where
+    M: RawMutex,
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    RST: OutputPin,
+    DC: OutputPin,
+    DELAY: DelayNs,
+{
+    fn name(&self) -> &'static str {
+        "epd"
+    }
+
+    fn usage(&self) -> &'static str {
+        "epd [refresh [full]|mode [full|quick]|limit <n>]"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let state = self.lock().await.cmd(pkg).await?;
+        reply.push_str(state).map_err(|_| REPLY_TOO_LONG)
+    }
+}
+
+impl<SPI, BUSY, RST, DC, DELAY> Panel for EPDMgr<SPI, BUSY, RST, DC, DELAY>
+where
+    SPI: SpiDevice,
+    BUSY: InputPin + Wait,
+    RST: OutputPin,
+    DC: OutputPin,
+    DELAY: DelayNs,
+{
+    async fn refresh_region(&mut self, area: Rectangle) -> Result<(), &'static str> {
+        let area = area.intersection(&self.bounding_box());
+        self.update_region(
+            area.top_left.x as usize,
+            area.top_left.y as usize,
+            area.size.width as usize,
+            area.size.height as usize,
+        )
+        .await
+        .map_err(|e| e.as_str())
+    }
+}
+
+impl<SPI, BUSY, RST, DC, DELAY> EPDMgr<SPI, BUSY, RST, DC, DELAY> {
+    fn set_pixel(&mut self, point: Point, color: BinaryColor) {
+        if point.x < 0 || point.y < 0 {
             return;
         }
+        let (x, y) = (point.x as usize, point.y as usize);
+        if x >= EPD_WIDTH || y >= EPD_HEIGHT {
+            return;
+        }
+
+        let idx = (y * EPD_WIDTH + x) / 8;
+        let mask = 0x80 >> (x % 8);
+        match color {
+            // On is ink: black pixel, cleared bit
+            BinaryColor::On => self.payload[idx] &= !mask,
+            BinaryColor::Off => self.payload[idx] |= mask,
+        }
+    }
+}
+
+impl<SPI, BUSY, RST, DC, DELAY> OriginDimensions for EPDMgr<SPI, BUSY, RST, DC, DELAY> {
+    fn size(&self) -> Size {
+        Size::new(EPD_WIDTH as u32, EPD_HEIGHT as u32)
+    }
+}
+
+impl<SPI, BUSY, RST, DC, DELAY> DrawTarget for EPDMgr<SPI, BUSY, RST, DC, DELAY> {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            self.set_pixel(point, color);
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        let fill = match color {
+            BinaryColor::On => 0x00,
+            BinaryColor::Off => 0xff,
+        };
+        self.payload.fill(fill);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Event};
+    use embassy_futures::block_on;
+
+    fn cmd(command: u8, data: &[u8]) -> Vec<Event> {
+        let mut bytes = vec![Event::Command(command)];
+        bytes.extend(data.iter().map(|&b| Event::Data(b)));
+        bytes
+    }
+
+    fn luts(vcom: &[u8], ww: &[u8], bw: &[u8], bb: &[u8], wb: &[u8]) -> Vec<Event> {
+        [
+            cmd(0x20, vcom),
+            cmd(0x21, ww),
+            cmd(0x22, bw),
+            cmd(0x23, bb),
+            cmd(0x24, wb),
+        ]
+        .concat()
+    }
+
+    fn full_luts() -> Vec<Event> {
+        luts(&LUT_VCOM0, &LUT_WW, &LUT_BW, &LUT_BB, &LUT_WB)
+    }
+
+    fn quick_luts() -> Vec<Event> {
+        luts(
+            &LUT_VCOM0_QUICK,
+            &LUT_WW_QUICK,
+            &LUT_BW_QUICK,
+            &LUT_BB_QUICK,
+            &LUT_WB_QUICK,
+        )
+    }
+
+    /// Unpack the framebuffer, a cleared bit is an inked pixel.
+    fn unpack(buffer: &[u8]) -> mock::Canvas {
+        let mut canvas = mock::Canvas::new(EPD_WIDTH as u32, EPD_HEIGHT as u32);
+        let pixels = (0..EPD_HEIGHT)
+            .flat_map(|y| (0..EPD_WIDTH).map(move |x| (x, y)))
+            .filter(|&(x, y)| buffer[(y * EPD_WIDTH + x) / 8] & (0x80 >> (x % 8)) == 0)
+            .map(|(x, y)| Pixel(Point::new(x as i32, y as i32), BinaryColor::On));
+        canvas.draw_iter(pixels).unwrap();
+        canvas
+    }
+
+    fn scene<D: DrawTarget<Color = BinaryColor>>(target: &mut D) -> Result<(), D::Error> {
+        use embedded_graphics::{
+            mono_font::{ascii::FONT_6X10, MonoTextStyle},
+            primitives::{Circle, Line, PrimitiveStyle},
+            text::Text,
+        };
+
+        let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        let fill = PrimitiveStyle::with_fill(BinaryColor::On);
+        // Both corners, partly off the panel on every side
+        Rectangle::new(Point::new(-4, -4), Size::new(12, 6))
+            .into_styled(fill)
+            .draw(target)?;
+        Rectangle::new(Point::new(392, 296), Size::new(16, 8))
+            .into_styled(fill)
+            .draw(target)?;
+        Line::new(Point::new(20, 20), Point::new(120, 60))
+            .into_styled(stroke)
+            .draw(target)?;
+        Circle::new(Point::new(150, 100), 41)
+            .into_styled(stroke)
+            .draw(target)?;
+        Text::new(
+            "dbhome 4.2\"",
+            Point::new(13, 200),
+            MonoTextStyle::new(&FONT_6X10, BinaryColor::On),
+        )
+        .draw(target)?;
+        Ok(())
+    }
+
+    #[test]
+    fn draw_packs_pixels() {
+        let (mut epd, _) = mock::epd();
+        scene(&mut epd).unwrap();
+        unpack(epd.buffer()).assert_golden("epd_scene");
+
+        // Same pixels as a plain canvas, whatever the byte alignment
+        let mut canvas = mock::Canvas::new(EPD_WIDTH as u32, EPD_HEIGHT as u32);
+        scene(&mut canvas).unwrap();
+        let packed = unpack(epd.buffer());
+        for y in 0..EPD_HEIGHT as u32 {
+            for x in 0..EPD_WIDTH as u32 {
+                assert_eq!(packed.pixel(x, y), canvas.pixel(x, y), "({x}, {y})");
+            }
+        }
+
+        // MSB first: columns 0..8 of the first two rows are inked
+        assert_eq!(epd.buffer()[0], 0x00);
+        assert_eq!(epd.buffer()[50], 0x00);
+        assert_eq!(epd.buffer()[100], 0xff);
+        // Clipped at 400 x 300, the last byte holds columns 392..400
+        let last = EPD_WIDTH * EPD_HEIGHT / 8 - 1;
+        assert_eq!(epd.buffer()[last], 0x00);
+        assert_eq!(epd.buffer()[last - 50], 0x00);
+        assert_eq!(epd.buffer()[last - 4 * 50], 0xff);
+
+        let (mut epd, _) = mock::epd();
+        for x in [-1, 0, 7, 9, 400] {
+            Pixel(Point::new(x, 0), BinaryColor::On)
+                .draw(&mut epd)
+                .unwrap();
+        }
+        Pixel(Point::new(3, -1), BinaryColor::On)
+            .draw(&mut epd)
+            .unwrap();
+        Pixel(Point::new(3, 300), BinaryColor::On)
+            .draw(&mut epd)
+            .unwrap();
+        // Columns 0 and 7, then 9, off-panel points are dropped
+        assert_eq!(epd.buffer()[..2], [0x7e, 0xbf]);
+        assert!(epd.buffer()[2..].iter().all(|&b| b == 0xff));
+
+        Pixel(Point::new(7, 0), BinaryColor::Off)
+            .draw(&mut epd)
+            .unwrap();
+        assert_eq!(epd.buffer()[0], 0x7f);
+
+        epd.clear(BinaryColor::On).unwrap();
+        assert!(epd.buffer().iter().all(|&b| b == 0x00));
+        epd.clear(BinaryColor::Off).unwrap();
+        assert!(epd.buffer().iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn init_sequence() {
+        let (mut epd, rec) = mock::epd();
+        block_on(epd.init());
+
+        let events = rec.events();
+        assert_eq!(
+            events[..4],
+            [
+                Event::Reset(false),
+                Event::DelayNs(200_000_000),
+                Event::Reset(true),
+                Event::DelayNs(200_000_000),
+            ]
+        );
+        let status = events
+            .iter()
+            .position(|e| *e == Event::Command(0x71))
+            .unwrap();
+        assert_eq!(events[status + 1], Event::WaitIdle);
+
+        let expected = [
+            cmd(0x01, &[0x03, 0x00, 0x2b, 0x2b, 0xff]),
+            cmd(0x06, &[0x17, 0x17, 0x17]),
+            cmd(0x04, &[]),
+            cmd(0x71, &[]),
+            cmd(0x00, &[0xbf, 0x0b]),
+            cmd(0x30, &[0x3c]),
+            // 400 x 300
+            cmd(0x61, &[0x01, 0x90, 0x01, 0x2c]),
+            cmd(0x82, &[0x12]),
+            cmd(0x50, &[0x97]),
+            full_luts(),
+        ]
+        .concat();
+        assert_eq!(rec.bytes(), expected);
+    }
+
+    #[test]
+    fn display_frame_stream() {
+        let (mut epd, rec) = mock::epd();
+        block_on(epd.init());
+        Pixel(Point::new(0, 0), BinaryColor::On)
+            .draw(&mut epd)
+            .unwrap();
+        Pixel(Point::new(399, 299), BinaryColor::On)
+            .draw(&mut epd)
+            .unwrap();
+        rec.clear();
+
+        block_on(epd.display_frame());
+
+        let mut frame = vec![0xff; EPD_WIDTH * EPD_HEIGHT / 8];
+        frame[0] = 0x7f;
+        *frame.last_mut().unwrap() = 0xfe;
+        let expected = [
+            cmd(0x10, &[0xff; EPD_WIDTH * EPD_HEIGHT / 8]),
+            cmd(0x13, &frame),
+            cmd(0x12, &[]),
+            cmd(0x71, &[]),
+        ]
+        .concat();
+        assert_eq!(rec.bytes(), expected);
+        assert_eq!(rec.events().last(), Some(&Event::WaitIdle));
+    }
+
+    #[test]
+    fn quick_refresh_forces_full() {
+        let (mut epd, rec) = mock::epd();
+        epd.set_refresh_mode(RefreshMode::Quick);
+        epd.set_quick_limit(2);
+        block_on(epd.init());
+        assert!(rec.bytes().ends_with(&quick_luts()));
+
+        let refresh = [
+            cmd(0x10, &[0xff; EPD_WIDTH * EPD_HEIGHT / 8]),
+            cmd(0x13, &[0xff; EPD_WIDTH * EPD_HEIGHT / 8]),
+            cmd(0x12, &[]),
+            cmd(0x71, &[]),
+        ]
+        .concat();
+        // Quick LUT already loaded, then the limit forces a full one
+        for luts in [vec![], vec![], full_luts(), quick_luts()] {
+            rec.clear();
+            block_on(epd.display_frame());
+            assert_eq!(rec.bytes(), [luts, refresh.clone()].concat());
+        }
+    }
+
+    #[test]
+    fn refresh_full_command() {
+        use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+        let (mut epd, rec) = mock::epd();
+        epd.set_refresh_mode(RefreshMode::Quick);
+        block_on(epd.init());
+        let epd = Mutex::<NoopRawMutex, _>::new(epd);
+        let run = |line: &str| {
+            let mut reply = Reply::new();
+            let pkg = ParserMgr::parse(line.as_bytes()).unwrap();
+            block_on((&epd).handle(pkg, &mut reply)).map(|()| reply)
+        };
+        let refresh = |luts: Vec<Event>| {
+            [
+                luts,
+                cmd(0x10, &[0xff; EPD_WIDTH * EPD_HEIGHT / 8]),
+                cmd(0x13, &[0xff; EPD_WIDTH * EPD_HEIGHT / 8]),
+                cmd(0x12, &[]),
+                cmd(0x71, &[]),
+            ]
+            .concat()
+        };
+
+        rec.clear();
+        assert_eq!(run("epd refresh full").unwrap(), "Update");
+        assert_eq!(rec.bytes(), refresh(full_luts()));
+
+        // The mode is left alone, the next refresh is quick again
+        rec.clear();
+        assert_eq!(run("epd refresh").unwrap(), "Update");
+        assert_eq!(rec.bytes(), refresh(quick_luts()));
+        assert_eq!(run("epd mode").unwrap(), "Quick");
+
+        assert_eq!(run("epd refresh fast"), Err(WRONG_ARGS));
+    }
+
+    #[test]
+    fn update_region_window() {
+        let (mut epd, rec) = mock::epd();
+        block_on(epd.init());
+        epd.buffer_mut()[5 * 50] = 0x12;
+        epd.buffer_mut()[6 * 50 + 1] = 0x34;
+        rec.clear();
+
+        // Columns 3..13 widen to the bytes 0..2
+        block_on(epd.update_region(3, 5, 10, 2)).unwrap();
+
+        let expected = [
+            quick_luts(),
+            cmd(0x50, &[0xf7]),
+            cmd(0x91, &[]),
+            cmd(
+                0x90,
+                &[0x00, 0x00, 0x00, 0x0f, 0x00, 0x05, 0x00, 0x06, 0x01],
+            ),
+            cmd(0x13, &[0x12, 0xff, 0xff, 0x34]),
+            cmd(0x12, &[]),
+            cmd(0x71, &[]),
+            cmd(0x92, &[]),
+            cmd(0x50, &[0x97]),
+        ]
+        .concat();
+        assert_eq!(rec.bytes(), expected);
+
+        assert!(block_on(epd.update_region(0, 0, 0, 1)).is_err());
+        assert!(block_on(epd.update_region(392, 0, 16, 1)).is_err());
+    }
+
+    #[test]
+    fn update_region_rejects() {
+        let (mut epd, rec) = mock::epd();
+        block_on(epd.init());
+        rec.clear();
+
+        for (x, y, w, h, err) in [
+            (0, 0, 0, 1, RegionError::Empty),
+            (0, 0, 1, 0, RegionError::Empty),
+            (399, 0, 2, 1, RegionError::OutOfRange),
+            (0, 299, 1, 2, RegionError::OutOfRange),
+            (400, 0, 1, 1, RegionError::OutOfRange),
+            // x + w wraps to 0 and y + h to 1 without the checked sums
+            (usize::MAX, 0, 1, 1, RegionError::OutOfRange),
+            (0, usize::MAX, 1, 2, RegionError::OutOfRange),
+            (1, 0, usize::MAX, 1, RegionError::OutOfRange),
+        ] {
+            assert_eq!(block_on(epd.update_region(x, y, w, h)), Err(err));
+        }
+        // Nothing reaches the panel
+        assert!(rec.events().is_empty());
+
+        // The last pixel is a valid window
+        block_on(epd.update_region(399, 299, 1, 1)).unwrap();
+        assert!(rec.bytes().starts_with(&quick_luts()));
+    }
 
-        let free_space = self.payload.len() - offset;
-        let max_bytes = core::cmp::min(free_space, size as usize);
+    #[test]
+    fn refresh_region_clips() {
+        let (mut epd, rec) = mock::epd();
+        block_on(epd.init());
+        rec.clear();
 
-        println!("{} {} {} {}", offset, free_space, max_bytes, size);
+        // Clipped to the bottom right corner, columns 392..400
+        let area = Rectangle::new(Point::new(395, 298), Size::new(20, 20));
+        block_on(epd.refresh_region(area)).unwrap();
+        let window = cmd(
+            0x90,
+            &[0x01, 0x88, 0x01, 0x8f, 0x01, 0x2a, 0x01, 0x2b, 0x01],
+        );
+        let bytes = rec.bytes();
+        assert!(bytes.windows(window.len()).any(|w| w == window));
 
-        self.payload[offset..(offset + max_bytes)].copy_from_slice(&chunk[..max_bytes]);
+        let outside = Rectangle::new(Point::new(-30, -30), Size::new(10, 10));
+        assert_eq!(block_on(epd.refresh_region(outside)), Err("empty region"));
     }
 
-    pub async fn cmd(&mut self, _pkg: ParserMgr) -> Result<&'static str, &'static str> {
-        self.display_frame().await;
-        Ok("Update")
+    #[test]
+    fn update_frame_bounds() {
+        let (mut epd, _) = mock::epd();
+        epd.update_frame(&[1, 2], 14998).unwrap();
+        assert_eq!(epd.buffer()[14998..], [1, 2]);
+        assert_eq!(
+            epd.update_frame(&[1, 2], 14999),
+            Err(FrameError::OutOfRange)
+        );
+        assert_eq!(
+            epd.update_frame(&[1], usize::MAX),
+            Err(FrameError::OutOfRange)
+        );
     }
 }
@@ -15,7 +15,6 @@ pub(crate) const LUT_VCOM0: [u8; 44] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-#[allow(dead_code)]
 #[rustfmt::skip]
 pub(crate) const LUT_VCOM0_QUICK: [u8; 44] = [
     0x00, 0x0E, 0x00, 0x00, 0x00, 0x01,
@@ -38,7 +37,6 @@ pub(crate) const LUT_WW: [u8; 42] =[
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-#[allow(dead_code)]
 #[rustfmt::skip]
 pub(crate) const LUT_WW_QUICK: [u8; 42] =[
     0xA0, 0x0E, 0x00, 0x00, 0x00, 0x01,
@@ -61,7 +59,6 @@ pub(crate) const LUT_BW: [u8; 42] =[
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-#[allow(dead_code)]
 #[rustfmt::skip]
 pub(crate) const LUT_BW_QUICK: [u8; 42] =[
     0xA0, 0x0E, 0x00, 0x00, 0x00, 0x01,
@@ -84,7 +81,6 @@ pub(crate) const LUT_BB: [u8; 42] =[
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-#[allow(dead_code)]
 #[rustfmt::skip]
 pub(crate) const LUT_BB_QUICK: [u8; 42] =[
     0x50, 0x0E, 0x00, 0x00, 0x00, 0x01,
@@ -107,7 +103,6 @@ pub(crate) const LUT_WB: [u8; 42] =[
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-#[allow(dead_code)]
 #[rustfmt::skip]
 pub(crate) const LUT_WB_QUICK: [u8; 42] =[
     0x50, 0x0E, 0x00, 0x00, 0x00, 0x01,
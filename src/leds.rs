@@ -1,52 +1,61 @@
 use core::result::Result;
-use esp_hal::gpio::{GpioPin, Level, Output};
+use embedded_hal::digital::OutputPin;
 
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
 use crate::proto_parser::ParserMgr;
 
-pub struct LedsMgr<'d> {
-    red: Output<'d>,
-    green: Output<'d>,
-    blue: Output<'d>,
+pub struct LedsMgr<P> {
+    red: P,
+    green: P,
+    blue: P,
 }
 
-impl<'d> LedsMgr<'d> {
-    pub fn new(red: GpioPin<3>, green: GpioPin<4>, blue: GpioPin<5>) -> Self {
-        Self {
-            red: Output::new(red, Level::Low).into(),
-            green: Output::new(green, Level::Low).into(),
-            blue: Output::new(blue, Level::Low).into(),
-        }
+impl<P: OutputPin> LedsMgr<P> {
+    /// Pins are expected to be already configured as outputs.
+    pub fn new(red: P, green: P, blue: P) -> Self {
+        Self { red, green, blue }
     }
 
-    pub fn get_led(&mut self, label: &str) -> Result<&mut Output<'d>, &'static str> {
-        let ret = match label {
+    pub fn get_led(&mut self, label: &str) -> Result<&mut P, CmdError> {
+        match label {
             "red" => Ok(&mut self.red),
             "green" => Ok(&mut self.green),
             "blue" => Ok(&mut self.blue),
-            _ => Err("ivalid label"),
+            _ => Err(CmdError::BadArgs("ivalid label")),
+        }
+    }
+
+    pub fn cmd(&mut self, pkg: ParserMgr) -> Result<&'static str, CmdError> {
+        let [label, state] = pkg.args.as_slice() else {
+            return Err(CmdError::BadArgs("invalid args number"));
         };
 
-        ret
+        let o = self.get_led(label.as_str())?;
+        match state.as_str() {
+            "on" => {
+                o.set_high().map_err(|_| "Pin error")?;
+                Ok("On")
+            }
+            "off" => {
+                o.set_low().map_err(|_| "Pin error")?;
+                Ok("Off")
+            }
+            _ => Err(WRONG_ARGS),
+        }
     }
+}
 
-    pub fn cmd(&mut self, pkg: ParserMgr) -> Result<&'static str, &'static str> {
-        if pkg.args.len() < 1 {
-            return Err("invalid args number");
-        }
+impl<P: OutputPin> CommandHandler for LedsMgr<P> {
+    fn name(&self) -> &'static str {
+        "led"
+    }
 
-        match self.get_led(pkg.args[0].as_str()) {
-            Ok(o) => match pkg.args[1].as_str() {
-                "on" => {
-                    o.set_high();
-                    Ok("On")
-                }
-                "off" => {
-                    o.set_low();
-                    Ok("Off")
-                }
-                _ => Err("Wrong args"),
-            },
-            Err(e) => Err(e),
-        }
+    fn usage(&self) -> &'static str {
+        "led <red|green|blue> <on|off>"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let state = self.cmd(pkg)?;
+        reply.push_str(state).map_err(|_| REPLY_TOO_LONG)
     }
 }
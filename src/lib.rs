@@ -1,7 +1,24 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+pub mod chart;
+pub mod clock;
+pub mod config;
+pub mod dashboard;
+pub mod dispatcher;
 pub mod leds;
+pub mod line_framer;
 pub mod proto_parser;
+pub mod provision;
+pub mod qr;
+pub mod schedule;
+pub mod sessions;
+pub mod system;
+pub mod text;
+#[cfg(target_os = "none")]
+pub mod wifi;
 
 pub mod epd4in2;
 mod epd4in2_cmd;
 mod epd4in2_const;
+
+#[cfg(test)]
+mod mock;
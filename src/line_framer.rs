@@ -0,0 +1,181 @@
+//! Splits the control session byte stream in command lines.
+//!
+//! TCP gives no message boundaries: a read may hold half a command or
+//! several of them, so bytes are kept until a newline shows up.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineError {
+    /// The line did not fit the buffer, it was dropped up to its newline.
+    TooLong,
+}
+
+impl LineError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineError::TooLong => "line too long",
+        }
+    }
+}
+
+/// Accumulates reads in `N` bytes, so a line holds at most `N - 1` bytes
+/// whether it ends with `\n` or `\r\n`.
+pub struct LineFramer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    pos: usize,
+    discarding: bool,
+    /// The `\r` ending the buffer was dropped to make room for the `\n`.
+    cr: bool,
+}
+
+impl<const N: usize> Default for LineFramer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LineFramer<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            pos: 0,
+            discarding: false,
+            cr: false,
+        }
+    }
+
+    /// Free room to read into, call [`Self::filled`] with what was read.
+    ///
+    /// Lines still pending are moved to the front; a buffer full without
+    /// a newline is dropped and its line reported as too long.
+    pub fn spare(&mut self) -> &mut [u8] {
+        self.buf.copy_within(self.pos..self.len, 0);
+        self.len -= self.pos;
+        self.pos = 0;
+
+        if self.len == N {
+            if self.buf[N - 1] == b'\r' && !self.cr {
+                self.len = N - 1;
+                self.cr = true;
+            } else {
+                self.len = 0;
+                self.discarding = true;
+                self.cr = false;
+            }
+        }
+        &mut self.buf[self.len..]
+    }
+
+    pub fn filled(&mut self, n: usize) {
+        self.len = core::cmp::min(self.len + n, N);
+    }
+
+    /// Next complete line without its `\n` or `\r\n`, in arrival order.
+    pub fn next_line(&mut self) -> Option<Result<&[u8], LineError>> {
+        let end = self.buf[self.pos..self.len]
+            .iter()
+            .position(|b| *b == b'\n')?;
+        let start = self.pos;
+        self.pos += end + 1;
+        self.cr = false;
+
+        if core::mem::take(&mut self.discarding) {
+            return Some(Err(LineError::TooLong));
+        }
+        let line = &self.buf[start..start + end];
+        Some(Ok(line.strip_suffix(b"\r").unwrap_or(line)))
+    }
+
+    /// Forget any partial line, for a new session.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.pos = 0;
+        self.discarding = false;
+        self.cr = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `data` as one read, returning the lines it completes.
+    fn feed<const N: usize>(
+        framer: &mut LineFramer<N>,
+        mut data: &[u8],
+    ) -> Vec<Result<std::string::String, LineError>> {
+        let mut lines = Vec::new();
+        while !data.is_empty() {
+            let spare = framer.spare();
+            let n = spare.len().min(data.len());
+            spare[..n].copy_from_slice(&data[..n]);
+            framer.filled(n);
+            data = &data[n..];
+            while let Some(line) = framer.next_line() {
+                lines.push(line.map(|l| std::string::String::from_utf8(l.to_vec()).unwrap()));
+            }
+        }
+        lines
+    }
+
+    fn ok(lines: &[&str]) -> Vec<Result<std::string::String, LineError>> {
+        lines.iter().map(|l| Ok(l.to_string())).collect()
+    }
+
+    #[test]
+    fn split_reads() {
+        let mut framer = LineFramer::<16>::new();
+        assert_eq!(feed(&mut framer, b"led r"), ok(&[]));
+        assert_eq!(feed(&mut framer, b"ed o"), ok(&[]));
+        assert_eq!(feed(&mut framer, b"n\nsys"), ok(&["led red on"]));
+        assert_eq!(feed(&mut framer, b"tem\r"), ok(&[]));
+        assert_eq!(feed(&mut framer, b"\n"), ok(&["system"]));
+    }
+
+    #[test]
+    fn lines_in_one_read() {
+        let mut framer = LineFramer::<32>::new();
+        assert_eq!(
+            feed(&mut framer, b"a\r\nbb\n\nccc\r\ndd"),
+            ok(&["a", "bb", "", "ccc"])
+        );
+        assert_eq!(feed(&mut framer, b"\r\n"), ok(&["dd"]));
+        // Only the final \r goes
+        assert_eq!(feed(&mut framer, b"\re\r\r\n"), ok(&["\re\r"]));
+    }
+
+    #[test]
+    fn too_long() {
+        let mut framer = LineFramer::<8>::new();
+        // 7 bytes is the longest line, with either ending
+        assert_eq!(feed(&mut framer, b"1234567\n"), ok(&["1234567"]));
+        assert_eq!(feed(&mut framer, b"1234567\r\n"), ok(&["1234567"]));
+        assert_eq!(feed(&mut framer, b"123456\r\n"), ok(&["123456"]));
+
+        // Reported once however many reads it spans, then framing resumes
+        assert_eq!(
+            feed(&mut framer, b"12345678\nok\n"),
+            [Err(LineError::TooLong), Ok("ok".into())]
+        );
+        assert_eq!(feed(&mut framer, b"0123456789abcdef"), []);
+        assert_eq!(feed(&mut framer, b"0123456789abcdef\r"), []);
+        assert_eq!(
+            feed(&mut framer, b"\r\nnext\r\n"),
+            [Err(LineError::TooLong), Ok("next".into())]
+        );
+        // A \r at the limit not followed by \n is part of a longer line
+        assert_eq!(
+            feed(&mut framer, b"1234567\rx\nok\n"),
+            [Err(LineError::TooLong), Ok("ok".into())]
+        );
+    }
+
+    #[test]
+    fn reset_drops_partial() {
+        let mut framer = LineFramer::<8>::new();
+        feed(&mut framer, b"1234567\r");
+        framer.reset();
+        assert_eq!(feed(&mut framer, b"\nab\n"), ok(&["", "ab"]));
+    }
+}
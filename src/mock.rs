@@ -0,0 +1,320 @@
+//! Recording bus, pins and delay standing in for the panel in host tests,
+//! and a canvas compared with golden images.
+use core::convert::Infallible;
+use std::{cell::RefCell, fs, path::PathBuf, rc::Rc, vec, vec::Vec};
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
+use embedded_hal::digital::{self, InputPin, OutputPin};
+use embedded_hal_async::{
+    delay::DelayNs,
+    digital::Wait,
+    spi::{self, Operation, SpiDevice},
+};
+
+use crate::dashboard::Panel;
+use crate::epd4in2::EPDMgr;
+
+/// What the panel went through, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Byte clocked in with DC low.
+    Command(u8),
+    /// Byte clocked in with DC high.
+    Data(u8),
+    /// Level driven on RST.
+    Reset(bool),
+    /// Wait for BUSY to go high.
+    WaitIdle,
+    DelayNs(u32),
+}
+
+#[derive(Default)]
+struct State {
+    dc: bool,
+    events: Vec<Event>,
+}
+
+/// Shared log of the bus, pins and delay of one mock panel.
+#[derive(Clone, Default)]
+pub struct Recorder(Rc<RefCell<State>>);
+
+impl Recorder {
+    fn push(&self, event: Event) {
+        self.0.borrow_mut().events.push(event);
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.0.borrow().events.clone()
+    }
+
+    /// Commands and data only, as the controller sees them.
+    pub fn bytes(&self) -> Vec<Event> {
+        self.0
+            .borrow()
+            .events
+            .iter()
+            .filter(|e| matches!(e, Event::Command(_) | Event::Data(_)))
+            .copied()
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().events.clear();
+    }
+}
+
+pub struct Spi(Recorder);
+
+impl spi::ErrorType for Spi {
+    type Error = Infallible;
+}
+
+impl SpiDevice for Spi {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Infallible> {
+        for op in operations.iter_mut() {
+            let written: &[u8] = match op {
+                Operation::Write(bytes) => bytes,
+                Operation::Transfer(read, write) => {
+                    read.fill(0);
+                    write
+                }
+                Operation::TransferInPlace(bytes) => bytes,
+                Operation::Read(bytes) => {
+                    bytes.fill(0);
+                    &[]
+                }
+                Operation::DelayNs(_) => &[],
+            };
+            let dc = self.0 .0.borrow().dc;
+            for &b in written {
+                self.0.push(if dc {
+                    Event::Data(b)
+                } else {
+                    Event::Command(b)
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Role {
+    Busy,
+    Rst,
+    Dc,
+}
+
+pub struct Pin(Recorder, Role);
+
+impl digital::ErrorType for Pin {
+    type Error = Infallible;
+}
+
+impl OutputPin for Pin {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.set(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.set(true);
+        Ok(())
+    }
+}
+
+impl Pin {
+    fn set(&mut self, high: bool) {
+        match self.1 {
+            Role::Dc => self.0 .0.borrow_mut().dc = high,
+            Role::Rst => self.0.push(Event::Reset(high)),
+            Role::Busy => {}
+        }
+    }
+}
+
+/// The panel is always idle.
+impl InputPin for Pin {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        Ok(true)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        Ok(false)
+    }
+}
+
+impl Wait for Pin {
+    async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+        self.0.push(Event::WaitIdle);
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+pub struct Delay(Recorder);
+
+impl DelayNs for Delay {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.0.push(Event::DelayNs(ns));
+    }
+}
+
+pub type MockEpd = EPDMgr<Spi, Pin, Pin, Pin, Delay>;
+
+/// A driver on the mock bus, with the log of what it sends.
+pub fn epd() -> (MockEpd, Recorder) {
+    let rec = Recorder::default();
+    let epd = EPDMgr::new(
+        Spi(rec.clone()),
+        Pin(rec.clone(), Role::Busy),
+        Pin(rec.clone(), Role::Rst),
+        Pin(rec.clone(), Role::Dc),
+        Delay(rec.clone()),
+    );
+    (epd, rec)
+}
+
+/// Drawing target of any size, black where pixels are on as on the panel.
+pub struct Canvas {
+    size: Size,
+    pixels: Vec<bool>,
+    /// Areas refreshed as a [`Panel`], in order.
+    pub refreshed: Vec<Rectangle>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            size: Size::new(width, height),
+            pixels: vec![false; (width * height) as usize],
+            refreshed: Vec::new(),
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        self.pixels[(y * self.size.width + x) as usize]
+    }
+
+    /// Smallest area holding every pixel that is on.
+    pub fn ink(&self) -> Option<Rectangle> {
+        let on = (0..self.size.height)
+            .flat_map(|y| (0..self.size.width).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.pixel(x, y))
+            .map(|(x, y)| Point::new(x as i32, y as i32));
+        let mut corners: Option<(Point, Point)> = None;
+        for p in on {
+            corners = Some(match corners {
+                None => (p, p),
+                Some((min, max)) => (min.component_min(p), max.component_max(p)),
+            });
+        }
+        corners.map(|(min, max)| Rectangle::with_corners(min, max))
+    }
+
+    /// 8 bit grey PNG of the canvas.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.size.width, self.size.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let grey: Vec<u8> = self
+            .pixels
+            .iter()
+            .map(|on| if *on { 0 } else { 255 })
+            .collect();
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&grey).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    fn from_png(data: &[u8]) -> Self {
+        let mut reader = png::Decoder::new(std::io::Cursor::new(data))
+            .read_info()
+            .unwrap();
+        let mut grey = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut grey).unwrap();
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        let mut canvas = Self::new(info.width, info.height);
+        for (pixel, grey) in canvas.pixels.iter_mut().zip(grey) {
+            *pixel = grey < 128;
+        }
+        canvas
+    }
+
+    /// Compare with `tests/golden/<name>.png`.
+    ///
+    /// With `UPDATE_GOLDEN` set the golden image is written instead. On a
+    /// mismatch the render is saved in the temporary directory to look at.
+    pub fn assert_golden(&self, name: &str) {
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", name]
+            .iter()
+            .collect::<PathBuf>()
+            .with_extension("png");
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, self.to_png()).unwrap();
+            return;
+        }
+
+        let golden = fs::read(&path)
+            .map(|data| Self::from_png(&data))
+            .unwrap_or_else(|e| panic!("{}: {}, run with UPDATE_GOLDEN=1", path.display(), e));
+        if golden.size != self.size || golden.pixels != self.pixels {
+            let actual = std::env::temp_dir().join(path.file_name().unwrap());
+            fs::write(&actual, self.to_png()).unwrap();
+            panic!("{} differs from {}", actual.display(), path.display());
+        }
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        for Pixel(p, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u32::try_from(p.x), u32::try_from(p.y)) {
+                if x < self.size.width && y < self.size.height {
+                    self.pixels[(y * self.size.width + x) as usize] = color.is_on();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Panel for Canvas {
+    async fn refresh_region(&mut self, area: Rectangle) -> Result<(), &'static str> {
+        self.refreshed.push(area);
+        Ok(())
+    }
+}
@@ -1,37 +1,178 @@
-use core::str::FromStr;
-
 use heapless::{String, Vec};
 
+/// Longest command line, newline included, not counting a `\r` before it.
+pub const LINE_LEN: usize = 128;
+pub const CMD_LEN: usize = 32;
+pub const ARG_LEN: usize = 64;
+pub const MAX_ARGS: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    TokenTooLong,
+    TooManyArgs,
+    InvalidUtf8,
+    UnterminatedQuote,
+}
+
+impl ParseError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParseError::Empty => "empty command",
+            ParseError::TokenTooLong => "token too long",
+            ParseError::TooManyArgs => "too many args",
+            ParseError::InvalidUtf8 => "invalid utf-8",
+            ParseError::UnterminatedQuote => "unterminated quote",
+        }
+    }
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 pub struct ParserMgr {
-    pub cmd: String<32>,
-    pub args: Vec<String<16>, 10>,
+    pub cmd: String<CMD_LEN>,
+    pub args: Vec<String<ARG_LEN>, MAX_ARGS>,
 }
 
 impl ParserMgr {
-    pub fn new(msg: String<128>) -> Self {
-        let mut tokens = msg.split_whitespace();
+    /// Split a command line in words.
+    ///
+    /// Words are separated by whitespace, a double quoted word may hold
+    /// spaces and `\"` or `\\` escapes.
+    pub fn parse(msg: &[u8]) -> Result<Self, ParseError> {
+        let msg = core::str::from_utf8(msg).map_err(|_| ParseError::InvalidUtf8)?;
+        let mut chars = msg.chars().peekable();
 
-        let mut cmd: String<32> = String::new();
-        if let Some(t) = tokens.next() {
-            cmd = String::try_from(t).unwrap();
-        }
+        let mut cmd: Option<String<CMD_LEN>> = None;
+        let mut args: Vec<String<ARG_LEN>, MAX_ARGS> = Vec::new();
+
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                break;
+            }
 
-        let mut args: Vec<String<16>, 10> = Vec::new();
-        for token in tokens {
-            args.push(String::try_from(token).unwrap()).unwrap();
+            let mut token: String<ARG_LEN> = String::new();
+            if chars.next_if_eq(&'"').is_some() {
+                loop {
+                    let c = match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => chars.next().ok_or(ParseError::UnterminatedQuote)?,
+                        Some(c) => c,
+                        None => return Err(ParseError::UnterminatedQuote),
+                    };
+                    token.push(c).map_err(|_| ParseError::TokenTooLong)?;
+                }
+            } else {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    token.push(c).map_err(|_| ParseError::TokenTooLong)?;
+                }
+            }
+
+            if cmd.is_none() {
+                cmd = Some(String::try_from(token.as_str()).map_err(|_| ParseError::TokenTooLong)?);
+            } else {
+                args.push(token).map_err(|_| ParseError::TooManyArgs)?;
+            }
         }
-        Self { cmd, args }
+
+        let cmd = cmd.ok_or(ParseError::Empty)?;
+        Ok(Self { cmd, args })
     }
 }
 
-pub fn reply_ok(payload: &str) -> String<64> {
-    let mut ret = String::from_str(payload).unwrap();
-    ret.push_str("\nOK\n").unwrap();
-    ret
-}
+#[cfg(test)]
+mod tests {
+    use std::{format, string::ToString, vec, vec::Vec};
+
+    use proptest::prelude::*;
 
-pub fn reply_err(payload: &str) -> String<64> {
-    let mut ret = String::from_str(payload).unwrap();
-    ret.push_str("\nErr\n").unwrap();
-    ret
+    use super::*;
+
+    fn words(line: &str) -> Result<Vec<std::string::String>, ParseError> {
+        let pkg = ParserMgr::parse(line.as_bytes())?;
+        let mut words = vec![pkg.cmd.to_string()];
+        words.extend(pkg.args.iter().map(|a| a.to_string()));
+        Ok(words)
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(words("led red on\r\n").unwrap(), ["led", "red", "on"]);
+        assert_eq!(
+            words("text 1 2 \"hello world\" x").unwrap(),
+            ["text", "1", "2", "hello world", "x"]
+        );
+        assert_eq!(words(r#"t "a\"b\\c" """#).unwrap(), ["t", r#"a"b\c"#, ""]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(words(""), Err(ParseError::Empty));
+        assert_eq!(words(" \t\r\n"), Err(ParseError::Empty));
+        assert_eq!(words("t \"abc"), Err(ParseError::UnterminatedQuote));
+        assert_eq!(words("t \"abc\\"), Err(ParseError::UnterminatedQuote));
+        assert_eq!(
+            words(&"a".repeat(CMD_LEN + 1)),
+            Err(ParseError::TokenTooLong)
+        );
+        assert_eq!(
+            words(&format!("a {}", "b".repeat(ARG_LEN + 1))),
+            Err(ParseError::TokenTooLong)
+        );
+        assert!(words("a 1 2 3 4 5 6 7 8 9 10").is_ok());
+        assert_eq!(
+            words("a 1 2 3 4 5 6 7 8 9 10 11"),
+            Err(ParseError::TooManyArgs)
+        );
+        assert_eq!(
+            ParserMgr::parse(&[b'a', b' ', 0xff]).err(),
+            Some(ParseError::InvalidUtf8)
+        );
+    }
+
+    /// Quote a word the way the parser reads it back.
+    fn quote(word: &str) -> std::string::String {
+        let mut quoted = std::string::String::from("\"");
+        for c in word.chars() {
+            if c == '"' || c == '\\' {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics(line in prop::collection::vec(any::<u8>(), 0..LINE_LEN)) {
+            let _ = ParserMgr::parse(&line);
+        }
+
+        /// Lines made of the characters the parser treats specially.
+        #[test]
+        fn never_panics_on_syntax(line in "[ a\"\\\\\t\r\n]{0,128}") {
+            let _ = ParserMgr::parse(line.as_bytes());
+        }
+
+        #[test]
+        fn quoted_words_roundtrip(
+            cmd in "[a-z]{1,32}",
+            args in prop::collection::vec("[ -~é]{0,20}", 0..=MAX_ARGS),
+        ) {
+            let mut line = cmd.clone();
+            for arg in &args {
+                line.push(' ');
+                line.push_str(&quote(arg));
+            }
+            let mut expected = vec![cmd];
+            expected.extend(args);
+            prop_assert_eq!(words(&line), Ok(expected));
+        }
+    }
 }
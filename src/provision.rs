@@ -0,0 +1,281 @@
+//! BLE Wi-Fi provisioning, between the GATT server and the connection task.
+//!
+//! The GATT callbacks and the `wifi` command post requests, the connection
+//! task serves them and publishes its state and scan results. Status
+//! changes are also signalled, for the GATT server to notify them.
+use core::cell::RefCell;
+
+use dbhome_common::provision::{
+    self, Command, Credentials, Field, Network, State, PASSWORD_LEN, SCAN_LEN, SSID_LEN, STATUS_LEN,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use log::warn;
+
+/// Requests waiting for the connection task.
+const MAX_REQUESTS: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Connect(Credentials),
+    Scan,
+    /// Drop the network in use.
+    Forget,
+    /// The saved networks changed.
+    Reload,
+}
+
+struct Values {
+    ssid: Field<SSID_LEN>,
+    password: Field<PASSWORD_LEN>,
+    status: [u8; STATUS_LEN],
+    scan: [u8; SCAN_LEN],
+    scan_len: usize,
+}
+
+pub struct Provisioner {
+    values: Mutex<CriticalSectionRawMutex, RefCell<Values>>,
+    requests: Channel<CriticalSectionRawMutex, Request, MAX_REQUESTS>,
+    scanned: Signal<CriticalSectionRawMutex, ()>,
+    status_changed: Signal<CriticalSectionRawMutex, [u8; STATUS_LEN]>,
+    connected: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl Default for Provisioner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Provisioner {
+    pub const fn new() -> Self {
+        Self {
+            values: Mutex::new(RefCell::new(Values {
+                ssid: Field::new(),
+                password: Field::new(),
+                status: [State::Idle as u8, 0, 0, 0, 0],
+                scan: [0; SCAN_LEN],
+                scan_len: 0,
+            })),
+            requests: Channel::new(),
+            scanned: Signal::new(),
+            status_changed: Signal::new(),
+            connected: Signal::new(),
+        }
+    }
+
+    pub fn write_ssid(&self, offset: usize, data: &[u8]) {
+        let ret = self
+            .values
+            .lock(|v| v.borrow_mut().ssid.write(offset, data));
+        if let Err(e) = ret {
+            warn!("provisioning SSID: {}", e);
+        }
+    }
+
+    pub fn write_password(&self, offset: usize, data: &[u8]) {
+        let ret = self
+            .values
+            .lock(|v| v.borrow_mut().password.write(offset, data));
+        if let Err(e) = ret {
+            warn!("provisioning password: {}", e);
+        }
+    }
+
+    pub fn write_control(&self, data: &[u8]) {
+        let request = Command::decode(data).and_then(|command| match command {
+            Command::Connect => self.values.lock(|v| {
+                let v = v.borrow();
+                Credentials::new(v.ssid.as_str()?, v.password.as_str()?).map(Request::Connect)
+            }),
+            Command::Scan => Ok(Request::Scan),
+            Command::Forget => Ok(Request::Forget),
+        });
+        match request {
+            Ok(request) => self.post(request),
+            Err(e) => warn!("provisioning command: {}", e),
+        }
+    }
+
+    pub fn read_status(&self, offset: usize, out: &mut [u8]) -> usize {
+        self.values
+            .lock(|v| provision::read_at(&v.borrow().status, offset, out))
+    }
+
+    pub fn read_scan(&self, offset: usize, out: &mut [u8]) -> usize {
+        self.values.lock(|v| {
+            let v = v.borrow();
+            provision::read_at(&v.scan[..v.scan_len], offset, out)
+        })
+    }
+
+    /// Queue a request, dropped with a warning when too many are waiting.
+    pub fn post(&self, request: Request) {
+        // Not logged, it can hold a password
+        if self.requests.try_send(request).is_err() {
+            warn!("provisioning request dropped, too many waiting");
+        }
+    }
+
+    /// Request a scan and wait for its results, see [`Provisioner::read_scan`].
+    pub async fn scan(&self) {
+        self.scanned.reset();
+        self.post(Request::Scan);
+        self.scanned.wait().await
+    }
+
+    /// Wait for the next request, they are served in order.
+    pub async fn request(&self) -> Request {
+        self.requests.receive().await
+    }
+
+    pub fn set_status(&self, state: State, addr: Option<[u8; 4]>) {
+        let status = provision::encode_status(state, addr);
+        let changed = self
+            .values
+            .lock(|v| core::mem::replace(&mut v.borrow_mut().status, status) != status);
+        if changed {
+            self.status_changed.signal(status);
+        }
+        if state == State::Connected {
+            self.connected.signal(());
+        }
+    }
+
+    /// Wait for the status to change, returns the latest one.
+    pub async fn status_changed(&self) -> [u8; STATUS_LEN] {
+        self.status_changed.wait().await
+    }
+
+    /// Wait until a network is joined, including one joined before.
+    pub async fn connected(&self) {
+        self.connected.wait().await;
+        // Joined for good, later waits return at once
+        self.connected.signal(());
+    }
+
+    /// Replace the scan results, the ones that do not fit are left out.
+    pub fn set_networks<'a>(&self, networks: impl Iterator<Item = Network<'a>>) {
+        self.values.lock(|v| {
+            let mut v = v.borrow_mut();
+            let mut len = 0;
+            for network in networks {
+                let Ok(end) = provision::encode_network(&network, &mut v.scan, len) else {
+                    break;
+                };
+                len = end;
+            }
+            v.scan_len = len;
+        });
+        self.scanned.signal(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use dbhome_common::provision::MAX_NETWORKS;
+    use embassy_futures::{block_on, poll_once};
+
+    use super::*;
+
+    fn scan_value(provisioner: &Provisioner) -> Vec<u8> {
+        let mut value = Vec::new();
+        let mut out = [0; 22];
+        loop {
+            let n = provisioner.read_scan(value.len(), &mut out);
+            if n == 0 {
+                return value;
+            }
+            value.extend_from_slice(&out[..n]);
+        }
+    }
+
+    #[test]
+    fn requests() {
+        let provisioner = Provisioner::new();
+        provisioner.write_ssid(0, b"home");
+        provisioner.write_password(0, b"hunter22");
+        provisioner.write_password(8, b"22");
+        provisioner.write_control(&[1]);
+        assert_eq!(
+            block_on(provisioner.request()),
+            Request::Connect(Credentials::new("home", "hunter2222").unwrap())
+        );
+
+        // Too short for WPA2, nothing is posted
+        provisioner.write_password(0, b"x");
+        provisioner.write_control(&[1]);
+        provisioner.write_control(&[9]);
+        assert!(poll_once(provisioner.request()).is_pending());
+        provisioner.write_control(&[2]);
+        assert_eq!(block_on(provisioner.request()), Request::Scan);
+        provisioner.write_control(&[3]);
+        assert_eq!(block_on(provisioner.request()), Request::Forget);
+    }
+
+    #[test]
+    fn queued() {
+        let provisioner = Provisioner::new();
+        provisioner.post(Request::Reload);
+        // A scan does not replace the pending request
+        assert!(poll_once(provisioner.scan()).is_pending());
+        assert_eq!(block_on(provisioner.request()), Request::Reload);
+        assert_eq!(block_on(provisioner.request()), Request::Scan);
+
+        for _ in 0..MAX_REQUESTS + 2 {
+            provisioner.post(Request::Forget);
+        }
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(block_on(provisioner.request()), Request::Forget);
+        }
+        assert!(poll_once(provisioner.request()).is_pending());
+    }
+
+    #[test]
+    fn status() {
+        let provisioner = Provisioner::new();
+        let mut out = [0; 22];
+        assert_eq!(provisioner.read_status(0, &mut out), STATUS_LEN);
+        assert_eq!(out[0], State::Idle as u8);
+        assert!(poll_once(provisioner.status_changed()).is_pending());
+
+        provisioner.set_status(State::Connecting, None);
+        assert_eq!(block_on(provisioner.status_changed()), [2, 0, 0, 0, 0]);
+        // The same status again is not a change
+        provisioner.set_status(State::Connecting, None);
+        assert!(poll_once(provisioner.status_changed()).is_pending());
+        assert!(poll_once(provisioner.connected()).is_pending());
+
+        provisioner.set_status(State::Connected, Some([192, 168, 1, 9]));
+        assert_eq!(block_on(provisioner.status_changed()), [3, 192, 168, 1, 9]);
+        assert_eq!(provisioner.read_status(1, &mut out), 4);
+        assert_eq!(out[..4], [192, 168, 1, 9]);
+        block_on(provisioner.connected());
+        block_on(provisioner.connected());
+    }
+
+    #[test]
+    fn scan() {
+        let provisioner = Provisioner::new();
+        assert!(scan_value(&provisioner).is_empty());
+        let long = "a-rather-long-network-name-here!";
+        let found = (0..20).map(|i| Network {
+            ssid: if i % 2 == 0 { long } else { "b" },
+            rssi: -50 - i as i8,
+            secured: true,
+        });
+        provisioner.set_networks(found);
+
+        let value = scan_value(&provisioner);
+        assert!(value.len() <= SCAN_LEN);
+        let networks: Vec<_> = provision::networks(&value)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert!(networks.len() >= MAX_NETWORKS, "{}", networks.len());
+        assert_eq!(networks[0].ssid, long);
+        assert_eq!(networks[1].rssi, -51);
+    }
+}
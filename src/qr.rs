@@ -0,0 +1,388 @@
+//! QR codes drawn in the framebuffer, e.g. guest Wi-Fi credentials or a URL.
+use core::convert::Infallible;
+use core::fmt::Write as _;
+
+use dbhome_common::{portal, provision::SSID_LEN};
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embedded_graphics::{
+    mono_font::ascii::FONT_10X20, pixelcolor::BinaryColor, prelude::*, primitives::Rectangle,
+    text::Alignment,
+};
+use heapless::String;
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
+
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
+use crate::proto_parser::ParserMgr;
+use crate::text::draw_text;
+
+/// Largest symbol encoded, 57x57 modules, enough for any command argument.
+pub const QR_MAX_VERSION: Version = Version::new(10);
+/// Room for the encoder, both its work and output buffers need this much.
+pub const QR_BUFFER_LEN: usize = QR_MAX_VERSION.buffer_len();
+/// Blank modules around the symbol that scanners need to find it.
+const QUIET_ZONE: i32 = 4;
+
+pub fn ecc_by_name(name: &str) -> Option<QrCodeEcc> {
+    match name {
+        "L" | "l" => Some(QrCodeEcc::Low),
+        "M" | "m" => Some(QrCodeEcc::Medium),
+        "Q" | "q" => Some(QrCodeEcc::Quartile),
+        "H" | "h" => Some(QrCodeEcc::High),
+        _ => None,
+    }
+}
+
+/// Encode `payload` in the smallest symbol that holds it, at exactly `ecc`.
+pub fn encode<'a>(
+    payload: &str,
+    ecc: QrCodeEcc,
+    temp: &mut [u8; QR_BUFFER_LEN],
+    out: &'a mut [u8; QR_BUFFER_LEN],
+) -> Result<QrCode<'a>, &'static str> {
+    QrCode::encode_text(
+        payload,
+        temp,
+        out,
+        ecc,
+        Version::MIN,
+        QR_MAX_VERSION,
+        None,
+        false,
+    )
+    .map_err(|_| "Payload too long")
+}
+
+/// Side in pixels of `qr` with its quiet zone, `scale` pixels per module.
+pub fn side(qr: &QrCode, scale: u32) -> u32 {
+    (qr.size() + 2 * QUIET_ZONE) as u32 * scale
+}
+
+/// Draw `qr` and its quiet zone from `top_left`, every module a square of
+/// `scale` pixels so edges stay sharp.
+pub fn draw_qr<D>(target: &mut D, qr: &QrCode, top_left: Point, scale: u32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let side = side(qr, scale);
+    target.fill_solid(
+        &Rectangle::new(top_left, Size::new(side, side)),
+        BinaryColor::Off,
+    )?;
+
+    let module = Size::new(scale, scale);
+    let origin = top_left + Point::new(QUIET_ZONE, QUIET_ZONE) * scale as i32;
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                let at = origin + Point::new(x, y) * scale as i32;
+                target.fill_solid(&Rectangle::new(at, module), BinaryColor::On)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Draw `payload` centered in `area` at the largest scale that fits.
+///
+/// Nothing is drawn when the code does not fit even one pixel per module.
+pub fn draw_qr_in<D>(target: &mut D, payload: &str, area: Rectangle) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut temp = [0; QR_BUFFER_LEN];
+    let mut out = [0; QR_BUFFER_LEN];
+    let Ok(qr) = encode(payload, QrCodeEcc::Medium, &mut temp, &mut out) else {
+        return Ok(());
+    };
+    let scale = core::cmp::min(area.size.width, area.size.height) / side(&qr, 1);
+    if scale == 0 {
+        return Ok(());
+    }
+    let side = side(&qr, scale);
+    let top_left = Rectangle::with_center(area.center(), Size::new(side, side)).top_left;
+    draw_qr(target, &qr, top_left, scale)
+}
+
+/// Draw the screen for joining the setup access point: its name and
+/// passphrase, and a QR code phones join it from.
+pub fn draw_wifi_setup<D>(target: &mut D, ssid: &str, password: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor> + OriginDimensions,
+{
+    target.clear(BinaryColor::Off)?;
+    let size = target.size();
+    let mut y = 4;
+    for line in ["Setup: join Wi-Fi", ssid, "Password", password] {
+        let at = Point::new(size.width as i32 / 2, y);
+        y += draw_text(target, &FONT_10X20, at, line, Alignment::Center, false)? as i32;
+    }
+
+    // Every character of the SSID may need escaping
+    let mut payload = String::<{ 2 * SSID_LEN + 2 * portal::AP_PASSWORD_LEN + 16 }>::new();
+    if portal::wifi_qr(&mut payload, ssid, password).is_err() {
+        return Ok(());
+    }
+    let top = Point::new(0, y);
+    let area = Rectangle::new(
+        top,
+        Size::new(size.width, size.height.saturating_sub(y as u32)),
+    );
+    draw_qr_in(target, &payload, area)
+}
+
+/// `qr` command, drawing in the framebuffer shared with the panel.
+pub struct QrMgr<'a, M: RawMutex, T> {
+    target: &'a Mutex<M, T>,
+}
+
+impl<'a, M: RawMutex, T> QrMgr<'a, M, T> {
+    pub fn new(target: &'a Mutex<M, T>) -> Self {
+        Self { target }
+    }
+}
+
+impl<M, T> CommandHandler for QrMgr<'_, M, T>
+where
+    M: RawMutex,
+    T: DrawTarget<Color = BinaryColor, Error = Infallible> + OriginDimensions,
+{
+    fn name(&self) -> &'static str {
+        "qr"
+    }
+
+    fn usage(&self) -> &'static str {
+        "qr <x> <y> <scale> \"payload\" [L|M|Q|H]"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let args = pkg.args.as_slice();
+        let (x, y, scale, payload) = match args {
+            [x, y, scale, payload, ..] => (x, y, scale, payload),
+            _ => return Err(WRONG_ARGS),
+        };
+        let x: u32 = x.parse().map_err(|_| WRONG_ARGS)?;
+        let y: u32 = y.parse().map_err(|_| WRONG_ARGS)?;
+        let scale: u32 = scale.parse().map_err(|_| WRONG_ARGS)?;
+        if scale == 0 {
+            return Err(WRONG_ARGS);
+        }
+        let ecc = match args.get(4) {
+            Some(name) => ecc_by_name(name).ok_or(CmdError::BadArgs("Unknown ECC level"))?,
+            None => QrCodeEcc::Medium,
+        };
+
+        let mut temp = [0; QR_BUFFER_LEN];
+        let mut out = [0; QR_BUFFER_LEN];
+        let qr = encode(payload, ecc, &mut temp, &mut out).map_err(CmdError::BadArgs)?;
+
+        let mut target = self.target.lock().await;
+        // Checked before anything is multiplied by a huge scale
+        let room = target.size();
+        let fits = |at: u32, room: u32| {
+            side(&qr, 1)
+                .checked_mul(scale)
+                .and_then(|side| at.checked_add(side))
+                .is_some_and(|end| end <= room)
+        };
+        if !fits(x, room.width) || !fits(y, room.height) {
+            return Err(CmdError::BadArgs("Does not fit"));
+        }
+        let side = side(&qr, scale);
+        let Ok(()) = draw_qr(&mut *target, &qr, Point::new(x as i32, y as i32), scale);
+        write!(reply, "{}", side).map_err(|_| REPLY_TOO_LONG)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{string::String, vec::Vec};
+
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::mock::Canvas;
+
+    /// Minimal reader for the versions 1 and 2 symbols of short payloads:
+    /// one block per level, byte mode, no error correction needed since
+    /// the image is exact.
+    fn read(canvas: &Canvas) -> (QrCodeEcc, String) {
+        let ink = canvas.ink().unwrap();
+        let (x0, y0) = (ink.top_left.x as u32, ink.top_left.y as u32);
+        // The top row of the finder pattern is 7 dark modules
+        let finder = (x0..).take_while(|&x| canvas.pixel(x, y0)).count() as u32;
+        assert_eq!(finder % 7, 0);
+        let scale = finder / 7;
+        let size = ink.size.width / scale;
+        assert_eq!(ink.size.height, ink.size.width);
+        let version = (size - 17) / 4;
+        assert!(version == 1 || version == 2, "version {version}");
+
+        let dark =
+            |x: u32, y: u32| canvas.pixel(x0 + x * scale + scale / 2, y0 + y * scale + scale / 2);
+
+        // Format information next to the top left finder
+        let mut format = 0u32;
+        let spots = (0..6)
+            .map(|i| (8, i))
+            .chain([(8, 7), (8, 8), (7, 8)])
+            .chain((9..15).map(|i| (14 - i, 8)));
+        for (i, (x, y)) in spots.enumerate() {
+            format |= (dark(x, y) as u32) << i;
+        }
+        format ^= 0x5412;
+        let data = format >> 10;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        assert_eq!(format, data << 10 | rem, "format BCH");
+        let ecc = [
+            QrCodeEcc::Medium,
+            QrCodeEcc::Low,
+            QrCodeEcc::High,
+            QrCodeEcc::Quartile,
+        ][(data >> 3) as usize];
+        let mask = data & 7;
+
+        let function = |x: u32, y: u32| {
+            (x < 9 && y < 9)
+                || (x >= size - 8 && y < 9)
+                || (x < 9 && y >= size - 8)
+                || x == 6
+                || y == 6
+                || (version == 2 && x.abs_diff(18) <= 2 && y.abs_diff(18) <= 2)
+        };
+        let masked = |x: u32, y: u32| match mask {
+            0 => (x + y).is_multiple_of(2),
+            1 => y.is_multiple_of(2),
+            2 => x.is_multiple_of(3),
+            3 => (x + y).is_multiple_of(3),
+            4 => (x / 3 + y / 2).is_multiple_of(2),
+            5 => x * y % 2 + x * y % 3 == 0,
+            6 => (x * y % 2 + x * y % 3).is_multiple_of(2),
+            _ => ((x + y) % 2 + x * y % 3).is_multiple_of(2),
+        };
+
+        // Two columns at a time from the right, zigzagging up and down
+        let mut bits = Vec::new();
+        let mut right = size as i32 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vert in 0..size {
+                let y = if upward { size - 1 - vert } else { vert };
+                for x in [right as u32, right as u32 - 1] {
+                    if !function(x, y) {
+                        bits.push(dark(x, y) ^ masked(x, y));
+                    }
+                }
+            }
+            right -= 2;
+        }
+
+        let mut at = 0;
+        let mut take = |n: usize| {
+            let v = bits[at..at + n]
+                .iter()
+                .fold(0usize, |v, b| v << 1 | *b as usize);
+            at += n;
+            v
+        };
+        assert_eq!(take(4), 0b0100, "byte mode");
+        let len = take(8);
+        let payload: Vec<u8> = (0..len).map(|_| take(8) as u8).collect();
+        (ecc, String::from_utf8(payload).unwrap())
+    }
+
+    #[test]
+    fn decode_rendered() {
+        for (payload, ecc, scale) in [
+            ("hello qr", QrCodeEcc::Low, 1),
+            ("hello qr", QrCodeEcc::High, 3),
+            ("WIFI:S:home;P:secret;;", QrCodeEcc::Medium, 2),
+            ("https://example.org/dbhome", QrCodeEcc::Medium, 4),
+            ("Quartile level", QrCodeEcc::Quartile, 2),
+        ] {
+            let mut temp = [0; QR_BUFFER_LEN];
+            let mut out = [0; QR_BUFFER_LEN];
+            let qr = encode(payload, ecc, &mut temp, &mut out).unwrap();
+            let mut canvas = Canvas::new(200, 200);
+            let Ok(()) = draw_qr(&mut canvas, &qr, Point::new(7, 11), scale);
+
+            // The quiet zone is left blank around the symbol
+            let ink = canvas.ink().unwrap();
+            assert_eq!(
+                ink.top_left,
+                Point::new(7, 11) + Point::new(QUIET_ZONE, QUIET_ZONE) * scale as i32
+            );
+            assert_eq!(read(&canvas), (ecc, payload.into()), "{payload}");
+        }
+    }
+
+    #[test]
+    fn decode_widget() {
+        let mut canvas = Canvas::new(120, 100);
+        let area = Rectangle::new(Point::new(10, 5), Size::new(100, 90));
+        let Ok(()) = draw_qr_in(&mut canvas, "hello qr", area);
+        // 21 modules and the quiet zone, 3 pixels each, centered
+        let ink = canvas.ink().unwrap();
+        assert_eq!(ink.size, Size::new(63, 63));
+        assert_eq!(ink.center(), area.center());
+        assert_eq!(read(&canvas), (QrCodeEcc::Medium, "hello qr".into()));
+    }
+
+    #[test]
+    fn wifi_setup() {
+        let mut canvas = Canvas::new(400, 300);
+        let Ok(()) = draw_wifi_setup(&mut canvas, "kitchen-setup", "abcdefghjkmn");
+        canvas.assert_golden("wifi_setup");
+    }
+
+    fn run(mgr: &mut QrMgr<'_, NoopRawMutex, Canvas>, line: &str) -> Result<Reply, CmdError> {
+        let mut reply = Reply::new();
+        let pkg = ParserMgr::parse(line.as_bytes()).unwrap();
+        block_on(mgr.handle(pkg, &mut reply)).map(|()| reply)
+    }
+
+    #[test]
+    fn command() {
+        let canvas = Mutex::new(Canvas::new(400, 300));
+        let mut mgr = QrMgr::new(&canvas);
+        assert_eq!(run(&mut mgr, "qr 10 20 3 \"hello qr\" H").unwrap(), "99");
+        assert_eq!(
+            read(&block_on(canvas.lock())),
+            (QrCodeEcc::High, "hello qr".into())
+        );
+        // The symbol exactly fills the panel height
+        assert_eq!(run(&mut mgr, "qr 0 0 10 \"hello qr\" L").unwrap(), "290");
+
+        for line in [
+            "qr 0 0 11 \"hello qr\"",
+            "qr 380 0 1 \"hello qr\"",
+            "qr 0 0 4294967295 \"hello qr\"",
+            "qr 4294967295 4294967295 1 \"hello qr\"",
+            "qr 0 2147483647 2 \"hello qr\"",
+        ] {
+            assert_eq!(
+                run(&mut mgr, line),
+                Err(CmdError::BadArgs("Does not fit")),
+                "{line}"
+            );
+        }
+        for line in [
+            "qr 0 0 0 x",
+            "qr -1 0 1 x",
+            "qr 0 0 1",
+            "qr 0 0 4294967296 x",
+        ] {
+            assert_eq!(run(&mut mgr, line), Err(WRONG_ARGS), "{line}");
+        }
+        assert_eq!(
+            run(&mut mgr, "qr 0 0 1 x Z"),
+            Err(CmdError::BadArgs("Unknown ECC level"))
+        );
+    }
+}
@@ -0,0 +1,404 @@
+//! Commands run on a timetable, e.g. a full refresh at night to clear
+//! ghosting or switching pages in the evening.
+//!
+//! Each entry is a [`Cron`] spec in local time and a command line, run as
+//! if it came from a session once the clock is synced. The timetable is
+//! saved in the settings as soon as it changes.
+use core::fmt::Write as _;
+
+use dbhome_common::cron::Cron;
+use dbhome_common::tz::DateTime;
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{String, Vec};
+
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
+use crate::proto_parser::{ParserMgr, ARG_LEN};
+
+pub const MAX_ENTRIES: usize = 8;
+
+pub struct Entry {
+    cron: Cron,
+    spec: String<ARG_LEN>,
+    command: String<ARG_LEN>,
+}
+
+impl Entry {
+    pub fn spec(&self) -> &str {
+        &self.spec
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+}
+
+#[derive(Default)]
+pub struct Schedule {
+    entries: Vec<Entry, MAX_ENTRIES>,
+    /// Minute last checked, so a clock stepping back runs nothing twice.
+    checked: Option<i64>,
+}
+
+impl Schedule {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            checked: None,
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Add an entry, returns its index.
+    pub fn add(&mut self, spec: &str, command: &str) -> Result<usize, CmdError> {
+        let entry = Entry {
+            cron: Cron::parse(spec).map_err(|_| CmdError::BadArgs("Bad timetable"))?,
+            spec: String::try_from(spec).map_err(|_| CmdError::BadArgs("Timetable too long"))?,
+            command: String::try_from(command)
+                .map_err(|_| CmdError::BadArgs("Command too long"))?,
+        };
+        self.entries.push(entry).map_err(|_| "Too many entries")?;
+        Ok(self.entries.len() - 1)
+    }
+
+    /// Remove an entry, the following ones move down. Returns it so it can
+    /// be put back with [`Self::insert`].
+    pub fn remove(&mut self, idx: usize) -> Result<Entry, CmdError> {
+        if idx >= self.entries.len() {
+            return Err(CmdError::BadArgs("Unknown entry"));
+        }
+        Ok(self.entries.remove(idx))
+    }
+
+    /// Put an entry back at `idx`, the following ones move up.
+    pub fn insert(&mut self, idx: usize, entry: Entry) -> Result<(), CmdError> {
+        if idx > self.entries.len() {
+            return Err(CmdError::BadArgs("Unknown entry"));
+        }
+        self.entries
+            .insert(idx, entry)
+            .map_err(|_| CmdError::Failed("Too many entries"))
+    }
+
+    /// Commands due in the minute of `now`, each minute is only due once.
+    pub fn due(&mut self, now: &DateTime) -> Vec<String<ARG_LEN>, MAX_ENTRIES> {
+        let minute = now.to_unix().div_euclid(60);
+        if self.checked.is_some_and(|m| minute <= m) {
+            return Vec::new();
+        }
+        self.checked = Some(minute);
+
+        self.entries
+            .iter()
+            .filter(|e| e.cron.matches(now))
+            .map(|e| e.command.clone())
+            .collect()
+    }
+}
+
+/// `schedule` command, edits the timetable.
+pub struct ScheduleMgr<'a, M: RawMutex, F> {
+    schedule: &'a Mutex<M, Schedule>,
+    clock: &'a Clock,
+    config: &'a Mutex<M, Config<F>>,
+}
+
+impl<'a, M: RawMutex, F: NorFlash> ScheduleMgr<'a, M, F> {
+    pub fn new(
+        schedule: &'a Mutex<M, Schedule>,
+        clock: &'a Clock,
+        config: &'a Mutex<M, Config<F>>,
+    ) -> Self {
+        Self {
+            schedule,
+            clock,
+            config,
+        }
+    }
+
+    /// Write the timetable to the settings, without the other pending edits.
+    async fn save(&self, schedule: &Schedule) -> Result<(), CmdError> {
+        let mut config = self.config.lock().await;
+        config.set_schedule(schedule).map_err(CmdError::BadArgs)?;
+        Ok(config.save_schedule()?)
+    }
+}
+
+impl<M: RawMutex, F: NorFlash> CommandHandler for ScheduleMgr<'_, M, F> {
+    fn name(&self) -> &'static str {
+        "schedule"
+    }
+
+    fn usage(&self) -> &'static str {
+        "schedule list | schedule add \"<min> <hour> <day> <month> <weekday>\" \"command\" | schedule del <id>"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let args = pkg.args.as_slice();
+        let mut schedule = self.schedule.lock().await;
+        match args.first().map(|a| a.as_str()) {
+            None | Some("list") => {
+                let now = self.clock.local();
+                for (id, e) in schedule.entries().iter().enumerate() {
+                    if id > 0 {
+                        reply.push('\n').map_err(|_| REPLY_TOO_LONG)?;
+                    }
+                    write!(reply, "{} [{}] {}", id, e.spec, e.command)
+                        .map_err(|_| REPLY_TOO_LONG)?;
+                    if let Some(next) = now.and_then(|now| e.cron.next_after(&now)) {
+                        write!(
+                            reply,
+                            ", next {:04}-{:02}-{:02} {:02}:{:02}",
+                            next.year, next.month, next.day, next.hour, next.minute
+                        )
+                        .map_err(|_| REPLY_TOO_LONG)?;
+                    }
+                }
+                Ok(())
+            }
+            Some("add") => {
+                let [_, spec, command] = args else {
+                    return Err(WRONG_ARGS);
+                };
+                let id = schedule.add(spec, command)?;
+                if let Err(e) = self.save(&schedule).await {
+                    let _ = schedule.remove(id);
+                    let _ = self.config.lock().await.set_schedule(&schedule);
+                    return Err(e);
+                }
+                write!(reply, "{}", id).map_err(|_| REPLY_TOO_LONG)
+            }
+            Some("del") => {
+                let id = args.get(1).and_then(|a| a.parse().ok()).ok_or(WRONG_ARGS)?;
+                let entry = schedule.remove(id)?;
+                if let Err(e) = self.save(&schedule).await {
+                    let _ = schedule.insert(id, entry);
+                    let _ = self.config.lock().await.set_schedule(&schedule);
+                    return Err(e);
+                }
+                reply.push_str("Removed").map_err(|_| REPLY_TOO_LONG)
+            }
+            _ => Err(WRONG_ARGS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dbhome_common::config::{MemFlash, Store};
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_time::Instant;
+
+    use super::*;
+    use crate::dispatcher::Reply;
+
+    type Flash = MemFlash<8192>;
+
+    fn at(year: i32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        let t = DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            weekday: 0,
+        };
+        DateTime::from_unix(t.to_unix())
+    }
+
+    fn config(flash: &mut Flash) -> Mutex<NoopRawMutex, Config<&mut Flash>> {
+        Mutex::new(Config::load(Store::new(flash, 0, 8192).unwrap()))
+    }
+
+    fn run<F: NorFlash>(
+        mgr: &mut ScheduleMgr<'_, NoopRawMutex, F>,
+        line: &str,
+    ) -> Result<Reply, CmdError> {
+        let mut reply = Reply::new();
+        let pkg = ParserMgr::parse(line.as_bytes()).unwrap();
+        block_on(mgr.handle(pkg, &mut reply)).map(|()| reply)
+    }
+
+    #[test]
+    fn due() {
+        let mut schedule = Schedule::new();
+        assert_eq!(schedule.add("0 3 * * *", "epd refresh full"), Ok(0));
+        assert_eq!(schedule.add("* * * * *", "led red on"), Ok(1));
+        assert_eq!(
+            schedule.add("0 3 * *", "x"),
+            Err(CmdError::BadArgs("Bad timetable"))
+        );
+
+        assert_eq!(schedule.due(&at(2026, 1, 1, 3, 0, 0)).len(), 2);
+        // Once a minute, and never again after the clock steps back
+        assert!(schedule.due(&at(2026, 1, 1, 3, 0, 30)).is_empty());
+        assert!(schedule.due(&at(2026, 1, 1, 2, 59, 30)).is_empty());
+        assert_eq!(
+            schedule.due(&at(2026, 1, 1, 3, 1, 0)).as_slice(),
+            ["led red on"]
+        );
+
+        let first = schedule.remove(0).unwrap();
+        assert_eq!(first.command(), "epd refresh full");
+        assert_eq!(
+            schedule.remove(1).err(),
+            Some(CmdError::BadArgs("Unknown entry"))
+        );
+        assert_eq!(schedule.entries()[0].command(), "led red on");
+        schedule.insert(0, first).unwrap();
+        assert_eq!(schedule.entries()[0].command(), "epd refresh full");
+    }
+
+    #[test]
+    fn full() {
+        let mut schedule = Schedule::new();
+        for _ in 0..MAX_ENTRIES {
+            schedule.add("* * * * *", "help").unwrap();
+        }
+        assert_eq!(
+            schedule.add("* * * * *", "help"),
+            Err(CmdError::Failed("Too many entries"))
+        );
+    }
+
+    #[test]
+    fn command() {
+        let clock = Clock::new();
+        let schedule = Mutex::new(Schedule::new());
+        let mut flash = Flash::new();
+        let config = config(&mut flash);
+        let mut mgr = ScheduleMgr::new(&schedule, &clock, &config);
+
+        assert_eq!(
+            run(&mut mgr, "schedule add \"0 3 * * *\" \"epd refresh full\"").unwrap(),
+            "0"
+        );
+        assert_eq!(
+            run(&mut mgr, "schedule add \"30 7 * * 1-5\" \"led red on\"").unwrap(),
+            "1"
+        );
+        assert_eq!(
+            run(&mut mgr, "schedule list").unwrap(),
+            "0 [0 3 * * *] epd refresh full\n1 [30 7 * * 1-5] led red on"
+        );
+        clock.sync(
+            at(2026, 10, 17, 14, 3, 22).to_unix() as u64 * 1_000_000,
+            Instant::now(),
+        );
+        assert_eq!(
+            run(&mut mgr, "schedule").unwrap(),
+            "0 [0 3 * * *] epd refresh full, next 2026-10-18 03:00\n\
+             1 [30 7 * * 1-5] led red on, next 2026-10-19 07:30"
+        );
+
+        assert_eq!(run(&mut mgr, "schedule del 0").unwrap(), "Removed");
+        assert_eq!(
+            run(&mut mgr, "schedule del 1"),
+            Err(CmdError::BadArgs("Unknown entry"))
+        );
+        for line in [
+            "schedule add \"0 3 * * *\"",
+            "schedule del x",
+            "schedule run",
+        ] {
+            assert_eq!(run(&mut mgr, line), Err(WRONG_ARGS), "{line}");
+        }
+        assert_eq!(
+            run(&mut mgr, "schedule add \"0 3 * *\" help"),
+            Err(CmdError::BadArgs("Bad timetable"))
+        );
+    }
+
+    #[test]
+    fn persisted() {
+        let mut flash = Flash::new();
+        {
+            let clock = Clock::new();
+            let schedule = Mutex::new(Schedule::new());
+            let config = config(&mut flash);
+            let mut mgr = ScheduleMgr::new(&schedule, &clock, &config);
+            run(&mut mgr, "schedule add \"0 3 * * *\" \"epd refresh full\"").unwrap();
+            run(
+                &mut mgr,
+                "schedule add \"*/5 * * * *\" \"text 0 0 \\\"tick\\\"\"",
+            )
+            .unwrap();
+            run(&mut mgr, "schedule add \"0 0 1 1 *\" \"led red on\"").unwrap();
+            run(&mut mgr, "schedule del 0").unwrap();
+
+            // Other pending edits stay in RAM
+            block_on(config.lock()).set("spi.mhz", "8").unwrap();
+            run(&mut mgr, "schedule add \"0 12 * * *\" help").unwrap();
+            // Too long for a setting value
+            let long = format!("schedule add \"0 12 * * *\" \"{}\"", "x".repeat(56));
+            assert_eq!(
+                run(&mut mgr, &long),
+                Err(CmdError::BadArgs("Entry too long"))
+            );
+            assert_eq!(block_on(schedule.lock()).entries().len(), 3);
+        }
+
+        let config = config(&mut flash);
+        let config = block_on(config.lock());
+        assert_eq!(config.get("spi.mhz"), Some("4"));
+        assert_eq!(config.get("sched.0"), Some("*/5 * * * * text 0 0 \"tick\""));
+        let schedule = config.schedule();
+        let entries: std::vec::Vec<_> = schedule
+            .entries()
+            .iter()
+            .map(|e| (e.spec(), e.command()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("*/5 * * * *", "text 0 0 \"tick\""),
+                ("0 0 1 1 *", "led red on"),
+                ("0 12 * * *", "help"),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_save_rolls_back() {
+        let mut flash = Flash::new();
+        {
+            let clock = Clock::new();
+            let schedule = Mutex::new(Schedule::new());
+            let config = config(&mut flash);
+            let mut mgr = ScheduleMgr::new(&schedule, &clock, &config);
+            run(&mut mgr, "schedule add \"0 3 * * *\" \"epd refresh full\"").unwrap();
+            run(&mut mgr, "schedule add \"0 7 * * *\" \"led red on\"").unwrap();
+        }
+
+        // The flash gives out on the next write
+        flash.writes_left = Some(0);
+        let clock = Clock::new();
+        let config = config(&mut flash);
+        let schedule = Mutex::new(block_on(config.lock()).schedule());
+        let mut mgr = ScheduleMgr::new(&schedule, &clock, &config);
+        let listed = "0 [0 3 * * *] epd refresh full\n1 [0 7 * * *] led red on";
+        assert_eq!(run(&mut mgr, "schedule").unwrap(), listed);
+
+        assert!(matches!(
+            run(&mut mgr, "schedule del 0"),
+            Err(CmdError::Failed(_))
+        ));
+        assert!(matches!(
+            run(&mut mgr, "schedule add \"0 12 * * *\" help"),
+            Err(CmdError::Failed(_))
+        ));
+        // Neither the timetable nor the settings kept the edits
+        assert_eq!(run(&mut mgr, "schedule").unwrap(), listed);
+        let config = block_on(config.lock());
+        assert_eq!(config.get("sched.0"), Some("0 3 * * * epd refresh full"));
+        assert_eq!(config.get("sched.1"), Some("0 7 * * * led red on"));
+        assert_eq!(config.schedule().entries().len(), 2);
+    }
+}
@@ -0,0 +1,172 @@
+//! Book keeping of the control sessions, for the `who` command.
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use dbhome_common::reply::{Format, Status};
+use embassy_net::IpEndpoint;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG};
+use crate::proto_parser::ParserMgr;
+
+#[derive(Clone, Copy)]
+struct Session {
+    peer: IpEndpoint,
+    since: Instant,
+}
+
+/// One slot per session task, indexed by the task id.
+pub struct SessionTable<const N: usize> {
+    slots: Mutex<CriticalSectionRawMutex, RefCell<[Option<Session>; N]>>,
+}
+
+impl<const N: usize> Default for SessionTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> SessionTable<N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: Mutex::new(RefCell::new([None; N])),
+        }
+    }
+
+    pub fn open(&self, id: usize, peer: IpEndpoint) {
+        self.slots.lock(|slots| {
+            if let Some(slot) = slots.borrow_mut().get_mut(id) {
+                *slot = Some(Session {
+                    peer,
+                    since: Instant::now(),
+                });
+            }
+        });
+    }
+
+    pub fn close(&self, id: usize) {
+        self.slots.lock(|slots| {
+            if let Some(slot) = slots.borrow_mut().get_mut(id) {
+                *slot = None;
+            }
+        });
+    }
+}
+
+impl<const N: usize> CommandHandler for &SessionTable<N> {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn usage(&self) -> &'static str {
+        "who"
+    }
+
+    async fn handle(&mut self, _pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        self.slots.lock(|slots| {
+            for (id, s) in slots.borrow().iter().enumerate() {
+                let Some(s) = s else {
+                    continue;
+                };
+                if !reply.is_empty() {
+                    reply.push('\n').map_err(|_| REPLY_TOO_LONG)?;
+                }
+                let age = Instant::now().saturating_duration_since(s.since);
+                write!(reply, "{} {} {}s", id, s.peer, age.as_secs())
+                    .map_err(|_| REPLY_TOO_LONG)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Commands about the session itself, answered by the session task.
+///
+/// `format [text|json]` picks how replies are encoded on this session.
+pub fn session_cmd(pkg: &ParserMgr, format: &mut Format) -> Option<(Status, &'static str)> {
+    if pkg.cmd.as_str() != "format" {
+        return None;
+    }
+
+    let ret = match pkg.args.first().map(|a| a.as_str()) {
+        Some("text") => {
+            *format = Format::Text;
+            (Status::Ok, "text")
+        }
+        Some("json") => {
+            *format = Format::Json;
+            (Status::Ok, "json")
+        }
+        None => match format {
+            Format::Text => (Status::Ok, "text"),
+            Format::Json => (Status::Ok, "json"),
+        },
+        _ => (Status::BadArgs, "Wrong args"),
+    };
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use embassy_net::IpAddress;
+
+    fn who(mut table: &SessionTable<3>) -> Result<Reply, CmdError> {
+        let mut reply = Reply::new();
+        let pkg = ParserMgr::parse(b"who").unwrap();
+        block_on(table.handle(pkg, &mut reply)).map(|()| reply)
+    }
+
+    fn peer(last: u8, port: u16) -> IpEndpoint {
+        IpEndpoint::new(IpAddress::v4(192, 168, 1, last), port)
+    }
+
+    #[test]
+    fn open_close() {
+        let table = SessionTable::<3>::new();
+        assert_eq!(who(&table).unwrap(), "");
+
+        table.open(2, peer(20, 40000));
+        table.open(0, peer(10, 50000));
+        // Out of the table, ignored
+        table.open(3, peer(30, 60000));
+        assert_eq!(
+            who(&table).unwrap(),
+            "0 192.168.1.10:50000 0s\n2 192.168.1.20:40000 0s"
+        );
+
+        table.close(0);
+        table.close(3);
+        assert_eq!(who(&table).unwrap(), "2 192.168.1.20:40000 0s");
+
+        // A new connection on the same task replaces the old one
+        table.open(2, peer(21, 40001));
+        assert_eq!(who(&table).unwrap(), "2 192.168.1.21:40001 0s");
+        table.close(2);
+        assert_eq!(who(&table).unwrap(), "");
+    }
+
+    #[test]
+    fn format() {
+        let run = |line: &str, format: &mut Format| {
+            session_cmd(&ParserMgr::parse(line.as_bytes()).unwrap(), format)
+        };
+
+        let mut format = Format::Text;
+        assert_eq!(run("who", &mut format), None);
+        assert_eq!(run("format", &mut format), Some((Status::Ok, "text")));
+        assert_eq!(run("format json", &mut format), Some((Status::Ok, "json")));
+        assert_eq!(format, Format::Json);
+        assert_eq!(run("format", &mut format), Some((Status::Ok, "json")));
+
+        assert_eq!(
+            run("format xml", &mut format),
+            Some((Status::BadArgs, "Wrong args"))
+        );
+        assert_eq!(format, Format::Json);
+        assert_eq!(run("format text", &mut format), Some((Status::Ok, "text")));
+        assert_eq!(format, Format::Text);
+    }
+}
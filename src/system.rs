@@ -0,0 +1,35 @@
+use core::fmt::Write as _;
+
+use embassy_time::Instant;
+
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
+use crate::proto_parser::ParserMgr;
+
+/// Board wide information, not tied to a peripheral.
+#[derive(Default)]
+pub struct SystemMgr;
+
+impl SystemMgr {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl CommandHandler for SystemMgr {
+    fn name(&self) -> &'static str {
+        "system"
+    }
+
+    fn usage(&self) -> &'static str {
+        "system [status|version]"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        match pkg.args.first().map(|a| a.as_str()) {
+            None | Some("status") => write!(reply, "up {}s", Instant::now().as_secs()),
+            Some("version") => reply.write_str(env!("CARGO_PKG_VERSION")),
+            _ => return Err(WRONG_ARGS),
+        }
+        .map_err(|_| REPLY_TOO_LONG)
+    }
+}
@@ -0,0 +1,323 @@
+//! Text drawn on the device, so a message needs no host side rendering.
+use core::convert::Infallible;
+use core::fmt::Write as _;
+
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoFont, MonoTextStyleBuilder,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use heapless::String;
+use ibm437::{IBM437_8X8_BOLD, IBM437_8X8_REGULAR, IBM437_9X14_REGULAR};
+
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
+use crate::proto_parser::{ParserMgr, ARG_LEN};
+
+pub const FONTS: [(&str, &MonoFont<'static>); 5] = [
+    ("6x10", &FONT_6X10),
+    ("8x8", &IBM437_8X8_REGULAR),
+    ("8x8b", &IBM437_8X8_BOLD),
+    ("9x14", &IBM437_9X14_REGULAR),
+    ("10x20", &FONT_10X20),
+];
+
+/// The 8x8 ibm437 font.
+const DEFAULT_FONT: usize = 1;
+
+/// Index in [`FONTS`] of the font called `name`.
+pub fn font_by_name(name: &str) -> Option<usize> {
+    FONTS.iter().position(|(n, _)| *n == name)
+}
+
+/// Draw `msg` wrapped on word boundaries to fit the target width.
+///
+/// `x` is the left edge, the center or the right edge of the lines
+/// depending on `align`, `y` the top of the first line. Inverted text is
+/// white on a black background. Returns the height used.
+pub fn draw_text<D>(
+    target: &mut D,
+    font: &MonoFont<'_>,
+    point: Point,
+    msg: &str,
+    align: Alignment,
+    inverse: bool,
+) -> Result<u32, D::Error>
+where
+    D: DrawTarget<Color = BinaryColor> + OriginDimensions,
+{
+    let (fg, bg) = match inverse {
+        false => (BinaryColor::On, BinaryColor::Off),
+        true => (BinaryColor::Off, BinaryColor::On),
+    };
+    let char_style = MonoTextStyleBuilder::new()
+        .font(font)
+        .text_color(fg)
+        .background_color(bg)
+        .build();
+    let text_style = TextStyleBuilder::new()
+        .alignment(align)
+        .baseline(Baseline::Top)
+        .build();
+
+    let columns = columns(font, target.size().width, point, align);
+    let line_height = font.character_size.height;
+    let mut at = point;
+    for line in wrap(msg, columns) {
+        Text::with_text_style(line, at, char_style, text_style).draw(target)?;
+        at.y += line_height as i32;
+    }
+    Ok((at.y - point.y) as u32)
+}
+
+/// Height [`draw_text`] would use for `msg` on a target `width` wide.
+pub fn text_height(
+    font: &MonoFont<'_>,
+    width: u32,
+    point: Point,
+    msg: &str,
+    align: Alignment,
+) -> u32 {
+    let columns = columns(font, width, point, align);
+    wrap(msg, columns).count() as u32 * font.character_size.height
+}
+
+/// Room left on the line from the anchor, in whole characters.
+fn columns(font: &MonoFont<'_>, width: u32, point: Point, align: Alignment) -> usize {
+    let width = width as i32;
+    let room = match align {
+        Alignment::Left => width - point.x,
+        Alignment::Center => 2 * core::cmp::min(point.x, width - point.x),
+        Alignment::Right => point.x,
+    };
+    let advance = (font.character_size.width + font.character_spacing) as i32;
+    core::cmp::max(room / advance, 1) as usize
+}
+
+/// Lines of at most `columns` characters, words longer than that are split.
+fn wrap(msg: &str, columns: usize) -> impl Iterator<Item = &str> {
+    let mut rest = msg.trim();
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        // Byte index after `columns` characters, or the whole rest
+        let limit = rest
+            .char_indices()
+            .nth(columns)
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        let end = match rest[..limit].find('\n') {
+            Some(nl) => nl,
+            None if limit == rest.len() || rest[limit..].starts_with(' ') => limit,
+            None => match rest[..limit].rfind(' ') {
+                Some(space) if space > 0 => space,
+                _ => limit,
+            },
+        };
+
+        let line = rest[..end].trim_end();
+        rest = rest[end..].trim_start();
+        Some(line)
+    })
+}
+
+fn parse_coord(arg: Option<&String<ARG_LEN>>) -> Result<i32, CmdError> {
+    arg.and_then(|a| a.parse().ok()).ok_or(WRONG_ARGS)
+}
+
+/// `text` commands, drawing in the framebuffer shared with the panel.
+///
+/// Drawing does not refresh the panel, `epd refresh` shows the result.
+pub struct TextMgr<'a, M: RawMutex, T> {
+    target: &'a Mutex<M, T>,
+    font: usize,
+}
+
+impl<'a, M: RawMutex, T> TextMgr<'a, M, T> {
+    pub fn new(target: &'a Mutex<M, T>) -> Self {
+        Self {
+            target,
+            font: DEFAULT_FONT,
+        }
+    }
+}
+
+impl<M, T> CommandHandler for TextMgr<'_, M, T>
+where
+    M: RawMutex,
+    T: DrawTarget<Color = BinaryColor, Error = Infallible> + OriginDimensions,
+{
+    fn name(&self) -> &'static str {
+        "text"
+    }
+
+    fn usage(&self) -> &'static str {
+        "text <x> <y> \"msg\" [left|center|right] [inverse] | text clear [black] | text font [name]"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let args = pkg.args.as_slice();
+        match args.first().map(|a| a.as_str()) {
+            Some("clear") => {
+                let color = match args.get(1).map(|a| a.as_str()) {
+                    None | Some("white") => BinaryColor::Off,
+                    Some("black") => BinaryColor::On,
+                    _ => return Err(WRONG_ARGS),
+                };
+                let _ = self.target.lock().await.clear(color);
+                reply.push_str("Clear").map_err(|_| REPLY_TOO_LONG)
+            }
+            Some("font") => {
+                if let Some(name) = args.get(1) {
+                    self.font = font_by_name(name).ok_or(CmdError::BadArgs("Unknown font"))?;
+                }
+                reply
+                    .push_str(FONTS[self.font].0)
+                    .map_err(|_| REPLY_TOO_LONG)
+            }
+            _ => {
+                let x = parse_coord(args.first())?;
+                let y = parse_coord(args.get(1))?;
+                let msg = args.get(2).ok_or(WRONG_ARGS)?;
+
+                let mut align = Alignment::Left;
+                let mut inverse = false;
+                for opt in args.iter().skip(3) {
+                    match opt.as_str() {
+                        "left" => align = Alignment::Left,
+                        "center" => align = Alignment::Center,
+                        "right" => align = Alignment::Right,
+                        "inverse" => inverse = true,
+                        _ => return Err(WRONG_ARGS),
+                    }
+                }
+
+                let mut target = self.target.lock().await;
+                let font = FONTS[self.font].1;
+                let Ok(height) =
+                    draw_text(&mut *target, font, Point::new(x, y), msg, align, inverse);
+                write!(reply, "{}", height).map_err(|_| REPLY_TOO_LONG)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embedded_graphics::primitives::Rectangle;
+
+    use super::*;
+    use crate::dispatcher::{CmdError, WRONG_ARGS};
+    use crate::mock::Canvas;
+
+    fn lines(msg: &str, columns: usize) -> Vec<&str> {
+        wrap(msg, columns).collect()
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(lines("hello big world", 10), ["hello big", "world"]);
+        assert_eq!(lines("  two  spaces  ", 20), ["two  spaces"]);
+        assert_eq!(lines("abcdefghijklm", 5), ["abcde", "fghij", "klm"]);
+        assert_eq!(lines("one\ntwo three", 20), ["one", "two three"]);
+        assert_eq!(lines("ééééé é", 5), ["ééééé", "é"]);
+        assert!(lines("   ", 5).is_empty());
+    }
+
+    #[test]
+    fn golden_wrap() {
+        let mut canvas = Canvas::new(64, 40);
+        let msg = "The quick brown fox jumps";
+        let Ok(height) = draw_text(
+            &mut canvas,
+            &FONT_6X10,
+            Point::zero(),
+            msg,
+            Alignment::Left,
+            false,
+        );
+        // 10 columns: "The quick", "brown fox", "jumps"
+        assert_eq!(height, 30);
+        canvas.assert_golden("text_wrap");
+    }
+
+    #[test]
+    fn golden_align() {
+        let mut canvas = Canvas::new(80, 60);
+        let mut draw = |y, align, inverse| {
+            let Ok(_) = draw_text(
+                &mut canvas,
+                &FONT_6X10,
+                Point::new(40, y),
+                "ab",
+                align,
+                inverse,
+            );
+        };
+        draw(0, Alignment::Left, false);
+        draw(12, Alignment::Center, false);
+        draw(24, Alignment::Right, false);
+        draw(40, Alignment::Center, true);
+        canvas.assert_golden("text_align");
+
+        let mut canvas = Canvas::new(80, 20);
+        let Ok(_) = draw_text(
+            &mut canvas,
+            &FONT_6X10,
+            Point::new(79, 0),
+            "ab",
+            Alignment::Right,
+            false,
+        );
+        let ink = canvas.ink().unwrap();
+        assert!(
+            ink.top_left.x >= 67 && ink.bottom_right().unwrap().x <= 79,
+            "{ink:?}"
+        );
+    }
+
+    fn run(mgr: &mut TextMgr<'_, NoopRawMutex, Canvas>, line: &str) -> Result<Reply, CmdError> {
+        let mut reply = Reply::new();
+        let pkg = ParserMgr::parse(line.as_bytes()).unwrap();
+        block_on(mgr.handle(pkg, &mut reply)).map(|()| reply)
+    }
+
+    #[test]
+    fn command() {
+        let canvas = Mutex::new(Canvas::new(100, 40));
+        let mut mgr = TextMgr::new(&canvas);
+        assert_eq!(run(&mut mgr, "text font 6x10").unwrap(), "6x10");
+        assert_eq!(run(&mut mgr, "text font").unwrap(), "6x10");
+        assert_eq!(run(&mut mgr, "text 2 3 \"hi there\"").unwrap(), "10");
+        let ink = block_on(canvas.lock()).ink().unwrap();
+        assert!(Rectangle::new(Point::new(2, 3), Size::new(48, 10)).contains(ink.top_left));
+
+        assert_eq!(run(&mut mgr, "text clear black").unwrap(), "Clear");
+        assert!(block_on(canvas.lock()).pixel(99, 39));
+        run(&mut mgr, "text clear").unwrap();
+        assert_eq!(block_on(canvas.lock()).ink(), None);
+
+        assert_eq!(
+            run(&mut mgr, "text font 7x7"),
+            Err(CmdError::BadArgs("Unknown font"))
+        );
+        for line in [
+            "text x 0 \"a\"",
+            "text 0 0",
+            "text 0 0 a sideways",
+            "text clear grey",
+        ] {
+            assert_eq!(run(&mut mgr, line), Err(WRONG_ARGS), "{line}");
+        }
+    }
+}
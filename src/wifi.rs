@@ -0,0 +1,258 @@
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use dbhome_common::provision::{self, Credentials, Network, MAX_NETWORKS, SCAN_LEN, SSID_LEN};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
+use embassy_time::{with_timeout, Duration, Instant};
+use embedded_storage::nor_flash::NorFlash;
+use esp_wifi::wifi::{WifiDevice, WifiStaDevice};
+use heapless::{String, Vec};
+
+use crate::config::Config;
+use crate::dispatcher::{CmdError, CommandHandler, Reply, REPLY_TOO_LONG, WRONG_ARGS};
+use crate::proto_parser::{ParserMgr, MAX_ARGS};
+use crate::provision::{Provisioner, Request};
+
+/// Longest wait for `wifi scan`.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Station link as last seen by the connection task.
+#[derive(Clone, Debug, Default)]
+pub struct LinkState {
+    /// Network joined or being joined.
+    pub ssid: Option<String<SSID_LEN>>,
+    /// Signal of that network at the last scan, in dBm.
+    pub rssi: Option<i8>,
+    /// Attempts failed in a row.
+    pub failures: u32,
+    pub retry_at: Option<Instant>,
+    /// When the current address was obtained.
+    pub addressed_at: Option<Instant>,
+}
+
+pub struct Link {
+    state: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<LinkState>>,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Self {
+            state: blocking_mutex::Mutex::new(RefCell::new(LinkState {
+                ssid: None,
+                rssi: None,
+                failures: 0,
+                retry_at: None,
+                addressed_at: None,
+            })),
+        }
+    }
+
+    pub fn get(&self) -> LinkState {
+        self.state.lock(|s| s.borrow().clone())
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut LinkState)) {
+        self.state.lock(|s| f(&mut s.borrow_mut()))
+    }
+}
+
+/// `wifi` command, shows the link and edits the saved networks. Edits are
+/// saved at once, without the pending `config set` ones.
+pub struct WifiMgr<'a, M: RawMutex, F> {
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    link: &'a Link,
+    provisioner: &'a Provisioner,
+    config: &'a Mutex<M, Config<F>>,
+}
+
+impl<'a, M: RawMutex, F> WifiMgr<'a, M, F> {
+    pub fn new(
+        stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+        link: &'a Link,
+        provisioner: &'a Provisioner,
+        config: &'a Mutex<M, Config<F>>,
+    ) -> Self {
+        Self {
+            stack,
+            link,
+            provisioner,
+            config,
+        }
+    }
+}
+
+impl<M: RawMutex, F: NorFlash> WifiMgr<'_, M, F> {
+    fn status(&self, config: &Config<F>, reply: &mut Reply) -> core::fmt::Result {
+        let link = self.link.get();
+        match self.stack.config_v4() {
+            Some(v4) if self.stack.is_link_up() => write!(reply, "up {}", v4.address)?,
+            _ if self.stack.is_link_up() => reply.write_str("link up, no address")?,
+            _ => reply.write_str("down")?,
+        }
+        if let Some(ssid) = &link.ssid {
+            write!(reply, " {}", ssid)?;
+        }
+        if let Some(rssi) = link.rssi {
+            write!(reply, " {}dBm", rssi)?;
+        }
+        if link.failures > 0 {
+            write!(reply, ", {} failures", link.failures)?;
+        }
+        if let Some(at) = link.retry_at {
+            let left = at.saturating_duration_since(Instant::now());
+            write!(reply, ", retry in {}s", left.as_secs())?;
+        }
+        for known in config.networks().iter() {
+            write!(reply, "\n{} {}", known.priority, known.credentials.ssid)?;
+        }
+        Ok(())
+    }
+
+    async fn scan(&self, reply: &mut Reply) -> Result<(), CmdError> {
+        with_timeout(SCAN_TIMEOUT, self.provisioner.scan())
+            .await
+            .map_err(|_| "Scan failed")?;
+        let mut value = [0; SCAN_LEN];
+        let len = self.provisioner.read_scan(0, &mut value);
+        let mut found: Vec<Network, MAX_NETWORKS> =
+            provision::networks(&value[..len]).flatten().collect();
+        found.sort_unstable_by_key(|n| -(n.rssi as i16));
+
+        // Strongest first, as many as fit
+        for (i, network) in found.iter().enumerate() {
+            let mut line: String<48> = String::new();
+            let open = if network.secured { "" } else { " open" };
+            let _ = write!(line, "{} {}{}", network.rssi, network.ssid, open);
+            if (i > 0 && reply.push('\n').is_err()) || reply.push_str(&line).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M: RawMutex, F: NorFlash> CommandHandler for WifiMgr<'_, M, F> {
+    fn name(&self) -> &'static str {
+        "wifi"
+    }
+
+    fn usage(&self) -> &'static str {
+        "wifi [status] | wifi scan | wifi add \"ssid\" \"password\" [priority] | wifi forget \"ssid\""
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let args: Vec<&str, MAX_ARGS> = pkg.args.iter().map(|a| a.as_str()).collect();
+        match args.as_slice() {
+            [] | ["status"] => {
+                let config = self.config.lock().await;
+                self.status(&config, reply).map_err(|_| REPLY_TOO_LONG)
+            }
+            ["scan"] => self.scan(reply).await,
+            ["add", ssid, password, priority @ ..] => {
+                let priority = match priority {
+                    [] => 0,
+                    [p] => p.parse().map_err(|_| CmdError::BadArgs("Bad priority"))?,
+                    _ => return Err(WRONG_ARGS),
+                };
+                let credentials = Credentials::new(ssid, password)
+                    .map_err(|_| CmdError::BadArgs("Bad credentials"))?;
+                let mut config = self.config.lock().await;
+                let mut networks = config.networks();
+                networks
+                    .add(credentials, priority)
+                    .map_err(|_| "Too many networks")?;
+                config.set_networks(&networks)?;
+                config.save_networks()?;
+                self.provisioner.post(Request::Reload);
+                reply.push_str("Saved").map_err(|_| REPLY_TOO_LONG)
+            }
+            ["forget", ssid] => {
+                let mut config = self.config.lock().await;
+                let mut networks = config.networks();
+                if !networks.forget(ssid) {
+                    return Err(CmdError::BadArgs("Unknown network"));
+                }
+                config.set_networks(&networks)?;
+                config.save_networks()?;
+                self.provisioner.post(Request::Reload);
+                reply.push_str("Forgotten").map_err(|_| REPLY_TOO_LONG)
+            }
+            _ => Err(WRONG_ARGS),
+        }
+    }
+}
+
+/// `net` command, shows the IPv4 configuration of the station.
+pub struct NetMgr<'a> {
+    stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+    link: &'a Link,
+    hostname: &'a str,
+    /// Whether the address comes from DHCP rather than the settings.
+    dhcp: bool,
+}
+
+impl<'a> NetMgr<'a> {
+    pub fn new(
+        stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
+        link: &'a Link,
+        hostname: &'a str,
+        dhcp: bool,
+    ) -> Self {
+        Self {
+            stack,
+            link,
+            hostname,
+            dhcp,
+        }
+    }
+
+    fn status(&self, reply: &mut Reply) -> core::fmt::Result {
+        let mode = if self.dhcp { "dhcp" } else { "static" };
+        write!(reply, "{} {}", mode, self.hostname)?;
+        let Some(v4) = self.stack.config_v4() else {
+            return reply.write_str("\nno address");
+        };
+        write!(reply, "\naddress {}", v4.address)?;
+        if let Some(gateway) = v4.gateway {
+            write!(reply, "\ngateway {}", gateway)?;
+        }
+        if !v4.dns_servers.is_empty() {
+            reply.write_str("\ndns")?;
+            for server in v4.dns_servers.iter() {
+                write!(reply, " {}", server)?;
+            }
+        }
+        // embassy-net renews the lease but does not tell its length, only
+        // when the address was obtained is known
+        if let (true, Some(at)) = (self.dhcp, self.link.get().addressed_at) {
+            write!(reply, "\naddressed {}s ago", at.elapsed().as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+impl CommandHandler for NetMgr<'_> {
+    fn name(&self) -> &'static str {
+        "net"
+    }
+
+    fn usage(&self) -> &'static str {
+        "net [status]"
+    }
+
+    async fn handle(&mut self, pkg: ParserMgr, reply: &mut Reply) -> Result<(), CmdError> {
+        let args: Vec<&str, MAX_ARGS> = pkg.args.iter().map(|a| a.as_str()).collect();
+        match args.as_slice() {
+            [] | ["status"] => self.status(reply).map_err(|_| REPLY_TOO_LONG),
+            _ => Err(WRONG_ARGS),
+        }
+    }
+}